@@ -0,0 +1,484 @@
+use anyhow::{Context, Result};
+use rand::RngCore;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+use x25519_dalek::StaticSecret;
+
+use crate::chunked::{ChunkReader, ChunkWriter};
+use crate::crypto::{
+    decrypt_file, decrypt_with_aad, derive_legacy_password_key, open_password_key, open_sealed_key,
+    DataKey,
+};
+use crate::error::ArchiveError;
+use crate::header::{ArchiveHeader, KeyInfo};
+use crate::manifest::{self, EntryDigests, IntegrityCheck, Manifest, ManifestEntry, MANIFEST_ENTRY};
+
+pub const EVENTS_ENTRY: &str = "events.json.enc";
+pub const SUMMARY_ENTRY: &str = "summary.json.enc";
+pub const METADATA_ENTRY: &str = "metadata.json.enc";
+pub const TERMINAL_OUTPUT_ENTRY: &str = "terminal_output.log.enc";
+pub const TERMINAL_STREAM_ENTRY: &str = "terminal_stream.cast.enc";
+pub const PASTES_ENTRY: &str = "pastes.json.enc";
+pub const COMMANDS_ENTRY: &str = "commands.json.enc";
+pub const PROCESSES_ENTRY: &str = "processes.json.enc";
+pub const CONNECTIONS_ENTRY: &str = "connections.json.enc";
+pub const FILE_HISTORY_ENTRY: &str = "file_history.json.enc";
+pub const EVENT_CHAIN_ENTRY: &str = "event_chain.json.enc";
+pub const COLLECTED_FILES_ENTRY: &str = "collected_files.json.enc";
+pub const STATE_COPY_ENTRY: &str = "state_copy.json.enc";
+
+/// Archive entry holding the contents of the `index`th collected file.
+pub fn collected_file_entry(index: usize) -> String {
+    format!("files/{}.enc", index)
+}
+
+/// Archive entry holding the gzip-compressed contents of the `index`th
+/// file version stored.
+pub fn file_version_entry(index: usize) -> String {
+    format!("history/{}.gz.enc", index)
+}
+
+/// Key material used to open an exam archive.
+pub enum ArchiveKey {
+    /// Instructor private key for archives sealed to its public half.
+    Private(StaticSecret),
+    /// Password for password-protected archives, or the instructor
+    /// password for archives written by older recorders.
+    Password(String),
+}
+
+/// Builds an archive on disk: each entry is encrypted as it is added and
+/// stored in a ZIP in an unnamed file, and the manifest is appended when
+/// the archive is finished. Nothing has to fit in memory at once.
+pub struct ArchiveWriter<'a> {
+    key: &'a DataKey,
+    spool_dir: &'a Path,
+    zip: zip::ZipWriter<File>,
+    manifest: Vec<ManifestEntry>,
+}
+
+impl<'a> ArchiveWriter<'a> {
+    /// Starts an archive whose working files are kept in `spool_dir`.
+    pub fn new(key: &'a DataKey, spool_dir: &'a Path) -> Result<Self> {
+        Ok(ArchiveWriter {
+            key,
+            spool_dir,
+            zip: zip::ZipWriter::new(unnamed_file(spool_dir)?),
+            manifest: Vec::new(),
+        })
+    }
+
+    /// Encrypts `data` and adds it as the entry `name`.
+    pub fn add(&mut self, name: impl Into<String>, data: &[u8]) -> Result<()> {
+        self.add_reader(name, data)
+    }
+
+    /// Adds `value` as pretty-printed JSON.
+    pub fn add_json<T: Serialize>(&mut self, name: impl Into<String>, value: &T) -> Result<()> {
+        let name = name.into();
+        let mut entry = self.start_entry(&name)?;
+        serde_json::to_writer_pretty(&mut entry, value)?;
+        let listed = entry.finish()?.into_entry(name);
+        self.manifest.push(listed);
+        Ok(())
+    }
+
+    /// Encrypts everything `data` yields and adds it as the entry `name`.
+    pub fn add_reader(&mut self, name: impl Into<String>, mut data: impl Read) -> Result<()> {
+        let name = name.into();
+        let mut entry = self.start_entry(&name)?;
+        io::copy(&mut data, &mut entry)
+            .with_context(|| format!("Failed to write {}", name))?;
+        let listed = entry.finish()?.into_entry(name);
+        self.manifest.push(listed);
+        Ok(())
+    }
+
+    /// Starts an entry that is written a piece at a time, in parallel with
+    /// others, and added with `add_spool` once it is complete.
+    pub fn spool(&self, name: impl Into<String>) -> Result<EntrySpool> {
+        let name = name.into();
+        let file = BufWriter::new(unnamed_file(self.spool_dir)?);
+        let writer = ChunkWriter::new(file, self.key, name.as_bytes());
+        Ok(EntrySpool { name, writer })
+    }
+
+    /// Adds an entry written with `spool`.
+    pub fn add_spool(&mut self, spool: EntrySpool) -> Result<()> {
+        let mut file = spool.writer.finish()?
+            .into_inner()
+            .map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+
+        self.zip.start_file(spool.name.as_str(), entry_options())
+            .context("Failed to start zip file entry")?;
+        let mut stored = Stored::new(&mut self.zip);
+        io::copy(&mut file, &mut stored)
+            .context("Failed to write zip file data")?;
+        self.manifest.push(stored.into_entry(spool.name));
+        Ok(())
+    }
+
+    fn start_entry(&mut self, name: &str) -> Result<ChunkWriter<Stored<'_>>> {
+        self.zip.start_file(name, entry_options())
+            .context("Failed to start zip file entry")?;
+        Ok(ChunkWriter::new(Stored::new(&mut self.zip), self.key, name.as_bytes()))
+    }
+
+    /// Appends the manifest and writes the archive to `out`: the header,
+    /// naming `recorder_version` and carrying the envelope the data key can
+    /// be recovered from (`seal_key` or `password_key`), then the ZIP
+    /// encrypted with the data key.
+    pub fn finish(mut self, recorder_version: &str, envelope: &[u8], out: impl Write) -> Result<()> {
+        // List every entry with its size and hash so the viewer can check
+        // each one, and in what order they were written
+        let manifest = Manifest::new(self.manifest)?;
+        self.zip.start_file(MANIFEST_ENTRY, entry_options())
+            .context("Failed to start zip file entry")?;
+        self.zip.write_all(&manifest.to_json()?)
+            .context("Failed to write zip file data")?;
+        let mut zip_file = self.zip.finish()
+            .context("Failed to finish zip file")?;
+
+        let zip_len = zip_file.seek(SeekFrom::End(0))?;
+        zip_file.seek(SeekFrom::Start(0))?;
+        let header = ArchiveHeader::encode(recorder_version, envelope, zip_len)?;
+
+        let mut out = BufWriter::new(out);
+        out.write_all(&header)
+            .context("Failed to write archive")?;
+        let mut payload = ChunkWriter::new(out, self.key, &header);
+        io::copy(&mut zip_file, &mut payload)
+            .context("Failed to write archive")?;
+        payload.finish()
+            .context("Failed to write archive")?
+            .flush()
+            .context("Failed to write archive")?;
+        Ok(())
+    }
+}
+
+/// An archive entry being written to an unnamed file, encrypted as it goes.
+pub struct EntrySpool {
+    name: String,
+    writer: ChunkWriter<BufWriter<File>>,
+}
+
+impl Write for EntrySpool {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.writer.write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Entries are ciphertext, which does not compress.
+fn entry_options() -> zip::write::FileOptions {
+    zip::write::FileOptions::default()
+        .compression_method(zip::CompressionMethod::Stored)
+        .large_file(true)
+}
+
+/// Passes an entry's stored bytes on to the ZIP, measuring them for the
+/// manifest.
+struct Stored<'a> {
+    zip: &'a mut zip::ZipWriter<File>,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<'a> Stored<'a> {
+    fn new(zip: &'a mut zip::ZipWriter<File>) -> Self {
+        Stored { zip, hasher: Sha256::new(), size: 0 }
+    }
+
+    fn into_entry(self, name: String) -> ManifestEntry {
+        ManifestEntry {
+            name,
+            size: self.size,
+            sha256: hex::encode(self.hasher.finalize()),
+        }
+    }
+}
+
+impl Write for Stored<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = self.zip.write(data)?;
+        self.hasher.update(&data[..len]);
+        self.size += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.zip.flush()
+    }
+}
+
+/// An opened archive: its header, its ZIP of still encrypted entries,
+/// unpacked into an unnamed file, and the data key to decrypt them with.
+pub struct ArchiveReader {
+    header: ArchiveHeader,
+    zip: zip::ZipArchive<SharedFile>,
+    /// Entry names in ZIP order.
+    names: Vec<String>,
+    key: DataKey,
+}
+
+impl ArchiveReader {
+    /// Opens an archive, unpacking its payload into the system temporary
+    /// directory. Archives from before format 4 are encrypted in one piece
+    /// and have to be decrypted in memory.
+    pub fn open(mut archive: impl Read + Seek, key: &ArchiveKey) -> Result<Self> {
+        let header = ArchiveHeader::read(&mut archive)?;
+        let envelope = &header.bytes[header.envelope.clone()];
+        let data_key = match (&header.key, key) {
+            (KeyInfo::Sealed { .. }, ArchiveKey::Private(secret)) => open_sealed_key(envelope, secret)?,
+            (KeyInfo::Password { .. }, ArchiveKey::Password(password)) => open_password_key(envelope, password)?,
+            (KeyInfo::LegacyPassword, ArchiveKey::Password(password)) => derive_legacy_password_key(password),
+            (KeyInfo::Sealed { key_id }, ArchiveKey::Password(_)) => {
+                return Err(ArchiveError::WrongKey(format!(
+                    "archive is sealed to instructor key {}; use --key <private key file>",
+                    hex::encode(key_id)
+                )).into());
+            }
+            (_, ArchiveKey::Private(_)) => {
+                return Err(ArchiveError::WrongKey("archive is protected by a password, not an instructor key".into()).into());
+            }
+        };
+
+        let not_authentic = || match header.key {
+            // Without a wrapped key, a wrong password only shows here
+            KeyInfo::LegacyPassword => ArchiveError::WrongKey(
+                "the password is wrong, or this is not an exam archive (it has no header)".into(),
+            ),
+            KeyInfo::Password { version: 1, .. } => ArchiveError::WrongKey(
+                "the password is wrong, or the archive is corrupted".into(),
+            ),
+            _ => ArchiveError::Corrupted("the encrypted payload does not authenticate".into()),
+        };
+        let mut zip_file = unnamed_file(&std::env::temp_dir())?;
+        if header.is_chunked() {
+            let mut payload = Tracked::new(ChunkReader::new(&mut archive, &data_key, &header.bytes));
+            let copied = io::copy(&mut payload, &mut zip_file);
+            if payload.failed {
+                return Err(not_authentic().into());
+            }
+            copied.context("Failed to unpack archive")?;
+        } else {
+            let mut encrypted = Vec::new();
+            archive.read_to_end(&mut encrypted)
+                .context("Failed to read archive")?;
+            let aad = if header.is_authenticated() { header.bytes.as_slice() } else { &[] };
+            let zip_data = decrypt_with_aad(&encrypted, &data_key, aad).map_err(|_| not_authentic())?;
+            zip_file.write_all(&zip_data)
+                .context("Failed to unpack archive")?;
+        }
+        zip_file.seek(SeekFrom::Start(0))?;
+
+        let corrupted = |_| ArchiveError::Corrupted("the payload is not a valid ZIP".into());
+        let mut zip = zip::ZipArchive::new(SharedFile::new(zip_file)?)
+            .map_err(corrupted)?;
+        let names = (0..zip.len())
+            .map(|i| zip.by_index_raw(i).map(|file| file.name().to_string()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(corrupted)?;
+
+        Ok(ArchiveReader { header, zip, names, key: data_key })
+    }
+
+    pub fn header(&self) -> &ArchiveHeader {
+        &self.header
+    }
+
+    /// Entry names, in ZIP order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(|name| name.as_str())
+    }
+
+    /// Checks every entry against the archive's manifest.
+    pub fn check_integrity(&mut self) -> Result<IntegrityCheck> {
+        let mut digests = EntryDigests::default();
+        for i in 0..self.zip.len() {
+            let file = self.zip.by_index(i)
+                .map_err(|_| ArchiveError::Corrupted("the payload is not a valid ZIP".into()))?;
+            let name = file.name().to_string();
+            digests.add(&name, file)
+                .map_err(|e| ArchiveError::CorruptedEntry { entry: name, reason: e.to_string() })?;
+        }
+        manifest::check(digests)
+    }
+
+    /// Decrypts the entry `name` as it is read by `read`, if the archive has
+    /// it. An entry that fails to decrypt part way is reported as corrupted,
+    /// whatever `read` made of it.
+    pub fn read_entry<T>(
+        &mut self,
+        name: &str,
+        read: impl FnOnce(&mut dyn BufRead) -> Result<T>,
+    ) -> Result<Option<T>> {
+        let Some(mut contents) = open_entry(&mut self.zip, &self.header, &self.key, name)? else {
+            return Ok(None);
+        };
+        let result = read(&mut contents);
+        if contents.get_ref().failed {
+            return Err(entry_corrupted(name).into());
+        }
+        result.map(Some)
+    }
+
+    /// Like `read_entry`, but also decrypts the entry `other` alongside
+    /// `name`, so the two can be read in step. `read` gets `None` for
+    /// `other` if the archive does not have it.
+    pub fn read_entries<T>(
+        &mut self,
+        name: &str,
+        other: &str,
+        read: impl FnOnce(&mut dyn BufRead, Option<&mut dyn BufRead>) -> Result<T>,
+    ) -> Result<Option<T>> {
+        // A second handle on the ZIP, reading at its own position
+        let mut other_zip = self.zip.clone();
+        let Some(mut contents) = open_entry(&mut self.zip, &self.header, &self.key, name)? else {
+            return Ok(None);
+        };
+        let mut other_contents = open_entry(&mut other_zip, &self.header, &self.key, other)?;
+        let result = read(&mut contents, other_contents.as_mut().map(|c| c as &mut dyn BufRead));
+        if contents.get_ref().failed {
+            return Err(entry_corrupted(name).into());
+        }
+        if other_contents.is_some_and(|c| c.get_ref().failed) {
+            return Err(entry_corrupted(other).into());
+        }
+        result.map(Some)
+    }
+
+    /// Decrypted contents of the entry `name`, if the archive has it.
+    pub fn decrypt(&mut self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.read_entry(name, |contents| {
+            let mut data = Vec::new();
+            contents.read_to_end(&mut data)?;
+            Ok(data)
+        })
+    }
+
+    /// Decrypts and parses the JSON entry `name`, if the archive has it.
+    pub fn read_json<T: DeserializeOwned>(&mut self, name: &str) -> Result<Option<T>> {
+        self.read_entry(name, |contents| {
+            serde_json::from_reader(contents).with_context(|| format!("Failed to parse {}", name))
+        })
+    }
+}
+
+/// Decrypted contents of an entry, as they are read.
+type EntryContents<'a> = BufReader<Tracked<Box<dyn Read + 'a>>>;
+
+/// Starts decrypting the entry `name` of `zip`, if it has one.
+fn open_entry<'a>(
+    zip: &'a mut zip::ZipArchive<SharedFile>,
+    header: &ArchiveHeader,
+    key: &'a DataKey,
+    name: &str,
+) -> Result<Option<EntryContents<'a>>> {
+    let file = match zip.by_name(name) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(_) => return Err(ArchiveError::Corrupted("the payload is not a valid ZIP".into()).into()),
+    };
+
+    let contents: Box<dyn Read> = if header.is_chunked() {
+        Box::new(ChunkReader::new(file, key, name.as_bytes()))
+    } else {
+        let mut encrypted = Vec::new();
+        let mut file = file;
+        file.read_to_end(&mut encrypted)
+            .context("Failed to read ZIP file contents")?;
+        Box::new(io::Cursor::new(decrypt_file(&encrypted, key).map_err(|_| entry_corrupted(name))?))
+    };
+    Ok(Some(BufReader::new(Tracked::new(contents))))
+}
+
+fn entry_corrupted(name: &str) -> ArchiveError {
+    ArchiveError::CorruptedEntry {
+        entry: name.to_string(),
+        reason: "does not decrypt".into(),
+    }
+}
+
+/// The unpacked ZIP. Each clone reads at its own position, so entries can
+/// be read side by side.
+#[derive(Clone)]
+struct SharedFile {
+    file: Arc<File>,
+    len: u64,
+    pos: u64,
+}
+
+impl SharedFile {
+    fn new(file: File) -> Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(SharedFile { file: Arc::new(file), len, pos: 0 })
+    }
+}
+
+impl Read for SharedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for SharedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the file"))?;
+        Ok(self.pos)
+    }
+}
+
+/// Remembers whether reading failed, so decryption errors can be told apart
+/// from what the caller does with the data.
+struct Tracked<R> {
+    inner: R,
+    failed: bool,
+}
+
+impl<R> Tracked<R> {
+    fn new(inner: R) -> Self {
+        Tracked { inner, failed: false }
+    }
+}
+
+impl<R: Read> Read for Tracked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).inspect_err(|_| self.failed = true)
+    }
+}
+
+/// Creates a file in `dir` and unlinks it straight away, so it is gone once
+/// closed even if the process dies. Only ever holds encrypted data.
+fn unnamed_file(dir: &Path) -> Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let path = dir.join(format!(".exam-spool-{}-{:016x}", std::process::id(), rand::thread_rng().next_u64()));
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .with_context(|| format!("Failed to create working file in {}", dir.display()))?;
+    std::fs::remove_file(&path)
+        .with_context(|| format!("Failed to create working file in {}", dir.display()))?;
+    Ok(file)
+}
@@ -0,0 +1,118 @@
+use anyhow::{Context, Result};
+use serde::de::{self, Deserializer as _, IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::Read;
+
+use crate::json::Elements;
+
+/// Domain separation for checkpoint signatures.
+const CHECKPOINT_CONTEXT: &[u8] = b"exam-recorder-suite/checkpoint/v1";
+
+/// One record of the event chain. The record itself stays in its archive
+/// entry (events, commands, pastes, processes, connections, file history
+/// or terminal output); the link fixes its position and contents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainLink {
+    pub seq: u64,
+    /// "keystroke", "command", "paste", "process", "connection",
+    /// "file_version" or "output".
+    pub kind: String,
+    pub timestamp: u64,
+    /// Length in bytes, for output chunks.
+    #[serde(default)]
+    pub len: Option<usize>,
+    /// SHA-256 of the record: canonical JSON for events, raw bytes for output.
+    pub hash: String,
+    /// Chain hash up to the previous record (zeros for the first).
+    pub prev: String,
+}
+
+/// Chain head signed by the recorder's session key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Number of records covered.
+    pub records: u64,
+    pub timestamp: u64,
+    /// Chain hash after the last covered record.
+    pub head: String,
+    pub signature: String,
+}
+
+/// The archive's `event_chain.json` entry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainLog {
+    pub links: Vec<ChainLink>,
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+impl ChainLog {
+    /// Reads an `event_chain.json` entry, passing each link to `each` as it
+    /// is parsed instead of collecting them, and returns the checkpoints.
+    /// An error from `each` stops the read and is returned as is.
+    pub fn read_links(reader: impl Read, each: impl FnMut(ChainLink) -> Result<()>) -> Result<Vec<Checkpoint>> {
+        let mut failed = None;
+        let mut deserializer = serde_json::Deserializer::from_reader(reader);
+        let parsed = deserializer.deserialize_map(LinksVisitor { each, failed: &mut failed })
+            .and_then(|checkpoints| deserializer.end().map(|()| checkpoints));
+        match failed {
+            Some(e) => Err(e),
+            None => parsed.context("Invalid event chain"),
+        }
+    }
+}
+
+/// Walks the fields of a `ChainLog`, streaming its links.
+struct LinksVisitor<'a, F> {
+    each: F,
+    failed: &'a mut Option<anyhow::Error>,
+}
+
+impl<'de, F: FnMut(ChainLink) -> Result<()>> Visitor<'de> for LinksVisitor<'_, F> {
+    type Value = Vec<Checkpoint>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an event chain")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Vec<Checkpoint>, A::Error> {
+        let mut each = Some(self.each);
+        let mut checkpoints = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "links" => {
+                    let each = each.take().ok_or_else(|| de::Error::duplicate_field("links"))?;
+                    map.next_value_seed(Elements::new(each, &mut *self.failed))?;
+                }
+                "checkpoints" => checkpoints = Some(map.next_value()?),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        if each.is_some() {
+            return Err(de::Error::missing_field("links"));
+        }
+        checkpoints.ok_or_else(|| de::Error::missing_field("checkpoints"))
+    }
+}
+
+/// Bytes signed for a checkpoint.
+pub fn checkpoint_message(records: u64, timestamp: u64, head: &[u8; 32]) -> Vec<u8> {
+    let mut message = CHECKPOINT_CONTEXT.to_vec();
+    message.extend_from_slice(&records.to_le_bytes());
+    message.extend_from_slice(&timestamp.to_le_bytes());
+    message.extend_from_slice(head);
+    message
+}
+
+/// Chain hash after appending the record `hash` of `kind` at `seq`.
+pub fn next_head(prev: &[u8; 32], seq: u64, kind: &str, hash: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(prev);
+    hasher.update(seq.to_le_bytes());
+    hasher.update(kind.as_bytes());
+    hasher.update(hash);
+    hasher.finalize().into()
+}
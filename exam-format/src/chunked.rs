@@ -0,0 +1,209 @@
+//! Chunked AES-GCM for data that should not have to fit in memory: the
+//! payload and entries of archives written in format 4 and later.
+//!
+//! The plaintext is cut into `CHUNK_LEN` pieces, each stored as nonce ||
+//! AES-GCM(piece) and authenticated together with the caller's AAD, the
+//! chunk's index and whether it is the last one. The last chunk is always
+//! shorter than `CHUNK_LEN`, possibly empty, so a reordered, dropped or cut
+//! off chunk fails to authenticate.
+
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, Nonce,
+};
+use rand::RngCore;
+use std::io::{self, Read, Write};
+
+use crate::crypto::DataKey;
+
+/// Plaintext bytes per chunk.
+pub const CHUNK_LEN: usize = 64 * 1024;
+/// Nonce and tag around each chunk.
+const CHUNK_OVERHEAD: usize = 12 + 16;
+
+/// Encrypted length of `len` bytes of plaintext.
+pub fn encrypted_len(len: u64) -> u64 {
+    let chunks = len / CHUNK_LEN as u64 + 1;
+    len + chunks * CHUNK_OVERHEAD as u64
+}
+
+/// Encrypts everything written to it into `inner`. `finish` must be called
+/// to write the last chunk.
+pub struct ChunkWriter<W: Write> {
+    inner: W,
+    cipher: Aes256Gcm,
+    aad: Vec<u8>,
+    index: u64,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> ChunkWriter<W> {
+    pub fn new(inner: W, key: &DataKey, aad: &[u8]) -> Self {
+        ChunkWriter {
+            inner,
+            cipher: key.cipher(),
+            aad: aad.to_vec(),
+            index: 0,
+            buffer: Vec::with_capacity(CHUNK_LEN),
+        }
+    }
+
+    /// Writes the last chunk and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_chunk(&mut self, last: bool) -> io::Result<()> {
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let aad = chunk_aad(&self.aad, self.index, last);
+        let ciphertext = self.cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: &self.buffer, aad: &aad })
+            .map_err(|e| io::Error::other(format!("Encryption failed: {}", e)))?;
+
+        self.inner.write_all(&nonce)?;
+        self.inner.write_all(&ciphertext)?;
+        self.index += 1;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ChunkWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = data.len().min(CHUNK_LEN - self.buffer.len());
+        self.buffer.extend_from_slice(&data[..len]);
+        // A full chunk is never the last one
+        if self.buffer.len() == CHUNK_LEN {
+            self.write_chunk(false)?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts what a `ChunkWriter` wrote, failing with `InvalidData` as soon
+/// as a chunk does not authenticate or the data ends before the last chunk.
+pub struct ChunkReader<R: Read> {
+    inner: R,
+    cipher: Aes256Gcm,
+    aad: Vec<u8>,
+    index: u64,
+    plaintext: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(inner: R, key: &DataKey, aad: &[u8]) -> Self {
+        ChunkReader {
+            inner,
+            cipher: key.cipher(),
+            aad: aad.to_vec(),
+            index: 0,
+            plaintext: Vec::new(),
+            position: 0,
+            done: false,
+        }
+    }
+
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let mut chunk = vec![0u8; CHUNK_LEN + CHUNK_OVERHEAD];
+        let mut len = 0;
+        while len < chunk.len() {
+            match self.inner.read(&mut chunk[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if len < CHUNK_OVERHEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "encrypted data ends early"));
+        }
+
+        let last = len < chunk.len();
+        let aad = chunk_aad(&self.aad, self.index, last);
+        self.plaintext = self.cipher
+            .decrypt(Nonce::from_slice(&chunk[..12]), Payload { msg: &chunk[12..len], aad: &aad })
+            .map_err(|_| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("chunk {} does not authenticate", self.index),
+            ))?;
+        self.position = 0;
+        self.index += 1;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ChunkReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if self.done || out.is_empty() {
+                return Ok(0);
+            }
+            self.read_chunk()?;
+        }
+        let len = out.len().min(self.plaintext.len() - self.position);
+        out[..len].copy_from_slice(&self.plaintext[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+fn chunk_aad(aad: &[u8], index: u64, last: bool) -> Vec<u8> {
+    let mut chunk_aad = Vec::with_capacity(aad.len() + 9);
+    chunk_aad.extend_from_slice(aad);
+    chunk_aad.extend_from_slice(&index.to_le_bytes());
+    chunk_aad.push(last as u8);
+    chunk_aad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(data: &[u8], key: &DataKey) -> Vec<u8> {
+        let mut writer = ChunkWriter::new(Vec::new(), key, b"entry");
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(data: &[u8], key: &DataKey, aad: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        ChunkReader::new(data, key, aad).read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn round_trips_at_chunk_boundaries() {
+        let key = DataKey::generate();
+        for len in [0, 1, CHUNK_LEN - 1, CHUNK_LEN, CHUNK_LEN + 1, 3 * CHUNK_LEN] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let encrypted = encrypt(&data, &key);
+            assert_eq!(encrypted.len() as u64, encrypted_len(len as u64));
+            assert_eq!(decrypt(&encrypted, &key, b"entry").unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn cut_off_or_reordered_chunks_fail() {
+        let key = DataKey::generate();
+        let encrypted = encrypt(&vec![7u8; 2 * CHUNK_LEN], &key);
+        let chunk = CHUNK_LEN + CHUNK_OVERHEAD;
+
+        // Cut at a chunk boundary, so what is left still authenticates
+        assert!(decrypt(&encrypted[..2 * chunk], &key, b"entry").is_err());
+        let mut swapped = encrypted[chunk..2 * chunk].to_vec();
+        swapped.extend_from_slice(&encrypted[..chunk]);
+        swapped.extend_from_slice(&encrypted[2 * chunk..]);
+        assert!(decrypt(&swapped, &key, b"entry").is_err());
+        assert!(decrypt(&encrypted, &key, b"other entry").is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::PathBuf;
+
+use crate::crypto::{calculate_file_hash, canonical_json};
+use crate::metadata::PastePolicy;
+
+/// Settings of an exam config, as the instructor signs them and the
+/// recorder runs a session under them. The recorder fills what a config
+/// leaves unset from its command line.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExamConfig {
+    pub exam_id: Option<String>,
+    pub student_id: Option<String>,
+    /// Where the finished archive is written (default: the state directory).
+    pub output_dir: Option<PathBuf>,
+    /// Shell to start instead of `$SHELL`.
+    pub shell: Option<String>,
+    /// Time limit in minutes.
+    pub time_limit: Option<u64>,
+    /// Minutes-left marks at which to warn the student (default: 10 and 1).
+    pub warnings: Option<Vec<u64>>,
+    /// Terminal output recorded in full, in MiB (default: 64). Past it,
+    /// output is only sampled.
+    pub output_limit: Option<u64>,
+    /// Program (and arguments) to record instead of an interactive shell.
+    pub command: Vec<String>,
+    /// The exam cannot be started before this time.
+    pub not_before: Option<DateTime<Utc>>,
+    /// The exam cannot be started after this time.
+    pub not_after: Option<DateTime<Utc>>,
+    /// Instructor public key (hex) to seal the archive to, instead of the
+    /// one installed with the recorder.
+    pub public_key: Option<String>,
+    pub paste_policy: Option<PastePolicy>,
+    /// Files copied into the archive when the session ends, relative to
+    /// the directory the recorder was started in.
+    pub collect_files: Vec<String>,
+    /// Program names reported as a finding when the shell runs them, e.g.
+    /// `firefox`.
+    pub flag_programs: Vec<String>,
+    /// Addresses and CIDR ranges, e.g. `10.0.0.0/8`, the session may
+    /// connect to without it being reported as a finding.
+    pub network_allowlist: Vec<String>,
+    /// Directory whose files are versioned each time they change,
+    /// relative to the directory the recorder was started in.
+    pub watch_dir: Option<PathBuf>,
+    /// Patterns of paths in `watch_dir` that are not versioned, e.g.
+    /// `*.o` (default: editor swap and backup files, and `.git`).
+    pub watch_ignore: Vec<String>,
+    /// Largest file whose versions are stored, in KiB (default: 1024).
+    pub watch_file_limit: Option<u64>,
+    /// Compressed file versions stored in all, in MiB (default: 64).
+    pub watch_limit: Option<u64>,
+    /// SHA-256 of the signed config, set by `SignedConfig::verify`.
+    #[serde(skip)]
+    pub config_hash: Option<String>,
+}
+
+impl ExamConfig {
+    /// Fills every setting not given in `self` from `defaults`.
+    pub fn or(self, defaults: ExamConfig) -> Self {
+        ExamConfig {
+            exam_id: self.exam_id.or(defaults.exam_id),
+            student_id: self.student_id.or(defaults.student_id),
+            output_dir: self.output_dir.or(defaults.output_dir),
+            shell: self.shell.or(defaults.shell),
+            time_limit: self.time_limit.or(defaults.time_limit),
+            warnings: self.warnings.or(defaults.warnings),
+            output_limit: self.output_limit.or(defaults.output_limit),
+            command: if self.command.is_empty() { defaults.command } else { self.command },
+            not_before: self.not_before.or(defaults.not_before),
+            not_after: self.not_after.or(defaults.not_after),
+            public_key: self.public_key.or(defaults.public_key),
+            paste_policy: self.paste_policy.or(defaults.paste_policy),
+            collect_files: if self.collect_files.is_empty() {
+                defaults.collect_files
+            } else {
+                self.collect_files
+            },
+            flag_programs: if self.flag_programs.is_empty() {
+                defaults.flag_programs
+            } else {
+                self.flag_programs
+            },
+            network_allowlist: if self.network_allowlist.is_empty() {
+                defaults.network_allowlist
+            } else {
+                self.network_allowlist
+            },
+            watch_dir: self.watch_dir.or(defaults.watch_dir),
+            watch_ignore: if self.watch_ignore.is_empty() {
+                defaults.watch_ignore
+            } else {
+                self.watch_ignore
+            },
+            watch_file_limit: self.watch_file_limit.or(defaults.watch_file_limit),
+            watch_limit: self.watch_limit.or(defaults.watch_limit),
+            config_hash: self.config_hash.or(defaults.config_hash),
+        }
+    }
+
+    /// Refuses to start the exam outside its allowed time window.
+    pub fn check_window(&self, now: DateTime<Utc>) -> Result<()> {
+        let local = |time: &DateTime<Utc>| {
+            time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string()
+        };
+        if let Some(start) = &self.not_before {
+            if now < *start {
+                anyhow::bail!("This exam opens at {}", local(start));
+            }
+        }
+        if let Some(end) = &self.not_after {
+            if now > *end {
+                anyhow::bail!("This exam closed at {}", local(end));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An exam config file as distributed to students: the settings and an
+/// Ed25519 signature over their canonical form, compact JSON with sorted
+/// keys.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SignedConfig {
+    config: Value,
+    signature: String,
+}
+
+impl SignedConfig {
+    /// Signs the settings in `config`, refusing any the recorder would not
+    /// accept.
+    pub fn sign(config: Value, key: &SigningKey) -> Result<Self> {
+        settings(&config)?;
+        let signature = key.sign(&canonical_json(&config)?);
+        Ok(SignedConfig { config, signature: hex::encode(signature.to_bytes()) })
+    }
+
+    /// Parses a signed config file without checking its signature.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(data)?)
+    }
+
+    /// Checks that the config was signed with `key` and returns its
+    /// settings.
+    pub fn verify(&self, key: &VerifyingKey) -> Result<ExamConfig> {
+        let signature = hex::decode(self.signature.trim())
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .context("Exam config signature is malformed")?;
+        key.verify_strict(&canonical_json(&self.config)?, &signature)
+            .map_err(|_| anyhow::anyhow!(
+                "Exam config signature is invalid; the file was modified or not signed by the instructor"
+            ))?;
+
+        let mut config = settings(&self.config)?;
+        config.config_hash = Some(self.hash()?);
+        Ok(config)
+    }
+
+    /// SHA-256 of the signed settings, recorded in the metadata of sessions
+    /// that ran under them.
+    pub fn hash(&self) -> Result<String> {
+        Ok(calculate_file_hash(&canonical_json(&self.config)?))
+    }
+}
+
+fn settings(config: &Value) -> Result<ExamConfig> {
+    ExamConfig::deserialize(config).map_err(|e| anyhow::anyhow!("Invalid exam config: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn signed_config_verifies_with_its_key_only() {
+        let key = SigningKey::from_bytes(&[3u8; 32]);
+        let config = json!({"exam_id": "CS101", "paste_policy": "block", "collect_files": ["main.c"]});
+        let signed = SignedConfig::sign(config, &key).unwrap();
+        let text = serde_json::to_vec(&signed).unwrap();
+
+        let settings = SignedConfig::parse(&text).unwrap().verify(&key.verifying_key()).unwrap();
+        assert_eq!(settings.exam_id.as_deref(), Some("CS101"));
+        assert_eq!(settings.paste_policy, Some(PastePolicy::Block));
+        assert_eq!(settings.config_hash, Some(signed.hash().unwrap()));
+
+        let other = SigningKey::from_bytes(&[4u8; 32]).verifying_key();
+        assert!(SignedConfig::parse(&text).unwrap().verify(&other).is_err());
+        let edited = String::from_utf8(text).unwrap().replace("block", "allow");
+        assert!(SignedConfig::parse(edited.as_bytes()).unwrap().verify(&key.verifying_key()).is_err());
+    }
+
+    #[test]
+    fn unknown_settings_are_refused() {
+        let key = SigningKey::from_bytes(&[3u8; 32]);
+        let error = SignedConfig::sign(json!({"time_limt": 30}), &key).err().unwrap();
+        assert!(error.to_string().starts_with("Invalid exam config: unknown field `time_limt`"), "{}", error);
+    }
+}
@@ -0,0 +1,389 @@
+use anyhow::{Context, Result};
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use rand::RngCore;
+use sha2::{Sha256, Digest};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::error::ArchiveError;
+
+/// Magic bytes identifying an archive sealed to an instructor public key.
+pub const SEALED_MAGIC: &[u8; 4] = b"EXRS";
+/// Version of the sealed envelope layout.
+pub const SEALED_VERSION: u8 = 1;
+/// Length of the sealed envelope header: magic | version | key id |
+/// ephemeral public key | nonce || wrapped key + tag.
+pub const SEALED_HEADER_LEN: usize = 4 + 1 + 8 + 32 + 12 + 32 + 16;
+
+const HKDF_INFO: &[u8] = b"exam-recorder-suite/seal/v1";
+
+/// Magic bytes identifying an archive encrypted with a password.
+pub const PASSWORD_MAGIC: &[u8; 4] = b"EXRP";
+/// Version of the password envelope layout. Version 1 envelopes use the
+/// derived key as the data key; version 2 wraps a random data key with it,
+/// so a wrong password can be told apart from a corrupted payload.
+pub const PASSWORD_VERSION: u8 = 2;
+/// Key derivation function identifiers in the password envelope.
+const KDF_ARGON2ID: u8 = 1;
+const PASSWORD_SALT_LEN: usize = 16;
+/// Length of a version 1 password envelope: magic | version | kdf |
+/// memory | iterations | parallelism | salt.
+pub const PASSWORD_V1_HEADER_LEN: usize = 4 + 1 + 1 + 4 + 4 + 4 + PASSWORD_SALT_LEN;
+/// Length of the password envelope: the version 1 fields, then the wrapped
+/// data key (nonce || AES-GCM).
+pub const PASSWORD_HEADER_LEN: usize = PASSWORD_V1_HEADER_LEN + 12 + 32 + 16;
+/// Most memory a password envelope may ask for, so a crafted header cannot
+/// make the viewer allocate without bound.
+const MAX_KDF_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+
+/// Fixed salt of archives encrypted with the legacy instructor password.
+const LEGACY_PASSWORD_SALT: &[u8] = b"exam-recorder-suite-salt-v1";
+const LEGACY_PASSWORD_ROUNDS: u32 = 100_000;
+
+/// Argon2id cost parameters, stored in the envelope of every
+/// password-protected archive so they can be raised for new archives
+/// without breaking old ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+impl std::fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Argon2id, {} MiB, {} iterations, {} lanes",
+            self.memory_kib / 1024,
+            self.iterations,
+            self.parallelism
+        )
+    }
+}
+
+/// Fresh symmetric key used to encrypt the contents of a single archive.
+pub struct DataKey(aes_gcm::Key<Aes256Gcm>);
+
+impl DataKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        DataKey(*aes_gcm::Key::<Aes256Gcm>::from_slice(&key))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 32 {
+            anyhow::bail!("Invalid data key length");
+        }
+        Ok(DataKey(*aes_gcm::Key::<Aes256Gcm>::from_slice(bytes)))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+
+    pub(crate) fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(&self.0)
+    }
+}
+
+pub fn parse_public_key(encoded: &str) -> Result<PublicKey> {
+    let bytes = hex::decode(encoded.trim())
+        .context("Instructor public key is not valid hex")?;
+    let bytes: [u8; 32] = bytes.try_into()
+        .map_err(|_| anyhow::anyhow!("Instructor public key must be 32 bytes"))?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// Short identifier of a public key, recorded in the archive envelope.
+pub fn key_id(public_key: &PublicKey) -> [u8; 8] {
+    let digest = Sha256::digest(public_key.as_bytes());
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&digest[..8]);
+    id
+}
+
+pub fn encrypt_file(data: &[u8], key: &DataKey) -> Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new(&key.0);
+
+    let mut nonce_bytes = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut nonce_bytes);
+    let nonce = Nonce::from_slice(&nonce_bytes);
+
+    let mut ciphertext = cipher.encrypt(nonce, data)
+        .map_err(|e| anyhow::anyhow!("Encryption failed: {}", e))?;
+
+    // Prepend nonce
+    let mut result = nonce_bytes.to_vec();
+    result.append(&mut ciphertext);
+
+    Ok(result)
+}
+
+pub fn decrypt_file(encrypted: &[u8], key: &DataKey) -> Result<Vec<u8>> {
+    if encrypted.len() < 12 {
+        anyhow::bail!("Invalid encrypted data length");
+    }
+
+    let cipher = Aes256Gcm::new(&key.0);
+    let nonce = Nonce::from_slice(&encrypted[..12]);
+
+    cipher.decrypt(nonce, &encrypted[12..])
+        .map_err(|e| anyhow::anyhow!("Decryption failed: {}", e))
+}
+
+/// Encrypts `data`, binding it to `aad` (authenticated but not encrypted).
+pub fn encrypt_with_aad(data: &[u8], key: &DataKey, aad: &[u8]) -> Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new(&key.0);
+
+    let mut nonce_bytes = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut nonce_bytes);
+    let nonce = Nonce::from_slice(&nonce_bytes);
+
+    let payload = Payload { msg: data, aad };
+    let mut ciphertext = cipher.encrypt(nonce, payload)
+        .map_err(|e| anyhow::anyhow!("Encryption failed: {}", e))?;
+
+    let mut result = nonce_bytes.to_vec();
+    result.append(&mut ciphertext);
+
+    Ok(result)
+}
+
+/// Decrypts data written by `encrypt_with_aad` with the same `aad`.
+pub fn decrypt_with_aad(encrypted: &[u8], key: &DataKey, aad: &[u8]) -> Result<Vec<u8>> {
+    if encrypted.len() < 12 {
+        anyhow::bail!("Invalid encrypted data length");
+    }
+
+    let cipher = Aes256Gcm::new(&key.0);
+    let nonce = Nonce::from_slice(&encrypted[..12]);
+    let payload = Payload { msg: &encrypted[12..], aad };
+
+    cipher.decrypt(nonce, payload)
+        .map_err(|e| anyhow::anyhow!("Decryption failed: {}", e))
+}
+
+/// Encrypts one journal chunk, binding it to its position in the journal.
+pub fn encrypt_chunk(data: &[u8], key: &DataKey, sequence: u64) -> Result<Vec<u8>> {
+    encrypt_with_aad(data, key, &sequence.to_le_bytes())
+}
+
+/// Decrypts and authenticates one journal chunk written at `sequence`.
+pub fn decrypt_chunk(encrypted: &[u8], key: &DataKey, sequence: u64) -> Result<Vec<u8>> {
+    decrypt_with_aad(encrypted, key, &sequence.to_le_bytes())
+}
+
+/// Builds the sealed envelope header that wraps `key` for the instructor.
+///
+/// Layout: magic | version | key id (8) | ephemeral public key (32) |
+/// wrapped data key (nonce || AES-GCM). The archive payload follows as
+/// nonce || AES-GCM(zip).
+pub fn seal_key(key: &DataKey, recipient: &PublicKey) -> Result<Vec<u8>> {
+    let ephemeral_secret = EphemeralSecret::random_from_rng(rand::thread_rng());
+    let ephemeral_public = PublicKey::from(&ephemeral_secret);
+    let shared = ephemeral_secret.diffie_hellman(recipient);
+    if !shared.was_contributory() {
+        anyhow::bail!("Instructor public key is invalid");
+    }
+
+    let wrapping_key = derive_wrapping_key(shared.as_bytes(), &ephemeral_public, recipient)?;
+    let wrapped_key = encrypt_file(key.0.as_slice(), &wrapping_key)?;
+
+    let mut result = Vec::new();
+    result.extend_from_slice(SEALED_MAGIC);
+    result.push(SEALED_VERSION);
+    result.extend_from_slice(&key_id(recipient));
+    result.extend_from_slice(ephemeral_public.as_bytes());
+    result.extend_from_slice(&wrapped_key);
+
+    Ok(result)
+}
+
+/// Unwraps the data key from a sealed envelope header with the
+/// instructor's private key.
+pub fn open_sealed_key(header: &[u8], secret: &StaticSecret) -> Result<DataKey> {
+    if header.len() < SEALED_HEADER_LEN || !header.starts_with(SEALED_MAGIC) {
+        anyhow::bail!("Invalid sealed archive");
+    }
+    if header[4] != SEALED_VERSION {
+        return Err(ArchiveError::UnsupportedVersion(format!("sealed key envelope v{}", header[4])).into());
+    }
+
+    let recipient = PublicKey::from(secret);
+    let archive_key_id = &header[5..13];
+    if archive_key_id != key_id(&recipient) {
+        return Err(ArchiveError::WrongKey(format!(
+            "archive was sealed to key {}, but the provided private key is {}",
+            hex::encode(archive_key_id),
+            hex::encode(key_id(&recipient))
+        )).into());
+    }
+
+    let mut ephemeral_bytes = [0u8; 32];
+    ephemeral_bytes.copy_from_slice(&header[13..45]);
+    let ephemeral_public = PublicKey::from(ephemeral_bytes);
+    let shared = secret.diffie_hellman(&ephemeral_public);
+    if !shared.was_contributory() {
+        anyhow::bail!("Invalid sealed archive");
+    }
+
+    let wrapping_key = derive_wrapping_key(shared.as_bytes(), &ephemeral_public, &recipient)?;
+    let data_key = decrypt_file(&header[45..SEALED_HEADER_LEN], &wrapping_key)
+        .map_err(|_| ArchiveError::Corrupted("the sealed archive key does not unwrap".to_string()))?;
+    DataKey::from_bytes(&data_key)
+}
+
+fn derive_wrapping_key(
+    shared_secret: &[u8],
+    ephemeral_public: &PublicKey,
+    recipient: &PublicKey,
+) -> Result<DataKey> {
+    let mut salt = Vec::with_capacity(64);
+    salt.extend_from_slice(ephemeral_public.as_bytes());
+    salt.extend_from_slice(recipient.as_bytes());
+
+    let hkdf = hkdf::Hkdf::<Sha256>::new(Some(&salt), shared_secret);
+    let mut key = [0u8; 32];
+    hkdf.expand(HKDF_INFO, &mut key)
+        .map_err(|e| anyhow::anyhow!("Key derivation failed: {}", e))?;
+
+    Ok(DataKey(*aes_gcm::Key::<Aes256Gcm>::from_slice(&key)))
+}
+
+/// Generates a data key and wraps it with a key derived from `password`
+/// and a fresh random salt, returning the key and the plaintext envelope
+/// it can be recovered from.
+///
+/// Layout: magic | version | kdf | memory KiB (u32) | iterations (u32) |
+/// parallelism (u32) | salt (16) | wrapped data key (nonce || AES-GCM).
+pub fn password_key(password: &str, params: &KdfParams) -> Result<(DataKey, Vec<u8>)> {
+    let mut salt = [0u8; PASSWORD_SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let wrapping_key = derive_argon2_key(password, &salt, params)?;
+    let key = DataKey::generate();
+
+    let mut header = Vec::with_capacity(PASSWORD_HEADER_LEN);
+    header.extend_from_slice(PASSWORD_MAGIC);
+    header.push(PASSWORD_VERSION);
+    header.push(KDF_ARGON2ID);
+    header.extend_from_slice(&params.memory_kib.to_le_bytes());
+    header.extend_from_slice(&params.iterations.to_le_bytes());
+    header.extend_from_slice(&params.parallelism.to_le_bytes());
+    header.extend_from_slice(&salt);
+    header.append(&mut encrypt_file(key.as_bytes(), &wrapping_key)?);
+
+    Ok((key, header))
+}
+
+/// Length of a password envelope of the given version.
+pub fn password_header_len(version: u8) -> Option<usize> {
+    match version {
+        1 => Some(PASSWORD_V1_HEADER_LEN),
+        PASSWORD_VERSION => Some(PASSWORD_HEADER_LEN),
+        _ => None,
+    }
+}
+
+/// Reads the KDF parameters from a password envelope header.
+pub fn password_params(header: &[u8]) -> Result<KdfParams> {
+    if header.len() < 6 || !header.starts_with(PASSWORD_MAGIC) {
+        anyhow::bail!("Invalid password-protected archive");
+    }
+    let Some(len) = password_header_len(header[4]) else {
+        return Err(ArchiveError::UnsupportedVersion(format!("password envelope v{}", header[4])).into());
+    };
+    if header.len() < len {
+        anyhow::bail!("Invalid password-protected archive");
+    }
+    if header[5] != KDF_ARGON2ID {
+        return Err(ArchiveError::UnsupportedVersion(format!("key derivation function {}", header[5])).into());
+    }
+
+    let field = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    let params = KdfParams {
+        memory_kib: field(6),
+        iterations: field(10),
+        parallelism: field(14),
+    };
+    if params.memory_kib > MAX_KDF_MEMORY_KIB {
+        anyhow::bail!("Archive asks for {} MiB to derive its key; refusing", params.memory_kib / 1024);
+    }
+    Ok(params)
+}
+
+/// Recovers the data key of a password-protected archive from its envelope
+/// header.
+pub fn open_password_key(header: &[u8], password: &str) -> Result<DataKey> {
+    let params = password_params(header)?;
+    let key = derive_argon2_key(password, &header[18..PASSWORD_V1_HEADER_LEN], &params)?;
+    if header[4] == 1 {
+        return Ok(key);
+    }
+
+    let data_key = decrypt_file(&header[PASSWORD_V1_HEADER_LEN..PASSWORD_HEADER_LEN], &key)
+        .map_err(|_| ArchiveError::WrongKey("the password is wrong".to_string()))?;
+    DataKey::from_bytes(&data_key)
+}
+
+fn derive_argon2_key(password: &str, salt: &[u8], params: &KdfParams) -> Result<DataKey> {
+    use argon2::{Algorithm, Argon2, Params, Version};
+
+    let params = Params::new(params.memory_kib, params.iterations, params.parallelism, Some(32))
+        .map_err(|e| anyhow::anyhow!("Invalid key derivation parameters: {}", e))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Key derivation failed: {}", e))?;
+
+    Ok(DataKey(*aes_gcm::Key::<Aes256Gcm>::from_slice(&key)))
+}
+
+/// Key of archives written by recorders that encrypted with the instructor
+/// password and a fixed salt, before archives carried a password envelope.
+pub fn derive_legacy_password_key(password: &str) -> DataKey {
+    use pbkdf2::pbkdf2_hmac;
+
+    let mut key = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), LEGACY_PASSWORD_SALT, LEGACY_PASSWORD_ROUNDS, &mut key);
+
+    DataKey(*aes_gcm::Key::<Aes256Gcm>::from_slice(&key))
+}
+
+pub fn calculate_file_hash(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hex::encode(hasher.finalize())
+}
+
+/// Serializes `value` as compact JSON with object keys sorted, so the
+/// viewer can reproduce the exact bytes from the parsed value.
+pub fn canonical_json<T: serde::Serialize>(value: &T) -> Result<Vec<u8>> {
+    fn sorted(value: serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Object(map) => {
+                let entries: std::collections::BTreeMap<_, _> =
+                    map.into_iter().map(|(k, v)| (k, sorted(v))).collect();
+                serde_json::Value::Object(entries.into_iter().collect())
+            }
+            serde_json::Value::Array(items) => {
+                serde_json::Value::Array(items.into_iter().map(sorted).collect())
+            }
+            other => other,
+        }
+    }
+    Ok(serde_json::to_vec(&sorted(serde_json::to_value(value)?))?)
+}
@@ -0,0 +1,36 @@
+use std::fmt;
+
+/// Why an archive could not be opened or read. Returned inside
+/// `anyhow::Error`, so callers that care can find it with `downcast_ref`.
+#[derive(Debug)]
+pub enum ArchiveError {
+    /// The file is not an exam archive at all.
+    NotAnArchive(String),
+    /// The archive was written in a format this build does not read.
+    UnsupportedVersion(String),
+    /// The key or password does not open the archive.
+    WrongKey(String),
+    /// The file is shorter than its header says it should be.
+    Truncated { expected: u64, actual: u64 },
+    /// Part of the archive outside its entries does not authenticate.
+    Corrupted(String),
+    /// An entry does not decrypt or does not match the manifest.
+    CorruptedEntry { entry: String, reason: String },
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::NotAnArchive(reason) => write!(f, "Not an exam archive: {}", reason),
+            ArchiveError::UnsupportedVersion(reason) => write!(f, "Unsupported archive version: {}", reason),
+            ArchiveError::WrongKey(reason) => write!(f, "Wrong key or password: {}", reason),
+            ArchiveError::Truncated { expected, actual } => {
+                write!(f, "Archive is truncated: {} of {} bytes present", actual, expected)
+            }
+            ArchiveError::Corrupted(reason) => write!(f, "Archive is corrupted: {}", reason),
+            ArchiveError::CorruptedEntry { entry, reason } => write!(f, "Corrupted entry {}: {}", entry, reason),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
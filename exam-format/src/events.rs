@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeystrokeEvent {
+    pub timestamp: u64,
+    pub key_code: u32,
+    pub key_name: String,
+    pub raw_bytes: Vec<u8>,
+    pub is_paste: bool,
+}
+
+impl KeystrokeEvent {
+    /// Characters the key puts in the line: its text for text keys, whose
+    /// name is the text they send, and one for named keys such as `Enter`.
+    pub fn inserted_chars(&self) -> usize {
+        if self.raw_bytes == self.key_name.as_bytes() {
+            self.key_name.chars().count()
+        } else {
+            1
+        }
+    }
+}
+
+/// Whether a key name (with or without modifiers) moves the cursor or view.
+/// The recorder counts these keys in the summary; the viewer counts them in
+/// archives whose summary lacks them.
+pub fn is_navigation_key(name: &str) -> bool {
+    let base = name.rsplit('+').next().unwrap_or("");
+    matches!(base, "Up" | "Down" | "Left" | "Right" | "Home" | "End" | "PageUp" | "PageDown")
+}
+
+/// Text the terminal delivered as one bracketed paste.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasteEvent {
+    pub timestamp: u64,
+    /// Number of characters pasted.
+    pub length: usize,
+    pub text: String,
+    /// Set when the exam's paste policy kept the text from the shell.
+    pub blocked: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandEvent {
+    pub timestamp: u64,
+    pub command: String,
+    pub source: CommandSource,
+    /// The fields below are reported by the shell integration hooks, and
+    /// are empty for commands reconstructed from keystrokes.
+    pub end_timestamp: Option<u64>,
+    pub exit_status: Option<i32>,
+    pub cwd: Option<String>,
+}
+
+/// Where a command was recorded from. Commands are always rebuilt from
+/// keystrokes; shells with integration hooks also report the commands they
+/// run, and the two lists are compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandSource {
+    /// Reported by the shell's OSC 133 markers.
+    Shell,
+    /// Typed text up to Enter, as the recorder saw it.
+    Keystrokes,
+}
+
+/// A process under the recorded shell starting a program or exiting, as
+/// seen by polling /proc.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessEvent {
+    /// When the recorder saw the exec or the exit (ms).
+    pub timestamp: u64,
+    pub kind: ProcessEventKind,
+    pub pid: u32,
+    pub ppid: u32,
+    /// When the process started (ms). Together with `pid` it names the
+    /// process, since pids are reused.
+    pub start_time: u64,
+    /// Command line of the program started; empty for exits.
+    pub argv: Vec<String>,
+    /// Resolved path of the executable, if it could be read.
+    pub exe: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessEventKind {
+    /// A new process, or a known one that started another program.
+    Exec,
+    Exit,
+}
+
+/// A network connection opened by a process under the recorded shell, as
+/// seen by sampling /proc/net.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionEvent {
+    /// When the recorder first saw the connection (ms).
+    pub timestamp: u64,
+    /// "tcp", "tcp6", "udp" or "udp6".
+    pub protocol: String,
+    pub local_port: u16,
+    /// Unspecified ("0.0.0.0" or "::"), with port 0, for a UDP socket that
+    /// is bound but not connected: it can send anywhere, unseen.
+    pub remote_address: String,
+    pub remote_port: u16,
+    /// Set when the connection was accepted on a port some process
+    /// listens on.
+    pub inbound: bool,
+    /// Process holding the socket, and its command line.
+    pub pid: u32,
+    pub argv: Vec<String>,
+}
+
+/// A version of a file in the exam's watched directory, recorded when the
+/// recorder saw the file change on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileVersion {
+    /// When the recorder read the file (ms).
+    pub timestamp: u64,
+    /// Path relative to the watched directory.
+    pub path: String,
+    pub kind: FileVersionKind,
+    /// Size in bytes; 0 for deletions.
+    pub size: u64,
+    /// SHA-256 of the contents; `None` for deletions.
+    pub sha256: Option<String>,
+    /// Archive entry holding the compressed contents, if they were stored.
+    pub entry: Option<String>,
+    /// Why the contents were not stored.
+    pub skipped: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileVersionKind {
+    /// The file as it was when the session started.
+    Initial,
+    Created,
+    Modified,
+    Deleted,
+}
+
+/// A file the exam config asked to collect, as stored in the archive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollectedFile {
+    pub path: String,
+    /// Archive entry holding the encrypted contents, if the file was read.
+    pub entry: Option<String>,
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+    /// Why the file could not be collected.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionSummary {
+    pub total_keystrokes: usize,
+    pub enter_pressed: usize,
+    pub backspace_used: usize,
+    pub delete_used: usize,
+    pub navigation_keys: usize,
+    pub paste_events: usize,
+    pub total_pasted_chars: usize,
+    pub commands_executed: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str, raw_bytes: &[u8]) -> KeystrokeEvent {
+        KeystrokeEvent {
+            timestamp: 0,
+            key_code: 0,
+            key_name: name.to_string(),
+            raw_bytes: raw_bytes.to_vec(),
+            is_paste: true,
+        }
+    }
+
+    #[test]
+    fn keys_count_the_text_they_insert() {
+        assert_eq!(key("a", b"a").inserted_chars(), 1);
+        assert_eq!(key("€", "€".as_bytes()).inserted_chars(), 1);
+        assert_eq!(key("Enter", b"\r").inserted_chars(), 1);
+        assert_eq!(key("Ctrl+Left", b"\x1b[1;5D").inserted_chars(), 1);
+        assert_eq!(key("0xFF", &[0xFF]).inserted_chars(), 1);
+    }
+
+    #[test]
+    fn navigation_keys_ignore_modifiers() {
+        assert!(is_navigation_key("Up"));
+        assert!(is_navigation_key("Ctrl+Shift+PageDown"));
+        assert!(!is_navigation_key("Enter"));
+        assert!(!is_navigation_key("U"));
+        assert!(!is_navigation_key("Alt+u"));
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::{Context, Result};
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::chunked;
+use crate::crypto::{
+    password_header_len, password_params, KdfParams, PASSWORD_HEADER_LEN, PASSWORD_MAGIC,
+    SEALED_HEADER_LEN, SEALED_MAGIC,
+};
+use crate::error::ArchiveError;
+use crate::manifest::ARCHIVE_FORMAT_VERSION;
+
+/// Magic bytes at the start of every archive written with a header.
+pub const ARCHIVE_MAGIC: &[u8; 4] = b"EXAM";
+/// First archive format version with a header.
+const FIRST_HEADER_VERSION: u32 = 3;
+/// First archive format version whose payload and entries are encrypted in
+/// chunks.
+const FIRST_CHUNKED_VERSION: u32 = 4;
+/// magic | format version (u16) | header length (u32) | payload length (u64) |
+/// recorder version length (u8).
+const FIXED_LEN: usize = 4 + 2 + 4 + 8 + 1;
+/// Longest header: the fixed fields, a recorder version of 255 bytes and
+/// the larger key envelope.
+const MAX_LEN: usize = FIXED_LEN + u8::MAX as usize + PASSWORD_HEADER_LEN;
+/// Nonce and tag around the encrypted ZIP of archives from before chunking.
+const PAYLOAD_OVERHEAD: usize = 12 + 16;
+
+/// How the data key of an archive is recovered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyInfo {
+    /// Wrapped for the instructor key with this ID.
+    Sealed { key_id: [u8; 8] },
+    /// Derived from a password with the parameters in the envelope.
+    Password { version: u8, kdf: KdfParams },
+    /// Derived from the instructor password and the fixed legacy salt.
+    LegacyPassword,
+}
+
+impl fmt::Display for KeyInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyInfo::Sealed { key_id } => write!(f, "sealed to key {}", hex::encode(key_id)),
+            KeyInfo::Password { kdf, .. } => write!(f, "password-protected ({})", kdf),
+            KeyInfo::LegacyPassword => write!(f, "legacy instructor password"),
+        }
+    }
+}
+
+/// The plaintext start of an archive, describing what follows: the format
+/// version, the recorder that wrote it and how its key is recovered.
+///
+/// Layout: magic | format version (u16) | header length (u32) | payload
+/// length (u64) | recorder version (u8 length, UTF-8) | key envelope. The
+/// payload, the ZIP encrypted in chunks (see [`crate::chunked`]), is
+/// authenticated together with the header bytes. Format 3 archives encrypt
+/// the ZIP in one piece as nonce || AES-GCM(zip). Archives from older
+/// recorders start directly with the key envelope, or with the payload if
+/// they were encrypted with the legacy instructor password.
+#[derive(Debug, Clone)]
+pub struct ArchiveHeader {
+    /// `None` for archives written before the header.
+    pub format_version: Option<u32>,
+    pub recorder_version: Option<String>,
+    pub key: KeyInfo,
+    /// Length of the encrypted payload, when the header records it.
+    pub payload_len: Option<u64>,
+    /// Bytes before the payload.
+    pub(crate) bytes: Vec<u8>,
+    /// Position of the key envelope in `bytes`.
+    pub(crate) envelope: Range<usize>,
+}
+
+impl ArchiveHeader {
+    /// Builds the header of a new archive whose payload is `zip_len` bytes
+    /// of ZIP before encryption.
+    pub(crate) fn encode(recorder_version: &str, envelope: &[u8], zip_len: u64) -> Result<Vec<u8>> {
+        let version = recorder_version.as_bytes();
+        if version.len() > u8::MAX as usize {
+            anyhow::bail!("Recorder version is too long for the archive header");
+        }
+
+        let len = FIXED_LEN + version.len() + envelope.len();
+        let mut header = Vec::with_capacity(len);
+        header.extend_from_slice(ARCHIVE_MAGIC);
+        header.extend_from_slice(&(ARCHIVE_FORMAT_VERSION as u16).to_le_bytes());
+        header.extend_from_slice(&(len as u32).to_le_bytes());
+        header.extend_from_slice(&chunked::encrypted_len(zip_len).to_le_bytes());
+        header.push(version.len() as u8);
+        header.extend_from_slice(version);
+        header.extend_from_slice(envelope);
+        Ok(header)
+    }
+
+    /// Reads the header at the start of `data`, which must be the whole
+    /// archive so its length can be checked against the header.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        Self::parse_start(data, data.len() as u64)
+    }
+
+    /// Reads the header of the archive `archive`, leaving it positioned at
+    /// the start of the payload.
+    pub fn read(archive: &mut (impl Read + Seek)) -> Result<Self> {
+        let total = archive.seek(SeekFrom::End(0)).context("Failed to read archive")?;
+        archive.seek(SeekFrom::Start(0)).context("Failed to read archive")?;
+        let mut start = Vec::with_capacity(MAX_LEN);
+        archive.by_ref().take(MAX_LEN as u64).read_to_end(&mut start)
+            .context("Failed to read archive")?;
+
+        let header = Self::parse_start(&start, total)?;
+        archive.seek(SeekFrom::Start(header.bytes.len() as u64)).context("Failed to read archive")?;
+        Ok(header)
+    }
+
+    /// Parses the header from the first bytes of an archive of `total`
+    /// bytes; `data` holds the whole header if the archive is long enough.
+    fn parse_start(data: &[u8], total: u64) -> Result<Self> {
+        if data.starts_with(ARCHIVE_MAGIC) {
+            return Self::parse_current(data, total);
+        }
+
+        // Archives from before the header
+        let len = if data.starts_with(SEALED_MAGIC) {
+            SEALED_HEADER_LEN
+        } else if data.starts_with(PASSWORD_MAGIC) {
+            let version = data.get(4).copied().unwrap_or_default();
+            password_header_len(version).ok_or_else(|| {
+                ArchiveError::UnsupportedVersion(format!("password envelope v{}", version))
+            })?
+        } else if data.starts_with(b"PK\x03\x04") {
+            return Err(ArchiveError::NotAnArchive("plain ZIP file, not an encrypted exam archive".into()).into());
+        } else if looks_like_text(data) {
+            return Err(ArchiveError::NotAnArchive("text file, not an encrypted exam archive".into()).into());
+        } else if total < PAYLOAD_OVERHEAD as u64 {
+            return Err(ArchiveError::NotAnArchive(format!("file is only {} bytes", total)).into());
+        } else {
+            0
+        };
+        if total < (len + PAYLOAD_OVERHEAD) as u64 {
+            return Err(ArchiveError::Truncated {
+                expected: (len + PAYLOAD_OVERHEAD) as u64,
+                actual: total,
+            }.into());
+        }
+        let key = if len == 0 { KeyInfo::LegacyPassword } else { key_info(&data[..len])? };
+
+        Ok(ArchiveHeader {
+            format_version: None,
+            recorder_version: None,
+            key,
+            payload_len: None,
+            bytes: data[..len].to_vec(),
+            envelope: 0..len,
+        })
+    }
+
+    fn parse_current(data: &[u8], total: u64) -> Result<Self> {
+        if data.len() < FIXED_LEN {
+            return Err(ArchiveError::Truncated { expected: FIXED_LEN as u64, actual: total }.into());
+        }
+        let format_version = u16::from_le_bytes([data[4], data[5]]) as u32;
+        if !(FIRST_HEADER_VERSION..=ARCHIVE_FORMAT_VERSION).contains(&format_version) {
+            return Err(ArchiveError::UnsupportedVersion(format!(
+                "archive format v{}, this build reads up to v{}; update exam-viewer",
+                format_version,
+                ARCHIVE_FORMAT_VERSION
+            )).into());
+        }
+
+        let len = u32::from_le_bytes(data[6..10].try_into().unwrap()) as usize;
+        let payload_len = u64::from_le_bytes(data[10..18].try_into().unwrap());
+        let expected = len as u64 + payload_len;
+        if total < expected {
+            return Err(ArchiveError::Truncated { expected, actual: total }.into());
+        }
+        if total > expected {
+            return Err(ArchiveError::Corrupted(format!(
+                "{} bytes of unexpected data after the payload",
+                total - expected
+            )).into());
+        }
+
+        let version_end = FIXED_LEN + data[18] as usize;
+        if version_end > len || len > data.len() {
+            return Err(ArchiveError::Corrupted("header length is inconsistent".into()).into());
+        }
+        let recorder_version = String::from_utf8_lossy(&data[FIXED_LEN..version_end]).to_string();
+
+        Ok(ArchiveHeader {
+            format_version: Some(format_version),
+            recorder_version: Some(recorder_version),
+            key: key_info(&data[version_end..len])?,
+            payload_len: Some(payload_len),
+            bytes: data[..len].to_vec(),
+            envelope: version_end..len,
+        })
+    }
+
+    /// Whether the payload is authenticated together with the header.
+    pub(crate) fn is_authenticated(&self) -> bool {
+        self.format_version.is_some()
+    }
+
+    /// Whether the payload and entries are encrypted in chunks.
+    pub(crate) fn is_chunked(&self) -> bool {
+        self.format_version.is_some_and(|version| version >= FIRST_CHUNKED_VERSION)
+    }
+
+    /// One-line description, e.g. "format v3, recorder 0.1.0, sealed to key
+    /// 1a2b3c4d5e6f7a8b".
+    pub fn describe(&self) -> String {
+        match (self.format_version, &self.recorder_version) {
+            (Some(format), Some(recorder)) => format!("format v{}, recorder {}, {}", format, recorder, self.key),
+            _ => format!("no header (older recorder), {}", self.key),
+        }
+    }
+}
+
+/// Legacy archives start with a random nonce, which is practically never
+/// all printable text.
+fn looks_like_text(data: &[u8]) -> bool {
+    let start = &data[..data.len().min(64)];
+    !start.is_empty() && start.iter().all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace())
+}
+
+fn key_info(envelope: &[u8]) -> Result<KeyInfo> {
+    if envelope.starts_with(SEALED_MAGIC) {
+        if envelope.len() != SEALED_HEADER_LEN {
+            return Err(ArchiveError::Corrupted("sealed key envelope has the wrong length".into()).into());
+        }
+        if envelope[4] != crate::crypto::SEALED_VERSION {
+            return Err(ArchiveError::UnsupportedVersion(format!("sealed key envelope v{}", envelope[4])).into());
+        }
+        return Ok(KeyInfo::Sealed { key_id: envelope[5..13].try_into().unwrap() });
+    }
+    if envelope.starts_with(PASSWORD_MAGIC) {
+        let kdf = password_params(envelope)?;
+        return Ok(KeyInfo::Password { version: envelope[4], kdf });
+    }
+    Err(ArchiveError::Corrupted("header has no recognizable key envelope".into()).into())
+}
@@ -0,0 +1,193 @@
+//! Reading large JSON array entries one element at a time, so the viewer
+//! never has to hold a whole entry to walk it.
+
+use anyhow::{Context, Result};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, SeqAccess, Visitor};
+use std::fmt;
+use std::io::BufRead;
+use std::marker::PhantomData;
+
+/// Yields the elements of a JSON array as they are read. Each element is
+/// parsed on its own once its closing bracket or brace has been seen.
+pub struct ArrayReader<R, T> {
+    reader: R,
+    started: bool,
+    done: bool,
+    element: Vec<u8>,
+    marker: PhantomData<T>,
+}
+
+impl<R: BufRead, T: DeserializeOwned> ArrayReader<R, T> {
+    pub fn new(reader: R) -> Self {
+        ArrayReader { reader, started: false, done: false, element: Vec::new(), marker: PhantomData }
+    }
+
+    fn next_element(&mut self) -> Result<Option<T>> {
+        if self.done {
+            return Ok(None);
+        }
+        let expected = if self.started { "',' or ']'" } else { "'['" };
+        let found = self.next_byte()?;
+        match (self.started, found) {
+            (false, Some(b'[')) => {
+                self.started = true;
+                if self.peek_byte()? == Some(b']') {
+                    self.reader.consume(1);
+                    self.done = true;
+                    return Ok(None);
+                }
+            }
+            (true, Some(b',')) => {}
+            (true, Some(b']')) => {
+                self.done = true;
+                return Ok(None);
+            }
+            _ => anyhow::bail!("Invalid JSON array: expected {}", expected),
+        }
+
+        // Collect the element's bytes: everything up to the next comma or
+        // bracket outside of strings and nested values
+        self.element.clear();
+        self.skip_whitespace()?;
+        let mut depth = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+        loop {
+            let Some(byte) = self.peek_byte_raw()? else {
+                anyhow::bail!("Invalid JSON array: unexpected end");
+            };
+            if !in_string && depth == 0 && matches!(byte, b',' | b']' | b' ' | b'\t' | b'\r' | b'\n') {
+                break;
+            }
+            self.reader.consume(1);
+            self.element.push(byte);
+            if in_string {
+                match byte {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+            } else {
+                match byte {
+                    b'"' => in_string = true,
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => depth = depth.checked_sub(1).context("Invalid JSON array: unbalanced brackets")?,
+                    _ => {}
+                }
+            }
+        }
+        serde_json::from_slice(&self.element)
+            .context("Invalid JSON array element")
+            .map(Some)
+    }
+
+    /// Next byte that is not whitespace, consumed.
+    fn next_byte(&mut self) -> Result<Option<u8>> {
+        let byte = self.peek_byte()?;
+        if byte.is_some() {
+            self.reader.consume(1);
+        }
+        Ok(byte)
+    }
+
+    /// Next byte that is not whitespace, left unread.
+    fn peek_byte(&mut self) -> Result<Option<u8>> {
+        self.skip_whitespace()?;
+        self.peek_byte_raw()
+    }
+
+    fn peek_byte_raw(&mut self) -> Result<Option<u8>> {
+        Ok(self.reader.fill_buf()?.first().copied())
+    }
+
+    fn skip_whitespace(&mut self) -> Result<()> {
+        while let Some(byte) = self.peek_byte_raw()? {
+            if !byte.is_ascii_whitespace() {
+                break;
+            }
+            self.reader.consume(1);
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead, T: DeserializeOwned> Iterator for ArrayReader<R, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Result<T>> {
+        let next = self.next_element();
+        if next.is_err() {
+            self.done = true;
+        }
+        next.transpose()
+    }
+}
+
+/// Deserializes a JSON array nested inside a larger value by passing each
+/// element to `each` instead of collecting them. An error from `each` stops
+/// the parse and is kept in `failed`, for the caller to return in place of
+/// the parser's.
+pub(crate) struct Elements<'a, T, F> {
+    each: F,
+    failed: &'a mut Option<anyhow::Error>,
+    marker: PhantomData<T>,
+}
+
+impl<'a, T, F> Elements<'a, T, F> {
+    pub(crate) fn new(each: F, failed: &'a mut Option<anyhow::Error>) -> Self {
+        Elements { each, failed, marker: PhantomData }
+    }
+}
+
+impl<'de, T: DeserializeOwned, F: FnMut(T) -> Result<()>> DeserializeSeed<'de> for Elements<'_, T, F> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, T: DeserializeOwned, F: FnMut(T) -> Result<()>> Visitor<'de> for Elements<'_, T, F> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a JSON array")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(element) = seq.next_element::<T>()? {
+            if let Err(e) = (self.each)(element) {
+                *self.failed = Some(e);
+                return Err(de::Error::custom("stopped"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn elements(text: &str) -> Result<Vec<Value>> {
+        ArrayReader::new(text.as_bytes()).collect()
+    }
+
+    #[test]
+    fn reads_elements_one_at_a_time() {
+        let text = r#" [ {"a": [1, 2], "b": "x,]}\"y"}, 3 ,"z" ,[] ] "#;
+        assert_eq!(elements(text).unwrap(), vec![json!({"a": [1, 2], "b": "x,]}\"y"}), json!(3), json!("z"), json!([])]);
+        assert!(elements("[]").unwrap().is_empty());
+        assert!(elements("\n[\n]\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_arrays_are_errors() {
+        assert!(elements("{}").is_err());
+        assert!(elements("[1, 2").is_err());
+        assert!(elements("[1,, 2]").is_err());
+        assert!(elements("[{\"a\": 1]").is_err());
+    }
+}
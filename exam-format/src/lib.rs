@@ -0,0 +1,33 @@
+//! Exam archive format shared by `exam-recorder` and `exam-viewer`.
+//!
+//! An archive is a plaintext [`ArchiveHeader`] followed by a ZIP of
+//! individually encrypted entries (events, summary, metadata, terminal
+//! output, ...) and a manifest, encrypted as a whole and sealed to the
+//! instructor's X25519 public key. Both layers are encrypted in chunks, so
+//! archives are written and read without holding them in memory. The recorder
+//! builds one with [`ArchiveWriter`]; the viewer opens it with
+//! [`ArchiveReader`].
+
+pub mod archive;
+pub mod chain;
+pub mod chunked;
+pub mod config;
+pub mod crypto;
+pub mod error;
+pub mod events;
+pub mod header;
+pub mod json;
+pub mod manifest;
+pub mod metadata;
+
+pub use archive::{ArchiveKey, ArchiveReader, ArchiveWriter, EntrySpool};
+pub use config::{ExamConfig, SignedConfig};
+pub use crypto::{DataKey, KdfParams};
+pub use error::ArchiveError;
+pub use header::{ArchiveHeader, KeyInfo};
+pub use events::{
+    CollectedFile, CommandEvent, CommandSource, ConnectionEvent, FileVersion, FileVersionKind, KeystrokeEvent,
+    PasteEvent, ProcessEvent, ProcessEventKind, SessionSummary,
+};
+pub use manifest::{IntegrityCheck, ARCHIVE_FORMAT_VERSION};
+pub use metadata::{EndReason, Metadata, PastePolicy, TimeWarning};
@@ -0,0 +1,333 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+
+use crate::crypto::{calculate_file_hash, canonical_json};
+use crate::error::ArchiveError;
+
+/// Archive layout version recorded in the header and the manifest. Version
+/// 1 archives have a single `integrity.sha256` over their encrypted entries
+/// instead of a manifest; version 2 archives have no plaintext header;
+/// version 3 archives encrypt the payload and each entry in one piece;
+/// version 4 archives do not record processes, version 5 archives no
+/// network connections, version 6 archives no file history, version 7
+/// archives record commands from the shell or from keystrokes, not both.
+pub const ARCHIVE_FORMAT_VERSION: u32 = 8;
+
+/// Name of the manifest entry, written after every entry it lists.
+pub const MANIFEST_ENTRY: &str = "manifest.json";
+const LEGACY_CHECKSUM_ENTRY: &str = "integrity.sha256";
+/// Largest manifest or legacy checksum read back from an archive.
+const MAX_LISTING_LEN: u64 = 16 * 1024 * 1024;
+
+/// The archive's `manifest.json` entry: every other entry in the order it
+/// was written, with the size and SHA-256 of its stored (encrypted) bytes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub entries: Vec<ManifestEntry>,
+    /// SHA-256 of the canonical JSON of `format_version` and `entries`.
+    pub manifest_hash: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// The part of the manifest its hash covers.
+#[derive(Serialize)]
+struct Listing<'a> {
+    format_version: u32,
+    entries: &'a [ManifestEntry],
+}
+
+impl ManifestEntry {
+    pub fn new(name: &str, data: &[u8]) -> Self {
+        ManifestEntry {
+            name: name.to_string(),
+            size: data.len() as u64,
+            sha256: calculate_file_hash(data),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn new(entries: Vec<ManifestEntry>) -> Result<Self> {
+        let manifest_hash = listing_hash(ARCHIVE_FORMAT_VERSION, &entries)?;
+        Ok(Manifest {
+            format_version: ARCHIVE_FORMAT_VERSION,
+            entries,
+            manifest_hash,
+        })
+    }
+
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+}
+
+fn listing_hash(format_version: u32, entries: &[ManifestEntry]) -> Result<String> {
+    Ok(calculate_file_hash(&canonical_json(&Listing { format_version, entries })?))
+}
+
+/// Result of checking an archive's entries against its manifest.
+#[derive(Clone)]
+pub struct IntegrityCheck {
+    /// Archive layout version; 1 for archives with only a legacy checksum.
+    pub format_version: u32,
+    pub entries: usize,
+    /// What did not match, one line per problem.
+    pub problems: Vec<String>,
+}
+
+impl IntegrityCheck {
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// One-line result, e.g. "manifest v2, 11 entries" or the problems found.
+    pub fn describe(&self) -> String {
+        if !self.passed() {
+            return self.problems.join("; ");
+        }
+        if self.format_version == 1 {
+            format!("legacy checksum, {} entries", self.entries)
+        } else {
+            format!("manifest v{}, {} entries", self.format_version, self.entries)
+        }
+    }
+}
+
+/// What the integrity check needs from an archive's entries: the size and
+/// SHA-256 of each one, gathered in a single pass over them in ZIP order,
+/// and the manifest or legacy checksum as stored.
+#[derive(Default)]
+pub struct EntryDigests {
+    stored: Vec<ManifestEntry>,
+    manifest: Option<Vec<u8>>,
+    legacy_checksum: Option<String>,
+    /// SHA-256 over every `.enc` entry in order, for the legacy checksum.
+    legacy_hash: Sha256,
+    legacy_entries: usize,
+}
+
+impl EntryDigests {
+    /// Hashes the next entry of the archive.
+    pub fn add(&mut self, name: &str, mut data: impl Read) -> Result<()> {
+        if name == MANIFEST_ENTRY || name == LEGACY_CHECKSUM_ENTRY {
+            let mut listing = Vec::new();
+            data.take(MAX_LISTING_LEN).read_to_end(&mut listing)?;
+            if name == MANIFEST_ENTRY {
+                self.manifest = Some(listing);
+                return Ok(());
+            }
+            self.legacy_checksum = Some(String::from_utf8_lossy(&listing).trim().to_string());
+            self.stored.push(ManifestEntry::new(name, &listing));
+            return Ok(());
+        }
+
+        let legacy = name.ends_with(".enc");
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        let mut buffer = [0u8; 64 * 1024];
+        loop {
+            let len = data.read(&mut buffer)?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buffer[..len]);
+            if legacy {
+                self.legacy_hash.update(&buffer[..len]);
+            }
+            size += len as u64;
+        }
+        if legacy {
+            self.legacy_entries += 1;
+        }
+        self.stored.push(ManifestEntry {
+            name: name.to_string(),
+            size,
+            sha256: hex::encode(hasher.finalize()),
+        });
+        Ok(())
+    }
+}
+
+/// Checks the digests of an archive's decrypted ZIP entries against the
+/// archive's manifest.
+pub fn check(digests: EntryDigests) -> Result<IntegrityCheck> {
+    let Some(data) = &digests.manifest else {
+        return check_legacy(digests);
+    };
+    let manifest: Manifest = serde_json::from_slice(data).map_err(|e| ArchiveError::CorruptedEntry {
+        entry: MANIFEST_ENTRY.to_string(),
+        reason: format!("malformed: {}", e),
+    })?;
+    if manifest.format_version > ARCHIVE_FORMAT_VERSION {
+        return Err(ArchiveError::UnsupportedVersion(format!(
+            "archive format v{}, this build reads up to v{}; update exam-viewer",
+            manifest.format_version,
+            ARCHIVE_FORMAT_VERSION
+        )).into());
+    }
+
+    let mut problems = Vec::new();
+    if listing_hash(manifest.format_version, &manifest.entries)? != manifest.manifest_hash {
+        problems.push("manifest hash does not match its entries".to_string());
+    }
+
+    for expected in &manifest.entries {
+        let Some(stored) = digests.stored.iter().find(|stored| stored.name == expected.name) else {
+            problems.push(format!("{} is missing", expected.name));
+            continue;
+        };
+        if stored.size != expected.size {
+            problems.push(format!(
+                "{} is {} bytes, manifest says {}",
+                expected.name,
+                stored.size,
+                expected.size
+            ));
+        } else if stored.sha256 != expected.sha256 {
+            problems.push(format!("{} was modified", expected.name));
+        }
+    }
+    for stored in &digests.stored {
+        if !manifest.entries.iter().any(|e| e.name == stored.name) {
+            problems.push(format!("{} is not in the manifest", stored.name));
+        }
+    }
+
+    Ok(IntegrityCheck {
+        format_version: manifest.format_version,
+        entries: manifest.entries.len(),
+        problems,
+    })
+}
+
+/// Archives from before the manifest carry one SHA-256 over their encrypted
+/// entries, concatenated in the order the recorder wrote them, which is the
+/// order they appear in the ZIP.
+fn check_legacy(digests: EntryDigests) -> Result<IntegrityCheck> {
+    let expected = digests.legacy_checksum
+        .context("Archive has no manifest or integrity checksum")?;
+
+    let mut problems = Vec::new();
+    if hex::encode(digests.legacy_hash.finalize()) != expected {
+        problems.push("archive checksum does not match its entries".to_string());
+    }
+
+    Ok(IntegrityCheck { format_version: 1, entries: digests.legacy_entries, problems })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<(String, Vec<u8>)> {
+        vec![
+            ("events.json.enc".to_string(), b"encrypted events".to_vec()),
+            ("metadata.json.enc".to_string(), b"encrypted metadata".to_vec()),
+            ("state_copy.json.enc".to_string(), b"encrypted state".to_vec()),
+        ]
+    }
+
+    fn listing(entries: &[(String, Vec<u8>)]) -> Vec<ManifestEntry> {
+        entries.iter().map(|(name, data)| ManifestEntry::new(name, data)).collect()
+    }
+
+    fn with_manifest(mut entries: Vec<(String, Vec<u8>)>) -> Vec<(String, Vec<u8>)> {
+        let manifest = Manifest::new(listing(&entries)).unwrap();
+        entries.push((MANIFEST_ENTRY.to_string(), manifest.to_json().unwrap()));
+        entries
+    }
+
+    fn check(entries: &[(String, Vec<u8>)]) -> Result<IntegrityCheck> {
+        let mut digests = EntryDigests::default();
+        for (name, data) in entries {
+            digests.add(name, data.as_slice()).unwrap();
+        }
+        super::check(digests)
+    }
+
+    #[test]
+    fn lists_entries_in_write_order() {
+        let manifest = Manifest::new(listing(&entries())).unwrap();
+        assert_eq!(manifest.format_version, ARCHIVE_FORMAT_VERSION);
+        let names: Vec<_> = manifest.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["events.json.enc", "metadata.json.enc", "state_copy.json.enc"]);
+        assert_eq!(manifest.entries[1].size, 18);
+        assert_eq!(manifest.entries[0].sha256, calculate_file_hash(b"encrypted events"));
+    }
+
+    #[test]
+    fn genuine_archive_passes() {
+        let check = check(&with_manifest(entries())).unwrap();
+        assert!(check.passed(), "{}", check.describe());
+        assert_eq!(check.describe(), format!("manifest v{}, 3 entries", ARCHIVE_FORMAT_VERSION));
+    }
+
+    #[test]
+    fn one_bit_change_in_any_entry_fails() {
+        for index in 0..3 {
+            let mut archive = with_manifest(entries());
+            archive[index].1[0] ^= 1;
+            let check = check(&archive).unwrap();
+            assert_eq!(check.problems, [format!("{} was modified", archive[index].0)]);
+        }
+    }
+
+    #[test]
+    fn edited_manifest_fails() {
+        let mut archive = with_manifest(entries());
+        let manifest = &mut archive.last_mut().unwrap().1;
+        let text = String::from_utf8(manifest.clone()).unwrap().replacen("\"size\": 16", "\"size\": 17", 1);
+        *manifest = text.into_bytes();
+        let check = check(&archive).unwrap();
+        assert!(check.problems.contains(&"manifest hash does not match its entries".to_string()));
+    }
+
+    #[test]
+    fn missing_and_extra_entries_fail() {
+        let mut archive = with_manifest(entries());
+        archive.remove(1);
+        archive.insert(0, ("extra.json.enc".to_string(), Vec::new()));
+        let check = check(&archive).unwrap();
+        assert_eq!(check.problems, [
+            "metadata.json.enc is missing",
+            "extra.json.enc is not in the manifest",
+        ]);
+    }
+
+    #[test]
+    fn newer_format_is_refused() {
+        let mut archive = with_manifest(entries());
+        let manifest = &mut archive.last_mut().unwrap().1;
+        *manifest = String::from_utf8(manifest.clone()).unwrap()
+            .replace(
+                &format!("\"format_version\": {}", ARCHIVE_FORMAT_VERSION),
+                &format!("\"format_version\": {}", ARCHIVE_FORMAT_VERSION + 1),
+            )
+            .into_bytes();
+        let error = check(&archive).err().unwrap();
+        assert!(matches!(error.downcast_ref(), Some(ArchiveError::UnsupportedVersion(_))));
+    }
+
+    #[test]
+    fn legacy_checksum_is_checked() {
+        let mut archive = entries();
+        let mut hasher = Sha256::new();
+        for (_, data) in &archive {
+            hasher.update(data);
+        }
+        archive.push((LEGACY_CHECKSUM_ENTRY.to_string(), hex::encode(hasher.finalize()).into_bytes()));
+        assert!(check(&archive).unwrap().passed());
+
+        archive[0].1[0] ^= 1;
+        assert!(!check(&archive).unwrap().passed());
+    }
+}
@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metadata {
+    pub username: String,
+    pub hostname: String,
+    pub machine_id: String,
+    pub run_counter: u64,
+    pub exam_id: Option<String>,
+    pub student_id: Option<String>,
+    /// Shell started for the session.
+    pub shell: String,
+    /// Program recorded instead of the shell, if one was given after `--`.
+    pub command: Vec<String>,
+    /// Set when the shell was started with hooks that report its commands.
+    pub shell_integration: bool,
+    pub time_limit_minutes: Option<u64>,
+    /// Time warnings shown to the student.
+    pub time_warnings: Vec<TimeWarning>,
+    /// Set when the session was ended by the time limit.
+    pub time_limit_reached: bool,
+    /// Directory the archive is written to, if not the state directory.
+    pub output_dir: Option<String>,
+    /// Public key (hex) of the session key that signed the event chain.
+    pub chain_key: Option<String>,
+    /// SHA-256 of the signed exam config the session ran under.
+    pub config_hash: Option<String>,
+    pub paste_policy: PastePolicy,
+    /// Absolute paths of the files to copy into the archive.
+    pub collect_files: Vec<String>,
+    /// Programs the exam config flags when they run under the shell.
+    pub flag_programs: Vec<String>,
+    /// Addresses and CIDR ranges the exam config allows connections to.
+    pub network_allowlist: Vec<String>,
+    /// Absolute path of the directory whose files are versioned.
+    pub watch_dir: Option<String>,
+    /// Patterns of paths in `watch_dir` that are not versioned.
+    pub watch_ignore: Vec<String>,
+    /// Largest file whose versions are stored, in KiB.
+    pub watch_file_limit_kib: Option<u64>,
+    /// Compressed file versions stored in all, in MiB.
+    pub watch_limit_mib: Option<u64>,
+    pub start_time: u64,
+    pub end_time: Option<u64>,
+    pub duration_seconds: Option<u64>,
+    pub terminal_width: u16,
+    pub terminal_height: u16,
+    /// Terminal output recorded in full, in MiB, before it was only sampled.
+    pub output_limit_mib: Option<u64>,
+    /// Terminal output shown to the student but left out of the recording.
+    pub output_dropped_bytes: u64,
+    /// Set when the archive was rebuilt from an orphaned session journal.
+    pub recovered: bool,
+    /// Timestamp (ms) of the last record recovered from the journal.
+    pub recording_stopped_at: Option<u64>,
+    /// How the session ended.
+    pub end_reason: Option<EndReason>,
+    /// Exit status of the shell, if it exited on its own.
+    pub exit_status: Option<i32>,
+    /// Signal that terminated the shell, e.g. "SIGKILL".
+    pub exit_signal: Option<String>,
+    /// Signal that stopped the recorder itself, for interrupted sessions.
+    pub recorder_signal: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeWarning {
+    pub timestamp: u64,
+    pub minutes_left: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    /// The shell exited (`exit`, Ctrl+D).
+    Logout,
+    /// The shell was terminated by a signal.
+    Killed,
+    /// The exam time limit ran out and the shell was ended.
+    TimeLimit,
+    /// The recorder was stopped by SIGTERM, SIGHUP or SIGINT (shutdown,
+    /// dropped SSH connection, admin) and hung up the shell.
+    Interrupted,
+}
+
+/// What happens to text the student pastes into the terminal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PastePolicy {
+    /// Pastes reach the shell and are recorded.
+    #[default]
+    Allow,
+    /// Bracketed pastes are recorded but not passed to the shell.
+    Block,
+}
+
+impl Metadata {
+    /// Metadata for a session starting now.
+    pub fn new(username: String, hostname: String, machine_id: String, run_counter: u64) -> Self {
+        let start_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        
+        Metadata {
+            username,
+            hostname,
+            machine_id,
+            run_counter,
+            exam_id: None,
+            student_id: None,
+            shell: String::new(),
+            command: Vec::new(),
+            shell_integration: false,
+            time_limit_minutes: None,
+            time_warnings: Vec::new(),
+            time_limit_reached: false,
+            output_dir: None,
+            chain_key: None,
+            config_hash: None,
+            paste_policy: PastePolicy::Allow,
+            collect_files: Vec::new(),
+            flag_programs: Vec::new(),
+            network_allowlist: Vec::new(),
+            watch_dir: None,
+            watch_ignore: Vec::new(),
+            watch_file_limit_kib: None,
+            watch_limit_mib: None,
+            start_time,
+            end_time: None,
+            duration_seconds: None,
+            terminal_width: 0,
+            terminal_height: 0,
+            output_limit_mib: None,
+            output_dropped_bytes: 0,
+            recovered: false,
+            recording_stopped_at: None,
+            end_reason: None,
+            exit_status: None,
+            exit_signal: None,
+            recorder_signal: None,
+        }
+    }
+    
+    pub fn finalize(&mut self) {
+        let end_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        
+        self.end_time = Some(end_time);
+        self.duration_seconds = Some(end_time.saturating_sub(self.start_time));
+    }
+    
+    pub fn mark_recovered(&mut self, stopped_at_ms: u64) {
+        let end_time = stopped_at_ms / 1000;
+        
+        self.recovered = true;
+        self.recording_stopped_at = Some(stopped_at_ms);
+        self.end_time = Some(end_time);
+        self.duration_seconds = Some(end_time.saturating_sub(self.start_time));
+    }
+}
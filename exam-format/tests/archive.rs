@@ -0,0 +1,347 @@
+//! Archives written the way the recorder writes them, read back the way
+//! the viewer reads them.
+
+use std::io::{Cursor, Write};
+
+use exam_format::archive::{self, ArchiveKey, ArchiveReader, ArchiveWriter};
+use exam_format::crypto::{
+    derive_legacy_password_key, encrypt_file, key_id, password_key, seal_key, DataKey, KdfParams,
+    PASSWORD_HEADER_LEN,
+};
+use exam_format::json::ArrayReader;
+use exam_format::manifest::{Manifest, ManifestEntry, MANIFEST_ENTRY};
+use exam_format::{
+    ArchiveError, ArchiveHeader, CommandEvent, CommandSource, KeyInfo, KeystrokeEvent, Metadata, PasteEvent,
+    SessionSummary,
+};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+fn keystroke(timestamp: u64, key: &str) -> KeystrokeEvent {
+    KeystrokeEvent {
+        timestamp,
+        key_code: key.as_bytes()[0] as u32,
+        key_name: key.to_string(),
+        raw_bytes: key.as_bytes().to_vec(),
+        is_paste: false,
+    }
+}
+
+fn session() -> (Vec<KeystrokeEvent>, Vec<CommandEvent>, Vec<PasteEvent>, SessionSummary, Metadata) {
+    let keystrokes = vec![keystroke(1_000, "l"), keystroke(1_100, "s"), keystroke(1_200, "\r")];
+    let commands = vec![CommandEvent {
+        timestamp: 1_200,
+        command: "ls".to_string(),
+        source: CommandSource::Shell,
+        end_timestamp: Some(1_250),
+        exit_status: Some(0),
+        cwd: Some("/home/student".to_string()),
+    }];
+    let pastes = vec![PasteEvent {
+        timestamp: 1_300,
+        length: 5,
+        text: "hello".to_string(),
+        blocked: true,
+    }];
+    let summary = SessionSummary {
+        total_keystrokes: 3,
+        enter_pressed: 1,
+        backspace_used: 0,
+        delete_used: 0,
+        navigation_keys: 0,
+        paste_events: 1,
+        total_pasted_chars: 5,
+        commands_executed: 1,
+    };
+    let mut metadata = Metadata::new("student".into(), "lab-01".into(), "abc123".into(), 4);
+    metadata.exam_id = Some("cs101-final".to_string());
+    metadata.finalize();
+    (keystrokes, commands, pastes, summary, metadata)
+}
+
+fn write(key: &DataKey, envelope: &[u8]) -> Vec<u8> {
+    let (keystrokes, commands, pastes, summary, metadata) = session();
+    let spool_dir = std::env::temp_dir();
+    let mut writer = ArchiveWriter::new(key, &spool_dir).unwrap();
+    writer.add_json(archive::EVENTS_ENTRY, &keystrokes).unwrap();
+    writer.add_json(archive::SUMMARY_ENTRY, &summary).unwrap();
+    writer.add_json(archive::METADATA_ENTRY, &metadata).unwrap();
+    writer.add(archive::TERMINAL_OUTPUT_ENTRY, b"$ ls\r\nnotes.txt\r\n").unwrap();
+    writer.add_json(archive::PASTES_ENTRY, &pastes).unwrap();
+    writer.add_json(archive::COMMANDS_ENTRY, &commands).unwrap();
+    writer.add(archive::collected_file_entry(0), b"print('hi')\n").unwrap();
+    let mut contents = Vec::new();
+    writer.finish("0.1.0", envelope, &mut contents).unwrap();
+    contents
+}
+
+/// An archive as recorders wrote them before the header: the envelope
+/// (empty for the legacy instructor password), then the encrypted ZIP.
+fn write_headerless(key: &DataKey, envelope: &[u8]) -> Vec<u8> {
+    write_headerless_with(key, envelope, None)
+}
+
+/// Like `write_headerless`, storing `terminal_output` as that entry's bytes
+/// when given.
+fn write_headerless_with(key: &DataKey, envelope: &[u8], terminal_output: Option<Vec<u8>>) -> Vec<u8> {
+    let terminal_output = terminal_output.unwrap_or_else(|| encrypt_file(b"$ ls\r\n", key).unwrap());
+    let mut entries = vec![
+        (archive::METADATA_ENTRY.to_string(), encrypt_file(br#"{"username":"student"}"#, key).unwrap()),
+        (archive::TERMINAL_OUTPUT_ENTRY.to_string(), terminal_output),
+    ];
+    let manifest = Manifest::new(entries.iter().map(|(name, data)| ManifestEntry::new(name, data)).collect()).unwrap();
+    entries.push((MANIFEST_ENTRY.to_string(), manifest.to_json().unwrap()));
+
+    let mut zip_data = Vec::new();
+    {
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(&mut zip_data));
+        for (name, data) in &entries {
+            zip.start_file(name.as_str(), zip::write::FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    let mut contents = envelope.to_vec();
+    contents.append(&mut encrypt_file(&zip_data, key).unwrap());
+    contents
+}
+
+/// The `ArchiveError` an archive fails to open with.
+fn open_error(contents: &[u8], key: &ArchiveKey) -> ArchiveError {
+    let error = ArchiveReader::open(Cursor::new(contents), key).err().expect("archive opened");
+    error.downcast::<ArchiveError>().expect("not an ArchiveError")
+}
+
+#[test]
+fn sealed_archive_round_trips() {
+    let secret = StaticSecret::from([11u8; 32]);
+    let public = PublicKey::from(&secret);
+    let data_key = DataKey::generate();
+    let contents = write(&data_key, &seal_key(&data_key, &public).unwrap());
+
+    let header = ArchiveHeader::parse(&contents).unwrap();
+    assert_eq!(header.format_version, Some(exam_format::ARCHIVE_FORMAT_VERSION));
+    assert_eq!(header.recorder_version.as_deref(), Some("0.1.0"));
+    assert_eq!(header.key, KeyInfo::Sealed { key_id: key_id(&public) });
+
+    let mut reader = ArchiveReader::open(Cursor::new(&contents), &ArchiveKey::Private(secret)).unwrap();
+    let integrity = reader.check_integrity().unwrap();
+    assert!(integrity.passed(), "{}", integrity.describe());
+    assert_eq!(integrity.entries, 7);
+
+    let (keystrokes, commands, pastes, _, metadata) = session();
+    let read: Vec<KeystrokeEvent> = reader.read_json(archive::EVENTS_ENTRY).unwrap().unwrap();
+    assert_eq!(read.len(), keystrokes.len());
+    assert_eq!(read[2].raw_bytes, b"\r");
+    let read: Vec<CommandEvent> = reader.read_json(archive::COMMANDS_ENTRY).unwrap().unwrap();
+    assert_eq!(read[0].command, commands[0].command);
+    assert_eq!(read[0].exit_status, Some(0));
+    let read: Vec<PasteEvent> = reader.read_json(archive::PASTES_ENTRY).unwrap().unwrap();
+    assert!(read[0].blocked);
+    assert_eq!(read[0].text, pastes[0].text);
+    let summary: SessionSummary = reader.read_json(archive::SUMMARY_ENTRY).unwrap().unwrap();
+    assert_eq!(summary.commands_executed, 1);
+    let read: Metadata = reader.read_json(archive::METADATA_ENTRY).unwrap().unwrap();
+    assert_eq!(read.exam_id, metadata.exam_id);
+    assert_eq!(read.run_counter, 4);
+    assert_eq!(
+        reader.decrypt(archive::TERMINAL_OUTPUT_ENTRY).unwrap().unwrap(),
+        b"$ ls\r\nnotes.txt\r\n"
+    );
+    assert_eq!(
+        reader.decrypt(&archive::collected_file_entry(0)).unwrap().unwrap(),
+        b"print('hi')\n"
+    );
+    assert!(reader.decrypt(archive::EVENT_CHAIN_ENTRY).unwrap().is_none());
+}
+
+#[test]
+fn spooled_entries_span_many_chunks() {
+    let secret = StaticSecret::from([11u8; 32]);
+    let data_key = DataKey::generate();
+    let spool_dir = std::env::temp_dir();
+    let mut writer = ArchiveWriter::new(&data_key, &spool_dir).unwrap();
+
+    // Written in turns, the way the recorder fills its entries
+    let mut output = writer.spool(archive::TERMINAL_OUTPUT_ENTRY).unwrap();
+    let mut events = writer.spool(archive::EVENTS_ENTRY).unwrap();
+    events.write_all(b"[").unwrap();
+    for i in 0..20_000u32 {
+        output.write_all(format!("line {}\r\n", i).as_bytes()).unwrap();
+        if i > 0 {
+            events.write_all(b",").unwrap();
+        }
+        serde_json::to_writer(&mut events, &keystroke(i as u64, "y")).unwrap();
+    }
+    events.write_all(b"]").unwrap();
+    writer.add_spool(events).unwrap();
+    writer.add_spool(output).unwrap();
+    let mut contents = Vec::new();
+    writer.finish("0.1.0", &seal_key(&data_key, &PublicKey::from(&secret)).unwrap(), &mut contents).unwrap();
+
+    let mut reader = ArchiveReader::open(Cursor::new(&contents), &ArchiveKey::Private(secret)).unwrap();
+    assert!(reader.check_integrity().unwrap().passed());
+    let names: Vec<_> = reader.names().collect();
+    assert_eq!(names, [archive::EVENTS_ENTRY, archive::TERMINAL_OUTPUT_ENTRY, MANIFEST_ENTRY]);
+    let events: Vec<KeystrokeEvent> = reader.read_json(archive::EVENTS_ENTRY).unwrap().unwrap();
+    assert_eq!(events.len(), 20_000);
+    assert_eq!(events[19_999].timestamp, 19_999);
+    let output = reader.decrypt(archive::TERMINAL_OUTPUT_ENTRY).unwrap().unwrap();
+    assert!(output.len() > 2 * exam_format::chunked::CHUNK_LEN);
+    assert!(output.ends_with(b"line 19999\r\n"));
+
+    // Read in step, each entry at its own position
+    let matched = reader.read_entries(archive::EVENTS_ENTRY, archive::TERMINAL_OUTPUT_ENTRY, |events, output| {
+        let output = output.unwrap();
+        let mut count = 0;
+        for event in ArrayReader::<_, KeystrokeEvent>::new(events) {
+            let mut line = String::new();
+            output.read_line(&mut line)?;
+            assert_eq!(line, format!("line {}\r\n", event?.timestamp));
+            count += 1;
+        }
+        Ok(count)
+    }).unwrap();
+    assert_eq!(matched, Some(20_000));
+}
+
+#[test]
+fn legacy_password_archive_still_opens() {
+    let data_key = derive_legacy_password_key("instructor password");
+    let contents = write_headerless(&data_key, &[]);
+
+    assert_eq!(ArchiveHeader::parse(&contents).unwrap().key, KeyInfo::LegacyPassword);
+    let mut reader = ArchiveReader::open(Cursor::new(&contents), &ArchiveKey::Password("instructor password".into())).unwrap();
+    assert!(reader.check_integrity().unwrap().passed());
+    assert_eq!(reader.decrypt(archive::TERMINAL_OUTPUT_ENTRY).unwrap().unwrap(), b"$ ls\r\n");
+
+    let error = open_error(&contents, &ArchiveKey::Password("guess".into()));
+    assert!(matches!(error, ArchiveError::WrongKey(_)), "{}", error);
+}
+
+#[test]
+fn sealed_archive_without_header_still_opens() {
+    let secret = StaticSecret::from([11u8; 32]);
+    let data_key = DataKey::generate();
+    let contents = write_headerless(&data_key, &seal_key(&data_key, &PublicKey::from(&secret)).unwrap());
+
+    let header = ArchiveHeader::parse(&contents).unwrap();
+    assert_eq!(header.format_version, None);
+    assert!(matches!(header.key, KeyInfo::Sealed { .. }));
+    let mut reader = ArchiveReader::open(Cursor::new(&contents), &ArchiveKey::Private(secret)).unwrap();
+    assert!(reader.check_integrity().unwrap().passed());
+}
+
+/// Cheap parameters so the tests do not spend their time in Argon2.
+const TEST_KDF: KdfParams = KdfParams { memory_kib: 64, iterations: 1, parallelism: 1 };
+
+#[test]
+fn password_archive_round_trips() {
+    let (data_key, envelope) = password_key("correct horse", &TEST_KDF).unwrap();
+    let contents = write(&data_key, &envelope);
+
+    let header = ArchiveHeader::parse(&contents).unwrap();
+    assert!(matches!(header.key, KeyInfo::Password { kdf: TEST_KDF, .. }));
+    let mut reader = ArchiveReader::open(Cursor::new(&contents), &ArchiveKey::Password("correct horse".into())).unwrap();
+    assert!(reader.check_integrity().unwrap().passed());
+    let metadata: Metadata = reader.read_json(archive::METADATA_ENTRY).unwrap().unwrap();
+    assert_eq!(metadata.username, "student");
+
+    let error = open_error(&contents, &ArchiveKey::Password("correct horse!".into()));
+    assert!(matches!(error, ArchiveError::WrongKey(_)), "{}", error);
+}
+
+#[test]
+fn password_archives_get_their_own_salt() {
+    let (first_key, first) = password_key("correct horse", &TEST_KDF).unwrap();
+    let (second_key, second) = password_key("correct horse", &TEST_KDF).unwrap();
+
+    assert_ne!(first, second);
+    assert_ne!(first_key.as_bytes(), second_key.as_bytes());
+}
+
+#[test]
+fn password_header_with_excessive_cost_is_refused() {
+    let (data_key, mut envelope) = password_key("correct horse", &TEST_KDF).unwrap();
+    envelope[6..10].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(envelope.len(), PASSWORD_HEADER_LEN);
+    let contents = write(&data_key, &envelope);
+
+    assert!(ArchiveReader::open(Cursor::new(&contents), &ArchiveKey::Password("correct horse".into())).is_err());
+}
+
+#[test]
+fn archive_does_not_open_with_another_key() {
+    let secret = StaticSecret::from([11u8; 32]);
+    let data_key = DataKey::generate();
+    let contents = write(&data_key, &seal_key(&data_key, &PublicKey::from(&secret)).unwrap());
+
+    let other = StaticSecret::from([12u8; 32]);
+    let error = open_error(&contents, &ArchiveKey::Private(other));
+    assert!(matches!(error, ArchiveError::WrongKey(_)), "{}", error);
+    let error = open_error(&contents, &ArchiveKey::Password("instructor password".into()));
+    assert!(matches!(error, ArchiveError::WrongKey(_)), "{}", error);
+}
+
+#[test]
+fn failures_are_told_apart() {
+    let secret = StaticSecret::from([11u8; 32]);
+    let key = ArchiveKey::Private(secret.clone());
+    let data_key = DataKey::generate();
+    let contents = write(&data_key, &seal_key(&data_key, &PublicKey::from(&secret)).unwrap());
+
+    let error = open_error(b"PK\x03\x04 just a zip file", &key);
+    assert!(matches!(error, ArchiveError::NotAnArchive(_)), "{}", error);
+    let error = open_error(b"Notes for the exam: remember to check the logs", &key);
+    assert!(matches!(error, ArchiveError::NotAnArchive(_)), "{}", error);
+    let error = open_error(&[0x17, 0xff, 0x80], &key);
+    assert!(matches!(error, ArchiveError::NotAnArchive(_)), "{}", error);
+
+    let mut newer = contents.clone();
+    newer[4..6].copy_from_slice(&99u16.to_le_bytes());
+    let error = open_error(&newer, &key);
+    assert!(matches!(error, ArchiveError::UnsupportedVersion(_)), "{}", error);
+
+    let error = open_error(&contents[..contents.len() - 100], &key);
+    assert!(matches!(error, ArchiveError::Truncated { .. }), "{}", error);
+    let error = open_error(&contents[..10], &key);
+    assert!(matches!(error, ArchiveError::Truncated { .. }), "{}", error);
+
+    // The recorder version is authenticated with the payload
+    let mut relabeled = contents.clone();
+    relabeled[19] = b'9';
+    let error = open_error(&relabeled, &key);
+    assert!(matches!(error, ArchiveError::Corrupted(_)), "{}", error);
+}
+
+#[test]
+fn corrupted_entry_is_named() {
+    // An entry encrypted under another key inside an otherwise intact
+    // archive, as if the ZIP had been rebuilt around a forged entry
+    let data_key = derive_legacy_password_key("instructor password");
+    let forged = encrypt_file(b"$ echo innocent\r\n", &DataKey::generate()).unwrap();
+    let contents = write_headerless_with(&data_key, &[], Some(forged));
+
+    let mut reader = ArchiveReader::open(Cursor::new(&contents), &ArchiveKey::Password("instructor password".into())).unwrap();
+    assert!(reader.decrypt(archive::METADATA_ENTRY).is_ok());
+    let error = reader.decrypt(archive::TERMINAL_OUTPUT_ENTRY).err().unwrap().downcast::<ArchiveError>().unwrap();
+    assert!(
+        matches!(&error, ArchiveError::CorruptedEntry { entry, .. } if entry == archive::TERMINAL_OUTPUT_ENTRY),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn one_bit_change_is_detected() {
+    let secret = StaticSecret::from([11u8; 32]);
+    let data_key = DataKey::generate();
+    let contents = write(&data_key, &seal_key(&data_key, &PublicKey::from(&secret)).unwrap());
+
+    for position in [0, 60, contents.len() / 2, contents.len() - 1] {
+        let mut tampered = contents.clone();
+        tampered[position] ^= 1;
+        let opened = ArchiveReader::open(Cursor::new(&tampered), &ArchiveKey::Private(secret.clone()));
+        assert!(opened.is_err(), "bit flip at byte {} went unnoticed", position);
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::Result;
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use exam_format::chain::{checkpoint_message, next_head, ChainLink, Checkpoint};
+use exam_format::crypto::canonical_json;
+
+use crate::journal::JournalRecord;
+
+/// How often the chain head is signed while records keep arriving.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+/// Most records between checkpoints, however quickly they arrive.
+const CHECKPOINT_RECORDS: usize = 1024;
+
+/// Hash chain over every event, process, connection, file version and
+/// output chunk of a session.
+///
+/// Each record's hash is folded into a running head, so removing,
+/// reordering or changing a record changes every head after it. The head
+/// is signed regularly with a key that only exists in the recorder's
+/// memory for this session; its public half is stored in the metadata.
+///
+/// Only the head is kept; each record's link is handed back as it is
+/// pushed, and the archive's chain is rebuilt from the journal.
+pub struct EventChain {
+    records: u64,
+    /// Records covered by the last checkpoint.
+    signed: u64,
+    head: [u8; 32],
+    /// `None` when the chain was rebuilt from a journal after a crash.
+    signing_key: Option<SigningKey>,
+    last_checkpoint: Instant,
+}
+
+impl EventChain {
+    /// Starts a chain with a fresh session signing key.
+    pub fn new() -> Self {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let mut chain = Self::rebuilt();
+        chain.signing_key = Some(SigningKey::from_bytes(&secret));
+        chain
+    }
+
+    /// Starts a chain that can only be extended, not signed.
+    pub fn rebuilt() -> Self {
+        EventChain {
+            records: 0,
+            signed: 0,
+            head: [0u8; 32],
+            signing_key: None,
+            last_checkpoint: Instant::now(),
+        }
+    }
+
+    pub fn public_key(&self) -> Option<VerifyingKey> {
+        self.signing_key.as_ref().map(|key| key.verifying_key())
+    }
+
+    /// Adds a record to the chain if it is one the chain covers, returning
+    /// its link.
+    pub fn push(&mut self, record: &JournalRecord) -> Result<Option<ChainLink>> {
+        let (kind, timestamp, len, hash) = match record {
+            JournalRecord::Keystroke(event) => ("keystroke", event.timestamp, None, sha256(&canonical_json(event)?)),
+            JournalRecord::Command(event) => ("command", event.timestamp, None, sha256(&canonical_json(event)?)),
+            JournalRecord::Paste(event) => ("paste", event.timestamp, None, sha256(&canonical_json(event)?)),
+            JournalRecord::Process(event) => ("process", event.timestamp, None, sha256(&canonical_json(event)?)),
+            JournalRecord::Connection(event) => ("connection", event.timestamp, None, sha256(&canonical_json(event)?)),
+            // The version carries the hash of the contents
+            JournalRecord::FileVersion { version, .. } => {
+                ("file_version", version.timestamp, None, sha256(&canonical_json(version)?))
+            }
+            JournalRecord::Output { timestamp, data, .. } => ("output", *timestamp, Some(data.len()), sha256(data)),
+            _ => return Ok(None),
+        };
+
+        let seq = self.records;
+        let link = ChainLink {
+            seq,
+            kind: kind.to_string(),
+            timestamp,
+            len,
+            hash: hex::encode(hash),
+            prev: hex::encode(self.head),
+        };
+        self.head = next_head(&self.head, seq, kind, &hash);
+        self.records += 1;
+        Ok(Some(link))
+    }
+
+    /// Takes note of a checkpoint read back from a journal.
+    pub fn restore_checkpoint(&mut self, checkpoint: &Checkpoint) {
+        self.signed = checkpoint.records;
+    }
+
+    /// Signs the current head if enough has happened since the last
+    /// checkpoint, or unconditionally with `force`.
+    pub fn checkpoint(&mut self, force: bool) -> Option<Checkpoint> {
+        let key = self.signing_key.as_ref()?;
+        let records = self.records;
+        let unsigned = (records - self.signed) as usize;
+        if unsigned == 0 {
+            return None;
+        }
+        if !force
+            && unsigned < CHECKPOINT_RECORDS
+            && self.last_checkpoint.elapsed() < CHECKPOINT_INTERVAL
+        {
+            return None;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let signature = key.sign(&checkpoint_message(records, timestamp, &self.head));
+        let checkpoint = Checkpoint {
+            records,
+            timestamp,
+            head: hex::encode(self.head),
+            signature: hex::encode(signature.to_bytes()),
+        };
+        self.signed = records;
+        self.last_checkpoint = Instant::now();
+        Some(checkpoint)
+    }
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
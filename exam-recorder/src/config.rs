@@ -0,0 +1,33 @@
+use anyhow::{Context, Result};
+use ed25519_dalek::VerifyingKey;
+use std::path::Path;
+
+use exam_format::{ExamConfig, SignedConfig};
+
+/// Public key exam configs must be signed with, compiled into the recorder.
+///
+/// Set `EXAM_CONFIG_PUBLIC_KEY` to the hex key printed by
+/// `exam-viewer config-keygen` when building. A key read at runtime could be
+/// swapped by anyone with root on the student's machine.
+const BUILTIN_CONFIG_KEY: &str = env!(
+    "EXAM_CONFIG_PUBLIC_KEY",
+    "set EXAM_CONFIG_PUBLIC_KEY to the key from `exam-viewer config-keygen` when building the recorder"
+);
+
+/// Reads a signed exam config, refusing it unless the signature was made by
+/// the pinned instructor config key.
+pub fn load(path: &Path) -> Result<ExamConfig> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("Failed to read exam config: {}", path.display()))?;
+    let signed = SignedConfig::parse(&data)
+        .with_context(|| format!("Exam config is not signed: {}", path.display()))?;
+    signed.verify(&load_config_key()?)
+}
+
+fn load_config_key() -> Result<VerifyingKey> {
+    let bytes = hex::decode(BUILTIN_CONFIG_KEY.trim())
+        .context("Exam config key is not valid hex")?;
+    let bytes: [u8; 32] = bytes.try_into()
+        .map_err(|_| anyhow::anyhow!("Exam config key must be 32 bytes"))?;
+    VerifyingKey::from_bytes(&bytes).context("Exam config key is invalid")
+}
@@ -0,0 +1,181 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::io::Write;
+
+use exam_format::archive::{self, ArchiveWriter, EntrySpool};
+use exam_format::chain::Checkpoint;
+use exam_format::events::is_navigation_key;
+use exam_format::{CommandSource, Metadata, SessionSummary};
+
+use crate::chain::EventChain;
+use crate::journal::{Journal, JournalRecord};
+use crate::stream::{StreamHeader, TerminalStream};
+
+/// The session's archive entries, written from the journal record by record
+/// into spool files, so a session of any length is packed without holding
+/// it in memory. Stored file versions go straight into the archive.
+pub struct SessionEntries {
+    events: JsonArray,
+    pastes: JsonArray,
+    commands: JsonArray,
+    processes: JsonArray,
+    connections: JsonArray,
+    file_history: JsonArray,
+    output: EntrySpool,
+    stream: TerminalStream<EntrySpool>,
+    /// The chain is rebuilt from the records; its links are written as they
+    /// come, its checkpoints once all links are in.
+    chain: EventChain,
+    links: JsonArray,
+    checkpoints: Vec<Checkpoint>,
+    summary: SessionSummary,
+    /// Commands from each source; the summary counts the shell's when it
+    /// reports them.
+    shell_commands: usize,
+    typed_commands: usize,
+}
+
+impl SessionEntries {
+    /// Replays `journal` into new entries of `writer`.
+    pub fn from_journal(journal: &Journal, writer: &mut ArchiveWriter, header: &StreamHeader) -> Result<Self> {
+        let mut chain = writer.spool(archive::EVENT_CHAIN_ENTRY)?;
+        chain.write_all(br#"{"links":"#)?;
+        let mut entries = SessionEntries {
+            events: JsonArray::new(writer.spool(archive::EVENTS_ENTRY)?)?,
+            pastes: JsonArray::new(writer.spool(archive::PASTES_ENTRY)?)?,
+            commands: JsonArray::new(writer.spool(archive::COMMANDS_ENTRY)?)?,
+            processes: JsonArray::new(writer.spool(archive::PROCESSES_ENTRY)?)?,
+            connections: JsonArray::new(writer.spool(archive::CONNECTIONS_ENTRY)?)?,
+            file_history: JsonArray::new(writer.spool(archive::FILE_HISTORY_ENTRY)?)?,
+            output: writer.spool(archive::TERMINAL_OUTPUT_ENTRY)?,
+            stream: TerminalStream::new(writer.spool(archive::TERMINAL_STREAM_ENTRY)?, header)?,
+            chain: EventChain::rebuilt(),
+            links: JsonArray::new(chain)?,
+            checkpoints: Vec::new(),
+            summary: SessionSummary {
+                total_keystrokes: 0,
+                enter_pressed: 0,
+                backspace_used: 0,
+                delete_used: 0,
+                navigation_keys: 0,
+                paste_events: 0,
+                total_pasted_chars: 0,
+                commands_executed: 0,
+            },
+            shell_commands: 0,
+            typed_commands: 0,
+        };
+        journal.replay(|record| entries.record(record, writer))?;
+        Ok(entries)
+    }
+
+    fn record(&mut self, record: JournalRecord, writer: &mut ArchiveWriter) -> Result<()> {
+        if let Some(link) = self.chain.push(&record)? {
+            self.links.push(&link)?;
+        }
+
+        match record {
+            JournalRecord::Keystroke(event) => {
+                self.summary.total_keystrokes += 1;
+                match event.key_name.as_str() {
+                    "Enter" => self.summary.enter_pressed += 1,
+                    "Backspace" => self.summary.backspace_used += 1,
+                    "Delete" => self.summary.delete_used += 1,
+                    name if is_navigation_key(name) => self.summary.navigation_keys += 1,
+                    _ => {}
+                }
+                if event.is_paste {
+                    self.summary.paste_events += 1;
+                    self.summary.total_pasted_chars += event.inserted_chars();
+                }
+                self.events.push(&event)
+            }
+            JournalRecord::Command(event) => {
+                match event.source {
+                    CommandSource::Shell => self.shell_commands += 1,
+                    CommandSource::Keystrokes => self.typed_commands += 1,
+                }
+                self.commands.push(&event)
+            }
+            JournalRecord::Paste(event) => {
+                self.summary.paste_events += 1;
+                self.summary.total_pasted_chars += event.length;
+                self.pastes.push(&event)
+            }
+            JournalRecord::Process(event) => self.processes.push(&event),
+            JournalRecord::Connection(event) => self.connections.push(&event),
+            JournalRecord::FileVersion { version, data } => {
+                if let Some(entry) = &version.entry {
+                    writer.add(entry.as_str(), &data)?;
+                }
+                self.file_history.push(&version)
+            }
+            JournalRecord::Output { offset, data, .. } => {
+                self.output.write_all(&data)?;
+                self.stream.record_output(offset, &data)
+            }
+            JournalRecord::Input { offset, data } => self.stream.record_input(offset, &data),
+            JournalRecord::Resize { offset, width, height, .. } => {
+                self.stream.record_resize(offset, width, height)
+            }
+            JournalRecord::Checkpoint(checkpoint) => {
+                self.checkpoints.push(checkpoint);
+                Ok(())
+            }
+            JournalRecord::Session { .. } | JournalRecord::TimeWarning(_) => Ok(()),
+        }
+    }
+
+    /// Adds the entries to `writer`, with the summary and `metadata`, in the
+    /// order recorders have always written them.
+    pub fn finish(mut self, writer: &mut ArchiveWriter, metadata: &Metadata) -> Result<()> {
+        self.summary.commands_executed = if metadata.shell_integration {
+            self.shell_commands
+        } else {
+            self.typed_commands
+        };
+        writer.add_spool(self.events.finish()?)?;
+        writer.add_json(archive::SUMMARY_ENTRY, &self.summary)?;
+        writer.add_json(archive::METADATA_ENTRY, metadata)?;
+        writer.add_spool(self.output)?;
+        writer.add_spool(self.stream.into_inner())?;
+        writer.add_spool(self.pastes.finish()?)?;
+        writer.add_spool(self.commands.finish()?)?;
+        writer.add_spool(self.processes.finish()?)?;
+        writer.add_spool(self.connections.finish()?)?;
+        writer.add_spool(self.file_history.finish()?)?;
+
+        let mut chain = self.links.finish()?;
+        chain.write_all(br#","checkpoints":"#)?;
+        serde_json::to_writer(&mut chain, &self.checkpoints)?;
+        chain.write_all(b"}")?;
+        writer.add_spool(chain)
+    }
+}
+
+/// A JSON array written one element at a time.
+struct JsonArray {
+    out: EntrySpool,
+    empty: bool,
+}
+
+impl JsonArray {
+    fn new(out: EntrySpool) -> Result<Self> {
+        let mut array = JsonArray { out, empty: true };
+        array.out.write_all(b"[")?;
+        Ok(array)
+    }
+
+    fn push<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        if !std::mem::take(&mut self.empty) {
+            self.out.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut self.out, value)?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<EntrySpool> {
+        self.out.write_all(b"]")?;
+        Ok(self.out)
+    }
+}
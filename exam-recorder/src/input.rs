@@ -0,0 +1,396 @@
+use std::time::{Duration, Instant};
+
+/// A single decoded key press.
+#[derive(Debug, Clone)]
+pub struct Key {
+    pub name: String,
+    pub bytes: Vec<u8>,
+    /// Whether the key inserts text rather than editing or navigating.
+    pub printable: bool,
+}
+
+impl Key {
+    fn named(name: impl Into<String>, bytes: &[u8]) -> Self {
+        Key { name: name.into(), bytes: bytes.to_vec(), printable: false }
+    }
+
+    fn text(c: char, bytes: &[u8]) -> Self {
+        Key { name: c.to_string(), bytes: bytes.to_vec(), printable: true }
+    }
+
+    /// Unicode code point for text keys, otherwise the first raw byte.
+    pub fn code(&self) -> u32 {
+        match self.name.chars().next() {
+            Some(c) if self.printable => c as u32,
+            _ => self.bytes[0] as u32,
+        }
+    }
+}
+
+/// Something typed or pasted at the terminal.
+#[derive(Debug, Clone)]
+pub enum Input {
+    Key(Key),
+    /// Text the terminal wrapped in bracketed paste markers.
+    Paste(Vec<u8>),
+}
+
+/// Turns raw terminal input into key presses, recognising CSI (`ESC [`)
+/// and SS3 (`ESC O`) sequences and UTF-8 characters as single keys.
+///
+/// Sequences split across reads are held until the rest arrives, and text
+/// between `ESC[200~` and `ESC[201~` is returned as a single paste. A paste
+/// that grows past `PASTE_LIMIT` or is left open for `PASTE_TIMEOUT` is
+/// returned as far as it got, so a missing end marker cannot swallow the
+/// rest of the session's input.
+pub struct InputDecoder {
+    pending: Vec<u8>,
+    /// Text collected so far while inside a bracketed paste.
+    paste: Option<Vec<u8>>,
+    /// When the open paste started.
+    paste_started: Instant,
+}
+
+impl InputDecoder {
+    pub fn new() -> Self {
+        InputDecoder { pending: Vec::new(), paste: None, paste_started: Instant::now() }
+    }
+
+    /// Returns the open paste, as far as it got, once its end marker is
+    /// overdue at `now`.
+    pub fn expire_paste(&mut self, now: Instant) -> Option<Input> {
+        if now.duration_since(self.paste_started) < PASTE_TIMEOUT {
+            return None;
+        }
+        self.paste.take().map(Input::Paste)
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> Vec<Input> {
+        self.pending.extend_from_slice(data);
+
+        let mut inputs = Vec::new();
+        let mut pos = 0;
+        while pos < self.pending.len() {
+            let rest = &self.pending[pos..];
+            if let Some(paste) = &mut self.paste {
+                let room = PASTE_LIMIT - paste.len();
+                match find(rest, PASTE_END) {
+                    Some(end) if end <= room => {
+                        paste.extend_from_slice(&rest[..end]);
+                        pos += end + PASTE_END.len();
+                        inputs.push(Input::Paste(self.paste.take().unwrap_or_default()));
+                    }
+                    // Cut off at the limit; what follows is read as keys
+                    Some(_) => {
+                        paste.extend_from_slice(&rest[..room]);
+                        pos += room;
+                        inputs.push(Input::Paste(self.paste.take().unwrap_or_default()));
+                    }
+                    None => {
+                        // Keep back anything that could be the start of the end marker
+                        let keep = partial_suffix(rest, PASTE_END);
+                        let take = (rest.len() - keep).min(room);
+                        paste.extend_from_slice(&rest[..take]);
+                        pos += take;
+                        if paste.len() == PASTE_LIMIT {
+                            inputs.push(Input::Paste(self.paste.take().unwrap_or_default()));
+                            continue;
+                        }
+                        break;
+                    }
+                }
+                continue;
+            }
+
+            match decode_one(rest) {
+                Some((key, len)) if key.bytes == PASTE_START => {
+                    self.paste = Some(Vec::new());
+                    self.paste_started = Instant::now();
+                    pos += len;
+                }
+                Some((key, len)) => {
+                    inputs.push(Input::Key(key));
+                    pos += len;
+                }
+                None => break,
+            }
+        }
+        self.pending.drain(..pos);
+
+        inputs
+    }
+}
+
+/// Tracks whether the program in the PTY has turned on bracketed paste
+/// (`ESC[?2004h` / `ESC[?2004l`), by watching its output.
+pub struct PasteMode {
+    enabled: bool,
+    /// End of the previous read, in case a marker was split.
+    tail: Vec<u8>,
+}
+
+impl PasteMode {
+    pub fn new() -> Self {
+        PasteMode { enabled: false, tail: Vec::new() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn observe_output(&mut self, data: &[u8]) {
+        let mut window = std::mem::take(&mut self.tail);
+        window.extend_from_slice(data);
+
+        // The last marker in the window decides the mode
+        let enable = rfind(&window, PASTE_MODE_ON);
+        let disable = rfind(&window, PASTE_MODE_OFF);
+        match (enable, disable) {
+            (Some(on), Some(off)) => self.enabled = on > off,
+            (Some(_), None) => self.enabled = true,
+            (None, Some(_)) => self.enabled = false,
+            (None, None) => {}
+        }
+
+        let keep = window.len().min(PASTE_MODE_ON.len() - 1);
+        self.tail = window[window.len() - keep..].to_vec();
+    }
+}
+
+/// Most pasted text held before a paste is returned without its end marker.
+const PASTE_LIMIT: usize = 64 * 1024;
+/// How long a paste may stay open; terminals send the whole paste at once.
+const PASTE_TIMEOUT: Duration = Duration::from_secs(2);
+
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+const PASTE_MODE_ON: &[u8] = b"\x1b[?2004h";
+const PASTE_MODE_OFF: &[u8] = b"\x1b[?2004l";
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|w| w == needle)
+}
+
+/// Length of the longest suffix of `data` that is a proper prefix of `marker`.
+fn partial_suffix(data: &[u8], marker: &[u8]) -> usize {
+    (1..marker.len())
+        .rev()
+        .find(|&n| data.len() >= n && data[data.len() - n..] == marker[..n])
+        .unwrap_or(0)
+}
+
+/// Decodes the key at the start of `data`, returning it with the number of
+/// bytes it used, or `None` if more bytes are needed.
+fn decode_one(data: &[u8]) -> Option<(Key, usize)> {
+    let byte = data[0];
+    if byte >= 0x80 {
+        return decode_utf8(data);
+    }
+    if byte != 27 {
+        return Some((decode_byte(byte), 1));
+    }
+
+    match data.get(1) {
+        // A lone ESC at the end of a read is the Escape key itself
+        None => Some((Key::named("ESC", &data[..1]), 1)),
+        Some(b'[') => decode_csi(data),
+        Some(b'O') => {
+            let final_byte = *data.get(2)?;
+            let name = match final_byte {
+                b'A' => "Up".to_string(),
+                b'B' => "Down".to_string(),
+                b'C' => "Right".to_string(),
+                b'D' => "Left".to_string(),
+                b'H' => "Home".to_string(),
+                b'F' => "End".to_string(),
+                b'P'..=b'S' => format!("F{}", final_byte - b'P' + 1),
+                _ => format!("SS3 {}", final_byte as char),
+            };
+            Some((Key::named(name, &data[..3]), 3))
+        }
+        Some(27) => Some((Key::named("ESC", &data[..1]), 1)),
+        Some(&next) => {
+            let inner = decode_byte(next);
+            Some((Key::named(format!("Alt+{}", inner.name), &data[..2]), 2))
+        }
+    }
+}
+
+/// Decodes one multibyte UTF-8 character. Invalid bytes are reported one at
+/// a time by their hex value.
+fn decode_utf8(data: &[u8]) -> Option<(Key, usize)> {
+    let len = match data[0] {
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF4 => 4,
+        _ => 0,
+    };
+    let available = &data[..len.min(data.len())];
+    match std::str::from_utf8(available) {
+        Ok(text) if len > 0 => {
+            let c = text.chars().next()?;
+            Some((Key::text(c, available), len))
+        }
+        // A valid prefix cut off at the end of the read
+        Err(e) if e.error_len().is_none() && len > 0 => None,
+        _ => Some((Key::named(format!("0x{:02X}", data[0]), &data[..1]), 1)),
+    }
+}
+
+fn decode_csi(data: &[u8]) -> Option<(Key, usize)> {
+    // ESC [ parameters (0x30-0x3F) intermediates (0x20-0x2F) final (0x40-0x7E)
+    let end = data[2..].iter().position(|b| (0x40..=0x7E).contains(b))? + 2;
+    let len = end + 1;
+    let params = std::str::from_utf8(&data[2..end]).unwrap_or("");
+    let final_byte = data[end];
+
+    let mut fields = params.split(';');
+    let first = fields.next().unwrap_or("");
+    let modifier = fields.next().and_then(|m| m.parse::<u8>().ok()).unwrap_or(1);
+
+    let base = match final_byte {
+        b'A' => Some("Up".to_string()),
+        b'B' => Some("Down".to_string()),
+        b'C' => Some("Right".to_string()),
+        b'D' => Some("Left".to_string()),
+        b'H' => Some("Home".to_string()),
+        b'F' => Some("End".to_string()),
+        b'Z' => Some("Shift+Tab".to_string()),
+        // The sequence is complete here, so a parameter that is not a
+        // number still makes a key, named by its raw sequence
+        b'~' => match first.parse::<u16>().unwrap_or(0) {
+            1 | 7 => Some("Home".to_string()),
+            2 => Some("Insert".to_string()),
+            3 => Some("Delete".to_string()),
+            4 | 8 => Some("End".to_string()),
+            5 => Some("PageUp".to_string()),
+            6 => Some("PageDown".to_string()),
+            n @ 11..=15 => Some(format!("F{}", n - 10)),
+            n @ 17..=21 => Some(format!("F{}", n - 11)),
+            n @ 23..=24 => Some(format!("F{}", n - 12)),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    let name = match base {
+        Some(base) => format!("{}{}", modifier_prefix(modifier), base),
+        None => format!("CSI {}{}", params, final_byte as char),
+    };
+    Some((Key::named(name, &data[..len]), len))
+}
+
+/// Prefix for the xterm modifier parameter (1 + Shift 1, Alt 2, Ctrl 4).
+fn modifier_prefix(modifier: u8) -> String {
+    let bits = modifier.saturating_sub(1);
+    let mut prefix = String::new();
+    if bits & 4 != 0 {
+        prefix.push_str("Ctrl+");
+    }
+    if bits & 2 != 0 {
+        prefix.push_str("Alt+");
+    }
+    if bits & 1 != 0 {
+        prefix.push_str("Shift+");
+    }
+    prefix
+}
+
+fn decode_byte(byte: u8) -> Key {
+    let name = match byte {
+        0 => "NULL".to_string(),
+        8 | 127 => "Backspace".to_string(),
+        9 => "Tab".to_string(),
+        10 | 13 => "Enter".to_string(),
+        27 => "ESC".to_string(),
+        1..=26 => format!("Ctrl+{}", (b'A' + byte - 1) as char),
+        32..=126 => return Key::text(byte as char, &[byte]),
+        _ => format!("0x{:02X}", byte),
+    };
+    Key::named(name, &[byte])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(inputs: &[Input]) -> Vec<String> {
+        inputs.iter().map(|input| match input {
+            Input::Key(key) => key.name.clone(),
+            Input::Paste(text) => format!("paste {}", String::from_utf8_lossy(text)),
+        }).collect()
+    }
+
+    #[test]
+    fn holds_sequences_split_across_reads() {
+        let mut decoder = InputDecoder::new();
+        assert_eq!(names(&decoder.decode(b"a\x1b[1;5")), ["a"]);
+        assert_eq!(names(&decoder.decode(b"C\x1b")), ["Ctrl+Right", "ESC"]);
+        assert_eq!(names(&decoder.decode(b"\x1b[3")), Vec::<String>::new());
+        assert_eq!(names(&decoder.decode(b"~b")), ["Delete", "b"]);
+    }
+
+    #[test]
+    fn invalid_parameters_do_not_stall_the_decoder() {
+        let mut decoder = InputDecoder::new();
+        assert_eq!(names(&decoder.decode(b"\x1b[99999~")), ["CSI 99999~"]);
+        assert_eq!(names(&decoder.decode(b"\x1b[;~x")), ["CSI ;~", "x"]);
+        assert_eq!(names(&decoder.decode(b"\x1b[42~\r")), ["CSI 42~", "Enter"]);
+    }
+
+    #[test]
+    fn decodes_ss3_and_utf8() {
+        let mut decoder = InputDecoder::new();
+        assert_eq!(names(&decoder.decode(b"\x1bOA\x1bOQ\x1bOx")), ["Up", "F2", "SS3 x"]);
+
+        let inputs = decoder.decode("ñ€".as_bytes());
+        assert_eq!(names(&inputs), ["ñ", "€"]);
+        assert!(matches!(&inputs[1], Input::Key(key) if key.printable && key.code() == 0x20AC));
+
+        let bytes = "ü".as_bytes();
+        assert!(decoder.decode(&bytes[..1]).is_empty());
+        assert_eq!(names(&decoder.decode(&bytes[1..])), ["ü"]);
+        assert_eq!(names(&decoder.decode(b"\xff")), ["0xFF"]);
+    }
+
+    #[test]
+    fn collects_bracketed_pastes() {
+        let mut decoder = InputDecoder::new();
+        assert_eq!(names(&decoder.decode(b"x\x1b[200~ls \x1b[A")), ["x"]);
+        assert_eq!(names(&decoder.decode(b"-l\x1b[20")), Vec::<String>::new());
+        assert_eq!(names(&decoder.decode(b"1~y")), ["paste ls \x1b[A-l", "y"]);
+    }
+
+    #[test]
+    fn unterminated_pastes_are_cut_off() {
+        // Past the limit, the paste is returned and decoding goes back to keys
+        let mut decoder = InputDecoder::new();
+        let mut data = b"\x1b[200~".to_vec();
+        data.extend(std::iter::repeat_n(b'a', PASTE_LIMIT - 1));
+        assert!(decoder.decode(&data).is_empty());
+        let inputs = decoder.decode(b"bcd");
+        assert!(matches!(&inputs[0], Input::Paste(text) if text.len() == PASTE_LIMIT && text.ends_with(b"ab")));
+        assert_eq!(names(&inputs[1..]), ["c", "d"]);
+
+        // An end marker that comes too late does not count
+        let mut decoder = InputDecoder::new();
+        data.extend_from_slice(b"bc\x1b[201~");
+        let inputs = decoder.decode(&data);
+        assert_eq!(inputs.len(), 3);
+        assert_eq!(names(&inputs[1..]), ["c", "CSI 201~"]);
+
+        // A paste left open is returned once it is overdue
+        let mut decoder = InputDecoder::new();
+        assert!(decoder.decode(b"\x1b[200~ls").is_empty());
+        let started = Instant::now();
+        assert!(decoder.expire_paste(started).is_none());
+        let expired = decoder.expire_paste(started + PASTE_TIMEOUT);
+        assert!(matches!(expired, Some(Input::Paste(text)) if text == b"ls"));
+        assert_eq!(names(&decoder.decode(b"x")), ["x"]);
+        assert!(decoder.expire_paste(started + PASTE_TIMEOUT).is_none());
+    }
+}
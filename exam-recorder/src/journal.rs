@@ -0,0 +1,553 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use x25519_dalek::PublicKey;
+
+use exam_format::chain::Checkpoint;
+use exam_format::crypto::{decrypt_chunk, encrypt_chunk, DataKey};
+use exam_format::{
+    CommandEvent, ConnectionEvent, FileVersion, KeystrokeEvent, Metadata, PasteEvent, ProcessEvent,
+    TimeWarning,
+};
+
+use crate::chain::EventChain;
+use crate::state::{decrypt_state, encrypt_state, get_state_dir, State};
+
+/// Magic bytes at the start of every session journal.
+pub const JOURNAL_MAGIC: &[u8; 4] = b"EXRJ";
+/// Version of the journal layout.
+pub const JOURNAL_VERSION: u8 = 3;
+/// File extension of session journals in the state directory.
+pub const JOURNAL_EXTENSION: &str = "journal";
+
+/// How often buffered records are written out and synced to disk.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+/// Terminal data and file contents buffered before records are written out
+/// early, so a burst of output never piles up in memory.
+const FLUSH_BYTES: usize = 1024 * 1024;
+
+/// One entry of the session journal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JournalRecord {
+    Session { metadata: Box<Metadata>, state: State },
+    Keystroke(KeystrokeEvent),
+    Command(CommandEvent),
+    Paste(PasteEvent),
+    Process(ProcessEvent),
+    Connection(ConnectionEvent),
+    FileVersion {
+        version: FileVersion,
+        /// Compressed contents, if the version's `entry` names one.
+        #[serde(with = "base64_data")]
+        data: Vec<u8>,
+    },
+    Output {
+        timestamp: u64,
+        #[serde(default)]
+        offset: f64,
+        #[serde(with = "base64_data")]
+        data: Vec<u8>,
+        /// Output not recorded before this record, which then holds the
+        /// marker saying so.
+        #[serde(default)]
+        dropped: u64,
+    },
+    Input {
+        offset: f64,
+        #[serde(with = "base64_data")]
+        data: Vec<u8>,
+    },
+    Resize { timestamp: u64, offset: f64, width: u16, height: u16 },
+    TimeWarning(TimeWarning),
+    Checkpoint(Checkpoint),
+}
+
+/// Terminal data and file contents as base64 strings, which take a third
+/// more than the bytes rather than the three to four times of a JSON array.
+mod base64_data {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Session data read back from a journal whose recorder never finished.
+/// The recorded events stay in the journal until the archive is written.
+pub struct RecoveredSession {
+    pub metadata: Metadata,
+    pub state: State,
+    pub time_warnings: Vec<TimeWarning>,
+    /// Output the recorder left out, as far as its markers were saved.
+    pub output_dropped: u64,
+    /// Timestamp (ms) of the last record that survived.
+    pub stopped_at: u64,
+    /// Number of chunks that passed authentication.
+    pub chunks: u64,
+    /// Trailing bytes that were cut off or failed authentication.
+    pub discarded_bytes: usize,
+}
+
+/// Append-only, crash-safe log of a running session.
+///
+/// Layout: magic | version | u32 length | instructor public key |
+/// u32 length | data key encrypted with the machine state key | chunks.
+/// Each chunk is a u32 length followed by nonce || AES-GCM(JSON records),
+/// authenticated together with its sequence number.
+///
+/// The journal's data key only protects the journal: the archive is written
+/// under a key of its own, made when it is written and sealed to `recipient`.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    key: DataKey,
+    recipient: PublicKey,
+    sequence: u64,
+    pending: Vec<JournalRecord>,
+    /// Terminal data and file contents in `pending`.
+    pending_bytes: usize,
+    last_flush: Instant,
+    chain: EventChain,
+}
+
+impl Journal {
+    pub fn create(path: &Path, key: DataKey, recipient: &PublicKey) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .with_context(|| format!("Failed to create session journal: {}", path.display()))?;
+
+        // Held for the lifetime of the session so `recover` leaves it alone
+        lock(&file)?;
+
+        // Set restrictive permissions
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mut perms = file.metadata()?.permissions();
+            perms.set_mode(0o600);
+            file.set_permissions(perms)?;
+        }
+
+        // The local copy of the key lets `exam-recorder recover` rebuild an
+        // archive after a crash. The state key can be derived by anyone on
+        // this machine, so the journal is no secret from the student; the
+        // recipient says who the rebuilt archive is sealed to
+        let local_key = encrypt_state(key.as_bytes())?;
+
+        let mut header = Vec::new();
+        header.extend_from_slice(JOURNAL_MAGIC);
+        header.push(JOURNAL_VERSION);
+        write_length_prefixed(&mut header, recipient.as_bytes());
+        write_length_prefixed(&mut header, &local_key);
+
+        file.write_all(&header)
+            .context("Failed to write session journal header")?;
+        file.sync_all()
+            .context("Failed to sync session journal")?;
+
+        Ok(Journal {
+            path: path.to_path_buf(),
+            file,
+            key,
+            recipient: *recipient,
+            sequence: 0,
+            pending: Vec::new(),
+            pending_bytes: 0,
+            last_flush: Instant::now(),
+            chain: EventChain::new(),
+        })
+    }
+
+    /// Opens an orphaned journal and checks every chunk that survived.
+    ///
+    /// Reading stops at the first chunk that is cut short or fails
+    /// authentication; `replay` goes over everything before it.
+    pub fn recover(path: &Path) -> Result<(Self, RecoveredSession)> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open session journal: {}", path.display()))?;
+        lock(&file)?;
+        let file_len = file.metadata()
+            .context("Failed to read session journal")?
+            .len();
+
+        let mut reader = BufReader::new(&file);
+        let (recipient, local_key, mut offset) = read_header(&mut reader)?;
+        let key = DataKey::from_bytes(&decrypt_state(&local_key)
+            .context("Failed to unlock session journal (was it recorded on this machine?)")?)?;
+
+        let mut session = None;
+        let mut time_warnings = Vec::new();
+        let mut output_dropped = 0;
+        let mut stopped_at = 0;
+        // The session key is gone, so records after the last checkpoint
+        // stay unsigned
+        let mut chain = EventChain::rebuilt();
+        let mut sequence = 0;
+        while let Some((records, len)) = read_chunk(&mut reader, &key, sequence)? {
+            offset += len;
+            sequence += 1;
+            for record in records {
+                chain.push(&record)?;
+                match record {
+                    JournalRecord::Session { metadata, state } => {
+                        stopped_at = stopped_at.max(metadata.start_time * 1000);
+                        session = Some((*metadata, state));
+                    }
+                    JournalRecord::Keystroke(event) => stopped_at = stopped_at.max(event.timestamp),
+                    JournalRecord::Command(event) => stopped_at = stopped_at.max(event.timestamp),
+                    JournalRecord::Paste(event) => stopped_at = stopped_at.max(event.timestamp),
+                    JournalRecord::Process(event) => stopped_at = stopped_at.max(event.timestamp),
+                    JournalRecord::Connection(event) => stopped_at = stopped_at.max(event.timestamp),
+                    JournalRecord::FileVersion { version, .. } => {
+                        stopped_at = stopped_at.max(version.timestamp);
+                    }
+                    JournalRecord::Output { timestamp, dropped, .. } => {
+                        stopped_at = stopped_at.max(timestamp);
+                        output_dropped += dropped;
+                    }
+                    JournalRecord::Input { .. } => {}
+                    JournalRecord::Resize { timestamp, .. } => stopped_at = stopped_at.max(timestamp),
+                    JournalRecord::TimeWarning(warning) => {
+                        stopped_at = stopped_at.max(warning.timestamp);
+                        time_warnings.push(warning);
+                    }
+                    JournalRecord::Checkpoint(checkpoint) => {
+                        stopped_at = stopped_at.max(checkpoint.timestamp);
+                        chain.restore_checkpoint(&checkpoint);
+                    }
+                }
+            }
+        }
+        drop(reader);
+        let (metadata, state) = session
+            .context("Session journal has no surviving session record")?;
+
+        let journal = Journal {
+            path: path.to_path_buf(),
+            file,
+            key,
+            recipient,
+            sequence,
+            pending: Vec::new(),
+            pending_bytes: 0,
+            last_flush: Instant::now(),
+            chain,
+        };
+        let recovered = RecoveredSession {
+            metadata,
+            state,
+            time_warnings,
+            output_dropped,
+            stopped_at,
+            chunks: sequence,
+            discarded_bytes: (file_len - offset) as usize,
+        };
+
+        Ok((journal, recovered))
+    }
+
+    /// Reads back every record written so far, in order, one chunk at a
+    /// time. Records still waiting to be flushed are not included.
+    pub fn replay(&self, mut visit: impl FnMut(JournalRecord) -> Result<()>) -> Result<()> {
+        let file = File::open(&self.path)
+            .with_context(|| format!("Failed to open session journal: {}", self.path.display()))?;
+        let mut reader = BufReader::new(file);
+        read_header(&mut reader)?;
+        for sequence in 0..self.sequence {
+            let (records, _) = read_chunk(&mut reader, &self.key, sequence)?
+                .context("Session journal changed while it was being read")?;
+            for record in records {
+                visit(record)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Instructor public key the session's archive is sealed to.
+    pub fn recipient(&self) -> &PublicKey {
+        &self.recipient
+    }
+
+    pub fn chain(&self) -> &EventChain {
+        &self.chain
+    }
+
+    pub fn append(&mut self, record: JournalRecord) -> Result<()> {
+        self.chain.push(&record)?;
+        if let JournalRecord::Output { data, .. }
+        | JournalRecord::Input { data, .. }
+        | JournalRecord::FileVersion { data, .. } = &record
+        {
+            self.pending_bytes += data.len();
+        }
+        self.pending.push(record);
+        Ok(())
+    }
+
+    /// Signs the chain up to the last record, so nothing recorded so far
+    /// can be changed without breaking a signature.
+    pub fn seal_chain(&mut self) {
+        if let Some(checkpoint) = self.chain.checkpoint(true) {
+            self.pending.push(JournalRecord::Checkpoint(checkpoint));
+        }
+    }
+
+    pub fn flush_if_due(&mut self) -> Result<()> {
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL || self.pending_bytes >= FLUSH_BYTES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.last_flush = Instant::now();
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        if let Some(checkpoint) = self.chain.checkpoint(false) {
+            self.pending.push(JournalRecord::Checkpoint(checkpoint));
+        }
+
+        let records = serde_json::to_vec(&self.pending)
+            .context("Failed to serialize journal records")?;
+        let encrypted = encrypt_chunk(&records, &self.key, self.sequence)?;
+
+        let mut chunk = Vec::with_capacity(encrypted.len() + 4);
+        write_length_prefixed(&mut chunk, &encrypted);
+
+        self.file.write_all(&chunk)
+            .context("Failed to write session journal")?;
+        self.file.sync_data()
+            .context("Failed to sync session journal")?;
+
+        self.sequence += 1;
+        self.pending.clear();
+        self.pending_bytes = 0;
+
+        Ok(())
+    }
+
+    /// Deletes the journal once its contents are safely in an archive.
+    pub fn remove(&mut self) -> Result<()> {
+        std::fs::remove_file(&self.path)
+            .context("Failed to remove session journal")
+    }
+}
+
+/// Lists journals in the state directory, oldest first.
+pub fn find_journals() -> Result<Vec<PathBuf>> {
+    let state_dir = get_state_dir()?;
+    if !state_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut journals: Vec<PathBuf> = std::fs::read_dir(&state_dir)
+        .context("Failed to read state directory")?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == JOURNAL_EXTENSION))
+        .collect();
+    journals.sort_by_key(|path| {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    });
+
+    Ok(journals)
+}
+
+fn lock(file: &File) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        anyhow::bail!("Session journal is in use by a running recorder");
+    }
+    Ok(())
+}
+
+/// Reads the journal header, returning the instructor public key, the
+/// locally encrypted data key and the header length.
+fn read_header(reader: &mut impl Read) -> Result<(PublicKey, Vec<u8>, u64)> {
+    let mut start = [0u8; 5];
+    reader.read_exact(&mut start)
+        .map_err(|_| anyhow::anyhow!("Not a session journal"))?;
+    if &start[..4] != JOURNAL_MAGIC {
+        anyhow::bail!("Not a session journal");
+    }
+    if start[4] != JOURNAL_VERSION {
+        anyhow::bail!("Unsupported journal version: {}", start[4]);
+    }
+
+    let recipient = read_length_prefixed(reader)
+        .context("Session journal header is truncated")?;
+    let local_key = read_length_prefixed(reader)
+        .context("Session journal header is truncated")?;
+    let len = 5 + 4 + recipient.len() as u64 + 4 + local_key.len() as u64;
+    let recipient: [u8; 32] = recipient.try_into()
+        .map_err(|_| anyhow::anyhow!("Session journal header is corrupted"))?;
+    Ok((PublicKey::from(recipient), local_key, len))
+}
+
+/// Reads and decrypts the chunk written at `sequence`, returning its records
+/// and its length on disk, or `None` if it is cut short or does not
+/// authenticate.
+fn read_chunk(reader: &mut impl Read, key: &DataKey, sequence: u64) -> Result<Option<(Vec<JournalRecord>, u64)>> {
+    let Some(chunk) = read_length_prefixed(reader) else {
+        return Ok(None);
+    };
+    let Ok(plaintext) = decrypt_chunk(&chunk, key, sequence) else {
+        return Ok(None);
+    };
+    let records = serde_json::from_slice(&plaintext)
+        .context("Failed to parse journal records")?;
+    Ok(Some((records, 4 + chunk.len() as u64)))
+}
+
+fn read_length_prefixed(reader: &mut impl Read) -> Option<Vec<u8>> {
+    let mut header = [0u8; 4];
+    reader.read_exact(&mut header).ok()?;
+    let len = u32::from_le_bytes(header) as u64;
+    // Grows with the data actually read, whatever the length claims
+    let mut body = Vec::new();
+    reader.take(len).read_to_end(&mut body).ok()?;
+    (body.len() as u64 == len).then_some(body)
+}
+
+fn write_length_prefixed(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::StaticSecret;
+
+    fn keystroke(timestamp: u64) -> JournalRecord {
+        JournalRecord::Keystroke(KeystrokeEvent {
+            timestamp,
+            key_code: 'a' as u32,
+            key_name: "a".into(),
+            raw_bytes: b"a".to_vec(),
+            is_paste: false,
+        })
+    }
+
+    /// Writes a journal of three chunks and returns its path, contents and
+    /// the offset where each chunk ends.
+    fn three_chunks(name: &str) -> (PathBuf, Vec<u8>, Vec<usize>) {
+        let path = std::env::temp_dir().join(format!("exam-recorder-test-{}-{}.journal", std::process::id(), name));
+        std::fs::remove_file(&path).ok();
+        let recipient = PublicKey::from(&StaticSecret::from([3u8; 32]));
+        let mut journal = Journal::create(&path, DataKey::generate(), &recipient).unwrap();
+        let mut metadata = Metadata::new("student".into(), "lab-01".into(), "abc123".into(), 1);
+        metadata.start_time = 0;
+        journal.append(JournalRecord::Session {
+            metadata: Box::new(metadata),
+            state: State { run_counter: 1, last_run_time: None },
+        }).unwrap();
+
+        let mut ends = Vec::new();
+        for chunk in 1..=3 {
+            journal.append(JournalRecord::Output {
+                timestamp: chunk * 1_000,
+                offset: chunk as f64,
+                data: vec![chunk as u8; 100],
+                dropped: 0,
+            }).unwrap();
+            journal.append(keystroke(chunk * 1_000)).unwrap();
+            journal.append(keystroke(chunk * 1_000 + 500)).unwrap();
+            journal.flush().unwrap();
+            ends.push(std::fs::metadata(&path).unwrap().len() as usize);
+        }
+        drop(journal);
+        let contents = std::fs::read(&path).unwrap();
+        (path, contents, ends)
+    }
+
+    fn recover(path: &Path, contents: &[u8]) -> (RecoveredSession, usize) {
+        std::fs::write(path, contents).unwrap();
+        let (journal, session) = Journal::recover(path).unwrap();
+        let mut keystrokes = 0;
+        journal.replay(|record| {
+            match record {
+                JournalRecord::Keystroke(_) => keystrokes += 1,
+                JournalRecord::Output { timestamp, data, .. } => {
+                    assert_eq!(data, vec![(timestamp / 1_000) as u8; 100]);
+                }
+                _ => {}
+            }
+            Ok(())
+        }).unwrap();
+        (session, keystrokes)
+    }
+
+    #[test]
+    fn data_is_stored_as_base64() {
+        let record = JournalRecord::Input { offset: 0.5, data: b"ls\r".to_vec() };
+        let json = serde_json::to_string(&record).unwrap();
+        assert_eq!(json, r#"{"type":"input","offset":0.5,"data":"bHMN"}"#);
+        let JournalRecord::Input { data, .. } = serde_json::from_str(&json).unwrap() else {
+            panic!("not an input record");
+        };
+        assert_eq!(data, b"ls\r");
+    }
+
+    #[test]
+    fn complete_journal_is_recovered() {
+        let (path, contents, _) = three_chunks("complete");
+        let (session, keystrokes) = recover(&path, &contents);
+        assert_eq!((session.chunks, session.discarded_bytes, keystrokes), (3, 0, 6));
+        assert_eq!(session.stopped_at, 3_500);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated_chunk_is_dropped() {
+        let (path, contents, ends) = three_chunks("truncated");
+        let cut = (ends[1] + ends[2]) / 2;
+        let (session, keystrokes) = recover(&path, &contents[..cut]);
+        assert_eq!((session.chunks, session.discarded_bytes, keystrokes), (2, cut - ends[1], 4));
+        assert_eq!(session.stopped_at, 2_500);
+
+        // Cut inside the length prefix
+        let (session, _) = recover(&path, &contents[..ends[0] + 2]);
+        assert_eq!((session.chunks, session.discarded_bytes), (1, 2));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_chunk_ends_the_journal() {
+        let (path, mut contents, ends) = three_chunks("corrupted");
+        contents[(ends[0] + ends[1]) / 2] ^= 1;
+        let (session, keystrokes) = recover(&path, &contents);
+        assert_eq!((session.chunks, session.discarded_bytes, keystrokes), (1, contents.len() - ends[0], 2));
+        assert_eq!(session.stopped_at, 1_500);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reordered_chunks_do_not_authenticate() {
+        let (path, contents, ends) = three_chunks("reordered");
+        let mut swapped = contents[..ends[0]].to_vec();
+        swapped.extend_from_slice(&contents[ends[1]..ends[2]]);
+        swapped.extend_from_slice(&contents[ends[0]..ends[1]]);
+        let (session, keystrokes) = recover(&path, &swapped);
+        assert_eq!((session.chunks, keystrokes), (1, 2));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
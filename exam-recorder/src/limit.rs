@@ -0,0 +1,86 @@
+use std::time::{Duration, Instant};
+
+/// Terminal output recorded in full when the exam does not set a limit.
+pub const DEFAULT_OUTPUT_LIMIT_MIB: u64 = 64;
+/// Output still recorded each second once the limit is reached, so what
+/// the student does afterwards keeps showing up.
+const SAMPLE_BYTES: u64 = 1024;
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Caps how much terminal output goes into the journal and the archive.
+///
+/// A runaway command (`yes`, `cat /dev/urandom`) would otherwise fill the
+/// disk and the archive. Output past the limit is sampled: the first
+/// `SAMPLE_BYTES` of every second are kept, and the recorder marks every
+/// gap with how many bytes were left out.
+pub struct OutputLimit {
+    limit: u64,
+    recorded: u64,
+    /// Dropped since the last marker.
+    unmarked: u64,
+    dropped: u64,
+    sample_started: Instant,
+    sampled: u64,
+}
+
+/// How much of one read of output to record.
+pub struct Admitted {
+    /// Bytes left out before this read, to be marked before it.
+    pub dropped_before: Option<u64>,
+    /// Length of the start of the read that is recorded.
+    pub len: usize,
+}
+
+impl OutputLimit {
+    pub fn new(limit_mib: u64) -> Self {
+        OutputLimit {
+            limit: limit_mib * 1024 * 1024,
+            recorded: 0,
+            unmarked: 0,
+            dropped: 0,
+            sample_started: Instant::now(),
+            sampled: 0,
+        }
+    }
+
+    pub fn admit(&mut self, len: usize) -> Admitted {
+        let allowance = if self.recorded < self.limit {
+            self.limit - self.recorded
+        } else {
+            if self.sample_started.elapsed() >= SAMPLE_INTERVAL {
+                self.sample_started = Instant::now();
+                self.sampled = 0;
+            }
+            SAMPLE_BYTES - self.sampled
+        };
+        let kept = (len as u64).min(allowance);
+        if self.recorded >= self.limit {
+            self.sampled += kept;
+        }
+        self.recorded += kept;
+
+        let dropped_before = if kept > 0 && self.unmarked > 0 {
+            Some(std::mem::take(&mut self.unmarked))
+        } else {
+            None
+        };
+        self.unmarked += len as u64 - kept;
+        self.dropped += len as u64 - kept;
+        Admitted { dropped_before, len: kept as usize }
+    }
+
+    /// Output left out since the last marker, when the session ends.
+    pub fn finish(&mut self) -> Option<u64> {
+        (self.unmarked > 0).then(|| std::mem::take(&mut self.unmarked))
+    }
+
+    /// All output left out so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// Text recorded in place of output that was left out.
+pub fn dropped_marker(bytes: u64) -> String {
+    format!("\r\n[exam-recorder: {} bytes of output not recorded]\r\n", bytes)
+}
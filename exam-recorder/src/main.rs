@@ -1,26 +1,50 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 use std::process;
 
+use exam_format::ExamConfig;
+
 mod recorder;
+mod config;
 mod encryption;
 mod state;
 mod metadata;
+mod journal;
+mod entries;
+mod limit;
+mod processes;
+mod network;
+mod sampler;
+mod watch;
+mod chain;
+mod stream;
+mod input;
+mod timer;
+mod shell;
 
 use recorder::Recorder;
 
 fn main() {
-    let _args = Args::parse();
+    let args = Args::parse();
+    
+    if let Some(Commands::Recover) = args.command {
+        if let Err(e) = Recorder::recover_all() {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
     
     println!("Exam Recorder Suite — Student Terminal Session Recorder");
     println!("Author: A. Z. M. Arif  |  Website: https://azmarif.dev");
     println!();
     println!("Recording your exam session...");
     println!("All terminal activity is being securely logged.");
-    println!("Type 'exit' to finish and generate your encrypted exam record.");
+    println!("Type 'exit' or press Ctrl+D to finish and generate your encrypted exam record.");
     println!();
     
-    if let Err(e) = run_recorder() {
+    if let Err(e) = run_recorder(args) {
         eprintln!("Error: {}", e);
         process::exit(1);
     }
@@ -29,11 +53,111 @@ fn main() {
 #[derive(Parser)]
 #[command(name = "exam-recorder")]
 #[command(about = "Student-side secure terminal session recorder")]
-struct Args {}
+struct Args {
+    /// Exam identifier, recorded in the archive and its filename
+    #[arg(long)]
+    exam_id: Option<String>,
+    /// Student identifier (default: the login name)
+    #[arg(long)]
+    student_id: Option<String>,
+    /// Directory to write the exam archive to (default: ~/.exam-recorder)
+    #[arg(long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+    /// Shell to start (default: $SHELL)
+    #[arg(long, value_name = "PATH")]
+    shell: Option<String>,
+    /// Time limit in minutes
+    #[arg(long, value_name = "MINUTES")]
+    time_limit: Option<u64>,
+    /// Warn when this many minutes are left, e.g. 10,1 (default: 10,1)
+    #[arg(long, value_name = "MINUTES", value_delimiter = ',')]
+    warn_at: Option<Vec<u64>>,
+    /// Terminal output to record in full; past it, output is only sampled (default: 64)
+    #[arg(long, value_name = "MIB")]
+    output_limit: Option<u64>,
+    /// Signed exam config file; its settings take precedence over the options above
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Program to record instead of an interactive shell
+    #[arg(last = true, value_name = "COMMAND")]
+    program: Vec<String>,
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Rebuild exam archives from sessions that ended without finishing
+    Recover,
+}
 
-fn run_recorder() -> Result<()> {
-    let mut recorder = Recorder::new()?;
+fn run_recorder(args: Args) -> Result<()> {
+    let config_path = args.config.clone();
+    let mut config = command_line_config(args);
+    // The instructor's settings cannot be overridden from the command line
+    if let Some(path) = &config_path {
+        config = config::load(path)?.or(config);
+    }
+    
+    let mut recorder = Recorder::new(config)?;
     recorder.start()?;
     Ok(())
 }
 
+/// The exam settings given as options, before any signed config's.
+fn command_line_config(args: Args) -> ExamConfig {
+    ExamConfig {
+        exam_id: args.exam_id,
+        student_id: args.student_id,
+        output_dir: args.output_dir,
+        shell: args.shell,
+        time_limit: args.time_limit,
+        warnings: args.warn_at,
+        output_limit: args.output_limit,
+        command: args.program,
+        ..ExamConfig::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Args {
+        Args::try_parse_from(std::iter::once("exam-recorder").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn options_become_the_exam_settings() {
+        let config = command_line_config(parse(&[
+            "--exam-id", "CS101-final",
+            "--student-id", "s1234",
+            "--output-dir", "/tmp/out",
+            "--time-limit", "90",
+            "--warn-at", "10,5,1",
+            "--", "python3", "-i", "quiz.py",
+        ]));
+        assert_eq!(config.exam_id.as_deref(), Some("CS101-final"));
+        assert_eq!(config.student_id.as_deref(), Some("s1234"));
+        assert_eq!(config.output_dir, Some(PathBuf::from("/tmp/out")));
+        assert_eq!(config.time_limit, Some(90));
+        assert_eq!(config.warnings, Some(vec![10, 5, 1]));
+        // Options of the recorded program are left to it
+        assert_eq!(config.command, ["python3", "-i", "quiz.py"]);
+        assert_eq!(config.shell, None);
+    }
+
+    #[test]
+    fn no_options_leave_the_defaults_to_the_recorder() {
+        let args = parse(&[]);
+        assert!(args.command.is_none() && args.config.is_none());
+        let config = command_line_config(args);
+        assert_eq!(config.exam_id, None);
+        assert_eq!(config.time_limit, None);
+        assert!(config.command.is_empty());
+
+        assert!(matches!(parse(&["recover"]).command, Some(Commands::Recover)));
+        assert!(Args::try_parse_from(["exam-recorder", "--time-limit", "soon"]).is_err());
+    }
+}
+
@@ -1,58 +1,23 @@
-use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
 use std::process::Command;
 use std::fs;
 use anyhow::Result;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Metadata {
-    pub username: String,
-    pub hostname: String,
-    pub machine_id: String,
-    pub run_counter: u64,
-    pub start_time: u64,
-    pub end_time: Option<u64>,
-    pub duration_seconds: Option<u64>,
-}
+use exam_format::Metadata;
 
-impl Metadata {
-    pub fn new(run_counter: u64) -> Result<Self> {
-        let username = std::env::var("USER")
-            .or_else(|_| std::env::var("USERNAME"))
-            .unwrap_or_else(|_| "unknown".to_string());
-        
-        let hostname = hostname::get()
-            .ok()
-            .and_then(|h| h.to_str().map(|s| s.to_string()))
-            .unwrap_or_else(|| "unknown".to_string());
-        
-        let machine_id = generate_machine_id(&hostname)?;
-        
-        let start_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        
-        Ok(Metadata {
-            username,
-            hostname,
-            machine_id,
-            run_counter,
-            start_time,
-            end_time: None,
-            duration_seconds: None,
-        })
-    }
+/// Metadata for a session starting now on this machine.
+pub fn collect(run_counter: u64) -> Result<Metadata> {
+    let username = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string());
     
-    pub fn finalize(&mut self) {
-        let end_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        
-        self.end_time = Some(end_time);
-        self.duration_seconds = Some(end_time.saturating_sub(self.start_time));
-    }
+    let hostname = hostname::get()
+        .ok()
+        .and_then(|h| h.to_str().map(|s| s.to_string()))
+        .unwrap_or_else(|| "unknown".to_string());
+    
+    let machine_id = generate_machine_id(&hostname)?;
+    
+    Ok(Metadata::new(username, hostname, machine_id, run_counter))
 }
 
 fn generate_machine_id(hostname: &str) -> Result<String> {
@@ -63,7 +28,7 @@ fn generate_machine_id(hostname: &str) -> Result<String> {
     
     // Try to get MAC address
     if let Ok(output) = Command::new("ip")
-        .args(&["link", "show"])
+        .args(["link", "show"])
         .output()
     {
         if let Ok(output_str) = String::from_utf8(output.stdout) {
@@ -0,0 +1,221 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use exam_format::ConnectionEvent;
+
+use crate::processes::ProcessMonitor;
+
+/// Socket tables sampled, by protocol.
+const SOCKET_TABLES: [(&str, &str); 4] = [
+    ("tcp", "/proc/net/tcp"),
+    ("tcp6", "/proc/net/tcp6"),
+    ("udp", "/proc/net/udp"),
+    ("udp6", "/proc/net/udp6"),
+];
+/// TCP states of a connection made or being made, and of a listening socket.
+const TCP_ESTABLISHED: u8 = 0x01;
+const TCP_SYN_SENT: u8 = 0x02;
+const TCP_LISTEN: u8 = 0x0A;
+
+/// Finds network connections of the session's processes by matching the
+/// sockets they hold (/proc/<pid>/fd) against the kernel's socket tables.
+/// Like the process monitor it samples, so a connection that opens and
+/// closes between two samples is missed.
+///
+/// The tables only show the peer of a connected socket. A UDP socket that
+/// sends with `sendto` is never connected, so it is reported once bound,
+/// with an unspecified remote address, and where it sent stays unknown.
+pub struct ConnectionMonitor {
+    /// Sockets already reported that were still open on the last sample,
+    /// with the remote address and port they were reported with.
+    reported: HashSet<(u64, IpAddr, u16)>,
+}
+
+/// One line of a /proc/net socket table.
+struct Socket {
+    local: IpAddr,
+    local_port: u16,
+    remote: IpAddr,
+    remote_port: u16,
+    state: u8,
+    inode: u64,
+}
+
+impl ConnectionMonitor {
+    pub fn new() -> Self {
+        ConnectionMonitor { reported: HashSet::new() }
+    }
+
+    /// Connections of `processes` opened since the last sample.
+    pub fn poll(&mut self, processes: &ProcessMonitor) -> Vec<ConnectionEvent> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let mut owners: HashMap<u64, (u32, &[String])> = HashMap::new();
+        for (pid, argv) in processes.processes() {
+            for inode in socket_inodes(pid) {
+                owners.entry(inode).or_insert((pid, argv));
+            }
+        }
+
+        let mut events = Vec::new();
+        let mut open = HashSet::new();
+        for (protocol, path) in SOCKET_TABLES {
+            let tcp = protocol.starts_with("tcp");
+            let table = read_sockets(path);
+            // Any process's listening ports, so a connection accepted for
+            // the session by a process outside it still counts as inbound
+            let listening: HashSet<u16> = table.iter()
+                .filter(|socket| tcp && socket.state == TCP_LISTEN)
+                .map(|socket| socket.local_port)
+                .collect();
+
+            for socket in table.into_iter().filter(|socket| owners.contains_key(&socket.inode)) {
+                if !is_reported(tcp, &socket) {
+                    continue;
+                }
+                let key = (socket.inode, socket.remote, socket.remote_port);
+                open.insert(key);
+                if self.reported.contains(&key) {
+                    continue;
+                }
+                let (pid, argv) = owners[&socket.inode];
+                events.push(ConnectionEvent {
+                    timestamp,
+                    protocol: protocol.to_string(),
+                    local_port: socket.local_port,
+                    remote_address: socket.remote.to_string(),
+                    remote_port: socket.remote_port,
+                    inbound: tcp && listening.contains(&socket.local_port),
+                    pid,
+                    argv: argv.to_vec(),
+                });
+            }
+        }
+        self.reported = open;
+        events
+    }
+}
+
+/// Whether `socket` can carry traffic off this machine: a TCP connection
+/// made or being made, or a UDP socket that is connected or bound to
+/// anything but a loopback address.
+fn is_reported(tcp: bool, socket: &Socket) -> bool {
+    let connected = socket.remote_port != 0 && !socket.remote.is_unspecified();
+    if tcp {
+        connected && (socket.state == TCP_ESTABLISHED || socket.state == TCP_SYN_SENT)
+    } else {
+        connected || (socket.local_port != 0 && !socket.local.is_loopback())
+    }
+}
+
+/// Inodes of the sockets `pid` has open.
+fn socket_inodes(pid: u32) -> Vec<u64> {
+    let Ok(entries) = fs::read_dir(format!("/proc/{}/fd", pid)) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+        .filter_map(|target| {
+            target.to_str()?
+                .strip_prefix("socket:[")?
+                .strip_suffix(']')?
+                .parse()
+                .ok()
+        })
+        .collect()
+}
+
+fn read_sockets(path: &str) -> Vec<Socket> {
+    let Ok(table) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    table.lines().skip(1).filter_map(parse_socket).collect()
+}
+
+/// Parses "sl local_address rem_address st ... inode ...".
+fn parse_socket(line: &str) -> Option<Socket> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (local, local_port) = parse_address(fields.get(1)?)?;
+    let (remote, remote_port) = parse_address(fields.get(2)?)?;
+    Some(Socket {
+        local,
+        local_port,
+        remote,
+        remote_port,
+        state: u8::from_str_radix(fields.get(3)?, 16).ok()?,
+        inode: fields.get(9)?.parse().ok()?,
+    })
+}
+
+/// Parses "0100007F:1F90": the address as 32-bit words in host byte order,
+/// then the port.
+fn parse_address(field: &str) -> Option<(IpAddr, u16)> {
+    let (address, port) = field.split_once(':')?;
+    let mut bytes = Vec::with_capacity(16);
+    for word in address.as_bytes().chunks(8) {
+        let word = u32::from_str_radix(std::str::from_utf8(word).ok()?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+    let address = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => {
+            let address = Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?);
+            // IPv4 connections made through an IPv6 socket
+            address.to_ipv4_mapped().map_or(IpAddr::V6(address), IpAddr::V4)
+        }
+        _ => return None,
+    };
+    Some((address, u16::from_str_radix(port, 16).ok()?))
+}
+
+// Addresses in /proc/net are 32-bit words in host byte order; the samples
+// below are as a little-endian kernel writes them
+#[cfg(all(test, target_endian = "little"))]
+mod tests {
+    use super::*;
+
+    fn address(field: &str) -> Option<(String, u16)> {
+        parse_address(field).map(|(address, port)| (address.to_string(), port))
+    }
+
+    #[test]
+    fn reads_ipv4_addresses_in_host_byte_order() {
+        assert_eq!(address("0100007F:0050"), Some(("127.0.0.1".to_string(), 80)));
+        assert_eq!(address("0F02000A:1F90"), Some(("10.0.2.15".to_string(), 8080)));
+    }
+
+    #[test]
+    fn reads_ipv6_and_v4_mapped_addresses() {
+        assert_eq!(address("00000000000000000000000001000000:0016"), Some(("::1".to_string(), 22)));
+        assert_eq!(address("B80D0120000000000000000001000000:01BB"), Some(("2001:db8::1".to_string(), 443)));
+        // Connections made through an IPv6 socket to an IPv4 host
+        assert_eq!(address("0000000000000000FFFF00000501A8C0:0050"), Some(("192.168.1.5".to_string(), 80)));
+    }
+
+    #[test]
+    fn udp_sockets_are_reported_once_bound_beyond_loopback() {
+        let socket = |line: &str| parse_socket(line).unwrap();
+        // Connected, as a resolver's socket is
+        assert!(is_reported(false, &socket("1: 0F02000A:A1B2 0100A8C0:0035 01 00000000:00000000 00:00000000 00000000 1000 0 4001")));
+        // Bound by sendto, destination unknown
+        assert!(is_reported(false, &socket("2: 00000000:C3D4 00000000:0000 07 00000000:00000000 00:00000000 00000000 1000 0 4002")));
+        assert!(!is_reported(false, &socket("3: 0100007F:1F90 00000000:0000 07 00000000:00000000 00:00000000 00000000 1000 0 4003")));
+        assert!(!is_reported(false, &socket("4: 00000000:0000 00000000:0000 07 00000000:00000000 00:00000000 00000000 1000 0 4004")));
+        // A listening TCP socket is not a connection
+        assert!(!is_reported(true, &socket("5: 00000000:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000 1000 0 4005")));
+        assert!(is_reported(true, &socket("6: 0F02000A:A1B2 22D8B85D:01BB 01 00000000:00000000 00:00000000 00000000 1000 0 4006")));
+    }
+
+    #[test]
+    fn malformed_addresses_are_skipped() {
+        assert_eq!(address("0100007F"), None);
+        assert_eq!(address("0100007F0:0050"), None);
+        assert_eq!(address("0100007G:0050"), None);
+        assert_eq!(address("0100007F:port"), None);
+    }
+}
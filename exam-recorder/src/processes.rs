@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use exam_format::{ProcessEvent, ProcessEventKind};
+
+/// Follows the processes of the recorded shell by polling /proc.
+///
+/// The shell is started as a session leader, so every process in its
+/// session belongs to the exam, as does every descendant of one of them.
+/// A process once seen is followed until it exits, even after it leaves
+/// the session or is reparented. Programs that start and exit between two
+/// polls are missed; the shell integration still records the command.
+pub struct ProcessMonitor {
+    shell: u32,
+    /// Processes seen on the last poll, by pid.
+    known: HashMap<u32, Known>,
+    boot_time_ms: u64,
+    ticks_per_second: u64,
+}
+
+struct Known {
+    ppid: u32,
+    start_ticks: u64,
+    program: Program,
+    /// Unset while the process is a fork still running its parent's
+    /// program, such as a subshell.
+    reported: bool,
+}
+
+#[derive(PartialEq)]
+struct Program {
+    argv: Vec<String>,
+    exe: Option<String>,
+}
+
+/// The fields of /proc/<pid>/stat the monitor uses.
+struct Stat {
+    pid: u32,
+    ppid: u32,
+    session: u32,
+    /// Start time in clock ticks since boot.
+    start_ticks: u64,
+}
+
+impl ProcessMonitor {
+    /// Starts following `shell`, or returns `None` without a readable /proc.
+    pub fn new(shell: u32) -> Option<Self> {
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        Some(ProcessMonitor {
+            shell,
+            known: HashMap::new(),
+            boot_time_ms: boot_time()? * 1000,
+            ticks_per_second: u64::try_from(ticks_per_second).ok().filter(|&ticks| ticks > 0)?,
+        })
+    }
+
+    /// Processes followed, subshells included, with their command lines.
+    pub fn processes(&self) -> impl Iterator<Item = (u32, &[String])> {
+        self.known.iter().map(|(pid, known)| (*pid, known.program.argv.as_slice()))
+    }
+
+    /// Programs started and processes exited since the last poll.
+    pub fn poll(&mut self) -> Vec<ProcessEvent> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let running = running_processes();
+        let mut members: HashMap<u32, &Stat> = running.iter()
+            .filter(|stat| {
+                stat.pid == self.shell
+                    || stat.session == self.shell
+                    || self.known.get(&stat.pid).is_some_and(|known| known.start_ticks == stat.start_ticks)
+            })
+            .map(|stat| (stat.pid, stat))
+            .collect();
+        // Descendants that started a session of their own, however deep
+        loop {
+            let before = members.len();
+            for stat in &running {
+                if members.contains_key(&stat.ppid) {
+                    members.entry(stat.pid).or_insert(stat);
+                }
+            }
+            if members.len() == before {
+                break;
+            }
+        }
+
+        let (boot_time_ms, ticks_per_second) = (self.boot_time_ms, self.ticks_per_second);
+        let start_time = |ticks: u64| boot_time_ms + ticks * 1000 / ticks_per_second;
+        let mut events = Vec::new();
+        self.known.retain(|pid, known| {
+            let running = members.get(pid).is_some_and(|stat| stat.start_ticks == known.start_ticks);
+            if !running && known.reported {
+                events.push(ProcessEvent {
+                    timestamp,
+                    kind: ProcessEventKind::Exit,
+                    pid: *pid,
+                    ppid: known.ppid,
+                    start_time: start_time(known.start_ticks),
+                    argv: Vec::new(),
+                    exe: None,
+                });
+            }
+            running
+        });
+
+        let mut members: Vec<&Stat> = members.into_values().collect();
+        members.sort_by_key(|stat| (stat.start_ticks, stat.pid));
+        for stat in members {
+            let Some(program) = read_program(stat.pid) else {
+                continue;
+            };
+            if let Some(known) = self.known.get_mut(&stat.pid) {
+                known.ppid = stat.ppid;
+                if known.program == program {
+                    continue;
+                }
+            } else if self.known.get(&stat.ppid).is_some_and(|parent| parent.program == program) {
+                // Forked, not (yet) executed anything
+                self.known.insert(stat.pid, Known {
+                    ppid: stat.ppid,
+                    start_ticks: stat.start_ticks,
+                    program,
+                    reported: false,
+                });
+                continue;
+            }
+            events.push(ProcessEvent {
+                timestamp,
+                kind: ProcessEventKind::Exec,
+                pid: stat.pid,
+                ppid: stat.ppid,
+                start_time: start_time(stat.start_ticks),
+                argv: program.argv.clone(),
+                exe: program.exe.clone(),
+            });
+            self.known.insert(stat.pid, Known {
+                ppid: stat.ppid,
+                start_ticks: stat.start_ticks,
+                program,
+                reported: true,
+            });
+        }
+        events
+    }
+}
+
+/// Seconds since the epoch at which the system booted.
+fn boot_time() -> Option<u64> {
+    fs::read_to_string("/proc/stat").ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Every process that has not exited, zombies excluded.
+fn running_processes() -> Vec<Stat> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .filter_map(read_stat)
+        .collect()
+}
+
+fn read_stat(pid: u32) -> Option<Stat> {
+    let text = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name comes first in parentheses and may contain anything
+    let fields: Vec<&str> = text.get(text.rfind(')')? + 2..)?.split(' ').collect();
+    if *fields.first()? == "Z" {
+        return None;
+    }
+    Some(Stat {
+        pid,
+        ppid: fields.get(1)?.parse().ok()?,
+        session: fields.get(3)?.parse().ok()?,
+        start_ticks: fields.get(19)?.parse().ok()?,
+    })
+}
+
+/// Command line and executable of `pid`, or `None` once it has exited.
+fn read_program(pid: u32) -> Option<Program> {
+    let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    // Arguments are NUL-terminated; kernel threads have none
+    let argv = match cmdline.strip_suffix(&[0]) {
+        Some(args) => args.split(|&byte| byte == 0)
+            .map(|arg| String::from_utf8_lossy(arg).to_string())
+            .collect(),
+        None => Vec::new(),
+    };
+    let exe = fs::read_link(format!("/proc/{}/exe", pid)).ok()
+        .map(|path| path.to_string_lossy().to_string());
+    Some(Program { argv, exe })
+}
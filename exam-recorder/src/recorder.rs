@@ -2,47 +2,70 @@ use anyhow::{Context, Result};
 use std::collections::VecDeque;
 use std::io::{Read, Write};
 use std::os::fd::RawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
-use serde::{Deserialize, Serialize};
+use nix::sys::wait::WaitStatus;
 
-use crate::encryption::{encrypt_file, calculate_file_hash, create_password_protected_zip};
-use crate::metadata::Metadata;
-use crate::state::State;
+use exam_format::archive::{self, collected_file_entry, ArchiveWriter};
+use exam_format::crypto::{calculate_file_hash, parse_public_key, seal_key, DataKey};
+use exam_format::{
+    CollectedFile, CommandEvent, CommandSource, EndReason, ExamConfig, KeystrokeEvent, Metadata,
+    PasteEvent, PastePolicy, TimeWarning,
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct KeystrokeEvent {
-    pub timestamp: u64,
-    pub key_code: u32,
-    pub key_name: String,
-    pub raw_bytes: Vec<u8>,
-    pub is_paste: bool,
-}
+use crate::encryption::load_instructor_public_key;
+use crate::entries::SessionEntries;
+use crate::journal::{find_journals, Journal, JournalRecord, JOURNAL_EXTENSION};
+use crate::limit::{dropped_marker, OutputLimit, DEFAULT_OUTPUT_LIMIT_MIB};
+use crate::metadata;
+use crate::sampler::Sampler;
+use crate::state::{get_state_dir, State};
+use crate::input::{Input, InputDecoder, PasteMode};
+use crate::shell::{self, prepare_launch, ShellTracker};
+use crate::stream::StreamHeader;
+use crate::timer::{ExamTimer, TimerEvent, DEFAULT_WARNINGS};
+use crate::watch::{
+    FileWatcher, DEFAULT_WATCH_FILE_LIMIT_KIB, DEFAULT_WATCH_IGNORE, DEFAULT_WATCH_LIMIT_MIB,
+};
+
+/// Set by the SIGWINCH handler and picked up by the recording loop.
+static WINDOW_RESIZED: AtomicBool = AtomicBool::new(false);
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct CommandEvent {
-    pub timestamp: u64,
-    pub command: String,
+extern "C" fn handle_sigwinch(_: libc::c_int) {
+    WINDOW_RESIZED.store(true, Ordering::SeqCst);
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SessionSummary {
-    pub total_keystrokes: usize,
-    pub enter_pressed: usize,
-    pub backspace_used: usize,
-    pub delete_used: usize,
-    pub paste_events: usize,
-    pub total_pasted_chars: usize,
-    pub commands_executed: usize,
+/// Signal that asked the recorder to stop (SIGTERM, SIGHUP or SIGINT), or 0.
+static TERMINATION_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn handle_termination(signal: libc::c_int) {
+    TERMINATION_SIGNAL.store(signal, Ordering::SeqCst);
 }
 
+/// Largest file copied into the archive from the exam's `collect_files`.
+const MAX_COLLECTED_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Records a session. Everything recorded goes straight to the session
+/// journal; the archive is built from the journal when the session ends,
+/// so memory use does not grow with the length of the session.
 pub struct Recorder {
     state: State,
     metadata: Metadata,
-    keystrokes: Vec<KeystrokeEvent>,
-    commands: Vec<CommandEvent>,
-    terminal_output: Vec<u8>,
+    output_limit: OutputLimit,
+    session_clock: Instant,
     current_input: String,
+    input_decoder: InputDecoder,
+    paste_mode: PasteMode,
     paste_detector: PasteDetector,
+    shell: ShellTracker,
+    /// Follows the shell's processes and their connections once it has
+    /// been started.
+    sampler: Option<Sampler>,
+    /// Versions the exam directory's files, if the exam names one.
+    files: Option<FileWatcher>,
+    timer: Option<ExamTimer>,
+    journal: Journal,
 }
 
 struct PasteDetector {
@@ -95,50 +118,230 @@ impl PasteDetector {
 }
 
 impl Recorder {
-    pub fn new() -> Result<Self> {
+    pub fn new(config: ExamConfig) -> Result<Self> {
+        config.check_window(chrono::Utc::now())?;
+        
+        // Fail before the exam starts rather than when the archive is written
+        let instructor_key = match &config.public_key {
+            Some(key) => parse_public_key(key)?,
+            None => load_instructor_public_key()?,
+        };
+        
         let mut state = State::load()?;
         state.increment_counter();
         state.save()?;
         
-        let metadata = Metadata::new(state.run_counter)?;
+        let mut metadata = metadata::collect(state.run_counter)?;
+        let (width, height) = terminal_size();
+        metadata.terminal_width = width;
+        metadata.terminal_height = height;
+        metadata.exam_id = config.exam_id;
+        metadata.student_id = config.student_id;
+        metadata.shell = config.shell
+            .or_else(|| std::env::var("SHELL").ok())
+            .unwrap_or_else(|| "/bin/bash".to_string());
+        metadata.command = config.command;
+        metadata.shell_integration = shell::has_hooks(&metadata.shell, &metadata.command);
+        metadata.time_limit_minutes = config.time_limit;
+        let warnings = config.warnings.unwrap_or_else(|| DEFAULT_WARNINGS.to_vec());
+        if let Some(dir) = config.output_dir {
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create output directory: {}", dir.display()))?;
+            let dir = dir.canonicalize()?;
+            metadata.output_dir = Some(dir.to_string_lossy().to_string());
+        }
+        metadata.config_hash = config.config_hash;
+        metadata.paste_policy = config.paste_policy.unwrap_or_default();
+        let output_limit = config.output_limit.unwrap_or(DEFAULT_OUTPUT_LIMIT_MIB);
+        metadata.output_limit_mib = Some(output_limit);
+        let cwd = std::env::current_dir()?;
+        metadata.collect_files = config.collect_files.iter()
+            .map(|path| cwd.join(path).to_string_lossy().to_string())
+            .collect();
+        metadata.flag_programs = config.flag_programs;
+        metadata.network_allowlist = config.network_allowlist;
+        
+        // Everything recorded from here on is streamed to an encrypted
+        // journal so a crash loses at most a few seconds of the session
+        let state_dir = get_state_dir()?;
+        std::fs::create_dir_all(&state_dir)?;
+        
+        let mut files = match config.watch_dir {
+            Some(dir) => {
+                let ignore = if config.watch_ignore.is_empty() {
+                    DEFAULT_WATCH_IGNORE.iter().map(|pattern| pattern.to_string()).collect()
+                } else {
+                    config.watch_ignore
+                };
+                let file_limit = config.watch_file_limit.unwrap_or(DEFAULT_WATCH_FILE_LIMIT_KIB);
+                let limit = config.watch_limit.unwrap_or(DEFAULT_WATCH_LIMIT_MIB);
+                // The journal must not be versioned along with the student's files
+                let watcher = FileWatcher::new(&cwd.join(dir), ignore.clone(), &[state_dir.canonicalize()?], file_limit, limit)?;
+                metadata.watch_dir = Some(watcher.root().to_string_lossy().to_string());
+                metadata.watch_ignore = ignore;
+                metadata.watch_file_limit_kib = Some(file_limit);
+                metadata.watch_limit_mib = Some(limit);
+                Some(watcher)
+            }
+            None => None,
+        };
+        let journal_path = state_dir.join(format!(
+            "session-{}-{}.{}",
+            metadata.username,
+            metadata.start_time,
+            JOURNAL_EXTENSION
+        ));
+        let mut journal = Journal::create(&journal_path, DataKey::generate(), &instructor_key)?;
+        metadata.chain_key = journal.chain().public_key()
+            .map(|key| hex::encode(key.as_bytes()));
+        journal.append(JournalRecord::Session {
+            metadata: Box::new(metadata.clone()),
+            state: state.clone(),
+        })?;
+        if let Some(watcher) = &mut files {
+            for (version, data) in watcher.initial_versions() {
+                journal.append(JournalRecord::FileVersion { version, data })?;
+            }
+        }
+        journal.flush()?;
+        
+        let session_clock = Instant::now();
+        let timer = metadata.time_limit_minutes
+            .map(|limit| ExamTimer::new(session_clock, limit, &warnings));
         
         Ok(Recorder {
             state,
             metadata,
-            keystrokes: Vec::new(),
-            commands: Vec::new(),
-            terminal_output: Vec::new(),
+            output_limit: OutputLimit::new(output_limit),
+            session_clock,
             current_input: String::new(),
+            input_decoder: InputDecoder::new(),
+            paste_mode: PasteMode::new(),
             paste_detector: PasteDetector::new(),
+            shell: ShellTracker::new(),
+            sampler: None,
+            files,
+            timer,
+            journal,
         })
     }
     
+    /// Rebuilds archives from journals left behind by sessions that died
+    /// before `finalize()` ran.
+    pub fn recover_all() -> Result<()> {
+        let journals = find_journals()?;
+        if journals.is_empty() {
+            println!("No unfinished sessions found.");
+            return Ok(());
+        }
+        
+        let mut recovered = 0;
+        for journal_path in journals {
+            let name = journal_path.file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+            match Self::recover(&journal_path) {
+                Ok(filename) => {
+                    println!("Recovered {} -> {}", name, filename);
+                    recovered += 1;
+                }
+                Err(e) => {
+                    eprintln!("Skipping {}: {:#}", name, e);
+                }
+            }
+        }
+        
+        if recovered > 0 {
+            println!("Submit the recovered files to your instructor.");
+        }
+        Ok(())
+    }
+    
+    fn recover(journal_path: &std::path::Path) -> Result<String> {
+        let (journal, session) = Journal::recover(journal_path)?;
+        
+        let mut metadata = session.metadata;
+        metadata.mark_recovered(session.stopped_at);
+        metadata.time_warnings = session.time_warnings;
+        metadata.output_dropped_bytes = session.output_dropped;
+        
+        let stopped_at = chrono::DateTime::from_timestamp_millis(session.stopped_at as i64)
+            .map(|dt| dt.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| session.stopped_at.to_string());
+        println!(
+            "Verified {} journal chunks; recording stopped at {}",
+            session.chunks,
+            stopped_at
+        );
+        if session.discarded_bytes > 0 {
+            eprintln!(
+                "Warning: {} trailing bytes of {} could not be verified and were dropped",
+                session.discarded_bytes,
+                journal_path.display()
+            );
+        }
+        
+        let mut recorder = Recorder {
+            state: session.state,
+            output_limit: OutputLimit::new(metadata.output_limit_mib.unwrap_or(DEFAULT_OUTPUT_LIMIT_MIB)),
+            metadata,
+            session_clock: Instant::now(),
+            current_input: String::new(),
+            input_decoder: InputDecoder::new(),
+            paste_mode: PasteMode::new(),
+            paste_detector: PasteDetector::new(),
+            shell: ShellTracker::new(),
+            sampler: None,
+            files: None,
+            timer: None,
+            journal,
+        };
+        recorder.write_archive()
+    }
+    
     pub fn start(&mut self) -> Result<()> {
         use std::os::unix::io::AsRawFd;
         use nix::pty::openpty;
         use nix::unistd::{fork, ForkResult, execvp, dup2, close};
-        use nix::sys::wait::waitpid;
         use std::ffi::CString;
         
-        // Create PTY
-        let pty_pair = openpty(None, None)
+        // Create PTY with the student's real terminal size
+        let (width, height) = terminal_size();
+        let pty_pair = openpty(&window_size(width, height), None)
             .context("Failed to create PTY")?;
         
         let master_fd = pty_pair.master.as_raw_fd();
         let slave_fd = pty_pair.slave.as_raw_fd();
         
+        // Hook the shell's prompt so it reports each command it runs
+        let launch = prepare_launch(&self.metadata.shell, &self.metadata.command)?;
+        
         // Fork process
         match unsafe { fork() } {
             Ok(ForkResult::Parent { child, .. }) => {
-                // Parent: record from master
-                close(slave_fd).ok();
-                self.record_from_master(master_fd)?;
-                waitpid(child, None).ok();
+                // Parent: record from master until the shell goes away
+                drop(pty_pair.slave);
+                self.sampler = Sampler::start(child.as_raw() as u32);
+                let mut master_file = std::fs::File::from(pty_pair.master);
+                let result = self.record_from_master(&mut master_file, child);
+                let status = match &result {
+                    Ok(Some(status)) => Some(*status),
+                    _ => wait_for_shell(child),
+                };
+                result?;
+                
+                record_exit(&mut self.metadata, status, termination_signal());
+                self.finalize()?;
             }
             Ok(ForkResult::Child) => {
                 // Child: execute shell
                 close(master_fd).ok();
                 
+                // Make the PTY the controlling terminal of a new session so
+                // the shell gets job control and SIGWINCH on resize
+                nix::unistd::setsid().ok();
+                unsafe { libc::ioctl(slave_fd, libc::TIOCSCTTY, 0) };
+                
                 // Connect slave to stdin/stdout/stderr
                 dup2(slave_fd, 0).ok();
                 dup2(slave_fd, 1).ok();
@@ -147,15 +350,21 @@ impl Recorder {
                 
                 // Set TERM environment
                 std::env::set_var("TERM", "xterm-256color");
+                for (name, value) in &launch.env {
+                    std::env::set_var(name, value);
+                }
                 
                 // Execute shell
-                let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/bash".to_string());
-                let shell_cstr = CString::new(shell.clone())
+                let shell_cstr = CString::new(launch.program.clone())
                     .context("Invalid shell path")?;
-                let args = vec![shell_cstr.clone()];
+                let args = launch.args.iter()
+                    .map(|arg| CString::new(arg.as_str()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .context("Invalid shell argument")?;
                 
-                execvp(&shell_cstr, &args)
-                    .expect("Failed to execute shell");
+                let Err(e) = execvp(&shell_cstr, &args);
+                eprintln!("Failed to execute shell: {}", e);
+                std::process::exit(127);
             }
             Err(e) => {
                 anyhow::bail!("Fork failed: {}", e);
@@ -165,20 +374,72 @@ impl Recorder {
         Ok(())
     }
     
-    fn record_from_master(&mut self, master_fd: RawFd) -> Result<()> {
-        use std::os::unix::io::{FromRawFd, AsFd};
+    /// Relays the session until the shell exits, the PTY is closed or the
+    /// student's terminal goes away. Returns the shell's status if it was
+    /// seen to exit while background jobs still held the PTY open.
+    fn record_from_master(
+        &mut self,
+        master_file: &mut std::fs::File,
+        child: nix::unistd::Pid,
+    ) -> Result<Option<WaitStatus>> {
+        use std::os::unix::io::{AsFd, AsRawFd};
         
-        let mut master_file = unsafe { std::fs::File::from_raw_fd(master_fd) };
+        let master_fd = master_file.as_raw_fd();
         let stdin_handle = std::io::stdin();
         let mut stdin = stdin_handle.lock();
         
         // Set terminal to raw mode
         let original_termios = self.set_raw_mode()?;
         
+        // Follow the student's terminal when it is resized, and finish
+        // cleanly if the recorder is told to stop
+        install_signal_handlers()?;
+        
         // Use poll/epoll for non-blocking I/O
         use nix::poll::{poll, PollFd, PollFlags};
         
         let result = loop {
+            if TERMINATION_SIGNAL.load(Ordering::SeqCst) != 0 {
+                break Ok(None);
+            }
+            
+            match self.timer.as_mut().and_then(|timer| timer.check()) {
+                Some(TimerEvent::Warning(minutes)) => {
+                    if let Err(e) = self.show_time_warning(minutes) {
+                        break Err(e);
+                    }
+                }
+                Some(TimerEvent::Expired) => {
+                    self.metadata.time_limit_reached = true;
+                    if let Err(e) = notify_student("Time is up. Your exam is being submitted.") {
+                        break Err(e);
+                    }
+                    break Ok(None);
+                }
+                None => {}
+            }
+            
+            if WINDOW_RESIZED.swap(false, Ordering::SeqCst) {
+                if let Err(e) = self.propagate_resize(master_fd) {
+                    break Err(e);
+                }
+            }
+            
+            if let Err(e) = self.expire_paste() {
+                break Err(e);
+            }
+            if let Err(e) = self.record_processes(false) {
+                break Err(e);
+            }
+            if let Err(e) = self.record_files(false) {
+                break Err(e);
+            }
+            
+            // Periodically persist buffered records to the session journal
+            if let Err(e) = self.journal.flush_if_due() {
+                break Err(e);
+            }
+            
             let stdin_fd = stdin.as_fd();
             let master_fd_borrowed = master_file.as_fd();
             
@@ -188,7 +449,14 @@ impl Recorder {
             ];
             
             match poll(&mut poll_fds, 100u16) {
-                Ok(0) => continue, // Timeout
+                Ok(0) => {
+                    // Timeout: check whether the shell itself has gone
+                    use nix::sys::wait::{waitpid, WaitPidFlag};
+                    match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+                        Ok(WaitStatus::StillAlive) | Err(_) => continue,
+                        Ok(status) => break Ok(Some(status)),
+                    }
+                }
                 Ok(_) => {}
                 Err(nix::errno::Errno::EINTR) => continue,
                 Err(e) => break Err(anyhow::anyhow!("Poll error: {}", e)),
@@ -201,6 +469,9 @@ impl Recorder {
             let master_ready = poll_fds[1].revents()
                 .map(|r| r.contains(PollFlags::POLLIN))
                 .unwrap_or(false);
+            let master_hung_up = poll_fds[1].revents()
+                .map(|r| r.intersects(PollFlags::POLLHUP | PollFlags::POLLERR))
+                .unwrap_or(false);
             
             // Drop poll_fds so we can use master_file mutably
             drop(poll_fds);
@@ -209,18 +480,16 @@ impl Recorder {
             if stdin_ready {
                 let mut buffer = [0u8; 4096];
                 match stdin.read(&mut buffer) {
-                    Ok(0) => break Ok(()), // EOF
+                    Ok(0) => break Ok(None), // EOF
                     Ok(n) => {
-                        let data = &buffer[..n];
-                        self.process_input(data)?;
+                        let forward = match self.process_input(&buffer[..n]) {
+                            Ok(forward) => forward,
+                            Err(e) => break Err(e),
+                        };
                         
                         // Forward to master PTY
-                        master_file.write_all(data)?;
-                        master_file.flush()?;
-                        
-                        // Check for exit command after processing
-                        if self.should_exit() {
-                            break Ok(());
+                        if let Err(e) = master_file.write_all(&forward).and_then(|_| master_file.flush()) {
+                            break Err(e.into());
                         }
                     }
                     Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
@@ -232,38 +501,84 @@ impl Recorder {
             if master_ready {
                 let mut buffer = [0u8; 4096];
                 match master_file.read(&mut buffer) {
-                    Ok(0) => break Ok(()), // EOF
+                    Ok(0) => break Ok(None), // EOF
                     Ok(n) => {
                         let data = &buffer[..n];
-                        self.terminal_output.extend_from_slice(data);
+                        if let Err(e) = self.record_output(data) {
+                            break Err(e);
+                        }
                         
                         // Forward to stdout
-                        std::io::stdout().write_all(data)?;
-                        std::io::stdout().flush()?;
+                        let mut stdout = std::io::stdout();
+                        if let Err(e) = stdout.write_all(data).and_then(|_| stdout.flush()) {
+                            break Err(e.into());
+                        }
                     }
                     Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                    // Linux reports EIO once the shell side of the PTY is closed
+                    Err(e) if e.raw_os_error() == Some(libc::EIO) => break Ok(None),
                     Err(e) => break Err(e.into()),
                 }
+            } else if master_hung_up {
+                break Ok(None);
             }
         };
         
         // Restore terminal mode (always, even on error)
         let _ = self.restore_terminal_mode(original_termios);
         
-        result?;
+        // On error the archive is not built, so get what is still buffered
+        // into the journal for `exam-recorder recover`
+        if result.is_err() {
+            let _ = self.journal.flush();
+        }
         
-        // Generate output files
-        self.finalize()?;
+        result
+    }
+    
+    fn show_time_warning(&mut self, minutes_left: u64) -> Result<()> {
+        let plural = if minutes_left == 1 { "" } else { "s" };
+        notify_student(&format!("{} minute{} left in this exam.", minutes_left, plural))?;
         
+        let warning = TimeWarning {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+            minutes_left,
+        };
+        self.journal.append(JournalRecord::TimeWarning(warning.clone()))?;
+        self.metadata.time_warnings.push(warning);
         Ok(())
     }
     
+    /// Copies the current terminal size to the PTY, which delivers SIGWINCH
+    /// to the shell, and logs the resize.
+    fn propagate_resize(&mut self, master_fd: RawFd) -> Result<()> {
+        let (width, height) = terminal_size();
+        let size = window_size(width, height);
+        unsafe { libc::ioctl(master_fd, libc::TIOCSWINSZ, &size) };
+        
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let offset = self.session_clock.elapsed().as_secs_f64();
+        
+        self.journal.append(JournalRecord::Resize {
+            timestamp,
+            offset,
+            width,
+            height,
+        })
+    }
+    
     fn set_raw_mode(&self) -> Result<termios::Termios> {
         use termios::*;
         
         let stdin_fd = 0;
         let mut termios = Termios::from_fd(stdin_fd)?;
-        let original = termios.clone();
+        let original = termios;
         
         // Set raw mode
         termios.c_iflag &= !(BRKINT | ICRNL | INPCK | ISTRIP | IXON);
@@ -284,180 +599,506 @@ impl Recorder {
         Ok(())
     }
     
-    fn process_input(&mut self, data: &[u8]) -> Result<()> {
+    fn record_output(&mut self, data: &[u8]) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let offset = self.session_clock.elapsed().as_secs_f64();
+        
+        // Commands are still seen in output that is not recorded
+        self.paste_mode.observe_output(data);
+        for event in self.shell.observe_output(data, timestamp) {
+            self.journal.append(JournalRecord::Command(event))?;
+        }
+        
+        let admitted = self.output_limit.admit(data.len());
+        if let Some(dropped) = admitted.dropped_before {
+            self.record_dropped_output(timestamp, offset, dropped)?;
+        }
+        if admitted.len == 0 {
+            return Ok(());
+        }
+        self.journal.append(JournalRecord::Output {
+            timestamp,
+            offset,
+            data: data[..admitted.len].to_vec(),
+            dropped: 0,
+        })
+    }
+    
+    /// Records the programs the shell's processes started, the processes
+    /// that exited and the connections they opened, as sampled so far, or
+    /// with `finish` after one last sample.
+    fn record_processes(&mut self, finish: bool) -> Result<()> {
+        let records = if finish {
+            self.sampler.take().map(Sampler::finish).unwrap_or_default()
+        } else {
+            self.sampler.as_ref().map(Sampler::records).unwrap_or_default()
+        };
+        for record in records {
+            self.journal.append(record)?;
+        }
+        Ok(())
+    }
+    
+    /// Records new versions of the exam directory's files: those that have
+    /// settled after a change, or with `finish` every file that changed.
+    fn record_files(&mut self, finish: bool) -> Result<()> {
+        let Some(watcher) = &mut self.files else {
+            return Ok(());
+        };
+        let versions = if finish { watcher.finish() } else { watcher.poll() };
+        for (version, data) in versions {
+            self.journal.append(JournalRecord::FileVersion { version, data })?;
+        }
+        Ok(())
+    }
+    
+    /// Records a marker in place of output left out by the output limit.
+    fn record_dropped_output(&mut self, timestamp: u64, offset: f64, dropped: u64) -> Result<()> {
+        self.journal.append(JournalRecord::Output {
+            timestamp,
+            offset,
+            data: dropped_marker(dropped).into_bytes(),
+            dropped,
+        })
+    }
+    
+    /// Records a read from the student's terminal and returns the bytes to
+    /// pass on to the shell, which leave out pastes the exam blocks.
+    fn process_input(&mut self, data: &[u8]) -> Result<Vec<u8>> {
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_millis() as u64;
         
-        for &byte in data {
-            let (key_name, is_special) = self.decode_key(byte);
-            let is_paste = if !is_special && byte >= 32 && byte < 127 {
-                // Check for paste detection
+        let offset = self.session_clock.elapsed().as_secs_f64();
+        self.journal.append(JournalRecord::Input {
+            offset,
+            data: data.to_vec(),
+        })?;
+        
+        let block_pastes = self.metadata.paste_policy == PastePolicy::Block;
+        let mut forward = if block_pastes { Vec::new() } else { data.to_vec() };
+        
+        for input in self.input_decoder.decode(data) {
+            let key = match input {
+                Input::Key(key) => key,
+                Input::Paste(bytes) => {
+                    self.record_paste(timestamp, &bytes, block_pastes)?;
+                    continue;
+                }
+            };
+            if block_pastes {
+                forward.extend_from_slice(&key.bytes);
+            }
+            
+            // The typing-rate heuristic is only a fallback for terminals
+            // that are not marking pastes themselves
+            let is_paste = if key.printable && !self.paste_mode.enabled() {
                 self.paste_detector.check_paste(1)
             } else {
                 false
             };
             
             // Track current input for command detection
-            if byte == b'\n' || byte == b'\r' {
-                if !self.current_input.trim().is_empty() {
-                    let cmd = self.current_input.trim().to_string();
-                    // Don't record "exit" as a command
-                    if cmd != "exit" {
-                        self.commands.push(CommandEvent {
-                            timestamp,
-                            command: cmd,
-                        });
+            match key.name.as_str() {
+                "Enter" => {
+                    // Kept even when the shell reports its commands, since
+                    // its markers can be printed by anything
+                    if !self.current_input.trim().is_empty() {
+                        let cmd = self.current_input.trim().to_string();
+                        // Don't record "exit" as a command
+                        if cmd != "exit" {
+                            let event = CommandEvent {
+                                timestamp,
+                                command: cmd,
+                                source: CommandSource::Keystrokes,
+                                end_timestamp: None,
+                                exit_status: None,
+                                cwd: None,
+                            };
+                            self.journal.append(JournalRecord::Command(event))?;
+                        }
                     }
+                    self.current_input.clear();
                 }
-                self.current_input.clear();
-            } else if byte == 127 || byte == 8 { // Backspace
-                self.current_input.pop();
-            } else if byte >= 32 && byte < 127 && !is_special {
-                self.current_input.push(byte as char);
+                "Backspace" => {
+                    self.current_input.pop();
+                }
+                _ if key.printable => self.current_input.push_str(&key.name),
+                _ => {}
             }
             
-            self.keystrokes.push(KeystrokeEvent {
+            let event = KeystrokeEvent {
                 timestamp,
-                key_code: byte as u32,
-                key_name,
-                raw_bytes: vec![byte],
+                key_code: key.code(),
+                key_name: key.name,
+                raw_bytes: key.bytes,
                 is_paste,
-            });
+            };
+            self.journal.append(JournalRecord::Keystroke(event))?;
         }
         
-        Ok(())
+        Ok(forward)
     }
     
-    pub fn should_exit(&self) -> bool {
-        self.current_input.trim() == "exit"
+    /// Records a paste whose end marker never arrived once it is overdue, so
+    /// keys typed after it reach the shell again when pastes are blocked.
+    fn expire_paste(&mut self) -> Result<()> {
+        let Some(Input::Paste(bytes)) = self.input_decoder.expire_paste(Instant::now()) else {
+            return Ok(());
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let blocked = self.metadata.paste_policy == PastePolicy::Block;
+        self.record_paste(timestamp, &bytes, blocked)
     }
     
-    fn decode_key(&self, byte: u8) -> (String, bool) {
-        match byte {
-            0 => ("NULL".to_string(), true),
-            1 => ("Ctrl+A".to_string(), true),
-            2 => ("Ctrl+B".to_string(), true),
-            3 => ("Ctrl+C".to_string(), true),
-            4 => ("Ctrl+D".to_string(), true),
-            5 => ("Ctrl+E".to_string(), true),
-            6 => ("Ctrl+F".to_string(), true),
-            7 => ("Ctrl+G".to_string(), true),
-            8 | 127 => ("Backspace".to_string(), true),
-            9 => ("Tab".to_string(), true),
-            10 | 13 => ("Enter".to_string(), true),
-            27 => ("ESC".to_string(), true),
-            32..=126 => (format!("{}", byte as char), false),
-            _ => (format!("0x{:02X}", byte), true),
+    /// Logs a bracketed paste as a single event with its exact text.
+    fn record_paste(&mut self, timestamp: u64, bytes: &[u8], blocked: bool) -> Result<()> {
+        if blocked {
+            notify_student("Pasting is not allowed in this exam.")?;
         }
+        let text = String::from_utf8_lossy(bytes).to_string();
+        
+        // The shell inserts pasted text into the line being edited
+        if !blocked {
+            self.current_input.extend(text.chars().filter(|c| !c.is_control() || *c == '\n'));
+        }
+        
+        let event = PasteEvent {
+            timestamp,
+            length: text.chars().count(),
+            text,
+            blocked,
+        };
+        self.journal.append(JournalRecord::Paste(event))
     }
     
     fn finalize(&mut self) -> Result<()> {
+        // Make sure everything is on disk before building the archive, so
+        // the journal can still be recovered if anything below fails
+        if let Some(event) = self.shell.finish() {
+            if event.command.trim() != "exit" {
+                self.journal.append(JournalRecord::Command(event))?;
+            }
+        }
+        // The shell has been reaped; anything still listed kept running
+        self.record_processes(true)?;
+        self.record_files(true)?;
+        if let Some(dropped) = self.output_limit.finish() {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64;
+            let offset = self.session_clock.elapsed().as_secs_f64();
+            self.record_dropped_output(timestamp, offset, dropped)?;
+        }
+        self.metadata.output_dropped_bytes = self.output_limit.dropped();
+        self.journal.seal_chain();
+        self.journal.flush()?;
+        
         self.metadata.finalize();
         
-        // Generate summary
-        let summary = self.generate_summary();
+        let filename = self.write_archive()?;
         
+        println!();
+        println!("Session ended.");
+        println!("Your encrypted exam log has been saved as: {}", filename);
+        println!("Submit this file to your instructor.");
+        
+        Ok(())
+    }
+    
+    /// Writes the sealed archive from the session journal and removes the
+    /// journal. Returns the archive filename.
+    fn write_archive(&mut self) -> Result<String> {
         // Create output directory
-        let output_dir = dirs::home_dir()
-            .context("Failed to get home directory")?
-            .join(".exam-recorder");
+        let output_dir = match &self.metadata.output_dir {
+            Some(dir) => std::path::PathBuf::from(dir),
+            None => get_state_dir()?,
+        };
         std::fs::create_dir_all(&output_dir)?;
         
         // Generate filename
         let timestamp = self.metadata.start_time;
+        let exam = self.metadata.exam_id.as_deref()
+            .map(|id| format!("{}-", filename_part(id)))
+            .unwrap_or_default();
+        let student = self.metadata.student_id.as_deref()
+            .unwrap_or(&self.metadata.username);
         let filename = format!(
-            "exam-result-{}-{}.zip",
-            self.metadata.username,
-            timestamp
+            "exam-result-{}{}-{}{}.zip",
+            exam,
+            filename_part(student),
+            timestamp,
+            if self.metadata.recovered { "-recovered" } else { "" }
         );
         let output_path = output_dir.join(&filename);
         
-        // Serialize data
-        let events_json = serde_json::to_string_pretty(&self.keystrokes)?;
-        let summary_json = serde_json::to_string_pretty(&summary)?;
-        let metadata_json = serde_json::to_string_pretty(&self.metadata)?;
-        let state_copy_json = serde_json::to_string_pretty(&self.state)?;
-        
-        // IMPORTANT: Change this password before production use!
-        // See CONFIGURATION.md for instructions on changing the instructor password.
-        // This password is used to encrypt all exam log files.
-        let instructor_password = "linuxisawesome";
-        
-        let events_enc = encrypt_file(events_json.as_bytes(), instructor_password)?;
-        let summary_enc = encrypt_file(summary_json.as_bytes(), instructor_password)?;
-        let metadata_enc = encrypt_file(metadata_json.as_bytes(), instructor_password)?;
-        let terminal_output_enc = encrypt_file(&self.terminal_output, instructor_password)?;
-        let state_copy_enc = encrypt_file(state_copy_json.as_bytes(), instructor_password)?;
-        
-        // Calculate integrity hash
-        let mut integrity_data = Vec::new();
-        integrity_data.extend_from_slice(&events_enc);
-        integrity_data.extend_from_slice(&summary_enc);
-        integrity_data.extend_from_slice(&metadata_enc);
-        integrity_data.extend_from_slice(&terminal_output_enc);
-        integrity_data.extend_from_slice(&state_copy_enc);
-        let integrity_hash = calculate_file_hash(&integrity_data);
-        
-        // Create ZIP with password protection
-        let zip_files = vec![
-            ("events.json.enc", events_enc),
-            ("summary.json.enc", summary_enc),
-            ("metadata.json.enc", metadata_enc),
-            ("terminal_output.log.enc", terminal_output_enc),
-            ("state_copy.json.enc", state_copy_enc),
-            ("integrity.sha256", integrity_hash.as_bytes().to_vec()),
-        ];
-        
-        let encrypted_zip = create_password_protected_zip(&zip_files, instructor_password)?;
-        
-        // Write ZIP file
-        std::fs::write(&output_path, encrypted_zip)?;
-        
-        // Set restrictive permissions
-        #[cfg(unix)]
+        // The archive gets a data key of its own, kept only in memory and
+        // sealed to the instructor public key: the journal's key can be
+        // unlocked on this machine, so it must not open the archive too.
+        // Entries are spooled next to the journal, not in memory.
+        let key = DataKey::generate();
+        let envelope = seal_key(&key, self.journal.recipient())?;
+        let spool_dir = get_state_dir()?;
+        let mut writer = ArchiveWriter::new(&key, &spool_dir)?;
+        let entries = SessionEntries::from_journal(&self.journal, &mut writer, &StreamHeader {
+            version: 2,
+            width: self.metadata.terminal_width,
+            height: self.metadata.terminal_height,
+            timestamp: self.metadata.start_time,
+        })?;
+        entries.finish(&mut writer, &self.metadata)?;
+        
+        if !self.metadata.collect_files.is_empty() {
+            let mut collected = Vec::new();
+            for (index, path) in self.metadata.collect_files.iter().enumerate() {
+                let entry = collected_file_entry(index);
+                match read_collected_file(path) {
+                    Ok(contents) => {
+                        collected.push(CollectedFile {
+                            path: path.clone(),
+                            entry: Some(entry.clone()),
+                            size: Some(contents.len() as u64),
+                            sha256: Some(calculate_file_hash(&contents)),
+                            error: None,
+                        });
+                        writer.add(entry, &contents)?;
+                    }
+                    Err(e) => collected.push(CollectedFile {
+                        path: path.clone(),
+                        entry: None,
+                        size: None,
+                        sha256: None,
+                        error: Some(format!("{:#}", e)),
+                    }),
+                }
+            }
+            writer.add_json(archive::COLLECTED_FILES_ENTRY, &collected)?;
+        }
+        
+        writer.add_json(archive::STATE_COPY_ENTRY, &self.state)?;
+        
+        // Write the archive sealed to the instructor key, with restrictive
+        // permissions from the start
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&output_path)
+            .with_context(|| format!("Failed to create {}", output_path.display()))?;
+        if let Err(e) = writer.finish(env!("CARGO_PKG_VERSION"), &envelope, &file)
+            .and_then(|()| Ok(file.sync_all()?))
         {
-            use std::os::unix::fs::PermissionsExt;
-            let mut perms = std::fs::metadata(&output_path)?.permissions();
-            perms.set_mode(0o600);
-            std::fs::set_permissions(&output_path, perms)?;
+            // The journal is kept, so the session can still be recovered
+            std::fs::remove_file(&output_path).ok();
+            return Err(e);
         }
         
-        println!();
-        println!("Session ended.");
-        println!("Your encrypted exam log has been saved as: {}", filename);
-        println!("Submit this file to your instructor.");
+        // The archive now holds everything the journal did
+        self.journal.remove()?;
         
-        Ok(())
+        Ok(filename)
+    }
+}
+
+/// Size of the student's terminal as (columns, rows), defaulting to 80x24
+/// when stdin is not a terminal.
+fn terminal_size() -> (u16, u16) {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::ioctl(0, libc::TIOCGWINSZ, &mut size) };
+    if result != 0 || size.ws_col == 0 || size.ws_row == 0 {
+        return (80, 24);
     }
+    (size.ws_col, size.ws_row)
+}
+
+fn window_size(width: u16, height: u16) -> libc::winsize {
+    libc::winsize {
+        ws_row: height,
+        ws_col: width,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+fn install_signal_handlers() -> Result<()> {
+    use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
     
-    fn generate_summary(&self) -> SessionSummary {
-        let mut summary = SessionSummary {
-            total_keystrokes: self.keystrokes.len(),
-            enter_pressed: 0,
-            backspace_used: 0,
-            delete_used: 0,
-            paste_events: 0,
-            total_pasted_chars: 0,
-            commands_executed: self.commands.len(),
-        };
-        
-        for keystroke in &self.keystrokes {
-            match keystroke.key_name.as_str() {
-                "Enter" => summary.enter_pressed += 1,
-                "Backspace" => summary.backspace_used += 1,
-                "Delete" => summary.delete_used += 1,
-                _ => {}
-            }
-            
-            if keystroke.is_paste {
-                summary.paste_events += 1;
-                summary.total_pasted_chars += keystroke.raw_bytes.len();
-            }
+    // No SA_RESTART: signals should interrupt poll() so they are acted on at once
+    let action = SigAction::new(
+        SigHandler::Handler(handle_sigwinch),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGWINCH, &action) }
+        .context("Failed to install SIGWINCH handler")?;
+    
+    let action = SigAction::new(
+        SigHandler::Handler(handle_termination),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    for signal in [Signal::SIGTERM, Signal::SIGHUP, Signal::SIGINT] {
+        unsafe { sigaction(signal, &action) }
+            .with_context(|| format!("Failed to install {} handler", signal))?;
+    }
+    Ok(())
+}
+
+/// The signal that stopped the recording loop, if any.
+/// Records in `metadata` how the shell ended, given its wait status and the
+/// signal that stopped the recorder, if any. The recorder's own reasons for
+/// ending the session win over how the shell went away.
+fn record_exit(
+    metadata: &mut Metadata,
+    status: Option<WaitStatus>,
+    recorder_signal: Option<nix::sys::signal::Signal>,
+) {
+    match status {
+        Some(WaitStatus::Exited(_, code)) => {
+            metadata.end_reason = Some(EndReason::Logout);
+            metadata.exit_status = Some(code);
         }
-        
-        summary
+        Some(WaitStatus::Signaled(_, signal, _)) => {
+            metadata.end_reason = Some(EndReason::Killed);
+            metadata.exit_signal = Some(signal.as_str().to_string());
+        }
+        _ => {}
+    }
+
+    if let Some(signal) = recorder_signal {
+        metadata.end_reason = Some(EndReason::Interrupted);
+        metadata.recorder_signal = Some(signal.as_str().to_string());
+    } else if metadata.time_limit_reached {
+        metadata.end_reason = Some(EndReason::TimeLimit);
+    }
+}
+
+fn termination_signal() -> Option<nix::sys::signal::Signal> {
+    match TERMINATION_SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signal => nix::sys::signal::Signal::try_from(signal).ok(),
+    }
+}
+
+/// Prints a notice from the recorder on the student's terminal, which is
+/// in raw mode while the session runs.
+fn notify_student(message: &str) -> Result<()> {
+    let mut stdout = std::io::stdout();
+    write!(stdout, "\r\n\x1b[1;33m[exam-recorder] {}\x1b[0m\r\n", message)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Reads a file named in the exam's `collect_files`.
+fn read_collected_file(path: &str) -> Result<Vec<u8>> {
+    let info = std::fs::metadata(path).context("File not found")?;
+    if !info.is_file() {
+        anyhow::bail!("Not a regular file");
+    }
+    if info.len() > MAX_COLLECTED_FILE_SIZE {
+        anyhow::bail!("File is larger than {} MiB", MAX_COLLECTED_FILE_SIZE / (1024 * 1024));
     }
+    std::fs::read(path).context("Failed to read file")
+}
+
+/// Makes an exam or student id safe to use in a file name.
+fn filename_part(id: &str) -> String {
+    id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '_' { c } else { '_' })
+        .collect()
 }
 
+/// Reaps the shell, hanging it up first if it is still running because
+/// recording stopped on the student's side or the recorder was told to stop.
+/// A shell that ignores the hangup is killed after a short grace period.
+fn wait_for_shell(child: nix::unistd::Pid) -> Option<WaitStatus> {
+    use nix::sys::signal::{kill, Signal};
+    use nix::sys::wait::{waitpid, WaitPidFlag};
+    
+    match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+        Ok(WaitStatus::StillAlive) => {}
+        status => return status.ok(),
+    }
+    
+    kill(child, Signal::SIGHUP).ok();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while Instant::now() < deadline {
+        match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => std::thread::sleep(Duration::from_millis(50)),
+            status => return status.ok(),
+        }
+    }
+    
+    kill(child, Signal::SIGKILL).ok();
+    waitpid(child, None).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::signal::Signal;
+    use nix::unistd::Pid;
+
+    fn metadata() -> Metadata {
+        Metadata::new("student".into(), "lab-01".into(), "abc123".into(), 1)
+    }
+
+    #[test]
+    fn shell_exit_is_a_logout_with_its_status() {
+        let mut metadata = metadata();
+        record_exit(&mut metadata, Some(WaitStatus::Exited(Pid::from_raw(42), 3)), None);
+        assert_eq!(metadata.end_reason, Some(EndReason::Logout));
+        assert_eq!(metadata.exit_status, Some(3));
+        assert_eq!(metadata.exit_signal, None);
+    }
+
+    #[test]
+    fn killed_shell_records_its_signal() {
+        let mut metadata = metadata();
+        record_exit(&mut metadata, Some(WaitStatus::Signaled(Pid::from_raw(42), Signal::SIGKILL, false)), None);
+        assert_eq!(metadata.end_reason, Some(EndReason::Killed));
+        assert_eq!(metadata.exit_signal.as_deref(), Some("SIGKILL"));
+        assert_eq!(metadata.exit_status, None);
+    }
+
+    #[test]
+    fn time_limit_wins_over_how_the_shell_went_away() {
+        let mut metadata = metadata();
+        metadata.time_limit_reached = true;
+        record_exit(&mut metadata, Some(WaitStatus::Signaled(Pid::from_raw(42), Signal::SIGHUP, false)), None);
+        assert_eq!(metadata.end_reason, Some(EndReason::TimeLimit));
+        assert_eq!(metadata.exit_signal.as_deref(), Some("SIGHUP"));
+    }
+
+    #[test]
+    fn ids_are_made_safe_for_the_archive_filename() {
+        assert_eq!(filename_part("CS101_final.v2"), "CS101_final.v2");
+        assert_eq!(filename_part("CS 101/../final"), "CS_101_.._final");
+        assert_eq!(filename_part("ö-1"), "__1");
+    }
+
+    #[test]
+    fn signal_to_the_recorder_marks_the_session_interrupted() {
+        handle_termination(libc::SIGTERM);
+        let signal = termination_signal();
+        TERMINATION_SIGNAL.store(0, Ordering::SeqCst);
+        assert_eq!(signal, Some(Signal::SIGTERM));
+
+        // The shell was hung up on the recorder's way out
+        let mut metadata = metadata();
+        metadata.time_limit_reached = true;
+        record_exit(&mut metadata, Some(WaitStatus::Signaled(Pid::from_raw(42), Signal::SIGHUP, false)), signal);
+        assert_eq!(metadata.end_reason, Some(EndReason::Interrupted));
+        assert_eq!(metadata.recorder_signal.as_deref(), Some("SIGTERM"));
+        assert_eq!(metadata.exit_signal.as_deref(), Some("SIGHUP"));
+    }
+}
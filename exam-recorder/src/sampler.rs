@@ -0,0 +1,57 @@
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::journal::JournalRecord;
+use crate::network::ConnectionMonitor;
+use crate::processes::ProcessMonitor;
+
+/// How often /proc is scanned for the shell's processes and their sockets.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Runs the process and connection monitors on a thread of their own. A
+/// sample walks all of /proc and every socket table, which must not hold up
+/// the terminal; the recording loop only picks up what was found.
+pub struct Sampler {
+    records: Receiver<JournalRecord>,
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Sampler {
+    /// Starts sampling the processes of `shell`, or returns `None` without
+    /// a readable /proc.
+    pub fn start(shell: u32) -> Option<Self> {
+        let mut processes = ProcessMonitor::new(shell)?;
+        let mut connections = ConnectionMonitor::new();
+        let (sender, records) = mpsc::channel();
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || loop {
+            // The first sample waits for the shell to be executed; a stop
+            // request takes one last sample at once
+            let last = !matches!(stopped.recv_timeout(POLL_INTERVAL), Err(RecvTimeoutError::Timeout));
+            let mut sampled: Vec<JournalRecord> = processes.poll().into_iter()
+                .map(JournalRecord::Process)
+                .collect();
+            sampled.extend(connections.poll(&processes).into_iter().map(JournalRecord::Connection));
+            // The recorder has gone away if nothing can be sent
+            if sampled.into_iter().any(|record| sender.send(record).is_err()) || last {
+                break;
+            }
+        });
+        Some(Sampler { records, stop, thread })
+    }
+
+    /// Records sampled since the last call, without waiting.
+    pub fn records(&self) -> Vec<JournalRecord> {
+        self.records.try_iter().collect()
+    }
+
+    /// Takes a last sample and stops the thread, returning every record not
+    /// yet picked up.
+    pub fn finish(self) -> Vec<JournalRecord> {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+        self.records.try_iter().collect()
+    }
+}
@@ -0,0 +1,349 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+
+use exam_format::{CommandEvent, CommandSource};
+
+use crate::state::get_state_dir;
+
+/// Longest OSC sequence held back while waiting for its terminator.
+const MAX_OSC_LEN: usize = 64 * 1024;
+
+/// Helpers shared by the bash and zsh hooks. Commands and paths are
+/// percent-encoded so any text survives inside an OSC sequence.
+const COMMON_HOOKS: &str = r#"
+__exam_urlencode() {
+    local LC_ALL=C s=$1 out= c i
+    for (( i = 0; i < ${#s}; i++ )); do
+        c=${s:$i:1}
+        case $c in
+            [a-zA-Z0-9.~_/-]) out+=$c ;;
+            *) printf -v c '%%%02X' "'$c"; out+=$c ;;
+        esac
+    done
+    printf '%s' "$out"
+}
+
+__exam_report_start() {
+    __exam_ran=1
+    printf '\e]133;C;cmdline_url=%s\a' "$(__exam_urlencode "$1")"
+}
+
+__exam_report_prompt() {
+    if [ -n "$__exam_ran" ]; then
+        printf '\e]133;D;%s\a' "$1"
+    fi
+    __exam_ran=
+    printf '\e]7;file://%s%s\a' "${HOSTNAME:-$HOST}" "$(__exam_urlencode "$PWD")"
+    printf '\e]133;A\a'
+}
+"#;
+
+const BASH_RC: &str = r#"
+[ -f ~/.bashrc ] && . ~/.bashrc
+
+__exam_preexec() {
+    [ -n "$__exam_at_prompt" ] || return
+    [[ $BASH_COMMAND == __exam_* ]] && return
+    __exam_at_prompt=
+
+    # Prefer the full line from history; fall back to the simple command
+    # when the line was not added (ignorespace, ignoredups, history off)
+    local entry line=$BASH_COMMAND
+    entry=$(HISTTIMEFORMAT= builtin history 1)
+    if [[ $entry =~ ^[[:space:]]*([0-9]+)[*]?[[:space:]]+(.*)$ ]] \
+        && [ "${BASH_REMATCH[1]}" != "$__exam_history" ]; then
+        __exam_history=${BASH_REMATCH[1]}
+        line=${BASH_REMATCH[2]}
+    fi
+    __exam_report_start "$line"
+}
+
+__exam_precmd() {
+    __exam_report_prompt "$__exam_status"
+    __exam_at_prompt=1
+}
+
+__exam_history=$(HISTTIMEFORMAT= builtin history 1 | awk '{print $1}')
+trap '__exam_preexec' DEBUG
+PROMPT_COMMAND="__exam_status=\$?${PROMPT_COMMAND:+; $PROMPT_COMMAND}; __exam_precmd"
+"#;
+
+const ZSH_ENV: &str = r#"
+__exam_zdotdir=$ZDOTDIR
+ZDOTDIR=$EXAM_RECORDER_ZDOTDIR
+[[ -f $ZDOTDIR/.zshenv ]] && . $ZDOTDIR/.zshenv
+ZDOTDIR=$__exam_zdotdir
+"#;
+
+const ZSH_RC: &str = r#"
+ZDOTDIR=$EXAM_RECORDER_ZDOTDIR
+unset EXAM_RECORDER_ZDOTDIR
+[[ -f $ZDOTDIR/.zshrc ]] && . $ZDOTDIR/.zshrc
+
+__exam_preexec() { __exam_report_start "$1" }
+__exam_precmd() { __exam_report_prompt "$?" }
+
+autoload -Uz add-zsh-hook
+add-zsh-hook preexec __exam_preexec
+add-zsh-hook precmd __exam_precmd
+"#;
+
+/// How to start the student's shell with command reporting hooked in.
+pub struct ShellLaunch {
+    pub program: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+}
+
+/// Writes the prompt hooks for bash or zsh to the state directory and
+/// returns how to start the shell with them. Other shells, and programs
+/// given in `command`, start as-is and commands fall back to being
+/// reconstructed from keystrokes.
+pub fn prepare_launch(shell: &str, command: &[String]) -> Result<ShellLaunch> {
+    if let Some(program) = command.first() {
+        return Ok(ShellLaunch {
+            program: program.clone(),
+            args: command.to_vec(),
+            env: Vec::new(),
+        });
+    }
+    
+    let mut launch = ShellLaunch {
+        program: shell.to_string(),
+        args: vec![shell.to_string()],
+        env: Vec::new(),
+    };
+
+    let dir = get_state_dir()?.join("shell-integration");
+    match shell_name(shell).as_str() {
+        "bash" => {
+            std::fs::create_dir_all(&dir)?;
+            let rcfile = dir.join("bashrc");
+            write_script(&rcfile, &[BASH_RC, COMMON_HOOKS])?;
+            launch.args.push("--rcfile".to_string());
+            launch.args.push(rcfile.to_string_lossy().to_string());
+        }
+        "zsh" => {
+            std::fs::create_dir_all(&dir)?;
+            write_script(&dir.join(".zshenv"), &[ZSH_ENV])?;
+            write_script(&dir.join(".zshrc"), &[ZSH_RC, COMMON_HOOKS])?;
+            let home = std::env::var("HOME").unwrap_or_default();
+            let original = std::env::var("ZDOTDIR").unwrap_or(home);
+            launch.env.push(("EXAM_RECORDER_ZDOTDIR".to_string(), original));
+            launch.env.push(("ZDOTDIR".to_string(), dir.to_string_lossy().to_string()));
+        }
+        _ => {}
+    }
+
+    Ok(launch)
+}
+
+/// Whether `prepare_launch` starts this shell with the reporting hooks.
+pub fn has_hooks(shell: &str, command: &[String]) -> bool {
+    command.is_empty() && matches!(shell_name(shell).as_str(), "bash" | "zsh")
+}
+
+fn shell_name(shell: &str) -> String {
+    Path::new(shell)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn write_script(path: &Path, parts: &[&str]) -> Result<()> {
+    let mut script = String::from("# Generated by exam-recorder for the running session\n");
+    for part in parts {
+        script.push_str(part);
+    }
+    std::fs::write(path, script)
+        .with_context(|| format!("Failed to write shell integration: {}", path.display()))
+}
+
+/// Follows OSC 133 (command boundaries) and OSC 7 (working directory)
+/// markers in the shell's output.
+///
+/// Any program can print these markers, so the commands reported here are
+/// only what the output claims; the viewer checks them against the
+/// commands rebuilt from keystrokes.
+pub struct ShellTracker {
+    /// Start of an OSC sequence whose terminator has not arrived yet.
+    pending: Vec<u8>,
+    cwd: Option<String>,
+    running: Option<CommandEvent>,
+}
+
+impl ShellTracker {
+    pub fn new() -> Self {
+        ShellTracker {
+            pending: Vec::new(),
+            cwd: None,
+            running: None,
+        }
+    }
+
+    /// Scans output for markers, returning commands that finished.
+    pub fn observe_output(&mut self, data: &[u8], timestamp: u64) -> Vec<CommandEvent> {
+        let mut window = std::mem::take(&mut self.pending);
+        window.extend_from_slice(data);
+
+        let mut finished = Vec::new();
+        let mut pos = 0;
+        loop {
+            let Some(start) = find(&window[pos..], b"\x1b]").map(|i| pos + i) else {
+                // A trailing ESC may be the start of the next sequence
+                if window[pos..].last() == Some(&0x1b) {
+                    self.pending.push(0x1b);
+                }
+                break;
+            };
+
+            let body = start + 2;
+            let terminator = window[body..].iter().enumerate().find_map(|(i, &b)| match b {
+                0x07 => Some((body + i, 1)),
+                0x1b if window.get(body + i + 1) == Some(&b'\\') => Some((body + i, 2)),
+                _ => None,
+            });
+            let Some((end, len)) = terminator else {
+                if window.len() - start <= MAX_OSC_LEN {
+                    self.pending = window[start..].to_vec();
+                }
+                break;
+            };
+
+            let payload = String::from_utf8_lossy(&window[body..end]).to_string();
+            if let Some(event) = self.handle(&payload, timestamp) {
+                finished.push(event);
+            }
+            pos = end + len;
+        }
+
+        finished
+    }
+
+    /// Takes the command still running when the session ended, if any.
+    pub fn finish(&mut self) -> Option<CommandEvent> {
+        self.running.take()
+    }
+
+    fn handle(&mut self, payload: &str, timestamp: u64) -> Option<CommandEvent> {
+        if let Some(url) = payload.strip_prefix("7;") {
+            // file://host/path
+            let path = url.strip_prefix("file://")
+                .and_then(|rest| rest.find('/').map(|i| &rest[i..]))?;
+            self.cwd = Some(percent_decode(path));
+            return None;
+        }
+
+        let mut fields = payload.strip_prefix("133;")?.split(';');
+        match fields.next()? {
+            "C" => {
+                let command = fields
+                    .find_map(|field| field.strip_prefix("cmdline_url="))
+                    .map(percent_decode)
+                    .unwrap_or_default();
+                // A command that never reported its end is kept without a status
+                let previous = self.running.take();
+                self.running = Some(CommandEvent {
+                    timestamp,
+                    command,
+                    source: CommandSource::Shell,
+                    end_timestamp: None,
+                    exit_status: None,
+                    cwd: self.cwd.clone(),
+                });
+                previous
+            }
+            "D" => {
+                let mut event = self.running.take()?;
+                event.end_timestamp = Some(timestamp);
+                event.exit_status = fields.next().and_then(|s| s.parse().ok());
+                Some(event)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(byte) = text.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(tracker: &mut ShellTracker, chunks: &[&[u8]]) -> Vec<CommandEvent> {
+        let mut finished = Vec::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            finished.extend(tracker.observe_output(chunk, 1_000 + i as u64));
+        }
+        finished
+    }
+
+    #[test]
+    fn follows_markers_split_across_reads() {
+        let mut tracker = ShellTracker::new();
+        let finished = commands(&mut tracker, &[
+            b"\x1b]7;file://lab-01/home/st",
+            b"udent\x07\x1b]133;A\x07$ ls\r\n\x1b]133;C;cmdline_",
+            b"url=ls%20-l\x07a.c\r\n\x1b",
+            b"]133;D;0\x07",
+        ]);
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].command, "ls -l");
+        assert_eq!(finished[0].source, CommandSource::Shell);
+        assert_eq!(finished[0].cwd.as_deref(), Some("/home/student"));
+        assert_eq!((finished[0].timestamp, finished[0].end_timestamp), (1_002, Some(1_003)));
+        assert_eq!(finished[0].exit_status, Some(0));
+    }
+
+    #[test]
+    fn accepts_bel_and_st_terminators() {
+        let mut tracker = ShellTracker::new();
+        let finished = commands(&mut tracker, &[
+            b"\x1b]133;C;cmdline_url=make\x1b\\out\x1b]133;D;2\x07",
+            b"\x1b]133;C;cmdline_url=true\x07\x1b]133;D;0\x1b\\",
+        ]);
+        let statuses: Vec<_> = finished.iter().map(|c| (c.command.as_str(), c.exit_status)).collect();
+        assert_eq!(statuses, [("make", Some(2)), ("true", Some(0))]);
+    }
+
+    #[test]
+    fn keeps_a_command_without_its_end() {
+        let mut tracker = ShellTracker::new();
+        let finished = commands(&mut tracker, &[
+            b"\x1b]133;C;cmdline_url=vim\x07",
+            b"\x1b]133;C;cmdline_url=ls\x07",
+        ]);
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].command, "vim");
+        assert_eq!((finished[0].end_timestamp, finished[0].exit_status), (None, None));
+        assert_eq!(tracker.finish().map(|c| c.command), Some("ls".to_string()));
+        assert!(commands(&mut tracker, &[b"\x1b]133;D;0\x07"]).is_empty());
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("echo%20%22h%C3%A9%22%3B"), "echo \"hé\";");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    }
+}
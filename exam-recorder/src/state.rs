@@ -32,7 +32,7 @@ impl State {
         
         // Verify checksum
         let (data, checksum) = split_checksum(&decrypted)?;
-        verify_checksum(data, &checksum)?;
+        verify_checksum(data, checksum)?;
         
         let state: State = serde_json::from_slice(data)
             .context("Failed to parse state JSON")?;
@@ -86,7 +86,7 @@ impl State {
     }
 }
 
-fn get_state_dir() -> Result<PathBuf> {
+pub fn get_state_dir() -> Result<PathBuf> {
     let home = home_dir()
         .context("Failed to get home directory")?;
     Ok(home.join(".exam-recorder"))
@@ -114,7 +114,7 @@ fn split_checksum(data: &[u8]) -> Result<(&[u8], &[u8])> {
     Ok((payload, checksum))
 }
 
-fn encrypt_state(data: &[u8]) -> Result<Vec<u8>> {
+pub fn encrypt_state(data: &[u8]) -> Result<Vec<u8>> {
     use aes_gcm::{
         aead::{Aead, KeyInit},
         Aes256Gcm, Nonce,
@@ -138,7 +138,7 @@ fn encrypt_state(data: &[u8]) -> Result<Vec<u8>> {
     Ok(result)
 }
 
-fn decrypt_state(encrypted: &[u8]) -> Result<Vec<u8>> {
+pub fn decrypt_state(encrypted: &[u8]) -> Result<Vec<u8>> {
     use aes_gcm::{
         aead::{Aead, KeyInit},
         Aes256Gcm, Nonce,
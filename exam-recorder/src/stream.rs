@@ -0,0 +1,99 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+/// One timed event of the terminal stream, serialized asciicast-style as
+/// `[offset_seconds, kind, data]` where kind is "o" (output), "i" (input)
+/// or "r" (resize, with data "COLSxROWS").
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamEvent(pub f64, pub String, pub String);
+
+/// Header line of the asciicast v2 stream.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamHeader {
+    pub version: u32,
+    pub width: u16,
+    pub height: u16,
+    pub timestamp: u64,
+}
+
+/// Terminal input and output recorded on a single monotonic clock, written
+/// out as asciicast v2: a header line followed by one JSON array per event.
+///
+/// Data is stored as UTF-8 text; sequences split across reads are held
+/// back until they are complete, and invalid bytes become U+FFFD.
+pub struct TerminalStream<W: Write> {
+    out: W,
+    pending_output: Vec<u8>,
+    pending_input: Vec<u8>,
+}
+
+impl<W: Write> TerminalStream<W> {
+    pub fn new(mut out: W, header: &StreamHeader) -> Result<Self> {
+        serde_json::to_writer(&mut out, header)?;
+        out.write_all(b"\n")?;
+        Ok(TerminalStream {
+            out,
+            pending_output: Vec::new(),
+            pending_input: Vec::new(),
+        })
+    }
+
+    pub fn record_output(&mut self, offset: f64, data: &[u8]) -> Result<()> {
+        self.pending_output.extend_from_slice(data);
+        let text = drain_utf8(&mut self.pending_output);
+        self.push(offset, "o", text)
+    }
+
+    pub fn record_input(&mut self, offset: f64, data: &[u8]) -> Result<()> {
+        self.pending_input.extend_from_slice(data);
+        let text = drain_utf8(&mut self.pending_input);
+        self.push(offset, "i", text)
+    }
+
+    pub fn record_resize(&mut self, offset: f64, width: u16, height: u16) -> Result<()> {
+        self.push(offset, "r", format!("{}x{}", width, height))
+    }
+
+    fn push(&mut self, offset: f64, kind: &str, text: String) -> Result<()> {
+        if !text.is_empty() {
+            serde_json::to_writer(&mut self.out, &StreamEvent(offset, kind.to_string(), text))?;
+            self.out.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// Takes the longest decodable prefix of `buffer`, leaving an incomplete
+/// trailing sequence in place for the next read.
+fn drain_utf8(buffer: &mut Vec<u8>) -> String {
+    let mut text = String::new();
+    loop {
+        match std::str::from_utf8(buffer) {
+            Ok(valid) => {
+                text.push_str(valid);
+                buffer.clear();
+                break;
+            }
+            Err(e) => {
+                let valid_up_to = e.valid_up_to();
+                text.push_str(&String::from_utf8_lossy(&buffer[..valid_up_to]));
+                match e.error_len() {
+                    Some(len) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        buffer.drain(..valid_up_to + len);
+                    }
+                    None => {
+                        buffer.drain(..valid_up_to);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    text
+}
@@ -0,0 +1,107 @@
+use std::time::{Duration, Instant};
+
+/// Minutes-left marks at which the student is warned, unless configured.
+pub const DEFAULT_WARNINGS: &[u64] = &[10, 1];
+
+#[derive(Debug, PartialEq)]
+pub enum TimerEvent {
+    /// The given number of minutes is left.
+    Warning(u64),
+    Expired,
+}
+
+/// Tracks the exam time limit against the session clock.
+pub struct ExamTimer {
+    deadline: Instant,
+    /// Marks not yet shown, largest first.
+    warnings: Vec<u64>,
+    expired: bool,
+}
+
+impl ExamTimer {
+    pub fn new(started: Instant, limit_minutes: u64, warnings: &[u64]) -> Self {
+        // Marks at or beyond the limit would fire as soon as the exam starts
+        let mut warnings: Vec<u64> = warnings.iter()
+            .copied()
+            .filter(|&minutes| minutes > 0 && minutes < limit_minutes)
+            .collect();
+        warnings.sort_unstable_by(|a, b| b.cmp(a));
+        warnings.dedup();
+
+        ExamTimer {
+            deadline: started + Duration::from_secs(limit_minutes * 60),
+            warnings,
+            expired: false,
+        }
+    }
+
+    /// Returns the next warning or the expiry once it is due. Each event
+    /// is reported once.
+    pub fn check(&mut self) -> Option<TimerEvent> {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&mut self, now: Instant) -> Option<TimerEvent> {
+        if self.expired {
+            return None;
+        }
+
+        let remaining = self.deadline.saturating_duration_since(now);
+        if remaining.is_zero() {
+            self.expired = true;
+            return Some(TimerEvent::Expired);
+        }
+
+        let mut due = None;
+        while let Some(&minutes) = self.warnings.first() {
+            if remaining > Duration::from_secs(minutes * 60) {
+                break;
+            }
+            // Only the closest mark is shown if several passed at once
+            due = Some(self.warnings.remove(0));
+        }
+        due.map(TimerEvent::Warning)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(minutes: u64) -> Duration {
+        Duration::from_secs(minutes * 60)
+    }
+
+    #[test]
+    fn warnings_come_in_order_then_the_expiry() {
+        let started = Instant::now();
+        let mut timer = ExamTimer::new(started, 30, &[1, 10, 5]);
+        assert_eq!(timer.check_at(started), None);
+        assert_eq!(timer.check_at(started + minutes(20) - Duration::from_secs(1)), None);
+        assert_eq!(timer.check_at(started + minutes(20)), Some(TimerEvent::Warning(10)));
+        assert_eq!(timer.check_at(started + minutes(21)), None);
+        assert_eq!(timer.check_at(started + minutes(25)), Some(TimerEvent::Warning(5)));
+        assert_eq!(timer.check_at(started + minutes(29)), Some(TimerEvent::Warning(1)));
+        assert_eq!(timer.check_at(started + minutes(30)), Some(TimerEvent::Expired));
+        assert_eq!(timer.check_at(started + minutes(31)), None);
+    }
+
+    #[test]
+    fn passed_marks_collapse_to_the_closest() {
+        let started = Instant::now();
+        let mut timer = ExamTimer::new(started, 30, &[10, 5, 1]);
+        // The recorder was busy past two marks
+        assert_eq!(timer.check_at(started + minutes(26)), Some(TimerEvent::Warning(5)));
+        assert_eq!(timer.check_at(started + minutes(27)), None);
+        // Past the deadline the remaining mark is not shown
+        assert_eq!(timer.check_at(started + minutes(40)), Some(TimerEvent::Expired));
+    }
+
+    #[test]
+    fn marks_outside_the_limit_are_dropped() {
+        let started = Instant::now();
+        let mut timer = ExamTimer::new(started, 5, &[0, 5, 10, 2, 2]);
+        assert_eq!(timer.warnings, [2]);
+        assert_eq!(timer.check_at(started), None);
+    }
+}
@@ -0,0 +1,440 @@
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use exam_format::archive::file_version_entry;
+use exam_format::{FileVersion, FileVersionKind};
+
+/// Largest file whose versions are stored when the exam does not set a
+/// limit, in KiB.
+pub const DEFAULT_WATCH_FILE_LIMIT_KIB: u64 = 1024;
+/// Compressed versions stored in all when the exam does not set a limit,
+/// in MiB.
+pub const DEFAULT_WATCH_LIMIT_MIB: u64 = 64;
+/// Paths left alone when the exam does not list any: version control, and
+/// the swap, backup and lock files editors write next to the file.
+pub const DEFAULT_WATCH_IGNORE: [&str; 7] = [".git", "*.swp", "*.swx", "*~", ".#*", "#*#", "4913"];
+
+/// How long a file has to be left alone after a change before it is read,
+/// so a save made of several writes becomes one version.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+/// Longest a file that keeps changing goes without a new version.
+const MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// Follows the exam directory with inotify and records a version of a file
+/// each time its contents change.
+///
+/// Every directory under the root is watched, including ones created during
+/// the session. A file is read once it has settled after a change; a
+/// version is recorded only if its contents differ from the last one, and
+/// its contents are stored compressed while it stays within the size
+/// limits. Events the kernel drops are caught up on by reading every file
+/// again when the session ends.
+pub struct FileWatcher {
+    root: PathBuf,
+    inotify: Inotify,
+    /// Watched directories, relative to `root`.
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    ignore: Vec<String>,
+    /// Paths under the root the recorder itself writes to, such as the
+    /// session journal.
+    excluded: Vec<PathBuf>,
+    file_limit_kib: u64,
+    limit_mib: u64,
+    /// Compressed bytes that may still be stored.
+    budget: u64,
+    /// SHA-256 of each file's last version, `None` once it was deleted.
+    latest: HashMap<String, Option<String>>,
+    /// Files changed since they were last read, with when the first and the
+    /// last change was seen.
+    pending: HashMap<String, (Instant, Instant)>,
+    /// Versions whose contents were stored.
+    stored: usize,
+}
+
+impl FileWatcher {
+    /// Starts watching `root`, leaving out paths matching `ignore` and
+    /// the recorder's own directories in `excluded`.
+    pub fn new(
+        root: &Path,
+        ignore: Vec<String>,
+        excluded: &[PathBuf],
+        file_limit_kib: u64,
+        limit_mib: u64,
+    ) -> Result<Self> {
+        let root = root.canonicalize()
+            .with_context(|| format!("Exam directory not found: {}", root.display()))?;
+        if !root.is_dir() {
+            anyhow::bail!("Exam directory is not a directory: {}", root.display());
+        }
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)
+            .context("Failed to watch the exam directory")?;
+        inotify.add_watch(&root, watch_flags())
+            .with_context(|| format!("Failed to watch the exam directory: {}", root.display()))?;
+        let excluded = excluded.iter()
+            .filter_map(|path| path.strip_prefix(&root).ok().map(Path::to_path_buf))
+            .filter(|path| !path.as_os_str().is_empty())
+            .collect();
+
+        Ok(FileWatcher {
+            root,
+            inotify,
+            dirs: HashMap::new(),
+            ignore,
+            excluded,
+            file_limit_kib,
+            limit_mib,
+            budget: limit_mib * 1024 * 1024,
+            latest: HashMap::new(),
+            pending: HashMap::new(),
+            stored: 0,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The files as they are when the session starts.
+    pub fn initial_versions(&mut self) -> Vec<(FileVersion, Vec<u8>)> {
+        self.rescan(true)
+    }
+
+    /// Versions of the files that changed and have settled since the last
+    /// poll, with their compressed contents.
+    pub fn poll(&mut self) -> Vec<(FileVersion, Vec<u8>)> {
+        let now = Instant::now();
+        let mut overflowed = false;
+        while let Ok(events) = self.inotify.read_events() {
+            if events.is_empty() {
+                break;
+            }
+            for event in events {
+                overflowed |= event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW);
+                self.observe(event.wd, event.mask, event.name.as_deref().and_then(|name| name.to_str()), now);
+            }
+        }
+        if overflowed {
+            return self.rescan(false);
+        }
+
+        let due: BTreeSet<String> = self.pending.iter()
+            .filter(|(_, (first, last))| now - *last >= SETTLE_TIME || now - *first >= MAX_DELAY)
+            .map(|(path, _)| path.clone())
+            .collect();
+        due.into_iter()
+            .filter_map(|path| {
+                self.pending.remove(&path);
+                self.read_version(&path, false)
+            })
+            .collect()
+    }
+
+    /// Versions of every file that changed since it was last read, whether
+    /// or not it has settled. Called once the shell has exited.
+    pub fn finish(&mut self) -> Vec<(FileVersion, Vec<u8>)> {
+        while matches!(self.inotify.read_events(), Ok(events) if !events.is_empty()) {}
+        self.pending.clear();
+        self.rescan(false)
+    }
+
+    /// Takes note of one inotify event on a watched directory.
+    fn observe(&mut self, wd: WatchDescriptor, mask: AddWatchFlags, name: Option<&str>, now: Instant) {
+        if mask.contains(AddWatchFlags::IN_IGNORED) {
+            self.dirs.remove(&wd);
+            return;
+        }
+        let (Some(dir), Some(name)) = (self.dirs.get(&wd), name) else {
+            return;
+        };
+        let path = dir.join(name);
+        if self.is_ignored(&path) {
+            return;
+        }
+
+        if !mask.contains(AddWatchFlags::IN_ISDIR) {
+            if let Some(path) = path.to_str() {
+                self.pending.entry(path.to_string()).or_insert((now, now)).1 = now;
+            }
+        } else if mask.intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO) {
+            for file in self.watch_tree(&path) {
+                self.pending.entry(file).or_insert((now, now)).1 = now;
+            }
+        } else if mask.intersects(AddWatchFlags::IN_DELETE | AddWatchFlags::IN_MOVED_FROM) {
+            // Watches of a directory moved away would report under the old
+            // path; it is watched again if it shows up inside the root
+            let gone: Vec<WatchDescriptor> = self.dirs.iter()
+                .filter(|(_, dir)| dir.starts_with(&path))
+                .map(|(wd, _)| *wd)
+                .collect();
+            for wd in gone {
+                self.dirs.remove(&wd);
+                self.inotify.rm_watch(wd).ok();
+            }
+            let files: Vec<String> = self.latest.iter()
+                .filter(|(file, hash)| hash.is_some() && Path::new(file).starts_with(&path))
+                .map(|(file, _)| file.clone())
+                .collect();
+            for file in files {
+                self.pending.entry(file).or_insert((now, now)).1 = now;
+            }
+        }
+    }
+
+    /// Reads every file under the root, watching each directory on the way,
+    /// and returns versions of the ones that changed since they were last
+    /// read, or that are gone.
+    fn rescan(&mut self, initial: bool) -> Vec<(FileVersion, Vec<u8>)> {
+        let mut paths: BTreeSet<String> = self.watch_tree(Path::new("")).into_iter().collect();
+        paths.extend(self.latest.iter()
+            .filter(|(_, hash)| hash.is_some())
+            .map(|(path, _)| path.clone()));
+        paths.into_iter()
+            .filter_map(|path| self.read_version(&path, initial))
+            .collect()
+    }
+
+    /// Watches `dir`, relative to the root, and every directory under it,
+    /// and returns the files found.
+    fn watch_tree(&mut self, dir: &Path) -> Vec<String> {
+        let mut files = Vec::new();
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let full = self.root.join(&dir);
+            let Ok(wd) = self.inotify.add_watch(&full, watch_flags()) else {
+                continue;
+            };
+            self.dirs.insert(wd, dir.clone());
+            let Ok(entries) = fs::read_dir(&full) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = dir.join(entry.file_name());
+                if self.is_ignored(&path) {
+                    continue;
+                }
+                match entry.file_type() {
+                    Ok(kind) if kind.is_dir() => dirs.push(path),
+                    Ok(kind) if kind.is_file() => files.extend(path.to_str().map(String::from)),
+                    // Symlinks are not followed out of the directory
+                    _ => {}
+                }
+            }
+        }
+        files
+    }
+
+    fn is_ignored(&self, path: &Path) -> bool {
+        if self.excluded.iter().any(|excluded| path.starts_with(excluded)) {
+            return true;
+        }
+        let Some(path) = path.to_str() else {
+            return true;
+        };
+        self.ignore.iter().any(|pattern| ignore_matches(pattern, path))
+    }
+
+    /// Reads `path` and returns a new version of it if it changed since the
+    /// last one, or a deletion if it is gone.
+    fn read_version(&mut self, path: &str, initial: bool) -> Option<(FileVersion, Vec<u8>)> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let existed = self.latest.get(path).is_some_and(|hash| hash.is_some());
+        let full = self.root.join(path);
+        let read = match fs::symlink_metadata(&full) {
+            Ok(info) if info.is_file() => {
+                // A file that cannot be read is left as it was last seen
+                Some(read_contents(&full, info.len(), self.file_limit_kib * 1024).ok()?)
+            }
+            _ => None,
+        };
+
+        let Some((size, sha256, contents)) = read else {
+            if !existed {
+                return None;
+            }
+            self.latest.insert(path.to_string(), None);
+            let version = FileVersion {
+                timestamp,
+                path: path.to_string(),
+                kind: FileVersionKind::Deleted,
+                size: 0,
+                sha256: None,
+                entry: None,
+                skipped: None,
+            };
+            return Some((version, Vec::new()));
+        };
+        if self.latest.get(path).is_some_and(|hash| hash.as_ref() == Some(&sha256)) {
+            return None;
+        }
+        self.latest.insert(path.to_string(), Some(sha256.clone()));
+
+        let (entry, data, skipped) = match contents {
+            None => (None, Vec::new(), Some(format!("larger than {} KiB", self.file_limit_kib))),
+            Some(contents) => {
+                let data = compress(&contents).ok()?;
+                if data.len() as u64 > self.budget {
+                    (None, Vec::new(), Some(format!("history limit of {} MiB reached", self.limit_mib)))
+                } else {
+                    self.budget -= data.len() as u64;
+                    let entry = file_version_entry(self.stored);
+                    self.stored += 1;
+                    (Some(entry), data, None)
+                }
+            }
+        };
+        let kind = if initial {
+            FileVersionKind::Initial
+        } else if existed {
+            FileVersionKind::Modified
+        } else {
+            FileVersionKind::Created
+        };
+        let version = FileVersion {
+            timestamp,
+            path: path.to_string(),
+            kind,
+            size,
+            sha256: Some(sha256),
+            entry,
+            skipped,
+        };
+        Some((version, data))
+    }
+}
+
+fn watch_flags() -> AddWatchFlags {
+    AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_MODIFY
+        | AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_MOVED_FROM
+        | AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_ONLYDIR
+        | AddWatchFlags::IN_DONT_FOLLOW
+}
+
+/// Reads a file's size and SHA-256, and its contents unless it is larger
+/// than `limit`, in which case it is only hashed.
+fn read_contents(path: &Path, len: u64, limit: u64) -> io::Result<(u64, String, Option<Vec<u8>>)> {
+    let mut file = File::open(path)?;
+    let mut contents = Vec::new();
+    if len <= limit {
+        // The file may have grown since it was listed; the viewer refuses
+        // stored contents over the limit, so neither may be kept here
+        (&mut file).take(limit + 1).read_to_end(&mut contents)?;
+        if contents.len() as u64 <= limit {
+            return Ok((contents.len() as u64, hex::encode(Sha256::digest(&contents)), Some(contents)));
+        }
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(&contents);
+    let size = contents.len() as u64 + io::copy(&mut file, &mut hasher)?;
+    Ok((size, hex::encode(hasher.finalize()), None))
+}
+
+fn compress(contents: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(contents)?;
+    encoder.finish()
+}
+
+/// Whether `pattern` leaves out `path`, relative to the root. Patterns
+/// without a slash match any component of the path, e.g. `*.o` or `.git`;
+/// patterns with one match the whole path, e.g. `build/*`. A trailing slash
+/// only marks the pattern as naming a directory, as in `.gitignore`, and
+/// the directory is left out with everything under it.
+fn ignore_matches(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.strip_suffix('/').unwrap_or(pattern);
+    if pattern.contains('/') {
+        glob_match(pattern.trim_start_matches('/').as_bytes(), path.as_bytes())
+    } else {
+        path.split('/').any(|component| glob_match(pattern.as_bytes(), component.as_bytes()))
+    }
+}
+
+/// Matches `text` against a pattern where `*` stands for any run of
+/// characters and `?` for any one.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and how much of the text it has taken
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        glob_match(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn star_matches_any_run_of_characters() {
+        assert!(matches("*", ""));
+        assert!(matches("*.o", "main.o"));
+        assert!(matches("*.o", ".o"));
+        assert!(matches("a*b*c", "aXbbYc"));
+        assert!(matches("build/*", "build/out/main.o"));
+        assert!(!matches("*.o", "main.c"));
+        assert!(!matches("*.o", "main.o.c"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(matches("?.c", "a.c"));
+        assert!(matches(".*.sw?", ".main.c.swp"));
+        assert!(!matches("?.c", ".c"));
+        assert!(!matches("?.c", "ab.c"));
+    }
+
+    #[test]
+    fn dot_is_literal() {
+        assert!(matches("main.c", "main.c"));
+        assert!(!matches("main.c", "mainxc"));
+        assert!(!matches(".git", "xgit"));
+        assert!(!matches("*.o", "maino"));
+    }
+
+    #[test]
+    fn trailing_slash_names_a_directory() {
+        assert!(ignore_matches("build/", "build"));
+        assert!(ignore_matches("build/", "src/build"));
+        assert!(ignore_matches("/out/gen/", "out/gen"));
+        assert!(!ignore_matches("build/", "rebuild"));
+        assert!(!ignore_matches("out/gen/", "src/out/gen"));
+        // glob_match itself takes the slash literally
+        assert!(!matches("build/", "build"));
+    }
+}
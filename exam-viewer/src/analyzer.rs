@@ -1,29 +1,257 @@
 use anyhow::Result;
-use serde_json::Value;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 
+use exam_format::{
+    CollectedFile, CommandEvent, CommandSource, ConnectionEvent, EndReason, FileVersion, FileVersionKind,
+    IntegrityCheck, KeystrokeEvent, Metadata, PasteEvent, PastePolicy, ProcessEvent, ProcessEventKind,
+    SessionSummary,
+};
+
+use crate::commands;
+use crate::history;
+use crate::network::Allowlist;
+use crate::chain::ChainReport;
+use crate::stream::StreamSummary;
+
+/// What the report is made from. Terminal output, collected files and the
+/// full terminal stream stay in the archive; only the subcommands that need
+/// them read them.
 pub struct DecryptedData {
-    pub events: Value,
-    pub summary: Value,
-    pub metadata: Value,
-    pub terminal_output: String,
-    pub state_copy: Value,
-    pub integrity_hash: String,
+    pub events: Vec<KeystrokeEvent>,
+    pub summary: SessionSummary,
+    pub metadata: Metadata,
+    /// Only present in archives from recorders that time their output
+    pub terminal_stream: Option<StreamSummary>,
+    pub pastes: Option<Vec<PasteEvent>>,
+    pub commands: Option<Vec<CommandEvent>>,
+    /// Only present in archives from recorders that follow processes
+    pub processes: Option<Vec<ProcessEvent>>,
+    /// Only present in archives from recorders that sample connections
+    pub connections: Option<Vec<ConnectionEvent>>,
+    /// Only present in archives from recorders that version files
+    pub file_history: Option<Vec<FileVersion>>,
+    /// Only present in archives from recorders that chain their events
+    pub event_chain: Option<ChainReport>,
+    pub collected_files: Option<Vec<CollectedFile>>,
+    pub integrity: IntegrityCheck,
 }
 
 pub struct AnalysisReport {
+    pub exam_id: Option<String>,
+    pub student_id: Option<String>,
     pub username: String,
     pub hostname: String,
     pub machine_id: String,
     pub session_duration: String,
+    /// Time limit, marked "(reached)" if the session ran out of time.
+    pub time_limit: Option<String>,
+    /// Time warnings shown to the student, e.g. "10 min left at 10:50:00".
+    pub time_warnings: Vec<String>,
+    /// Program recorded instead of the shell, e.g. "python3 -q".
+    pub recorded_program: Option<String>,
+    /// SHA-256 of the signed exam config the session ran under.
+    pub config_hash: Option<String>,
+    /// Whether `config_hash` matches the config the instructor expected,
+    /// when one was given with `--exam-config`.
+    pub config_matches: Option<bool>,
+    /// Set when the exam config blocked pasting.
+    pub pastes_blocked: bool,
+    pub collected_files: Vec<CollectedFileEntry>,
+    /// Files of the exam's watched directory, by path.
+    pub watched_files: Vec<WatchedFileEntry>,
     pub recorder_runs_before: u64,
     pub total_keystrokes: usize,
     pub enter_pressed: usize,
     pub backspace_used: usize,
+    pub delete_used: usize,
+    pub navigation_keys: usize,
+    /// Named (non-text) keys with how often each was pressed, most used first.
+    pub key_usage: Vec<(String, usize)>,
     pub paste_events: usize,
     pub total_pasted_chars: usize,
-    pub commands: Vec<String>,
+    pub longest_input_pause: Option<String>,
+    pub terminal_size: Option<String>,
+    /// Terminal output left out past the recorder's output limit, e.g.
+    /// "1048576 bytes (limit 64 MiB)".
+    pub output_dropped: Option<String>,
+    pub commands: Vec<CommandEntry>,
+    /// Programs started under the shell, in the order they started.
+    pub processes: Vec<ProcessEntry>,
+    /// Network connections of the shell's processes, in the order seen.
+    pub connections: Vec<ConnectionEntry>,
+    pub pastes: Vec<PastedText>,
     pub suspicious_activities: Vec<SuspiciousActivity>,
     pub integrity_passed: bool,
+    /// What the integrity check covered, or what failed it.
+    pub integrity: String,
+    /// Result of checking the event chain, for archives that have one.
+    pub event_chain: Option<String>,
+    pub event_chain_passed: Option<bool>,
+    pub recovered: bool,
+    /// How the session ended, e.g. "logout (exit status 0)".
+    pub end_reason: Option<String>,
+}
+
+/// A command from the session timeline. Only the command text is known for
+/// commands rebuilt from keystrokes; the rest comes from shell integration.
+pub struct CommandEntry {
+    pub command: String,
+    pub duration: Option<String>,
+    pub exit_status: Option<i32>,
+    pub cwd: Option<String>,
+    /// Set when the shell's report and the keystrokes disagree about it.
+    pub mismatch: Option<&'static str>,
+}
+
+impl CommandEntry {
+    fn typed(command: String) -> Self {
+        CommandEntry { command, duration: None, exit_status: None, cwd: None, mismatch: None }
+    }
+    
+    fn recorded(event: &CommandEvent, mismatch: Option<&'static str>) -> Self {
+        CommandEntry {
+            command: event.command.clone(),
+            duration: event.end_timestamp
+                .map(|end| format_elapsed(end.saturating_sub(event.timestamp))),
+            exit_status: event.exit_status,
+            cwd: event.cwd.clone(),
+            mismatch,
+        }
+    }
+    
+    /// Exit status, run time and directory, e.g. "exit 1, 2.5s, /home/student".
+    pub fn details(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        if let Some(mismatch) = self.mismatch {
+            parts.push(mismatch.to_string());
+        }
+        if let Some(status) = self.exit_status {
+            parts.push(format!("exit {}", status));
+        }
+        if let Some(duration) = &self.duration {
+            parts.push(duration.clone());
+        }
+        if let Some(cwd) = &self.cwd {
+            parts.push(cwd.clone());
+        }
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(", "))
+        }
+    }
+}
+
+/// A program started under the recorded shell.
+pub struct ProcessEntry {
+    pub started: String,
+    pub pid: u32,
+    pub ppid: u32,
+    pub command: String,
+    pub exe: Option<String>,
+    /// How long the program ran; `None` if it was still running when the
+    /// recording stopped.
+    pub duration: Option<String>,
+    /// Set when the exam config flags the program.
+    pub flagged: bool,
+}
+
+impl ProcessEntry {
+    /// Pid, parent and run time, e.g. "pid 4242, parent 4100, 2.5s".
+    pub fn details(&self) -> String {
+        format!(
+            "pid {}, parent {}, {}",
+            self.pid,
+            self.ppid,
+            self.duration.as_deref().unwrap_or("still running at the end")
+        )
+    }
+}
+
+/// A network connection of a process under the recorded shell.
+pub struct ConnectionEntry {
+    pub timestamp: String,
+    pub protocol: String,
+    /// Remote address and port, e.g. "93.184.216.34:443", or "any address
+    /// (unconnected)" for a UDP socket that can send anywhere.
+    pub remote: String,
+    pub local_port: u16,
+    pub inbound: bool,
+    pub pid: u32,
+    pub command: String,
+    /// Set when the exam's network allowlist covers the remote address,
+    /// or it is this machine.
+    pub allowed: bool,
+}
+
+impl ConnectionEntry {
+    /// E.g. "outbound tcp to 93.184.216.34:443 by curl example.com (pid 4242)".
+    pub fn describe(&self) -> String {
+        format!(
+            "{} {} {} {} by {} (pid {})",
+            if self.inbound { "inbound" } else { "outbound" },
+            self.protocol,
+            if self.inbound { "from" } else { "to" },
+            self.remote,
+            self.command,
+            self.pid
+        )
+    }
+}
+
+/// Text the student pasted, as marked by the terminal.
+pub struct PastedText {
+    pub timestamp: String,
+    pub length: usize,
+    pub text: String,
+    /// Set when the paste policy kept the text from the shell.
+    pub blocked: bool,
+}
+
+/// A file the exam config asked the recorder to collect.
+pub struct CollectedFileEntry {
+    pub path: String,
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+    /// Why the recorder could not collect the file.
+    pub error: Option<String>,
+}
+
+impl CollectedFileEntry {
+    /// Size and hash, e.g. "120 bytes, sha256 3f2a9c...", or the error.
+    pub fn details(&self) -> String {
+        if let Some(error) = &self.error {
+            return format!("not collected: {}", error);
+        }
+        format!(
+            "{} bytes, sha256 {}",
+            self.size.unwrap_or(0),
+            self.sha256.as_deref().unwrap_or("")
+        )
+    }
+}
+
+/// A file in the exam's watched directory and how often it changed.
+pub struct WatchedFileEntry {
+    pub path: String,
+    pub versions: usize,
+    /// Whether it changed after the session started.
+    pub changed: bool,
+    /// The last version, e.g. "modified at 10:41:07, 1532 bytes".
+    pub last: String,
+}
+
+impl WatchedFileEntry {
+    /// e.g. "4 versions, last modified at 10:41:07, 1532 bytes".
+    pub fn details(&self) -> String {
+        format!(
+            "{} version{}, last {}",
+            self.versions,
+            if self.versions == 1 { "" } else { "s" },
+            self.last
+        )
+    }
 }
 
 pub struct SuspiciousActivity {
@@ -46,127 +274,453 @@ impl Analyzer {
         let summary = &self.data.summary;
         let events = &self.data.events;
         
-        let username = metadata["username"].as_str()
-            .unwrap_or("unknown").to_string();
-        let hostname = metadata["hostname"].as_str()
-            .unwrap_or("unknown").to_string();
-        let machine_id = metadata["machine_id"].as_str()
-            .unwrap_or("unknown").to_string();
-        let recorder_runs_before = metadata["run_counter"].as_u64()
-            .unwrap_or(0)
-            .saturating_sub(1);
-        
-        let start_time = metadata["start_time"].as_u64().unwrap_or(0);
-        let end_time = metadata["end_time"].as_u64().unwrap_or(start_time);
+        let exam_id = metadata.exam_id.clone();
+        let student_id = metadata.student_id.clone();
+        let time_limit = metadata.time_limit_minutes
+            .map(|minutes| if metadata.time_limit_reached {
+                format!("{} (reached)", format_duration(minutes * 60))
+            } else {
+                format_duration(minutes * 60)
+            });
+        let time_warnings = metadata.time_warnings.iter()
+            .map(|warning| format!(
+                "{} min left at {}",
+                warning.minutes_left,
+                format_timestamp(warning.timestamp)
+            ))
+            .collect();
+        let recorded_program = (!metadata.command.is_empty())
+            .then(|| metadata.command.join(" "));
+        let config_hash = metadata.config_hash.clone();
+        let pastes_blocked = metadata.paste_policy == PastePolicy::Block;
+        let collected_files = self.data.collected_files.iter()
+            .flatten()
+            .map(|file| CollectedFileEntry {
+                path: file.path.clone(),
+                size: file.size,
+                sha256: file.sha256.clone(),
+                error: file.error.clone(),
+            })
+            .collect();
+        let username = metadata.username.clone();
+        let hostname = metadata.hostname.clone();
+        let machine_id = metadata.machine_id.clone();
+        let recorder_runs_before = metadata.run_counter.saturating_sub(1);
+        
+        let start_time = metadata.start_time;
+        let end_time = metadata.end_time.unwrap_or(start_time);
         let duration_secs = end_time.saturating_sub(start_time);
         let session_duration = format_duration(duration_secs);
         
-        let total_keystrokes = summary["total_keystrokes"].as_u64()
-            .unwrap_or(0) as usize;
-        let enter_pressed = summary["enter_pressed"].as_u64()
-            .unwrap_or(0) as usize;
-        let backspace_used = summary["backspace_used"].as_u64()
-            .unwrap_or(0) as usize;
-        let paste_events = summary["paste_events"].as_u64()
-            .unwrap_or(0) as usize;
-        let total_pasted_chars = summary["total_pasted_chars"].as_u64()
-            .unwrap_or(0) as usize;
-        
-        // Extract commands
+        let total_keystrokes = summary.total_keystrokes;
+        let enter_pressed = summary.enter_pressed;
+        let backspace_used = summary.backspace_used;
+        let delete_used = summary.delete_used;
+        let navigation_keys = summary.navigation_keys;
+        let paste_events = summary.paste_events;
+        let total_pasted_chars = summary.total_pasted_chars;
+        
+        let longest_input_pause = self.data.terminal_stream.as_ref()
+            .map(|stream| format_duration(stream.longest_input_pause as u64));
+        let terminal_size = self.data.terminal_stream.as_ref()
+            .map(|stream| match stream.resize_count {
+                0 => format!("{}x{}", stream.header.width, stream.header.height),
+                n => format!("{}x{} (resized {} times)", stream.header.width, stream.header.height, n),
+            });
+        let output_dropped = (metadata.output_dropped_bytes > 0).then(|| {
+            match metadata.output_limit_mib {
+                Some(limit) => format!("{} bytes (limit {} MiB)", metadata.output_dropped_bytes, limit),
+                None => format!("{} bytes", metadata.output_dropped_bytes),
+            }
+        });
+        
+        let raw_pastes: &[PasteEvent] = self.data.pastes.as_deref().unwrap_or_default();
+        let pastes: Vec<PastedText> = raw_pastes.iter()
+            .map(|paste| PastedText {
+                timestamp: format_timestamp(paste.timestamp),
+                length: paste.text.chars().count(),
+                text: paste.text.clone(),
+                blocked: paste.blocked,
+            })
+            .collect();
+        
+        // Extract commands and count named keys
         let mut commands = Vec::new();
-        if let Some(events_array) = events.as_array() {
-            let mut current_command = String::new();
-            for event in events_array {
-                if let Some(key_name) = event["key_name"].as_str() {
-                    if key_name == "Enter" {
-                        if !current_command.trim().is_empty() {
-                            commands.push(current_command.trim().to_string());
+        let mut key_counts: HashMap<String, usize> = HashMap::new();
+        let mut current_command = String::new();
+        // Blocked pastes never reached the shell
+        let mut next_paste = raw_pastes.iter()
+            .filter(|paste| !paste.blocked)
+            .peekable();
+        for event in events {
+            let key_name = event.key_name.as_str();
+            // Pasted text lands in the line before the keys that follow it
+            while let Some(paste) = next_paste.next_if(|paste| paste.timestamp <= event.timestamp) {
+                current_command.extend(paste.text.chars().filter(|c| !c.is_control() || *c == '\n'));
+            }
+            let mut chars = key_name.chars();
+            match (chars.next(), chars.next()) {
+                // Text keys are logged under the character they insert
+                (Some(c), None) if !c.is_control() => current_command.push(c),
+                _ => {
+                    *key_counts.entry(key_name.to_string()).or_insert(0) += 1;
+                    match key_name {
+                        "Enter" => {
+                            if !current_command.trim().is_empty() {
+                                commands.push(CommandEntry::typed(current_command.trim().to_string()));
+                            }
                             current_command.clear();
                         }
-                    } else if let Some(raw_bytes) = event["raw_bytes"].as_array() {
-                        for byte_val in raw_bytes {
-                            if let Some(byte) = byte_val.as_u64() {
-                                let b = byte as u8;
-                                if b >= 32 && b < 127 && b != 127 {
-                                    current_command.push(b as char);
-                                } else if b == 127 || b == 8 {
-                                    current_command.pop();
-                                }
-                            }
+                        "Backspace" => {
+                            current_command.pop();
                         }
+                        _ => {}
                     }
                 }
             }
         }
+        // Commands reported by the shell itself are exact, so prefer them,
+        // but keep the typed lines it did not report
+        let mut command_check = None;
+        if let Some(recorded) = &self.data.commands {
+            if metadata.shell_integration {
+                let check = commands::compare(
+                    recorded,
+                    events,
+                    self.data.processes.as_deref().unwrap_or_default(),
+                );
+                let listed = |list: &[&CommandEvent], event: &CommandEvent| {
+                    list.iter().any(|&other| std::ptr::eq(other, event))
+                };
+                // The shell's commands are recorded as they finish and the
+                // typed ones at Enter, so put them back in the order they ran
+                let mut timeline: Vec<&CommandEvent> = recorded.iter().collect();
+                timeline.sort_by_key(|event| event.timestamp);
+                commands = timeline.into_iter()
+                    .filter_map(|event| match event.source {
+                        CommandSource::Shell if listed(&check.not_typed, event) => {
+                            Some(CommandEntry::recorded(event, Some("not typed")))
+                        }
+                        CommandSource::Shell => Some(CommandEntry::recorded(event, None)),
+                        CommandSource::Keystrokes if listed(&check.not_reported, event) => {
+                            Some(CommandEntry::recorded(event, Some("typed, not reported by the shell")))
+                        }
+                        CommandSource::Keystrokes => None,
+                    })
+                    .collect();
+                command_check = Some(check);
+            } else {
+                commands = recorded.iter().map(|event| CommandEntry::recorded(event, None)).collect();
+            }
+        }
+        
+        let processes = process_timeline(
+            self.data.processes.as_deref().unwrap_or_default(),
+            &metadata.flag_programs,
+        );
+        
+        let allowlist = Allowlist::parse(&metadata.network_allowlist)?;
+        let connections: Vec<ConnectionEntry> = self.data.connections.as_deref().unwrap_or_default()
+            .iter()
+            .map(|event| connection_entry(event, &allowlist))
+            .collect();
+        
+        let watched_files = history::by_path(self.data.file_history.as_deref().unwrap_or_default())
+            .into_iter()
+            .map(|(path, versions)| WatchedFileEntry {
+                path: path.to_string(),
+                versions: versions.len(),
+                changed: versions.iter().any(|version| version.kind != FileVersionKind::Initial),
+                last: versions.last().map(|&version| history::describe(version)).unwrap_or_default(),
+            })
+            .collect();
+        
+        let mut key_usage: Vec<(String, usize)> = key_counts.into_iter().collect();
+        key_usage.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
         
         // Detect suspicious activities
-        let suspicious_activities = self.detect_suspicious_activities(events)?;
+        let mut suspicious_activities = self.detect_suspicious_activities(events)?;
+        for paste in &pastes {
+            suspicious_activities.push(SuspiciousActivity {
+                timestamp: paste.timestamp.clone(),
+                description: if paste.blocked {
+                    format!("Pasted {} chars (blocked)", paste.length)
+                } else {
+                    format!("Pasted {} chars", paste.length)
+                },
+                severity: if paste.length > 100 { "HIGH".to_string() } else { "MEDIUM".to_string() },
+            });
+        }
+        
+        if let Some(check) = &command_check {
+            if let Some(first) = check.not_typed.first() {
+                suspicious_activities.push(SuspiciousActivity {
+                    timestamp: format_timestamp(first.timestamp),
+                    description: format!(
+                        "Shell reported {} without Enter being pressed, first `{}`; its markers may have been printed by a program",
+                        plural(check.not_typed.len(), "command"),
+                        first.command
+                    ),
+                    severity: "HIGH".to_string(),
+                });
+            }
+            if let Some(last_report) = check.stopped {
+                suspicious_activities.push(SuspiciousActivity {
+                    timestamp: format_timestamp(last_report.unwrap_or(start_time * 1000)),
+                    description: format!(
+                        "Shell stopped reporting commands{}; {} typed at the prompt were not reported",
+                        if last_report.is_some() { "" } else { " from the start" },
+                        plural(check.not_reported.len(), "command")
+                    ),
+                    severity: "HIGH".to_string(),
+                });
+            } else if let Some(first) = check.not_reported.first() {
+                suspicious_activities.push(SuspiciousActivity {
+                    timestamp: format_timestamp(first.timestamp),
+                    description: format!(
+                        "{} typed at the prompt not reported by the shell, first `{}`",
+                        plural(check.not_reported.len(), "command"),
+                        first.command
+                    ),
+                    severity: "MEDIUM".to_string(),
+                });
+            }
+        }
+        
+        for process in processes.iter().filter(|process| process.flagged) {
+            suspicious_activities.push(SuspiciousActivity {
+                timestamp: process.started.clone(),
+                description: format!("Flagged program started: {} (pid {})", process.command, process.pid),
+                severity: "HIGH".to_string(),
+            });
+        }
+        for connection in connections.iter().filter(|connection| !connection.inbound) {
+            suspicious_activities.push(SuspiciousActivity {
+                timestamp: connection.timestamp.clone(),
+                description: format!(
+                    "Network connection: {}{}",
+                    connection.describe(),
+                    if connection.allowed { " (allowed)" } else { "" }
+                ),
+                severity: if connection.allowed { "LOW".to_string() } else { "HIGH".to_string() },
+            });
+        }
+        // Only a recorder that finished looks for processes at the end
+        if !metadata.recovered {
+            for process in processes.iter().filter(|process| process.duration.is_none()) {
+                suspicious_activities.push(SuspiciousActivity {
+                    timestamp: process.started.clone(),
+                    description: format!(
+                        "Process still running when the session ended: {} (pid {})",
+                        process.command,
+                        process.pid
+                    ),
+                    severity: "MEDIUM".to_string(),
+                });
+            }
+        }
+        
+        // Without a signed config the time limit, paste policy and the rest
+        // came from the student's command line
+        if metadata.config_hash.is_none() {
+            suspicious_activities.push(SuspiciousActivity {
+                timestamp: format_timestamp(start_time * 1000),
+                description: "Session ran without a signed exam config".to_string(),
+                severity: "MEDIUM".to_string(),
+            });
+        }
+        
+        // Archives rebuilt by `exam-recorder recover` end where the journal
+        // did. The journal, chain key and checkpoints included, can be
+        // unlocked and rewritten on the student's machine
+        let recovered = metadata.recovered;
+        if recovered {
+            let stopped_at = metadata.recording_stopped_at
+                .unwrap_or(end_time * 1000);
+            suspicious_activities.insert(0, SuspiciousActivity {
+                timestamp: format_timestamp(stopped_at),
+                description: "Session recovered from journal; the journal could have been rewritten \
+                              on the student's machine, so nothing in it proves what happened".to_string(),
+                severity: "HIGH".to_string(),
+            });
+        }
+        
+        let end_reason = metadata.end_reason.map(|reason| {
+            let mut reason = end_reason_name(reason).to_string();
+            if let Some(signal) = &metadata.recorder_signal {
+                reason = format!("{} by {}", reason, signal);
+            }
+            if let Some(status) = metadata.exit_status {
+                format!("{} (exit status {})", reason, status)
+            } else if let Some(signal) = &metadata.exit_signal {
+                format!("{} (shell {})", reason, signal)
+            } else {
+                reason
+            }
+        });
+        if let Some(signal) = &metadata.recorder_signal {
+            suspicious_activities.insert(0, SuspiciousActivity {
+                timestamp: format_timestamp(end_time * 1000),
+                description: format!("Recorder interrupted by {}; session ended early", signal),
+                severity: "MEDIUM".to_string(),
+            });
+        }
         
         // Verify integrity
-        let integrity_passed = self.verify_integrity()?;
+        let chain = self.data.event_chain.as_ref();
+        if let Some(broken) = chain.and_then(|c| c.first_break.as_ref()) {
+            suspicious_activities.insert(0, SuspiciousActivity {
+                timestamp: format_timestamp(broken.timestamp),
+                description: format!("Event log altered at record #{}: {}", broken.seq + 1, broken.reason),
+                severity: "HIGH".to_string(),
+            });
+        }
         
         Ok(AnalysisReport {
+            exam_id,
+            student_id,
             username,
             hostname,
             machine_id,
             session_duration,
+            time_limit,
+            time_warnings,
+            recorded_program,
+            config_hash,
+            config_matches: None,
+            pastes_blocked,
+            collected_files,
+            watched_files,
             recorder_runs_before,
             total_keystrokes,
             enter_pressed,
             backspace_used,
+            delete_used,
+            navigation_keys,
+            key_usage,
             paste_events,
             total_pasted_chars,
+            longest_input_pause,
+            terminal_size,
+            output_dropped,
             commands,
+            processes,
+            connections,
+            pastes,
             suspicious_activities,
-            integrity_passed,
+            integrity_passed: self.data.integrity.passed(),
+            integrity: self.data.integrity.describe(),
+            event_chain: chain.map(|c| c.describe()),
+            event_chain_passed: chain.map(|c| c.passed()),
+            recovered,
+            end_reason,
         })
     }
     
-    fn detect_suspicious_activities(&self, events: &Value) -> Result<Vec<SuspiciousActivity>> {
+    fn detect_suspicious_activities(&self, events: &[KeystrokeEvent]) -> Result<Vec<SuspiciousActivity>> {
         let mut activities = Vec::new();
         
-        if let Some(events_array) = events.as_array() {
-            for event in events_array {
-                if let Some(is_paste) = event["is_paste"].as_bool() {
-                    if is_paste {
-                        let timestamp = event["timestamp"].as_u64().unwrap_or(0);
-                        let timestamp_str = format_timestamp(timestamp);
-                        let raw_bytes = event["raw_bytes"].as_array()
-                            .map(|arr| arr.len())
-                            .unwrap_or(0);
-                        
-                        activities.push(SuspiciousActivity {
-                            timestamp: timestamp_str,
-                            description: format!(
-                                "Detected paste burst ({} chars)",
-                                raw_bytes
-                            ),
-                            severity: if raw_bytes > 100 { "HIGH".to_string() } else { "MEDIUM".to_string() },
-                        });
-                    }
-                }
-            }
+        for (timestamp, chars) in paste_bursts(events) {
+            activities.push(SuspiciousActivity {
+                timestamp: format_timestamp(timestamp),
+                description: format!(
+                    "Detected paste burst ({} chars)",
+                    chars
+                ),
+                severity: if chars > 100 { "HIGH".to_string() } else { "MEDIUM".to_string() },
+            });
         }
         
         Ok(activities)
     }
-    
-    fn verify_integrity(&self) -> Result<bool> {
-        use sha2::{Sha256, Digest};
-        
-        // Recalculate hash from decrypted data
-        let mut hasher = Sha256::new();
-        hasher.update(self.data.events.to_string().as_bytes());
-        hasher.update(self.data.summary.to_string().as_bytes());
-        hasher.update(self.data.metadata.to_string().as_bytes());
-        hasher.update(self.data.terminal_output.as_bytes());
-        hasher.update(self.data.state_copy.to_string().as_bytes());
-        
-        let calculated = hex::encode(hasher.finalize());
-        Ok(calculated == self.data.integrity_hash)
+}
+
+/// Start and length in characters of each run of keystrokes typed too fast
+/// to be typed by hand.
+fn paste_bursts(events: &[KeystrokeEvent]) -> Vec<(u64, usize)> {
+    let mut bursts: Vec<(u64, usize)> = Vec::new();
+    let mut in_burst = false;
+    for event in events {
+        if !event.is_paste {
+            in_burst = false;
+            continue;
+        }
+        match bursts.last_mut() {
+            Some((_, chars)) if in_burst => *chars += event.inserted_chars(),
+            _ => bursts.push((event.timestamp, event.inserted_chars())),
+        }
+        in_burst = true;
+    }
+    bursts
+}
+
+fn end_reason_name(reason: EndReason) -> &'static str {
+    match reason {
+        EndReason::Logout => "logout",
+        EndReason::Killed => "killed",
+        EndReason::TimeLimit => "time limit",
+        EndReason::Interrupted => "interrupted",
+    }
+}
+
+fn connection_entry(event: &ConnectionEvent, allowlist: &Allowlist) -> ConnectionEntry {
+    let address: Option<IpAddr> = event.remote_address.parse().ok();
+    ConnectionEntry {
+        timestamp: format_timestamp(event.timestamp),
+        protocol: event.protocol.clone(),
+        remote: match address {
+            Some(address) if address.is_unspecified() => "any address (unconnected)".to_string(),
+            Some(address) => SocketAddr::new(address, event.remote_port).to_string(),
+            None => format!("{}:{}", event.remote_address, event.remote_port),
+        },
+        local_port: event.local_port,
+        inbound: event.inbound,
+        pid: event.pid,
+        command: event.argv.join(" "),
+        allowed: address.is_some_and(|address| address.is_loopback() || allowlist.allows(address)),
     }
 }
 
+/// Pairs each program started with the exit of its process, or with the
+/// next program the same process started.
+fn process_timeline(events: &[ProcessEvent], flag_programs: &[String]) -> Vec<ProcessEntry> {
+    let mut timeline: Vec<ProcessEntry> = Vec::new();
+    // Entry still running for each process, by pid and start time
+    let mut running: HashMap<(u32, u64), (usize, u64)> = HashMap::new();
+    for event in events {
+        let key = (event.pid, event.start_time);
+        let previous = running.remove(&key);
+        if let Some((index, started)) = previous {
+            timeline[index].duration = Some(format_elapsed(event.timestamp.saturating_sub(started)));
+        }
+        if event.kind == ProcessEventKind::Exit {
+            continue;
+        }
+
+        // A process that starts another program does so after it was seen
+        let started = if previous.is_some() { event.timestamp } else { event.start_time };
+        running.insert(key, (timeline.len(), started));
+        let command = match (event.argv.is_empty(), &event.exe) {
+            (true, Some(exe)) => exe.clone(),
+            _ => event.argv.join(" "),
+        };
+        let names = event.argv.first().into_iter().chain(&event.exe);
+        let flagged = names
+            .map(|path| path.rsplit('/').next().unwrap_or(path))
+            .any(|name| flag_programs.iter().any(|flag| flag == name));
+        timeline.push(ProcessEntry {
+            started: format_timestamp(started),
+            pid: event.pid,
+            ppid: event.ppid,
+            command,
+            exe: event.exe.clone(),
+            duration: None,
+            flagged,
+        });
+    }
+    timeline
+}
+
 fn format_duration(seconds: u64) -> String {
     let hours = seconds / 3600;
     let minutes = (seconds % 3600) / 60;
@@ -179,7 +733,20 @@ fn format_duration(seconds: u64) -> String {
     }
 }
 
-fn format_timestamp(timestamp_ms: u64) -> String {
+fn format_elapsed(ms: u64) -> String {
+    if ms < 60_000 {
+        format!("{:.1}s", ms as f64 / 1000.0)
+    } else {
+        format_duration(ms / 1000)
+    }
+}
+
+/// "1 command", "2 commands".
+fn plural(count: usize, noun: &str) -> String {
+    format!("{} {}{}", count, noun, if count == 1 { "" } else { "s" })
+}
+
+pub fn format_timestamp(timestamp_ms: u64) -> String {
     use chrono::DateTime;
     let timestamp_secs = timestamp_ms / 1000;
     if let Some(dt) = DateTime::from_timestamp(timestamp_secs as i64, 0) {
@@ -189,3 +756,70 @@ fn format_timestamp(timestamp_ms: u64) -> String {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(timestamp: u64, name: &str, raw_bytes: &[u8], is_paste: bool) -> KeystrokeEvent {
+        KeystrokeEvent {
+            timestamp,
+            key_code: raw_bytes[0] as u32,
+            key_name: name.to_string(),
+            raw_bytes: raw_bytes.to_vec(),
+            is_paste,
+        }
+    }
+
+    #[test]
+    fn paste_bursts_count_inserted_chars() {
+        let events = [
+            key(1_000, "l", b"l", false),
+            key(1_001, "s", b"s", true),
+            key(1_002, "Enter", b"\r", true),
+            key(1_003, "é", "é".as_bytes(), true),
+            key(2_000, "Enter", b"\r", false),
+            key(3_000, "Tab", b"\t", true),
+        ];
+        assert_eq!(paste_bursts(&events), vec![(1_001, 3), (3_000, 1)]);
+    }
+
+    fn process(timestamp: u64, kind: ProcessEventKind, pid: u32, start_time: u64, argv: &[&str]) -> ProcessEvent {
+        ProcessEvent {
+            timestamp,
+            kind,
+            pid,
+            ppid: 1,
+            start_time,
+            argv: argv.iter().map(|arg| arg.to_string()).collect(),
+            exe: None,
+        }
+    }
+
+    #[test]
+    fn process_timeline_pairs_exits_by_pid_and_start_time() {
+        use ProcessEventKind::{Exec, Exit};
+        let events = [
+            process(1_250, Exec, 100, 1_000, &["python3", "solve.py"]),
+            process(2_250, Exec, 200, 2_000, &["bash"]),
+            // The same process starts another program
+            process(4_000, Exec, 200, 2_000, &["/usr/bin/vim", "main.c"]),
+            // pid 100 was reused before its first process was seen to exit
+            process(6_250, Exec, 100, 6_000, &["firefox"]),
+            process(7_000, Exit, 200, 2_000, &[]),
+            process(8_500, Exit, 100, 6_000, &[]),
+        ];
+        let timeline = process_timeline(&events, &["vim".to_string()]);
+        let summary: Vec<(&str, Option<&str>, bool)> = timeline.iter()
+            .map(|entry| (entry.command.as_str(), entry.duration.as_deref(), entry.flagged))
+            .collect();
+        assert_eq!(summary, [
+            ("python3 solve.py", None, false),
+            ("bash", Some("2.0s"), false),
+            ("/usr/bin/vim main.c", Some("3.0s"), true),
+            ("firefox", Some("2.5s"), false),
+        ]);
+        assert_eq!(timeline[0].started, format_timestamp(1_000));
+        assert_eq!(timeline[2].started, format_timestamp(4_000));
+    }
+}
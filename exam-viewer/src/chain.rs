@@ -0,0 +1,518 @@
+use anyhow::{Context, Result};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::io::{self, BufRead, Read};
+
+use exam_format::archive::{self, ArchiveReader};
+use exam_format::chain::{checkpoint_message, next_head, ChainLink, ChainLog, Checkpoint};
+use exam_format::crypto::canonical_json;
+use exam_format::json::ArrayReader;
+use exam_format::Metadata;
+
+use crate::analyzer::format_timestamp;
+
+/// Result of checking an archive's event chain.
+pub struct ChainReport {
+    pub records: usize,
+    /// Checkpoints whose signature and head are valid.
+    pub checkpoints: usize,
+    /// Records after the last valid checkpoint. Only expected in recovered
+    /// sessions, whose recorder died before it could sign them.
+    pub unsigned: usize,
+    /// Set for archives rebuilt from a session journal. The journal, chain
+    /// key included, can be rewritten on the student's machine, so its
+    /// checkpoints show the chain is consistent, not that it is genuine.
+    pub from_journal: bool,
+    pub first_break: Option<ChainBreak>,
+}
+
+/// The first record that does not match the chain.
+pub struct ChainBreak {
+    /// Position of the record in the session (0-based).
+    pub seq: u64,
+    pub kind: String,
+    pub timestamp: u64,
+    pub reason: String,
+}
+
+impl ChainReport {
+    pub fn passed(&self) -> bool {
+        self.first_break.is_none()
+    }
+
+    /// One-line result, e.g. "VERIFIED (812 records, 3 signed checkpoints)".
+    pub fn describe(&self) -> String {
+        if let Some(broken) = &self.first_break {
+            return format!(
+                "BROKEN at record #{} ({} at {}): {}",
+                broken.seq + 1,
+                broken.kind,
+                format_timestamp(broken.timestamp),
+                broken.reason
+            );
+        }
+        if self.from_journal {
+            return format!("CONSISTENT ({} records, rebuilt from the session journal)", self.records);
+        }
+        let mut text = format!(
+            "VERIFIED ({} records, {} signed checkpoints)",
+            self.records,
+            self.checkpoints
+        );
+        if self.unsigned > 0 {
+            text.push_str(&format!(", last {} records unsigned", self.unsigned));
+        }
+        text
+    }
+}
+
+/// Kinds of records in the chain and the entries holding them, in the order
+/// records left over are reported.
+const RECORD_ENTRIES: [(&str, &str); 7] = [
+    ("keystroke", archive::EVENTS_ENTRY),
+    ("command", archive::COMMANDS_ENTRY),
+    ("paste", archive::PASTES_ENTRY),
+    ("process", archive::PROCESSES_ENTRY),
+    ("connection", archive::CONNECTIONS_ENTRY),
+    ("file_version", archive::FILE_HISTORY_ENTRY),
+    ("output", archive::TERMINAL_OUTPUT_ENTRY),
+];
+
+/// Checks every event, process, connection, file version and output chunk
+/// against the archive's event chain, and the chain against the recorder's
+/// signed checkpoints. Returns `None` for archives from recorders without an
+/// event chain.
+///
+/// Nothing is held whole: the chain is read once for its checkpoints, once
+/// to follow its links, and once more for each kind of record, alongside
+/// the entry holding them.
+pub fn verify(reader: &mut ArchiveReader, metadata: &Metadata) -> Result<Option<ChainReport>> {
+    // The checkpoints come after the links
+    let Some(checkpoints) = reader.read_entry(archive::EVENT_CHAIN_ENTRY, |chain| {
+        ChainLog::read_links(chain, |_| Ok(()))
+    })? else {
+        return Ok(None);
+    };
+
+    let mut walk = LinkWalk::new(&checkpoints, chain_public_key(metadata)?);
+    reader.read_entry(archive::EVENT_CHAIN_ENTRY, |chain| {
+        ChainLog::read_links(chain, |link| walk.step(link))
+    })?;
+    walk.check_checkpoints(true);
+
+    // Once one record is off, the ones after it no longer line up, so only
+    // the first mismatch counts
+    let mut mismatch: Option<ChainBreak> = None;
+    let mut leftover = None;
+    for (kind, entry) in RECORD_ENTRIES {
+        let check = reader.read_entries(archive::EVENT_CHAIN_ENTRY, entry, |chain, records| {
+            compare_records(kind, entry, chain, records)
+        })?.context("Missing event_chain.json.enc")?;
+        match check.mismatch {
+            Some(found) if mismatch.as_ref().is_none_or(|b| found.seq < b.seq) => mismatch = Some(found),
+            Some(_) => {}
+            None if check.leftover && leftover.is_none() => leftover = Some(kind),
+            None => {}
+        }
+    }
+
+    let mut first_break = match (walk.first_break, mismatch) {
+        (Some(order), Some(contents)) if contents.seq < order.seq => Some(contents),
+        (Some(order), _) => Some(order),
+        (None, contents) => contents,
+    };
+    if let (None, Some(kind)) = (&first_break, leftover) {
+        first_break = Some(ChainBreak {
+            seq: walk.index,
+            kind: kind.to_string(),
+            timestamp: walk.last_timestamp,
+            reason: format!("{} records were added that are not in the chain", kind),
+        });
+    }
+
+    // Checkpoints catch a chain that was rewritten to match edited records
+    if let Some(failed) = walk.failed {
+        // A break found inside the range pinpoints the record more exactly
+        let within = first_break.as_ref().is_some_and(|b| b.seq < failed.records);
+        if !within {
+            first_break = Some(ChainBreak {
+                seq: failed.seq,
+                kind: failed.start.as_ref().map(|(kind, _)| kind.clone()).unwrap_or_default(),
+                timestamp: failed.start.map_or(failed.timestamp, |(_, timestamp)| timestamp),
+                reason: format!(
+                    "records {}-{} do not match the checkpoint signed by the recorder",
+                    failed.seq + 1,
+                    failed.records
+                ),
+            });
+        }
+    }
+
+    Ok(Some(ChainReport {
+        records: walk.index as usize,
+        checkpoints: walk.valid,
+        unsigned: walk.index.saturating_sub(walk.signed) as usize,
+        from_journal: metadata.recovered,
+        first_break,
+    }))
+}
+
+/// Follows the chain's links in order, recomputing its head and checking
+/// each checkpoint once the records it covers have been passed.
+struct LinkWalk<'a> {
+    checkpoints: std::iter::Peekable<std::slice::Iter<'a, Checkpoint>>,
+    public_key: Option<VerifyingKey>,
+    /// Links walked so far.
+    index: u64,
+    head: [u8; 32],
+    last_timestamp: u64,
+    /// First link that does not follow the one before it.
+    first_break: Option<ChainBreak>,
+    valid: usize,
+    /// Records covered by the last valid checkpoint, and its head.
+    signed: u64,
+    signed_head: [u8; 32],
+    /// Kind and time of the first record after the last valid checkpoint.
+    start: Option<(String, u64)>,
+    failed: Option<FailedCheckpoint>,
+}
+
+/// The first checkpoint that does not match the chain.
+struct FailedCheckpoint {
+    /// First record it covers that no earlier checkpoint does.
+    seq: u64,
+    records: u64,
+    timestamp: u64,
+    start: Option<(String, u64)>,
+}
+
+impl<'a> LinkWalk<'a> {
+    fn new(checkpoints: &'a [Checkpoint], public_key: Option<VerifyingKey>) -> Self {
+        LinkWalk {
+            checkpoints: checkpoints.iter().peekable(),
+            public_key,
+            index: 0,
+            head: [0u8; 32],
+            last_timestamp: 0,
+            first_break: None,
+            valid: 0,
+            signed: 0,
+            signed_head: [0u8; 32],
+            start: None,
+            failed: None,
+        }
+    }
+
+    fn step(&mut self, link: ChainLink) -> Result<()> {
+        if self.first_break.is_none() && (link.seq != self.index || link.prev != hex::encode(self.head)) {
+            self.first_break = Some(ChainBreak {
+                seq: self.index,
+                kind: link.kind.clone(),
+                timestamp: link.timestamp,
+                reason: "record does not follow the one before it (records removed or reordered)".to_string(),
+            });
+        }
+        if self.index == self.signed {
+            self.start = Some((link.kind.clone(), link.timestamp));
+        }
+
+        let hash: [u8; 32] = hex::decode(&link.hash).ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .context("Event chain has an invalid record hash")?;
+        self.head = next_head(&self.head, self.index, &link.kind, &hash);
+        self.index += 1;
+        self.last_timestamp = link.timestamp;
+        self.check_checkpoints(false);
+        Ok(())
+    }
+
+    /// Checks the checkpoints covering the records walked so far, or, once
+    /// `ended`, the rest of them. Stops at the first that fails.
+    fn check_checkpoints(&mut self, ended: bool) {
+        while self.failed.is_none() {
+            let Some(&checkpoint) = self.checkpoints.peek() else {
+                return;
+            };
+            if checkpoint.records > self.index && !ended {
+                return;
+            }
+            self.checkpoints.next();
+
+            let head = match checkpoint.records {
+                0 => None,
+                records if records == self.index => Some(self.head),
+                records if records == self.signed => Some(self.signed_head),
+                _ => None,
+            };
+            let valid = match (head, &self.public_key) {
+                (Some(head), Some(key)) => {
+                    hex::encode(head) == checkpoint.head && verify_checkpoint(key, checkpoint, &head)
+                }
+                _ => false,
+            };
+            if !valid {
+                self.failed = Some(FailedCheckpoint {
+                    seq: self.signed,
+                    records: checkpoint.records,
+                    timestamp: checkpoint.timestamp,
+                    start: self.start.take(),
+                });
+                return;
+            }
+            self.valid += 1;
+            self.signed = checkpoint.records;
+            self.signed_head = self.head;
+        }
+    }
+}
+
+/// Result of checking one kind of record against its links.
+struct RecordCheck {
+    mismatch: Option<ChainBreak>,
+    /// Records were left over once every link had been matched.
+    leftover: bool,
+}
+
+/// Walks the links of `kind` in `chain` alongside the records in `entry`.
+fn compare_records(
+    kind: &str,
+    entry: &str,
+    chain: &mut dyn BufRead,
+    records: Option<&mut dyn BufRead>,
+) -> Result<RecordCheck> {
+    let mut records = match records {
+        Some(records) if kind == "output" => Records::Output(records),
+        Some(records) => Records::Json(ArrayReader::new(records)),
+        None => Records::Missing,
+    };
+    let mut index = 0;
+    let mut mismatch = None;
+    ChainLog::read_links(chain, |link| {
+        let seq = index;
+        index += 1;
+        if link.kind != kind || mismatch.is_some() {
+            return Ok(());
+        }
+        let reason = match records.next(link.len).with_context(|| format!("Failed to parse {}", entry))? {
+            Some(hash) if hex::encode(hash) == link.hash => return Ok(()),
+            Some(_) => "record was modified or removed",
+            None => "record is missing from the archive",
+        };
+        mismatch = Some(ChainBreak {
+            seq,
+            kind: link.kind,
+            timestamp: link.timestamp,
+            reason: reason.to_string(),
+        });
+        Ok(())
+    })?;
+
+    let leftover = mismatch.is_none() && records.any_left()?;
+    Ok(RecordCheck { mismatch, leftover })
+}
+
+/// Session key the checkpoints were signed with. It is only as good as the
+/// metadata holding it, which is encrypted under the archive's own data key,
+/// sealed to the instructor: an archive the recorder wrote cannot have its
+/// key swapped and its checkpoints re-signed on the student's machine.
+fn chain_public_key(metadata: &Metadata) -> Result<Option<VerifyingKey>> {
+    let Some(encoded) = &metadata.chain_key else {
+        return Ok(None);
+    };
+    let bytes: [u8; 32] = hex::decode(encoded).ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .context("Event chain key in metadata is invalid")?;
+    Ok(VerifyingKey::from_bytes(&bytes).ok())
+}
+
+fn verify_checkpoint(key: &VerifyingKey, checkpoint: &Checkpoint, head: &[u8; 32]) -> bool {
+    let Some(signature) = hex::decode(&checkpoint.signature).ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+    else {
+        return false;
+    };
+    let message = checkpoint_message(checkpoint.records, checkpoint.timestamp, head);
+    key.verify_strict(&message, &signature).is_ok()
+}
+
+/// The records of one kind, read as they are compared.
+enum Records<'a> {
+    /// Events, hashed as stored: what the recorder chained is exactly what
+    /// it wrote, whichever fields its format version had.
+    Json(ArrayReader<&'a mut dyn BufRead, Value>),
+    /// Terminal output, chained in chunks.
+    Output(&'a mut dyn BufRead),
+    Missing,
+}
+
+impl Records<'_> {
+    /// Hash of the next record, as long as `len` for output chunks, or
+    /// `None` if there is none.
+    fn next(&mut self, len: Option<usize>) -> Result<Option<[u8; 32]>> {
+        match self {
+            Records::Json(records) => match records.next().transpose()? {
+                Some(record) => Ok(Some(Sha256::digest(canonical_json(&record)?).into())),
+                None => Ok(None),
+            },
+            Records::Output(output) => {
+                let len = len.unwrap_or(0) as u64;
+                let mut hasher = Sha256::new();
+                if io::copy(&mut output.take(len), &mut hasher)? < len {
+                    return Ok(None);
+                }
+                Ok(Some(hasher.finalize().into()))
+            }
+            Records::Missing => Ok(None),
+        }
+    }
+
+    fn any_left(&mut self) -> Result<bool> {
+        match self {
+            Records::Json(records) => Ok(records.next().is_some()),
+            Records::Output(output) => Ok(!output.fill_buf()?.is_empty()),
+            Records::Missing => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+    use exam_format::crypto::{seal_key, DataKey};
+    use exam_format::{ArchiveKey, ArchiveWriter, KeystrokeEvent};
+    use std::io::Cursor;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    /// Keystrokes and output chunks, chained and signed the way the
+    /// recorder does it.
+    struct Session {
+        events: Vec<KeystrokeEvent>,
+        output: Vec<u8>,
+        links: Vec<ChainLink>,
+        checkpoints: Vec<Checkpoint>,
+        metadata: Metadata,
+    }
+
+    fn keystroke(timestamp: u64, key: &str) -> KeystrokeEvent {
+        KeystrokeEvent {
+            timestamp,
+            key_code: key.as_bytes()[0] as u32,
+            key_name: key.to_string(),
+            raw_bytes: key.as_bytes().to_vec(),
+            is_paste: false,
+        }
+    }
+
+    fn session() -> Session {
+        let mut session = Session {
+            events: vec![keystroke(1_000, "l"), keystroke(1_100, "s"), keystroke(1_200, "x")],
+            output: b"ls\r\nfile\r\n".to_vec(),
+            links: Vec::new(),
+            checkpoints: Vec::new(),
+            metadata: Metadata::new("student".into(), "lab-01".into(), "abc123".into(), 1),
+        };
+        rechain(&mut session, &SigningKey::from_bytes(&[7u8; 32]));
+        session
+    }
+
+    /// Chains the session's records in turns and signs the head.
+    fn rechain(session: &mut Session, key: &SigningKey) {
+        let records: [(&str, u64, Option<usize>, [u8; 32]); 5] = [
+            ("keystroke", 1_000, None, Sha256::digest(canonical_json(&session.events[0]).unwrap()).into()),
+            ("output", 1_010, Some(4), Sha256::digest(&session.output[..4]).into()),
+            ("keystroke", 1_100, None, Sha256::digest(canonical_json(&session.events[1]).unwrap()).into()),
+            ("output", 1_110, Some(6), Sha256::digest(&session.output[4..]).into()),
+            ("keystroke", 1_200, None, Sha256::digest(canonical_json(&session.events[2]).unwrap()).into()),
+        ];
+        let mut head = [0u8; 32];
+        session.links.clear();
+        for (seq, (kind, timestamp, len, hash)) in records.into_iter().enumerate() {
+            session.links.push(ChainLink {
+                seq: seq as u64,
+                kind: kind.to_string(),
+                timestamp,
+                len,
+                hash: hex::encode(hash),
+                prev: hex::encode(head),
+            });
+            head = next_head(&head, seq as u64, kind, &hash);
+        }
+        let signature = key.sign(&checkpoint_message(5, 1_200, &head));
+        session.checkpoints = vec![Checkpoint {
+            records: 5,
+            timestamp: 1_200,
+            head: hex::encode(head),
+            signature: hex::encode(signature.to_bytes()),
+        }];
+        session.metadata.chain_key = Some(hex::encode(SigningKey::from_bytes(&[7u8; 32]).verifying_key().as_bytes()));
+    }
+
+    fn check(session: &Session) -> ChainReport {
+        let secret = StaticSecret::from([5u8; 32]);
+        let data_key = DataKey::generate();
+        let spool_dir = std::env::temp_dir();
+        let mut writer = ArchiveWriter::new(&data_key, &spool_dir).unwrap();
+        writer.add_json(archive::EVENTS_ENTRY, &session.events).unwrap();
+        writer.add(archive::TERMINAL_OUTPUT_ENTRY, &session.output).unwrap();
+        let log = ChainLog { links: session.links.clone(), checkpoints: session.checkpoints.clone() };
+        writer.add_json(archive::EVENT_CHAIN_ENTRY, &log).unwrap();
+        let mut contents = Vec::new();
+        writer.finish("0.1.0", &seal_key(&data_key, &PublicKey::from(&secret)).unwrap(), &mut contents).unwrap();
+
+        let mut reader = ArchiveReader::open(Cursor::new(contents), &ArchiveKey::Private(secret)).unwrap();
+        verify(&mut reader, &session.metadata).unwrap().unwrap()
+    }
+
+    #[test]
+    fn genuine_chain_verifies() {
+        let report = check(&session());
+        assert!(report.passed(), "{}", report.describe());
+        assert_eq!((report.records, report.checkpoints, report.unsigned), (5, 1, 0));
+        assert!(report.describe().starts_with("VERIFIED"));
+
+        // A journal's checkpoints could have been re-signed with a new key
+        let mut recovered = session();
+        recovered.metadata.recovered = true;
+        let report = check(&recovered);
+        assert!(report.passed());
+        assert_eq!(report.describe(), "CONSISTENT (5 records, rebuilt from the session journal)");
+    }
+
+    #[test]
+    fn changed_and_added_records_are_found() {
+        let mut changed = session();
+        changed.events[1].key_name = "t".into();
+        let broken = check(&changed).first_break.unwrap();
+        assert_eq!((broken.seq, broken.kind.as_str()), (2, "keystroke"));
+        assert_eq!(broken.reason, "record was modified or removed");
+
+        let mut removed = session();
+        removed.output.truncate(8);
+        let broken = check(&removed).first_break.unwrap();
+        assert_eq!((broken.seq, broken.reason.as_str()), (3, "record is missing from the archive"));
+
+        let mut added = session();
+        added.events.push(keystroke(1_300, "y"));
+        let broken = check(&added).first_break.unwrap();
+        assert_eq!(broken.seq, 5);
+        assert_eq!(broken.reason, "keystroke records were added that are not in the chain");
+    }
+
+    #[test]
+    fn rewritten_chain_fails_its_checkpoint() {
+        let mut rewritten = session();
+        rewritten.events[1].key_name = "t".into();
+        rechain(&mut rewritten, &SigningKey::from_bytes(&[8u8; 32]));
+        let broken = check(&rewritten).first_break.unwrap();
+        assert_eq!(broken.seq, 0);
+        assert_eq!(broken.reason, "records 1-5 do not match the checkpoint signed by the recorder");
+
+        let mut reordered = session();
+        reordered.links.swap(0, 2);
+        let broken = check(&reordered).first_break.unwrap();
+        assert_eq!(broken.seq, 0);
+        assert!(broken.reason.starts_with("record does not follow"), "{}", broken.reason);
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashSet;
+
+use exam_format::{CommandEvent, CommandSource, KeystrokeEvent, ProcessEvent, ProcessEventKind};
+
+/// Longest gap between Enter and the shell reporting the command it ran.
+const REPORT_DELAY_MS: u64 = 2000;
+
+/// Slack for process start times, which are derived from the boot time in
+/// whole seconds.
+const START_SLACK_MS: u64 = 1000;
+
+/// Typed commands after the shell's last report before it counts as the
+/// shell having stopped reporting, rather than the odd line it did not run.
+const STOPPED_AFTER: usize = 3;
+
+/// Where the commands the shell reported and the commands rebuilt from
+/// keystrokes disagree. The shell's markers can be printed by any program,
+/// and its hooks can be removed, so neither list is taken on its own.
+pub struct CommandCheck<'a> {
+    /// Reported by the shell with no Enter pressed to run them.
+    pub not_typed: Vec<&'a CommandEvent>,
+    /// Typed at the shell prompt but never reported by the shell.
+    pub not_reported: Vec<&'a CommandEvent>,
+    /// Start of the shell's last reported command (`None` if it reported
+    /// none), when at least `STOPPED_AFTER` typed commands followed it
+    /// without being reported.
+    pub stopped: Option<Option<u64>>,
+}
+
+/// Compares the shell's commands in `commands` with the typed ones and the
+/// Enter presses in `events`. `processes` tells whether a command the shell
+/// never reported the end of was still running a program.
+pub fn compare<'a>(
+    commands: &'a [CommandEvent],
+    events: &[KeystrokeEvent],
+    processes: &[ProcessEvent],
+) -> CommandCheck<'a> {
+    let mut shell: Vec<&CommandEvent> = commands.iter()
+        .filter(|command| command.source == CommandSource::Shell)
+        .collect();
+    shell.sort_by_key(|command| command.timestamp);
+    let typed: Vec<&CommandEvent> = commands.iter()
+        .filter(|command| command.source == CommandSource::Keystrokes)
+        .collect();
+    let enters: Vec<u64> = events.iter()
+        .filter(|event| event.key_name == "Enter")
+        .map(|event| event.timestamp)
+        .collect();
+
+    // Each command the shell runs needs an Enter of its own. The line typed
+    // as the shell reports it is taken first, also when it was typed ahead
+    // while the previous command ran; lines recalled from history or
+    // completed with Tab only match the Enter pressed just before
+    let mut used = HashSet::new();
+    let mut unmatched = Vec::new();
+    for (i, command) in shell.iter().enumerate() {
+        let mut earliest = command.timestamp.saturating_sub(REPORT_DELAY_MS);
+        if i > 0 {
+            earliest = earliest.min(shell[i - 1].timestamp);
+        }
+        let line = typed.iter().find(|line| {
+            (earliest..=command.timestamp).contains(&line.timestamp)
+                && line.command == command.command.trim()
+                && !used.contains(&line.timestamp)
+        });
+        match line {
+            Some(line) => {
+                used.insert(line.timestamp);
+            }
+            None => unmatched.push(*command),
+        }
+    }
+    let mut not_typed = Vec::new();
+    for command in unmatched {
+        let earliest = command.timestamp.saturating_sub(REPORT_DELAY_MS);
+        let enter = enters.iter()
+            .rev()
+            .find(|&&enter| (earliest..=command.timestamp).contains(&enter) && !used.contains(&enter));
+        match enter {
+            Some(&enter) => {
+                used.insert(enter);
+            }
+            None => not_typed.push(command),
+        }
+    }
+
+    // Lines typed while a command ran were input to it, not to the shell.
+    // A start marker without an end may have been printed just before the
+    // hooks were removed, so such a command only runs while a program it
+    // started does
+    let running = |timestamp: u64| shell.iter().enumerate().any(|(i, command)| {
+        let Some(end) = command.end_timestamp else {
+            let until = shell.get(i + 1).map_or(u64::MAX, |next| next.timestamp);
+            return command.timestamp < timestamp
+                && timestamp <= until
+                && program_running(processes, command.timestamp, timestamp);
+        };
+        command.timestamp < timestamp && timestamp <= end
+    });
+    let not_reported: Vec<&CommandEvent> = typed.into_iter()
+        .filter(|command| !used.contains(&command.timestamp) && !running(command.timestamp))
+        .collect();
+
+    let last_report = shell.last().map(|command| command.timestamp);
+    let after_last = not_reported.iter()
+        .filter(|command| last_report.is_none_or(|last| command.timestamp > last))
+        .count();
+    let stopped = (after_last >= STOPPED_AFTER).then_some(last_report);
+
+    CommandCheck { not_typed, not_reported, stopped }
+}
+
+/// Whether a program started after `since` was still running at `at`.
+fn program_running(processes: &[ProcessEvent], since: u64, at: u64) -> bool {
+    processes.iter()
+        .filter(|event| event.kind == ProcessEventKind::Exec)
+        .filter(|event| event.start_time + START_SLACK_MS >= since && event.timestamp <= at)
+        .any(|started| !processes.iter().any(|event| {
+            event.kind == ProcessEventKind::Exit
+                && event.pid == started.pid
+                && event.start_time == started.start_time
+                && event.timestamp < at
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(timestamp: u64, text: &str, source: CommandSource, end: Option<u64>) -> CommandEvent {
+        CommandEvent {
+            timestamp,
+            command: text.to_string(),
+            source,
+            end_timestamp: end,
+            exit_status: end.map(|_| 0),
+            cwd: None,
+        }
+    }
+
+    fn enter(timestamp: u64) -> KeystrokeEvent {
+        KeystrokeEvent {
+            timestamp,
+            key_code: 13,
+            key_name: "Enter".to_string(),
+            raw_bytes: vec![13],
+            is_paste: false,
+        }
+    }
+
+    fn process(timestamp: u64, kind: ProcessEventKind, pid: u32, start_time: u64) -> ProcessEvent {
+        ProcessEvent {
+            timestamp,
+            kind,
+            pid,
+            ppid: 100,
+            start_time,
+            argv: Vec::new(),
+            exe: None,
+        }
+    }
+
+    #[test]
+    fn matching_lists_agree() {
+        let commands = [
+            command(1_000, "sleep 5", CommandSource::Keystrokes, None),
+            command(1_010, "sleep 5", CommandSource::Shell, Some(6_000)),
+            // Typed ahead while sleep ran, run once it finished
+            command(3_000, "ls", CommandSource::Keystrokes, None),
+            command(6_010, "ls", CommandSource::Shell, Some(6_020)),
+            // Recalled from history, so no typed text
+            command(9_010, "ls", CommandSource::Shell, Some(9_020)),
+            // Lines typed into a program are not for the shell
+            command(10_000, "python3", CommandSource::Keystrokes, None),
+            command(10_010, "python3", CommandSource::Shell, Some(12_000)),
+            command(11_000, "x = 1", CommandSource::Keystrokes, None),
+            command(12_500, "echo done", CommandSource::Keystrokes, None),
+            command(12_510, "echo done", CommandSource::Shell, Some(12_520)),
+        ];
+        let enters: Vec<_> = [1_000, 3_000, 9_000, 10_000, 11_000, 12_500].into_iter().map(enter).collect();
+        let check = compare(&commands, &enters, &[]);
+        assert!(check.not_typed.is_empty());
+        assert!(check.not_reported.is_empty());
+        assert_eq!(check.stopped, None);
+    }
+
+    #[test]
+    fn printed_markers_are_not_typed() {
+        // printf '\e]133;C;cmdline_url=make\a' reports a second command
+        // while printf itself runs
+        let commands = [
+            command(1_000, "printf ...", CommandSource::Keystrokes, None),
+            command(1_010, "printf ...", CommandSource::Shell, None),
+            command(1_020, "make", CommandSource::Shell, Some(1_030)),
+        ];
+        let check = compare(&commands, &[enter(1_000)], &[]);
+        assert_eq!(check.not_typed.len(), 1);
+        assert_eq!(check.not_typed[0].command, "make");
+    }
+
+    #[test]
+    fn unreported_commands_and_stopped_markers_are_found() {
+        let mut commands = vec![
+            command(1_000, "unset PROMPT_COMMAND", CommandSource::Keystrokes, None),
+            command(1_010, "unset PROMPT_COMMAND", CommandSource::Shell, Some(1_020)),
+        ];
+        let mut enters = vec![enter(1_000)];
+        for (i, text) in ["curl ...", "gcc -o a a.c", "./a"].iter().enumerate() {
+            let timestamp = 5_000 + i as u64 * 1_000;
+            commands.push(command(timestamp, text, CommandSource::Keystrokes, None));
+            enters.push(enter(timestamp));
+        }
+        let check = compare(&commands, &enters, &[]);
+        assert_eq!(check.not_reported.len(), 3);
+        assert_eq!(check.stopped, Some(Some(1_010)));
+
+        let check = compare(&commands[..3], &enters[..2], &[]);
+        assert_eq!(check.not_reported.len(), 1);
+        assert_eq!(check.stopped, None);
+
+        // Removing the hooks leaves the last command without an end, which
+        // only hides what is typed while it runs a program
+        commands[1].end_timestamp = None;
+        let check = compare(&commands, &enters, &[]);
+        assert_eq!(check.not_reported.len(), 3);
+        let python = [
+            process(1_200, ProcessEventKind::Exec, 4242, 1_100),
+            process(6_500, ProcessEventKind::Exit, 4242, 1_100),
+        ];
+        let check = compare(&commands, &enters, &python);
+        assert_eq!(check.not_reported.len(), 1);
+        assert_eq!(check.not_reported[0].command, "./a");
+    }
+}
@@ -1,8 +1,26 @@
 use anyhow::{Context, Result};
+use std::collections::HashSet;
 use std::fs;
+use std::io::{self, BufReader, Write};
 use std::path::Path;
 
+use exam_format::archive::{self, ArchiveKey, ArchiveReader};
+use exam_format::crypto::password_key;
+use exam_format::{ArchiveHeader, ArchiveWriter, IntegrityCheck, KdfParams};
+
 use crate::analyzer::DecryptedData;
+use crate::chain::{self, ChainReport};
+use crate::history::FileHistory;
+use crate::model;
+use crate::stream::{StreamSummary, TerminalStream};
+
+/// Result of `Decryptor::verify_chain`.
+pub struct ChainCheck {
+    /// `None` for archives from recorders without an event chain.
+    pub chain: Option<ChainReport>,
+    /// Hash of the signed exam config the session ran under.
+    pub config_hash: Option<String>,
+}
 
 pub struct Decryptor {
     zip_path: std::path::PathBuf,
@@ -16,149 +34,301 @@ impl Decryptor {
         }
         Ok(Decryptor { zip_path: path })
     }
-    
-    pub fn decrypt(&self, password: &str) -> Result<DecryptedData> {
-        // Read encrypted ZIP
-        let encrypted_zip = fs::read(&self.zip_path)
+
+    /// The archive's plaintext header: format and recorder version, and how
+    /// its key is recovered.
+    pub fn header(&self) -> Result<ArchiveHeader> {
+        let mut file = fs::File::open(&self.zip_path)
             .context("Failed to read ZIP file")?;
-        
-        // Decrypt ZIP
-        let zip_data = decrypt_file(&encrypted_zip, password)
-            .context("Failed to decrypt ZIP file")?;
-        
-        // Extract files from ZIP
-        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(zip_data))
-            .context("Failed to open ZIP archive")?;
-        
-        let mut events_json = None;
-        let mut summary_json = None;
-        let mut metadata_json = None;
-        let mut terminal_output = None;
-        let mut state_copy_json = None;
-        let mut integrity_hash = None;
-        
-        for i in 0..archive.len() {
-            let mut file = archive.by_index(i)
-                .context("Failed to read ZIP entry")?;
-            
-            let mut contents = Vec::new();
-            std::io::copy(&mut file, &mut contents)
-                .context("Failed to read ZIP file contents")?;
-            
-            let name = file.name().to_string();
-            
-            match name.as_str() {
-                "events.json.enc" => {
-                    let decrypted = decrypt_file(&contents, password)?;
-                    events_json = Some(serde_json::from_slice(&decrypted)?);
-                }
-                "summary.json.enc" => {
-                    let decrypted = decrypt_file(&contents, password)?;
-                    summary_json = Some(serde_json::from_slice(&decrypted)?);
-                }
-                "metadata.json.enc" => {
-                    let decrypted = decrypt_file(&contents, password)?;
-                    metadata_json = Some(serde_json::from_slice(&decrypted)?);
-                }
-                "terminal_output.log.enc" => {
-                    let decrypted = decrypt_file(&contents, password)?;
-                    terminal_output = Some(String::from_utf8_lossy(&decrypted).to_string());
-                }
-                "state_copy.json.enc" => {
-                    let decrypted = decrypt_file(&contents, password)?;
-                    state_copy_json = Some(serde_json::from_slice(&decrypted)?);
-                }
-                "integrity.sha256" => {
-                    integrity_hash = Some(String::from_utf8_lossy(&contents).trim().to_string());
-                }
-                _ => {}
-            }
+        ArchiveHeader::read(&mut file)
+    }
+
+    /// Opens the archive for streaming: the file is read as entries are,
+    /// never loaded whole.
+    fn open(&self, key: &ArchiveKey) -> Result<ArchiveReader> {
+        let file = fs::File::open(&self.zip_path)
+            .context("Failed to read ZIP file")?;
+        ArchiveReader::open(BufReader::new(file), key)
+    }
+
+    pub fn decrypt(&self, key: &ArchiveKey) -> Result<DecryptedData> {
+        let mut reader = self.open(key)?;
+        let integrity = reader.check_integrity()?;
+
+        // A tampered entry usually fails to decrypt; say why
+        if !integrity.passed() {
+            return decrypt_entries(&mut reader, integrity.clone()).with_context(|| {
+                format!("Integrity check FAILED - TAMPERED: {}", integrity.describe())
+            });
         }
-        
-        Ok(DecryptedData {
-            events: events_json.context("Missing events.json.enc")?,
-            summary: summary_json.context("Missing summary.json.enc")?,
-            metadata: metadata_json.context("Missing metadata.json.enc")?,
-            terminal_output: terminal_output.context("Missing terminal_output.log.enc")?,
-            state_copy: state_copy_json.context("Missing state_copy.json.enc")?,
-            integrity_hash: integrity_hash.context("Missing integrity.sha256")?,
+        decrypt_entries(&mut reader, integrity)
+    }
+
+    pub fn verify_integrity(&self, key: &ArchiveKey) -> Result<IntegrityCheck> {
+        self.open(key)?.check_integrity()
+    }
+
+    /// Checks the archive's event chain, reading only the metadata and the
+    /// chained entries, as they are compared. Refuses archives that fail
+    /// their integrity check.
+    pub fn verify_chain(&self, key: &ArchiveKey) -> Result<ChainCheck> {
+        let (mut reader, integrity) = self.open_checked(key)?;
+        let metadata = model::read_metadata(&mut reader, integrity.format_version)?;
+        Ok(ChainCheck {
+            chain: chain::verify(&mut reader, &metadata)?,
+            config_hash: metadata.config_hash,
         })
     }
-    
-    pub fn verify_integrity(&self, password: &str) -> Result<bool> {
-        // Read encrypted ZIP
-        let encrypted_zip = fs::read(&self.zip_path)
-            .context("Failed to read ZIP file")?;
-        
-        // Decrypt ZIP
-        let zip_data = decrypt_file(&encrypted_zip, password)
-            .context("Failed to decrypt ZIP file")?;
-        
-        // Extract files and verify
-        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(zip_data))
-            .context("Failed to open ZIP archive")?;
-        
-        let mut integrity_hash = None;
-        let mut encrypted_files = Vec::new();
-        
-        for i in 0..archive.len() {
-            let mut file = archive.by_index(i)?;
-            let mut contents = Vec::new();
-            std::io::copy(&mut file, &mut contents)?;
-            
-            let name = file.name().to_string();
-            
-            if name == "integrity.sha256" {
-                integrity_hash = Some(String::from_utf8_lossy(&contents).trim().to_string());
-            } else if name.ends_with(".enc") {
-                encrypted_files.push((name, contents));
+
+    /// Reads the archive's timed terminal stream, if the recorder timed its
+    /// output. Refuses archives that fail their integrity check.
+    pub fn terminal_stream(&self, key: &ArchiveKey) -> Result<Option<TerminalStream>> {
+        let (mut reader, _) = self.open_checked(key)?;
+        reader.read_entry(archive::TERMINAL_STREAM_ENTRY, TerminalStream::read)
+    }
+
+    /// Writes the files collected by the recorder to `dir`, named after the
+    /// original files, decrypting each straight to disk. Refuses archives
+    /// that fail their integrity check.
+    pub fn export_collected_files(&self, key: &ArchiveKey, dir: &Path) -> Result<usize> {
+        let (mut reader, integrity) = self.open_checked(key)?;
+        let Some(collected) = model::read(&mut reader, integrity.format_version)?.collected_files else {
+            return Ok(0);
+        };
+        fs::create_dir_all(dir)?;
+
+        let mut used = HashSet::new();
+        let mut written = 0;
+        for (index, file) in collected.iter().enumerate() {
+            let Some(entry) = &file.entry else {
+                continue;
+            };
+            let path = Path::new(&file.path);
+            let mut name = path.file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| format!("file-{}", index));
+            if !used.insert(name.clone()) {
+                name = format!("{}-{}", index, name);
+            }
+            let target = dir.join(&name);
+            let copied = reader.read_entry(entry, |contents| {
+                let mut out = fs::File::create(&target)?;
+                io::copy(contents, &mut out)?;
+                Ok(())
+            });
+            match copied {
+                Ok(Some(())) => written += 1,
+                Ok(None) => {}
+                Err(e) => {
+                    let _ = fs::remove_file(&target);
+                    return Err(e);
+                }
             }
         }
-        
-        let expected_hash = integrity_hash.context("Missing integrity.sha256")?;
-        
-        // Calculate hash of all encrypted files
-        use sha2::{Sha256, Digest};
-        let mut hasher = Sha256::new();
-        for (_, data) in &encrypted_files {
-            hasher.update(data);
+        Ok(written)
+    }
+
+    /// Opens the archive's file history, reading versions of files only as
+    /// they are asked for. Refuses archives that fail their integrity check.
+    pub fn file_history(&self, key: &ArchiveKey) -> Result<FileHistory> {
+        let (mut reader, integrity) = self.open_checked(key)?;
+        let metadata = model::read_metadata(&mut reader, integrity.format_version)?;
+        FileHistory::new(reader, metadata.watch_file_limit_kib)
+    }
+
+    /// Re-encrypts the archive under `password`, with a fresh salt and the
+    /// given Argon2id parameters, writing the copy to `out` one entry at a
+    /// time. Refuses archives that fail their integrity check, so a copy
+    /// never launders tampering into a clean manifest.
+    pub fn protect(&self, key: &ArchiveKey, password: &str, params: &KdfParams, out: impl Write) -> Result<()> {
+        let (mut reader, _) = self.open_checked(key)?;
+
+        let (data_key, envelope) = password_key(password, params)?;
+        let spool_dir = std::env::temp_dir();
+        let mut writer = ArchiveWriter::new(&data_key, &spool_dir)?;
+        // The manifest (or legacy checksum) is rebuilt for the new entries
+        let names: Vec<String> = reader.names()
+            .filter(|name| name.ends_with(".enc"))
+            .map(String::from)
+            .collect();
+        for name in names {
+            reader.read_entry(&name, |contents| writer.add_reader(name.as_str(), contents))?;
         }
-        let calculated_hash = hex::encode(hasher.finalize());
-        
-        Ok(calculated_hash == expected_hash)
+        // The copy keeps naming the recorder that made the recording
+        let recorder_version = reader.header().recorder_version.as_deref().unwrap_or("unknown");
+        writer.finish(recorder_version, &envelope, out)
     }
-}
 
-fn decrypt_file(encrypted: &[u8], password: &str) -> Result<Vec<u8>> {
-    use aes_gcm::{
-        aead::{Aead, KeyInit},
-        Aes256Gcm, Nonce,
-    };
-    
-    if encrypted.len() < 12 {
-        anyhow::bail!("Invalid encrypted data length");
-    }
-    
-    let key = derive_key_from_password(password)?;
-    let cipher = Aes256Gcm::new(&key);
-    
-    let nonce = Nonce::from_slice(&encrypted[..12]);
-    let ciphertext = &encrypted[12..];
-    
-    let plaintext = cipher.decrypt(nonce, ciphertext)
-        .map_err(|e| anyhow::anyhow!("Decryption failed: {}", e))?;
-    
-    Ok(plaintext)
+    /// Opens the archive, refusing it if it fails its integrity check.
+    fn open_checked(&self, key: &ArchiveKey) -> Result<(ArchiveReader, IntegrityCheck)> {
+        let mut reader = self.open(key)?;
+        let integrity = reader.check_integrity()?;
+        if !integrity.passed() {
+            anyhow::bail!("Integrity check FAILED - TAMPERED: {}", integrity.describe());
+        }
+        Ok((reader, integrity))
+    }
 }
 
-fn derive_key_from_password(password: &str) -> Result<aes_gcm::Key<aes_gcm::Aes256Gcm>> {
-    use pbkdf2::pbkdf2_hmac;
-    use sha2::Sha256;
-    
-    let salt = b"exam-recorder-suite-salt-v1";
-    let mut key = [0u8; 32];
-    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, 100000, &mut key);
-    
-    Ok(*aes_gcm::Key::<aes_gcm::Aes256Gcm>::from_slice(&key))
+/// Reads what the report is made from: the keystrokes, deserialized
+/// straight from the archive, the other JSON entries, a summary of the
+/// terminal stream, and the result of checking the event chain.
+fn decrypt_entries(reader: &mut ArchiveReader, integrity: IntegrityCheck) -> Result<DecryptedData> {
+    let entries = model::read(reader, integrity.format_version)?;
+    let events = reader.read_json(archive::EVENTS_ENTRY)?
+        .context("Missing events.json.enc")?;
+    let terminal_stream = reader.read_entry(archive::TERMINAL_STREAM_ENTRY, StreamSummary::read)?;
+    let event_chain = chain::verify(reader, &entries.metadata)?;
+
+    Ok(DecryptedData {
+        events,
+        summary: entries.summary,
+        metadata: entries.metadata,
+        terminal_stream,
+        pastes: entries.pastes,
+        commands: entries.commands,
+        processes: entries.processes,
+        connections: entries.connections,
+        file_history: entries.file_history,
+        event_chain,
+        collected_files: entries.collected_files,
+        integrity,
+    })
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exam_format::crypto::{seal_key, DataKey};
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    /// Writes a sealed archive the way the recorder does, letting `add`
+    /// add entries and change the metadata first and `tamper` change the
+    /// file afterwards.
+    fn write_archive(
+        name: &str,
+        secret: &StaticSecret,
+        add: impl FnOnce(&mut ArchiveWriter, &mut exam_format::Metadata),
+        tamper: impl FnOnce(&mut Vec<u8>),
+    ) -> std::path::PathBuf {
+        let data_key = DataKey::generate();
+        let spool_dir = std::env::temp_dir();
+        let mut writer = ArchiveWriter::new(&data_key, &spool_dir).unwrap();
+        let summary = exam_format::SessionSummary {
+            total_keystrokes: 0,
+            enter_pressed: 0,
+            backspace_used: 0,
+            delete_used: 0,
+            navigation_keys: 0,
+            paste_events: 0,
+            total_pasted_chars: 0,
+            commands_executed: 0,
+        };
+        let mut metadata = exam_format::Metadata::new("student".into(), "lab-01".into(), "abc123".into(), 1);
+        add(&mut writer, &mut metadata);
+        writer.add(archive::EVENTS_ENTRY, b"[]").unwrap();
+        writer.add_json(archive::SUMMARY_ENTRY, &summary).unwrap();
+        writer.add_json(archive::METADATA_ENTRY, &metadata).unwrap();
+        writer.add(archive::TERMINAL_OUTPUT_ENTRY, b"$ ls\r\n").unwrap();
+        writer.add(archive::STATE_COPY_ENTRY, br#"{"run_counter":1}"#).unwrap();
+        let mut contents = Vec::new();
+        writer
+            .finish("0.1.0", &seal_key(&data_key, &PublicKey::from(secret)).unwrap(), &mut contents)
+            .unwrap();
+        tamper(&mut contents);
+
+        let path = std::env::temp_dir().join(format!("exam-viewer-test-{}-{}.zip", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn genuine_archive_passes_open_and_verify() {
+        let secret = StaticSecret::from([5u8; 32]);
+        let path = write_archive("genuine", &secret, |_, _| {}, |_| {});
+        let decryptor = Decryptor::new(&path).unwrap();
+        let key = ArchiveKey::Private(secret);
+
+        let check = decryptor.verify_integrity(&key).unwrap();
+        assert!(check.passed(), "{}", check.describe());
+        let data = decryptor.decrypt(&key).unwrap();
+        assert!(data.integrity.passed());
+        assert_eq!(data.metadata.username, "student");
+        let check = decryptor.verify_chain(&key).unwrap();
+        assert!(check.chain.is_none());
+        assert_eq!(check.config_hash, None);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn protected_copy_opens_with_password_only() {
+        let secret = StaticSecret::from([5u8; 32]);
+        let path = write_archive("protect", &secret, |_, _| {}, |_| {});
+        let params = KdfParams { memory_kib: 64, iterations: 1, parallelism: 1 };
+        let copy_path = path.with_extension("protected.zip");
+        Decryptor::new(&path).unwrap()
+            .protect(&ArchiveKey::Private(secret), "hunter2", &params, fs::File::create(&copy_path).unwrap())
+            .unwrap();
+
+        let decryptor = Decryptor::new(&copy_path).unwrap();
+        assert!(matches!(decryptor.header().unwrap().key, exam_format::KeyInfo::Password { .. }));
+        let data = decryptor.decrypt(&ArchiveKey::Password("hunter2".into())).unwrap();
+        assert!(data.integrity.passed());
+        assert_eq!(data.metadata.username, "student");
+        assert!(decryptor.decrypt(&ArchiveKey::Password("hunter3".into())).is_err());
+        fs::remove_file(path).unwrap();
+        fs::remove_file(copy_path).unwrap();
+    }
+
+    #[test]
+    fn one_bit_change_fails_to_open() {
+        let secret = StaticSecret::from([5u8; 32]);
+        let path = write_archive("tampered", &secret, |_, _| {}, |contents| {
+            let last = contents.len() - 1;
+            contents[last] ^= 1;
+        });
+        let decryptor = Decryptor::new(&path).unwrap();
+        let key = ArchiveKey::Private(secret);
+
+        assert!(decryptor.verify_integrity(&key).is_err());
+        assert!(decryptor.decrypt(&key).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_contents_are_read_up_to_the_recorder_limit() {
+        use exam_format::{FileVersion, FileVersionKind};
+        use flate2::{write::GzEncoder, Compression};
+        use sha2::{Digest, Sha256};
+
+        let secret = StaticSecret::from([5u8; 32]);
+        let version = |index: usize, path: &str, contents: &[u8]| FileVersion {
+            timestamp: 1000,
+            path: path.into(),
+            kind: FileVersionKind::Initial,
+            size: contents.len() as u64,
+            sha256: Some(hex::encode(Sha256::digest(contents))),
+            entry: Some(archive::file_version_entry(index)),
+            skipped: None,
+        };
+        let (fits, bomb) = (vec![b'a'; 1024], vec![0u8; 1024 * 1024]);
+        let path = write_archive("history", &secret, |writer, metadata| {
+            metadata.watch_dir = Some("/home/student/exam".into());
+            metadata.watch_file_limit_kib = Some(1);
+            for (index, contents) in [&fits, &bomb].into_iter().enumerate() {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(contents).unwrap();
+                writer.add(archive::file_version_entry(index), &encoder.finish().unwrap()).unwrap();
+            }
+            writer.add_json(archive::FILE_HISTORY_ENTRY, &[version(0, "fits.txt", &fits), version(1, "bomb.txt", &bomb)]).unwrap();
+        }, |_| {});
+        let mut history = Decryptor::new(&path).unwrap().file_history(&ArchiveKey::Private(secret)).unwrap();
+
+        let versions = history.versions_of("fits.txt").unwrap();
+        assert_eq!(history.contents(&versions[0]).unwrap(), fits);
+        let versions = history.versions_of("bomb.txt").unwrap();
+        let error = history.contents(&versions[0]).unwrap_err().to_string();
+        assert!(error.contains("more than the recorder's limit of 1024 bytes"), "{}", error);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,48 @@
+use anyhow::{Context, Result};
+use ed25519_dalek::SigningKey;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+use exam_format::{ExamConfig, SignedConfig};
+
+use crate::network::Allowlist;
+
+/// Signs the draft config at `draft_path` and returns the signed file's
+/// contents and the config hash recorded in archives made with it. Drafts
+/// are checked against the settings the recorder accepts first, so a typo
+/// cannot produce a config the recorder refuses.
+pub fn sign(draft_path: &Path, key: &SigningKey) -> Result<(String, String)> {
+    let data = fs::read(draft_path)
+        .with_context(|| format!("Failed to read exam config: {}", draft_path.display()))?;
+    let config: Value = serde_json::from_slice(&data)
+        .with_context(|| format!("Exam config is not valid JSON: {}", draft_path.display()))?;
+    let checked: ExamConfig = serde_json::from_value(config.clone())
+        .with_context(|| format!("Invalid exam config: {}", draft_path.display()))?;
+    if let (Some(start), Some(end)) = (checked.not_before, checked.not_after) {
+        if end <= start {
+            anyhow::bail!("not_after must be later than not_before");
+        }
+    }
+    if let Some(key) = &checked.public_key {
+        let valid = hex::decode(key.trim()).map(|bytes| bytes.len() == 32).unwrap_or(false);
+        if !valid {
+            anyhow::bail!("public_key must be a 32-byte hex key from `exam-viewer keygen`");
+        }
+    }
+    Allowlist::parse(&checked.network_allowlist)?;
+
+    let signed = SignedConfig::sign(config, key)?;
+    let contents = serde_json::to_string_pretty(&signed)? + "\n";
+    Ok((contents, signed.hash()?))
+}
+
+/// Hash of a signed exam config, as recorded in the metadata of sessions
+/// that ran under it.
+pub fn config_hash(path: &Path) -> Result<String> {
+    let data = fs::read(path)
+        .with_context(|| format!("Failed to read exam config: {}", path.display()))?;
+    SignedConfig::parse(&data)
+        .with_context(|| format!("Not a signed exam config: {}", path.display()))?
+        .hash()
+}
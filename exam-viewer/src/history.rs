@@ -0,0 +1,254 @@
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+use similar::TextDiff;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use exam_format::archive::{self, ArchiveReader};
+use exam_format::{ArchiveError, FileVersion, FileVersionKind};
+
+use crate::analyzer::format_timestamp;
+
+/// An archive's file history: every version the recorder took of the files
+/// in the exam's watched directory. Contents are decrypted only when asked
+/// for.
+pub struct FileHistory {
+    reader: ArchiveReader,
+    versions: Vec<FileVersion>,
+    /// The recorder's per-file limit, in KiB, from the metadata.
+    file_limit_kib: Option<u64>,
+}
+
+/// Files written by `FileHistory::extract`, and the ones it could not write.
+pub struct Extracted {
+    pub written: usize,
+    /// Files whose last version has no stored contents, with the reason.
+    pub missing: Vec<(String, String)>,
+}
+
+impl FileHistory {
+    pub fn new(mut reader: ArchiveReader, file_limit_kib: Option<u64>) -> Result<Self> {
+        let versions = reader.read_json(archive::FILE_HISTORY_ENTRY)?.context(
+            "Archive has no file history (the exam config set no watch_dir, or it was recorded by an older recorder)",
+        )?;
+        Ok(FileHistory { reader, versions, file_limit_kib })
+    }
+
+    /// Every file's versions, oldest first, by path.
+    pub fn files(&self) -> BTreeMap<&str, Vec<&FileVersion>> {
+        by_path(&self.versions)
+    }
+
+    /// Versions of `path`, oldest first.
+    pub fn versions_of(&self, path: &str) -> Result<Vec<FileVersion>> {
+        let versions: Vec<FileVersion> = self.versions.iter()
+            .filter(|version| version.path == path)
+            .cloned()
+            .collect();
+        if versions.is_empty() {
+            anyhow::bail!("No versions of {} were recorded", path);
+        }
+        Ok(versions)
+    }
+
+    /// Decompressed contents of `version`, checked against the hash the
+    /// recorder took. Deleted files are empty; versions whose contents were
+    /// not stored, or that decompress to more than the recorder would have
+    /// stored, are an error.
+    pub fn contents(&mut self, version: &FileVersion) -> Result<Vec<u8>> {
+        if version.kind == FileVersionKind::Deleted {
+            return Ok(Vec::new());
+        }
+        let Some(entry) = &version.entry else {
+            anyhow::bail!(
+                "Contents of {} at {} were not stored: {}",
+                version.path,
+                format_timestamp(version.timestamp),
+                version.skipped.as_deref().unwrap_or("unknown reason")
+            );
+        };
+        // A small entry can inflate to any size, so it is read no further
+        // than the recorder's limit
+        let limit = self.file_limit_kib.map_or(version.size, |kib| kib * 1024);
+        let contents = self.reader.read_entry(entry, |compressed| {
+            let mut contents = Vec::new();
+            GzDecoder::new(compressed).take(limit + 1).read_to_end(&mut contents)?;
+            Ok(contents)
+        })?.with_context(|| format!("Missing {}", entry))?;
+        if contents.len() as u64 > limit {
+            return Err(ArchiveError::CorruptedEntry {
+                entry: entry.clone(),
+                reason: format!("decompresses to more than the recorder's limit of {} bytes", limit),
+            }.into());
+        }
+        if version.sha256.as_deref() != Some(hex::encode(Sha256::digest(&contents)).as_str()) {
+            return Err(ArchiveError::CorruptedEntry {
+                entry: entry.clone(),
+                reason: "contents do not match the recorded hash".into(),
+            }.into());
+        }
+        Ok(contents)
+    }
+
+    /// Writes every file as it was when the session ended into `dir`, at
+    /// its path in the watched directory. Deleted files are left out.
+    /// Existing files and symlinks under `dir` are never written through:
+    /// extracting over them is an error.
+    pub fn extract(&mut self, dir: &Path) -> Result<Extracted> {
+        let last: Vec<FileVersion> = self.files()
+            .into_values()
+            .filter_map(|versions| versions.last().map(|&version| version.clone()))
+            .filter(|version| version.kind != FileVersionKind::Deleted)
+            .collect();
+
+        std::fs::create_dir_all(dir)?;
+        let mut extracted = Extracted { written: 0, missing: Vec::new() };
+        for version in last {
+            if let Some(reason) = &version.skipped {
+                extracted.missing.push((version.path.clone(), reason.clone()));
+                continue;
+            }
+            let relative = relative_path(&version.path)?;
+            let contents = self.contents(&version)?;
+            write_new(dir, &relative, &contents)?;
+            extracted.written += 1;
+        }
+        Ok(extracted)
+    }
+}
+
+/// Versions in `versions`, oldest first, by path.
+pub fn by_path(versions: &[FileVersion]) -> BTreeMap<&str, Vec<&FileVersion>> {
+    let mut files: BTreeMap<&str, Vec<&FileVersion>> = BTreeMap::new();
+    for version in versions {
+        files.entry(version.path.as_str()).or_default().push(version);
+    }
+    files
+}
+
+/// One version for listings, e.g. "modified at 10:41:07, 1532 bytes".
+pub fn describe(version: &FileVersion) -> String {
+    let kind = match version.kind {
+        FileVersionKind::Initial => "initial",
+        FileVersionKind::Created => "created",
+        FileVersionKind::Modified => "modified",
+        FileVersionKind::Deleted => return format!("deleted at {}", format_timestamp(version.timestamp)),
+    };
+    let mut text = format!("{} at {}, {} bytes", kind, format_timestamp(version.timestamp), version.size);
+    if let Some(reason) = &version.skipped {
+        text.push_str(&format!(" (contents not stored: {})", reason));
+    }
+    text
+}
+
+/// Unified diff from `old` to `new`, or a note if either is binary.
+pub fn unified_diff(old: &[u8], new: &[u8], old_label: &str, new_label: &str) -> String {
+    if old == new {
+        return String::new();
+    }
+    if is_binary(old) || is_binary(new) {
+        return format!("Binary files {} and {} differ\n", old_label, new_label);
+    }
+    let (old, new) = (String::from_utf8_lossy(old), String::from_utf8_lossy(new));
+    TextDiff::from_lines(old.as_ref(), new.as_ref())
+        .unified_diff()
+        .context_radius(3)
+        .header(old_label, new_label)
+        .to_string()
+}
+
+/// Same test as git: a NUL byte near the start.
+fn is_binary(contents: &[u8]) -> bool {
+    contents.iter().take(8000).any(|&byte| byte == 0)
+}
+
+/// `path` from the archive as a path relative to the extraction directory,
+/// refusing anything that would leave it.
+fn relative_path(path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    if path.is_empty() || !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+        anyhow::bail!("Refusing to extract {}: not a path inside the watched directory", path);
+    }
+    Ok(relative.to_path_buf())
+}
+
+/// Writes `contents` to `relative` under `dir`, creating its directories.
+fn write_new(dir: &Path, relative: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = relative.parent() {
+        create_dirs(dir, parent)?;
+    }
+    let path = dir.join(relative);
+    // create_new fails on anything already at the path, dangling symlinks
+    // included, so a planted link cannot redirect the write
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(contents))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Creates `relative`'s directories under `dir` one at a time, refusing to
+/// descend through a symlink or anything else that is not a directory.
+fn create_dirs(dir: &Path, relative: &Path) -> Result<()> {
+    let mut path = dir.to_path_buf();
+    for component in relative.components() {
+        path.push(component);
+        match std::fs::symlink_metadata(&path) {
+            Ok(info) if info.is_dir() => {}
+            Ok(_) => anyhow::bail!("Refusing to extract into {}: not a directory", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                std::fs::create_dir(&path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?;
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to inspect {}", path.display())),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_text_and_refuses_paths_outside_the_directory() {
+        let diff = unified_diff(b"a\nb\nc\n", b"a\nB\nc\n", "main.c #1", "main.c #2");
+        assert!(diff.starts_with("--- main.c #1\n+++ main.c #2\n"), "{}", diff);
+        assert!(diff.contains("-b\n+B\n"), "{}", diff);
+        assert_eq!(unified_diff(b"same", b"same", "a", "b"), "");
+        assert_eq!(unified_diff(b"\0\x01", b"\0\x02", "a", "b"), "Binary files a and b differ\n");
+
+        assert!(relative_path("src/main.c").is_ok());
+        assert!(relative_path("../etc/passwd").is_err());
+        assert!(relative_path("/etc/passwd").is_err());
+        assert!(relative_path("").is_err());
+    }
+
+    #[test]
+    fn extraction_does_not_follow_symlinks() {
+        let dir = std::env::temp_dir().join(format!("exam-viewer-test-{}-extract", std::process::id()));
+        let outside = dir.with_extension("outside");
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&outside);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("target"), b"untouched").unwrap();
+        std::os::unix::fs::symlink(outside.join("target"), dir.join("main.c")).unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("src")).unwrap();
+
+        write_new(&dir, Path::new("lib/util.c"), b"new").unwrap();
+        assert_eq!(std::fs::read(dir.join("lib/util.c")).unwrap(), b"new");
+        assert!(write_new(&dir, Path::new("lib/util.c"), b"again").is_err());
+        assert!(write_new(&dir, Path::new("main.c"), b"planted").is_err());
+        assert!(write_new(&dir, Path::new("src/target"), b"planted").is_err());
+        assert!(write_new(&dir, Path::new("src/new.c"), b"planted").is_err());
+        assert_eq!(std::fs::read(outside.join("target")).unwrap(), b"untouched");
+        assert!(!outside.join("new.c").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::{Context, Result};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use std::fs;
+use std::path::Path;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub fn generate_keypair(private_path: &Path) -> Result<PublicKey> {
+    let secret = StaticSecret::random_from_rng(rand::thread_rng());
+    let public = PublicKey::from(&secret);
+
+    write_keypair(private_path, &secret.to_bytes(), public.as_bytes())?;
+    Ok(public)
+}
+
+/// Generates an Ed25519 key pair for signing exam configs.
+pub fn generate_signing_key(private_path: &Path) -> Result<VerifyingKey> {
+    let signing_key = SigningKey::generate(&mut rand::thread_rng());
+    let public = signing_key.verifying_key();
+
+    write_keypair(private_path, &signing_key.to_bytes(), public.as_bytes())?;
+    Ok(public)
+}
+
+pub fn load_signing_key(path: &Path) -> Result<SigningKey> {
+    let encoded = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read signing key: {}", path.display()))?;
+    let bytes = hex::decode(encoded.trim())
+        .context("Signing key is not valid hex")?;
+    let bytes: [u8; 32] = bytes.try_into()
+        .map_err(|_| anyhow::anyhow!("Signing key must be 32 bytes"))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// Writes a private key readable only by its owner, and the public key
+/// next to it with a .pub extension.
+fn write_keypair(private_path: &Path, private: &[u8], public: &[u8]) -> Result<()> {
+    if private_path.exists() {
+        anyhow::bail!("Refusing to overwrite existing key: {}", private_path.display());
+    }
+
+    fs::write(private_path, format!("{}\n", hex::encode(private)))
+        .context("Failed to write private key")?;
+
+    // Set restrictive permissions
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mut perms = fs::metadata(private_path)?.permissions();
+        perms.set_mode(0o600);
+        fs::set_permissions(private_path, perms)?;
+    }
+
+    let public_path = private_path.with_extension("pub");
+    fs::write(&public_path, format!("{}\n", hex::encode(public)))
+        .context("Failed to write public key")?;
+
+    Ok(())
+}
+
+pub fn load_private_key(path: &Path) -> Result<StaticSecret> {
+    let encoded = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read private key: {}", path.display()))?;
+    let bytes = hex::decode(encoded.trim())
+        .context("Private key is not valid hex")?;
+    let bytes: [u8; 32] = bytes.try_into()
+        .map_err(|_| anyhow::anyhow!("Private key must be 32 bytes"))?;
+    Ok(StaticSecret::from(bytes))
+}
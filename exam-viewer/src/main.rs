@@ -1,14 +1,28 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use colored::Colorize;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use exam_format::{ArchiveError, ArchiveKey, KdfParams, KeyInfo};
 
 mod decryptor;
 mod analyzer;
 mod reporter;
+mod keys;
+mod stream;
+mod replay;
+mod exam_config;
+mod chain;
+mod model;
+mod network;
+mod history;
+mod commands;
 
 use decryptor::Decryptor;
-use analyzer::Analyzer;
+use analyzer::{AnalysisReport, Analyzer};
 use reporter::Reporter;
+use replay::Player;
 
 fn main() {
     let args = Args::parse();
@@ -19,7 +33,27 @@ fn main() {
     
     if let Err(e) = run_command(args) {
         eprintln!("Error: {}", e);
-        std::process::exit(1);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+/// Exit codes that tell scripts why an archive could not be read; anything
+/// else exits with 1 (2 is taken by usage errors).
+const EXIT_NOT_AN_ARCHIVE: i32 = 3;
+const EXIT_UNSUPPORTED_VERSION: i32 = 4;
+const EXIT_WRONG_KEY: i32 = 5;
+const EXIT_TRUNCATED: i32 = 6;
+const EXIT_CORRUPTED: i32 = 7;
+
+fn exit_code(error: &anyhow::Error) -> i32 {
+    let archive_error = error.chain().find_map(|cause| cause.downcast_ref::<ArchiveError>());
+    match archive_error {
+        Some(ArchiveError::NotAnArchive(_)) => EXIT_NOT_AN_ARCHIVE,
+        Some(ArchiveError::UnsupportedVersion(_)) => EXIT_UNSUPPORTED_VERSION,
+        Some(ArchiveError::WrongKey(_)) => EXIT_WRONG_KEY,
+        Some(ArchiveError::Truncated { .. }) => EXIT_TRUNCATED,
+        Some(ArchiveError::Corrupted(_) | ArchiveError::CorruptedEntry { .. }) => EXIT_CORRUPTED,
+        None => 1,
     }
 }
 
@@ -27,6 +61,12 @@ fn main() {
 #[command(name = "exam-viewer")]
 #[command(about = "Instructor-side decrypter, analyzer, and log viewer")]
 struct Args {
+    /// Instructor private key file (from `exam-viewer keygen`)
+    #[arg(long, global = true)]
+    key: Option<PathBuf>,
+    /// Signed exam config the session is expected to have run under
+    #[arg(long, global = true, value_name = "FILE")]
+    exam_config: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -59,22 +99,136 @@ enum Commands {
         markdown: Option<PathBuf>,
         #[arg(long)]
         json: Option<PathBuf>,
+        /// Directory to write the files collected by the exam config to
+        #[arg(long, value_name = "DIR")]
+        files: Option<PathBuf>,
+    },
+    /// Replay the recorded terminal session with its original timing
+    Replay {
+        /// Path to the encrypted ZIP file
+        file: PathBuf,
+        /// Playback speed (0.5 to 16)
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+        /// Shorten pauses longer than this many seconds
+        #[arg(long)]
+        idle_limit: Option<f64>,
+        /// Start at the Nth command
+        #[arg(long)]
+        command: Option<usize>,
     },
+    /// List the recorded versions of the files in the exam's watched directory
+    History {
+        /// Path to the encrypted ZIP file
+        file: PathBuf,
+        /// File to list the versions of, relative to the watched directory (default: every file)
+        path: Option<String>,
+    },
+    /// Show how a file in the watched directory changed between two versions
+    Diff {
+        /// Path to the encrypted ZIP file
+        file: PathBuf,
+        /// File relative to the watched directory
+        path: String,
+        /// Version to compare from, numbered as `history` lists them; 0 is an empty file (default: the one before --to)
+        #[arg(long)]
+        from: Option<usize>,
+        /// Version to compare to (default: the last)
+        #[arg(long)]
+        to: Option<usize>,
+    },
+    /// Write the files of the watched directory as they were when the session ended
+    Extract {
+        /// Path to the encrypted ZIP file
+        file: PathBuf,
+        /// Directory to write the files to
+        #[arg(long, value_name = "DIR")]
+        out: PathBuf,
+    },
+    /// Write a password-protected copy of an exam log
+    Protect {
+        /// Path to the encrypted ZIP file
+        file: PathBuf,
+        /// Copy to write (default: <file>-protected.zip)
+        #[arg(long)]
+        out: Option<PathBuf>,
+        /// Argon2id memory cost in MiB
+        #[arg(long, default_value_t = 64)]
+        kdf_memory: u32,
+        /// Argon2id passes over memory
+        #[arg(long, default_value_t = 3)]
+        kdf_iterations: u32,
+        /// Argon2id lanes
+        #[arg(long, default_value_t = 1)]
+        kdf_parallelism: u32,
+    },
+    /// Generate an instructor key pair for sealing exam archives
+    Keygen {
+        /// Path of the private key file; the public key is written next to it with a .pub extension
+        #[arg(long, default_value = "instructor.key")]
+        out: PathBuf,
+    },
+    /// Generate a key pair for signing exam configs
+    ConfigKeygen {
+        /// Path of the private key file; the public key is written next to it with a .pub extension
+        #[arg(long, default_value = "config-signing.key")]
+        out: PathBuf,
+    },
+    /// Sign an exam config for distribution to students
+    SignConfig {
+        /// Exam config draft (JSON)
+        file: PathBuf,
+        /// Private key from `exam-viewer config-keygen`
+        #[arg(long, default_value = "config-signing.key")]
+        signing_key: PathBuf,
+        /// Signed config to write (default: <file>.signed.json)
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+}
+
+fn archive_key(decryptor: &Decryptor, key_path: Option<&Path>) -> Result<ArchiveKey> {
+    if let KeyInfo::Sealed { key_id } = decryptor.header()?.key {
+        let Some(key_path) = key_path else {
+            return Err(ArchiveError::WrongKey(format!(
+                "archive is sealed to instructor key {}; pass --key <private key file>",
+                hex::encode(key_id)
+            )).into());
+        };
+        return Ok(ArchiveKey::Private(keys::load_private_key(key_path)?));
+    }
+    
+    // Protected copies, and archives from older recorders that were
+    // encrypted with the instructor password
+    let password = rpassword::prompt_password("Enter decryption password: ")?;
+    Ok(ArchiveKey::Password(password))
+}
+
+/// Compares the session's config hash with the expected signed config.
+fn check_exam_config(report: &mut AnalysisReport, config_path: Option<&Path>) -> Result<()> {
+    if let Some(path) = config_path {
+        let expected = exam_config::config_hash(path)?;
+        report.config_matches = Some(report.config_hash.as_deref() == Some(expected.as_str()));
+    }
+    Ok(())
 }
 
 fn run_command(args: Args) -> Result<()> {
+    let key_path = args.key;
+    let config_path = args.exam_config;
     match args.command {
         Commands::Open { file } => {
             println!("Decrypting archive...");
             let decryptor = Decryptor::new(&file)?;
-            let password = rpassword::prompt_password("Enter decryption password: ")?;
+            let key = archive_key(&decryptor, key_path.as_deref())?;
             
             println!("Verifying integrity...");
-            let data = decryptor.decrypt(&password)?;
+            let data = decryptor.decrypt(&key)?;
             
             println!("Generating session report...");
             let analyzer = Analyzer::new(data);
-            let report = analyzer.analyze()?;
+            let mut report = analyzer.analyze()?;
+            check_exam_config(&mut report, config_path.as_deref())?;
             
             let reporter = Reporter::new();
             reporter.print_full_report(&report)?;
@@ -84,11 +238,12 @@ fn run_command(args: Args) -> Result<()> {
         }
         Commands::Summary { file } => {
             let decryptor = Decryptor::new(&file)?;
-            let password = rpassword::prompt_password("Enter decryption password: ")?;
-            let data = decryptor.decrypt(&password)?;
+            let key = archive_key(&decryptor, key_path.as_deref())?;
+            let data = decryptor.decrypt(&key)?;
             
             let analyzer = Analyzer::new(data);
-            let report = analyzer.analyze()?;
+            let mut report = analyzer.analyze()?;
+            check_exam_config(&mut report, config_path.as_deref())?;
             
             let reporter = Reporter::new();
             reporter.print_summary(&report)?;
@@ -97,27 +252,57 @@ fn run_command(args: Args) -> Result<()> {
         }
         Commands::Verify { file } => {
             let decryptor = Decryptor::new(&file)?;
-            let password = rpassword::prompt_password("Enter decryption password: ")?;
+            println!("Archive: {}", decryptor.header()?.describe());
+            let key = archive_key(&decryptor, key_path.as_deref())?;
             
-            match decryptor.verify_integrity(&password) {
-                Ok(true) => {
-                    println!("✓ Integrity check: PASSED");
-                    Ok(())
+            let integrity = decryptor.verify_integrity(&key)?;
+            if integrity.passed() {
+                println!("✓ Integrity check: PASSED ({})", integrity.describe());
+            } else {
+                println!("✗ Integrity check: FAILED - File may have been tampered with!");
+                for problem in &integrity.problems {
+                    println!("    {}", problem);
                 }
-                Ok(false) => {
-                    println!("✗ Integrity check: FAILED - File may have been tampered with!");
+                std::process::exit(EXIT_CORRUPTED)
+            }
+            
+            let check = decryptor.verify_chain(&key)?;
+            match &check.chain {
+                Some(chain) if chain.passed() => println!("✓ Event chain: {}", chain.describe()),
+                Some(chain) => {
+                    println!("✗ Event chain: {}", chain.describe());
                     std::process::exit(1)
                 }
-                Err(e) => Err(e),
+                None => println!("- Event chain: not recorded (archive from an older recorder)"),
             }
+            
+            if let Some(path) = config_path {
+                let expected = exam_config::config_hash(&path)?;
+                if check.config_hash.as_deref() == Some(expected.as_str()) {
+                    println!("✓ Exam config: MATCHES {}", path.display());
+                } else {
+                    println!(
+                        "✗ Exam config: DOES NOT MATCH - session ran under {}",
+                        check.config_hash.as_deref().unwrap_or("no signed config")
+                    );
+                    std::process::exit(1)
+                }
+            }
+            Ok(())
         }
-        Commands::Export { file, pdf, markdown, json } => {
+        Commands::Export { file, pdf, markdown, json, files } => {
             let decryptor = Decryptor::new(&file)?;
-            let password = rpassword::prompt_password("Enter decryption password: ")?;
-            let data = decryptor.decrypt(&password)?;
+            let key = archive_key(&decryptor, key_path.as_deref())?;
+            if let Some(dir) = files {
+                let count = decryptor.export_collected_files(&key, &dir)?;
+                println!("{} collected files written to: {}", count, dir.display());
+            }
+            
+            let data = decryptor.decrypt(&key)?;
             
             let analyzer = Analyzer::new(data);
-            let report = analyzer.analyze()?;
+            let mut report = analyzer.analyze()?;
+            check_exam_config(&mut report, config_path.as_deref())?;
             
             let reporter = Reporter::new();
             
@@ -136,6 +321,179 @@ fn run_command(args: Args) -> Result<()> {
             
             Ok(())
         }
+        Commands::Replay { file, speed, idle_limit, command } => {
+            let decryptor = Decryptor::new(&file)?;
+            let key = archive_key(&decryptor, key_path.as_deref())?;
+            let stream = decryptor.terminal_stream(&key)?.ok_or_else(|| anyhow::anyhow!(
+                "Archive has no timed terminal stream (recorded by an older recorder)"
+            ))?;
+            
+            let mut player = Player::new(&stream, speed, idle_limit)?;
+            println!(
+                "Replaying session ({} commands, recorded at {}x{})...",
+                player.command_count(),
+                stream.header.width,
+                stream.header.height
+            );
+            player.play(command)?;
+            
+            println!("Replay finished.");
+            Ok(())
+        }
+        Commands::History { file, path } => {
+            let decryptor = Decryptor::new(&file)?;
+            let key = archive_key(&decryptor, key_path.as_deref())?;
+            let history = decryptor.file_history(&key)?;
+            
+            let Some(path) = path else {
+                let files = history.files();
+                println!("{} files in the watched directory:", files.len());
+                for (path, versions) in files {
+                    let last = versions.last().map(|&version| history::describe(version)).unwrap_or_default();
+                    let plural = if versions.len() == 1 { "" } else { "s" };
+                    println!("  {}  {}", path, format!("({} version{}, last {})", versions.len(), plural, last).dimmed());
+                }
+                return Ok(());
+            };
+            println!("Versions of {}:", path);
+            for (index, version) in history.versions_of(&path)?.iter().enumerate() {
+                let hash = version.sha256.as_deref()
+                    .map(|hash| format!("  sha256 {}", &hash[..hash.len().min(12)]))
+                    .unwrap_or_default();
+                println!("  #{:<3} {}{}", index + 1, history::describe(version), hash.dimmed());
+            }
+            Ok(())
+        }
+        Commands::Diff { file, path, from, to } => {
+            let decryptor = Decryptor::new(&file)?;
+            let key = archive_key(&decryptor, key_path.as_deref())?;
+            let mut history = decryptor.file_history(&key)?;
+            
+            let versions = history.versions_of(&path)?;
+            let to = to.unwrap_or(versions.len());
+            let from = from.unwrap_or(to.saturating_sub(1));
+            if to == 0 || to > versions.len() || from > versions.len() {
+                anyhow::bail!("{} has versions 1 to {}", path, versions.len());
+            }
+            let old = match from {
+                0 => Vec::new(),
+                n => history.contents(&versions[n - 1])?,
+            };
+            let new = history.contents(&versions[to - 1])?;
+            let label = |n: usize| match n {
+                0 => format!("{} (empty)", path),
+                n => format!("{} #{} ({})", path, n, analyzer::format_timestamp(versions[n - 1].timestamp)),
+            };
+            
+            let diff = history::unified_diff(&old, &new, &label(from), &label(to));
+            if diff.is_empty() {
+                println!("No changes between #{} and #{}", from, to);
+            }
+            for line in diff.lines() {
+                if line.starts_with("---") || line.starts_with("+++") {
+                    println!("{}", line.bold());
+                } else if line.starts_with("@@") {
+                    println!("{}", line.cyan());
+                } else if line.starts_with('+') {
+                    println!("{}", line.green());
+                } else if line.starts_with('-') {
+                    println!("{}", line.red());
+                } else {
+                    println!("{}", line);
+                }
+            }
+            Ok(())
+        }
+        Commands::Extract { file, out } => {
+            let decryptor = Decryptor::new(&file)?;
+            let key = archive_key(&decryptor, key_path.as_deref())?;
+            let mut history = decryptor.file_history(&key)?;
+            
+            let extracted = history.extract(&out)?;
+            println!("{} files written to: {}", extracted.written, out.display());
+            for (path, reason) in &extracted.missing {
+                println!("  {} not written: its contents were not stored ({})", path, reason);
+            }
+            Ok(())
+        }
+        Commands::Protect { file, out, kdf_memory, kdf_iterations, kdf_parallelism } => {
+            let decryptor = Decryptor::new(&file)?;
+            let key = archive_key(&decryptor, key_path.as_deref())?;
+            let params = KdfParams {
+                memory_kib: kdf_memory.saturating_mul(1024),
+                iterations: kdf_iterations,
+                parallelism: kdf_parallelism,
+            };
+            
+            let out = out.unwrap_or_else(|| {
+                let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+                file.with_file_name(format!("{}-protected.zip", stem))
+            });
+            if out == file {
+                anyhow::bail!("Refusing to overwrite the original archive; pass a different --out");
+            }
+            if out.exists() {
+                anyhow::bail!("{} already exists; pass a different --out", out.display());
+            }
+            
+            let password = rpassword::prompt_password("New password: ")?;
+            if password.is_empty() {
+                anyhow::bail!("Password must not be empty");
+            }
+            if rpassword::prompt_password("Repeat new password: ")? != password {
+                anyhow::bail!("Passwords do not match");
+            }
+            
+            // Never replaces an existing file, and only the owner can read the copy
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&out)
+                .with_context(|| format!("Failed to create {}", out.display()))?;
+            if let Err(e) = decryptor.protect(&key, &password, &params, &file) {
+                drop(file);
+                let _ = std::fs::remove_file(&out);
+                return Err(e);
+            }
+            println!("Protected copy written to: {}", out.display());
+            println!("Key derivation: {}", params);
+            Ok(())
+        }
+        Commands::Keygen { out } => {
+            let public = keys::generate_keypair(&out)?;
+            println!("Private key written to: {}", out.display());
+            println!("Public key written to:  {}", out.with_extension("pub").display());
+            println!();
+            println!("Public key: {}", hex::encode(public.as_bytes()));
+            println!("Key ID:     {}", hex::encode(exam_format::crypto::key_id(&public)));
+            println!();
+            println!("Build the recorder with EXAM_INSTRUCTOR_PUBLIC_KEY set to this key; keep the");
+            println!("private key secret.");
+            Ok(())
+        }
+        Commands::ConfigKeygen { out } => {
+            let public = keys::generate_signing_key(&out)?;
+            println!("Signing key written to: {}", out.display());
+            println!("Public key written to:  {}", out.with_extension("pub").display());
+            println!();
+            println!("Public key: {}", hex::encode(public.as_bytes()));
+            println!();
+            println!("Build the recorder with EXAM_CONFIG_PUBLIC_KEY set to this key; keep the");
+            println!("signing key secret.");
+            Ok(())
+        }
+        Commands::SignConfig { file, signing_key, out } => {
+            let key = keys::load_signing_key(&signing_key)?;
+            let (signed, hash) = exam_config::sign(&file, &key)?;
+            let out = out.unwrap_or_else(|| file.with_extension("signed.json"));
+            if out == file {
+                anyhow::bail!("Refusing to overwrite the config draft; pass a different --out");
+            }
+            std::fs::write(&out, signed)?;
+            println!("Signed exam config written to: {}", out.display());
+            println!("Config hash: {}", hash);
+            Ok(())
+        }
     }
 }
-
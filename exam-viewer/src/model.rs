@@ -0,0 +1,402 @@
+//! Typed contents of an archive's JSON entries. Entries are read as stored,
+//! upgraded from the archive's format version to the current layout by the
+//! migrations below, and only then deserialized, so a field that is missing
+//! from the current layout is reported rather than quietly defaulted. The
+//! keystrokes and the event chain, the largest entries, are never migrated:
+//! the analyzer reads the keystrokes itself and the chain is checked as it
+//! is read.
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use exam_format::archive::{self, ArchiveReader};
+use exam_format::events::is_navigation_key;
+use exam_format::json::ArrayReader;
+use exam_format::{
+    ArchiveError, CollectedFile, CommandEvent, ConnectionEvent, FileVersion, Metadata, PasteEvent,
+    ProcessEvent, SessionSummary, ARCHIVE_FORMAT_VERSION,
+};
+
+/// JSON entries as stored in the archive.
+pub struct RawEntries {
+    /// Navigation keys among the recorded keystrokes, only counted for
+    /// format 1 archives, whose summary lacks them.
+    pub navigation_keys: usize,
+    pub summary: Value,
+    pub metadata: Value,
+    pub pastes: Option<Value>,
+    pub commands: Option<Value>,
+    pub processes: Option<Value>,
+    pub connections: Option<Value>,
+    pub file_history: Option<Value>,
+    pub collected_files: Option<Value>,
+}
+
+/// JSON entries in the current layout.
+pub struct Entries {
+    pub summary: SessionSummary,
+    pub metadata: Metadata,
+    /// Only present in archives from recorders that track bracketed paste
+    pub pastes: Option<Vec<PasteEvent>>,
+    /// Older archives only let commands be rebuilt from keystrokes
+    pub commands: Option<Vec<CommandEvent>>,
+    /// Only present in archives from recorders that follow processes
+    pub processes: Option<Vec<ProcessEvent>>,
+    /// Only present in archives from recorders that sample connections
+    pub connections: Option<Vec<ConnectionEvent>>,
+    /// Only present in archives from recorders that version files
+    pub file_history: Option<Vec<FileVersion>>,
+    /// Only present when the exam config asked for files
+    pub collected_files: Option<Vec<CollectedFile>>,
+}
+
+/// Upgrades entries written in one format version to the next.
+type Migration = fn(&mut RawEntries) -> Result<()>;
+
+/// The migration out of each older format version, oldest first.
+const MIGRATIONS: [(u32, Migration); 7] = [
+    (1, from_v1),
+    (2, from_v2),
+    (3, from_v3),
+    (4, from_v4),
+    (5, from_v5),
+    (6, from_v6),
+    (7, from_v7),
+];
+
+/// Reads the archive's JSON entries other than the keystrokes and the
+/// event chain, written in `format_version`, in the current layout.
+pub fn read(reader: &mut ArchiveReader, format_version: u32) -> Result<Entries> {
+    check_version(format_version)?;
+    let raw = RawEntries {
+        navigation_keys: if format_version == 1 { count_navigation_keys(reader)? } else { 0 },
+        summary: required(reader, archive::SUMMARY_ENTRY)?,
+        metadata: required(reader, archive::METADATA_ENTRY)?,
+        pastes: reader.read_json(archive::PASTES_ENTRY)?,
+        commands: reader.read_json(archive::COMMANDS_ENTRY)?,
+        processes: reader.read_json(archive::PROCESSES_ENTRY)?,
+        connections: reader.read_json(archive::CONNECTIONS_ENTRY)?,
+        file_history: reader.read_json(archive::FILE_HISTORY_ENTRY)?,
+        collected_files: reader.read_json(archive::COLLECTED_FILES_ENTRY)?,
+    };
+    load(raw, format_version)
+}
+
+/// Reads only the archive's metadata, in the current layout.
+pub fn read_metadata(reader: &mut ArchiveReader, format_version: u32) -> Result<Metadata> {
+    check_version(format_version)?;
+    if format_version < ARCHIVE_FORMAT_VERSION {
+        // Older metadata is completed from the other entries
+        return Ok(read(reader, format_version)?.metadata);
+    }
+    typed(archive::METADATA_ENTRY, required(reader, archive::METADATA_ENTRY)?)
+}
+
+fn check_version(format_version: u32) -> Result<()> {
+    if format_version > ARCHIVE_FORMAT_VERSION {
+        return Err(ArchiveError::UnsupportedVersion(format!(
+            "archive format v{}, this build reads up to v{}; update exam-viewer",
+            format_version,
+            ARCHIVE_FORMAT_VERSION
+        )).into());
+    }
+    Ok(())
+}
+
+fn required(reader: &mut ArchiveReader, entry: &str) -> Result<Value> {
+    reader.read_json(entry)?
+        .with_context(|| format!("Missing {}", entry))
+}
+
+/// Counts the navigation keys among the recorded keystrokes, reading them
+/// one at a time.
+fn count_navigation_keys(reader: &mut ArchiveReader) -> Result<usize> {
+    #[derive(Deserialize)]
+    struct Key {
+        key_name: String,
+    }
+
+    reader.read_entry(archive::EVENTS_ENTRY, |events| {
+        let mut count = 0;
+        for key in ArrayReader::<_, Key>::new(events) {
+            if is_navigation_key(&key?.key_name) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    })?.context("Missing events.json.enc")
+}
+
+/// Brings entries written in `format_version` up to the current layout and
+/// deserializes them.
+pub fn load(mut raw: RawEntries, format_version: u32) -> Result<Entries> {
+    check_version(format_version)?;
+    for (version, migrate) in MIGRATIONS {
+        if version >= format_version {
+            migrate(&mut raw)?;
+        }
+    }
+
+    Ok(Entries {
+        summary: typed(archive::SUMMARY_ENTRY, raw.summary)?,
+        metadata: typed(archive::METADATA_ENTRY, raw.metadata)?,
+        pastes: raw.pastes.map(|v| typed(archive::PASTES_ENTRY, v)).transpose()?,
+        commands: raw.commands.map(|v| typed(archive::COMMANDS_ENTRY, v)).transpose()?,
+        processes: raw.processes.map(|v| typed(archive::PROCESSES_ENTRY, v)).transpose()?,
+        connections: raw.connections.map(|v| typed(archive::CONNECTIONS_ENTRY, v)).transpose()?,
+        file_history: raw.file_history.map(|v| typed(archive::FILE_HISTORY_ENTRY, v)).transpose()?,
+        collected_files: raw.collected_files.map(|v| typed(archive::COLLECTED_FILES_ENTRY, v)).transpose()?,
+    })
+}
+
+/// Format 1 archives come from recorders from before the manifest, which
+/// gained fields one release at a time. Fields the recorder did not know
+/// about are added with the value that means "not recorded".
+fn from_v1(raw: &mut RawEntries) -> Result<()> {
+    let metadata = object(&mut raw.metadata, archive::METADATA_ENTRY)?;
+    let added = [
+        ("exam_id", Value::Null),
+        ("student_id", Value::Null),
+        ("shell", Value::from("")),
+        ("command", Value::Array(Vec::new())),
+        ("time_limit_minutes", Value::Null),
+        ("time_warnings", Value::Array(Vec::new())),
+        ("time_limit_reached", Value::Bool(false)),
+        ("output_dir", Value::Null),
+        ("chain_key", Value::Null),
+        ("config_hash", Value::Null),
+        ("paste_policy", Value::from("allow")),
+        ("collect_files", Value::Array(Vec::new())),
+        ("terminal_width", Value::from(0)),
+        ("terminal_height", Value::from(0)),
+        ("recovered", Value::Bool(false)),
+        ("recording_stopped_at", Value::Null),
+        ("end_reason", Value::Null),
+        ("exit_status", Value::Null),
+        ("exit_signal", Value::Null),
+        ("recorder_signal", Value::Null),
+    ];
+    for (field, value) in added {
+        metadata.entry(field).or_insert(value);
+    }
+
+    // The first recorders did not count navigation keys; the keystrokes
+    // they recorded still say which keys were pressed
+    object(&mut raw.summary, archive::SUMMARY_ENTRY)?
+        .entry("navigation_keys")
+        .or_insert(Value::from(raw.navigation_keys));
+
+    // Commands from before shell integration only have their text
+    for command in items(&mut raw.commands) {
+        for field in ["end_timestamp", "exit_status", "cwd"] {
+            command.entry(field).or_insert(Value::Null);
+        }
+    }
+    // Pastes could only be blocked once there was a paste policy
+    for paste in items(&mut raw.pastes) {
+        paste.entry("blocked").or_insert(Value::Bool(false));
+    }
+    Ok(())
+}
+
+/// Format 2 moved the key envelope into a plaintext header; the entries did
+/// not change.
+fn from_v2(_raw: &mut RawEntries) -> Result<()> {
+    Ok(())
+}
+
+/// Format 4 encrypts in chunks and caps recorded output. Older recorders
+/// kept all output, so nothing was dropped.
+fn from_v3(raw: &mut RawEntries) -> Result<()> {
+    let metadata = object(&mut raw.metadata, archive::METADATA_ENTRY)?;
+    metadata.entry("output_limit_mib").or_insert(Value::Null);
+    metadata.entry("output_dropped_bytes").or_insert(Value::from(0));
+    Ok(())
+}
+
+/// Format 5 follows the shell's processes and lets the exam config flag
+/// programs; older recorders flagged none.
+fn from_v4(raw: &mut RawEntries) -> Result<()> {
+    object(&mut raw.metadata, archive::METADATA_ENTRY)?
+        .entry("flag_programs")
+        .or_insert(Value::Array(Vec::new()));
+    Ok(())
+}
+
+/// Format 6 samples network connections and lets the exam config allow
+/// some; older recorders allowed none.
+fn from_v5(raw: &mut RawEntries) -> Result<()> {
+    object(&mut raw.metadata, archive::METADATA_ENTRY)?
+        .entry("network_allowlist")
+        .or_insert(Value::Array(Vec::new()));
+    Ok(())
+}
+
+/// Format 7 versions the files of a watched directory; older recorders
+/// watched none.
+fn from_v6(raw: &mut RawEntries) -> Result<()> {
+    let metadata = object(&mut raw.metadata, archive::METADATA_ENTRY)?;
+    metadata.entry("watch_dir").or_insert(Value::Null);
+    metadata.entry("watch_ignore").or_insert(Value::Array(Vec::new()));
+    metadata.entry("watch_file_limit_kib").or_insert(Value::Null);
+    metadata.entry("watch_limit_mib").or_insert(Value::Null);
+    Ok(())
+}
+
+/// Format 8 records typed commands next to the ones the shell reports.
+/// Older recorders kept one list: the shell's, whose commands always carry
+/// the directory they ran in, or else the typed one.
+fn from_v7(raw: &mut RawEntries) -> Result<()> {
+    let mut shell_integration = false;
+    for command in items(&mut raw.commands) {
+        let from_shell = ["end_timestamp", "exit_status", "cwd"].iter()
+            .any(|field| command.get(*field).is_some_and(|value| !value.is_null()));
+        shell_integration |= from_shell;
+        command.entry("source").or_insert(Value::from(if from_shell { "shell" } else { "keystrokes" }));
+    }
+    object(&mut raw.metadata, archive::METADATA_ENTRY)?
+        .entry("shell_integration")
+        .or_insert(Value::Bool(shell_integration));
+    Ok(())
+}
+
+/// Deserializes `value`, requiring every field of `T` to be present.
+fn typed<T: Serialize + DeserializeOwned>(entry: &str, value: Value) -> Result<T> {
+    let corrupted = |reason: String| ArchiveError::CorruptedEntry { entry: entry.to_string(), reason };
+    let parsed = T::deserialize(&value).map_err(|e| corrupted(e.to_string()))?;
+    // Serde treats missing optional fields as empty; the current layout
+    // always writes them
+    if let Some(field) = missing_field(&serde_json::to_value(&parsed)?, &value, "") {
+        return Err(corrupted(format!("missing field `{}`", field)).into());
+    }
+    Ok(parsed)
+}
+
+/// First field of `expected` that `actual` lacks, as a path like
+/// `time_warnings[0].minutes_left`.
+fn missing_field(expected: &Value, actual: &Value, path: &str) -> Option<String> {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => expected.iter().find_map(|(key, value)| {
+            let path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+            match actual.get(key) {
+                Some(actual) => missing_field(value, actual, &path),
+                None => Some(path),
+            }
+        }),
+        (Value::Array(expected), Value::Array(actual)) => expected.iter()
+            .zip(actual)
+            .enumerate()
+            .find_map(|(i, (expected, actual))| missing_field(expected, actual, &format!("{}[{}]", path, i))),
+        _ => None,
+    }
+}
+
+fn object<'a>(value: &'a mut Value, entry: &str) -> Result<&'a mut Map<String, Value>> {
+    value.as_object_mut().ok_or_else(|| ArchiveError::CorruptedEntry {
+        entry: entry.to_string(),
+        reason: "not a JSON object".into(),
+    }.into())
+}
+
+/// The objects in an optional array entry.
+fn items(value: &mut Option<Value>) -> impl Iterator<Item = &mut Map<String, Value>> {
+    value.as_mut()
+        .and_then(|v| v.as_array_mut())
+        .into_iter()
+        .flatten()
+        .filter_map(|item| item.as_object_mut())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn raw(metadata: Value, summary: Value) -> RawEntries {
+        RawEntries {
+            navigation_keys: 1,
+            summary,
+            metadata,
+            pastes: None,
+            commands: Some(json!([{"timestamp": 1200, "command": "ls"}])),
+            processes: None,
+            connections: None,
+            file_history: None,
+            collected_files: None,
+        }
+    }
+
+    fn baseline_metadata() -> Value {
+        json!({
+            "username": "student",
+            "hostname": "lab-01",
+            "machine_id": "abc123",
+            "run_counter": 1,
+            "start_time": 1700000000,
+            "end_time": 1700000600,
+            "duration_seconds": 600,
+        })
+    }
+
+    fn baseline_summary() -> Value {
+        json!({
+            "total_keystrokes": 2,
+            "enter_pressed": 0,
+            "backspace_used": 0,
+            "delete_used": 0,
+            "paste_events": 0,
+            "total_pasted_chars": 0,
+            "commands_executed": 1,
+        })
+    }
+
+    #[test]
+    fn format_1_archive_is_migrated() {
+        let entries = load(raw(baseline_metadata(), baseline_summary()), 1).unwrap();
+        assert_eq!(entries.metadata.username, "student");
+        assert_eq!(entries.metadata.exam_id, None);
+        assert!(!entries.metadata.recovered);
+        assert_eq!(entries.summary.navigation_keys, 1);
+        let commands = entries.commands.unwrap();
+        assert_eq!(commands[0].command, "ls");
+        assert_eq!(commands[0].exit_status, None);
+        assert_eq!(commands[0].source, exam_format::CommandSource::Keystrokes);
+        assert!(!entries.metadata.shell_integration);
+    }
+
+    #[test]
+    fn missing_field_in_current_layout_is_reported() {
+        let error = load(raw(baseline_metadata(), baseline_summary()), ARCHIVE_FORMAT_VERSION).err().unwrap();
+        let error = error.downcast::<ArchiveError>().unwrap();
+        assert!(matches!(&error, ArchiveError::CorruptedEntry { entry, .. } if entry == archive::SUMMARY_ENTRY));
+        assert_eq!(error.to_string(), "Corrupted entry summary.json.enc: missing field `navigation_keys`");
+    }
+
+    #[test]
+    fn missing_event_fields_in_current_layout_are_reported() {
+        let mut summary = baseline_summary();
+        summary["navigation_keys"] = json!(0);
+        // Metadata in the current layout, so only the events fall short
+        let metadata = serde_json::to_value(load(raw(baseline_metadata(), summary.clone()), 1).unwrap().metadata).unwrap();
+
+        let mut commands = raw(metadata.clone(), summary.clone());
+        commands.commands = Some(json!([{"timestamp": 1200, "command": "ls", "source": "shell", "exit_status": 0}]));
+        let error = load(commands, ARCHIVE_FORMAT_VERSION).err().unwrap();
+        assert_eq!(error.to_string(), "Corrupted entry commands.json.enc: missing field `[0].cwd`");
+
+        let mut pastes = raw(metadata, summary);
+        pastes.commands = None;
+        pastes.pastes = Some(json!([{"timestamp": 1200, "length": 2, "text": "ls"}]));
+        let error = load(pastes, ARCHIVE_FORMAT_VERSION).err().unwrap();
+        assert!(error.to_string().contains("missing field `blocked`"), "{}", error);
+    }
+
+    #[test]
+    fn missing_required_field_is_not_migrated_away() {
+        let mut metadata = baseline_metadata();
+        metadata.as_object_mut().unwrap().remove("username");
+        let error = load(raw(metadata, baseline_summary()), 1).err().unwrap();
+        assert!(error.to_string().contains("missing field `username`"), "{}", error);
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::{Context, Result};
+use std::net::IpAddr;
+
+/// Addresses the exam allows connections to, from the exam config's
+/// `network_allowlist`: single addresses ("192.168.1.10", "::1") and
+/// CIDR ranges ("10.0.0.0/8", "2001:db8::/32").
+pub struct Allowlist {
+    ranges: Vec<(IpAddr, u8)>,
+}
+
+impl Allowlist {
+    pub fn parse(entries: &[String]) -> Result<Self> {
+        let ranges = entries.iter()
+            .map(|entry| parse_range(entry)
+                .with_context(|| format!("Invalid network_allowlist entry: {}", entry)))
+            .collect::<Result<_>>()?;
+        Ok(Allowlist { ranges })
+    }
+
+    pub fn allows(&self, address: IpAddr) -> bool {
+        self.ranges.iter().any(|(network, prefix)| in_range(address, *network, *prefix))
+    }
+}
+
+fn parse_range(entry: &str) -> Result<(IpAddr, u8)> {
+    let (address, prefix) = match entry.trim().split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (entry.trim(), None),
+    };
+    let address: IpAddr = address.parse().context("not an IP address")?;
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.parse().ok().filter(|&prefix| prefix <= max).context("invalid prefix length")?,
+        None => max,
+    };
+    Ok((address, prefix))
+}
+
+fn in_range(address: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    let (address, network, bits) = match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) => (u32::from(address) as u128, u32::from(network) as u128, 32),
+        (IpAddr::V6(address), IpAddr::V6(network)) => (u128::from(address), u128::from(network), 128),
+        _ => return false,
+    };
+    let shift = bits - prefix as u32;
+    shift == bits || address >> shift == network >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_addresses_and_ranges() {
+        let allowlist = Allowlist::parse(&[
+            "10.0.0.0/8".to_string(),
+            "192.168.1.10".to_string(),
+            "2001:db8::/32".to_string(),
+        ]).unwrap();
+        assert!(allowlist.allows("10.20.30.40".parse().unwrap()));
+        assert!(allowlist.allows("192.168.1.10".parse().unwrap()));
+        assert!(!allowlist.allows("192.168.1.11".parse().unwrap()));
+        assert!(allowlist.allows("2001:db8::1".parse().unwrap()));
+        assert!(!allowlist.allows("11.0.0.1".parse().unwrap()));
+
+        assert!(Allowlist::parse(&["0.0.0.0/0".to_string()]).unwrap().allows("8.8.8.8".parse().unwrap()));
+        assert!(Allowlist::parse(&["pypi.org".to_string()]).is_err());
+        assert!(Allowlist::parse(&["10.0.0.0/33".to_string()]).is_err());
+    }
+}
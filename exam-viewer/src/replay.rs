@@ -0,0 +1,292 @@
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::stream::{StreamEvent, TerminalStream};
+
+pub const MIN_SPEED: f64 = 0.5;
+pub const MAX_SPEED: f64 = 16.0;
+
+const CONTROLS: &str = "\
+Controls: space pause/resume | + / - speed | s skip wait | n / p next/previous command
+          <N>g jump to command N | q quit";
+
+/// Plays a recorded terminal stream back in the instructor's terminal.
+pub struct Player<'a> {
+    stream: &'a TerminalStream,
+    /// Index of the input event where each command was submitted.
+    commands: Vec<usize>,
+    speed: f64,
+    idle_limit: Option<f64>,
+    paused: bool,
+    /// Index of the next event to play.
+    position: usize,
+}
+
+enum Action {
+    Continue,
+    Skip,
+    Jump(usize),
+    Quit,
+}
+
+impl<'a> Player<'a> {
+    pub fn new(stream: &'a TerminalStream, speed: f64, idle_limit: Option<f64>) -> Result<Self> {
+        if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+            anyhow::bail!("Speed must be between {}x and {}x", MIN_SPEED, MAX_SPEED);
+        }
+        Ok(Player {
+            stream,
+            commands: find_commands(stream),
+            speed,
+            idle_limit,
+            paused: false,
+            position: 0,
+        })
+    }
+
+    pub fn command_count(&self) -> usize {
+        self.commands.len()
+    }
+
+    /// Replays the stream, optionally starting at the given 1-based command.
+    pub fn play(&mut self, start_command: Option<usize>) -> Result<()> {
+        println!("{}", CONTROLS);
+        println!();
+
+        let original = set_raw_mode()?;
+        let result = self.run(start_command);
+        restore_mode(original);
+
+        print!("\x1b]2;\x07\r\n");
+        std::io::stdout().flush()?;
+        result
+    }
+
+    fn run(&mut self, start_command: Option<usize>) -> Result<()> {
+        let stream = self.stream;
+        let events = &stream.events;
+        let mut last_offset = 0.0;
+
+        if let Some(n) = start_command {
+            self.position = self.command_position(n)?;
+            self.render_range(0, self.position)?;
+            last_offset = events.get(self.position).map(|e| e.offset).unwrap_or(0.0);
+        }
+
+        self.update_title();
+        let mut pending_number = String::new();
+        while self.position < events.len() {
+            let event = &events[self.position];
+            let Some(bytes) = playback_bytes(event) else {
+                self.position += 1;
+                continue;
+            };
+
+            let mut gap = (event.offset - last_offset).max(0.0);
+            if let Some(limit) = self.idle_limit {
+                gap = gap.min(limit);
+            }
+
+            match self.wait(gap, &mut pending_number)? {
+                Action::Continue | Action::Skip => {}
+                Action::Jump(target) => {
+                    if target < self.position {
+                        // Rebuild the screen from the start of the session
+                        print!("\x1bc");
+                        self.render_range(0, target)?;
+                    } else {
+                        self.render_range(self.position, target)?;
+                    }
+                    self.position = target;
+                    last_offset = events.get(target).map(|e| e.offset).unwrap_or(last_offset);
+                    continue;
+                }
+                Action::Quit => return Ok(()),
+            }
+
+            let mut stdout = std::io::stdout();
+            stdout.write_all(bytes.as_bytes())?;
+            stdout.flush()?;
+
+            last_offset = event.offset;
+            self.position += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for `gap` seconds of recording time while handling keys.
+    fn wait(&mut self, gap: f64, pending_number: &mut String) -> Result<Action> {
+        let mut remaining = Duration::from_secs_f64(gap / self.speed);
+        let mut started = Instant::now();
+
+        loop {
+            let elapsed = started.elapsed();
+            if !self.paused && elapsed >= remaining {
+                return Ok(Action::Continue);
+            }
+            let timeout = if self.paused {
+                Duration::from_millis(100)
+            } else {
+                (remaining - elapsed).min(Duration::from_millis(100))
+            };
+
+            let Some(key) = read_key(timeout)? else {
+                continue;
+            };
+
+            match key {
+                b' ' => {
+                    if self.paused {
+                        started = Instant::now();
+                    } else {
+                        remaining = remaining.saturating_sub(started.elapsed());
+                    }
+                    self.paused = !self.paused;
+                }
+                b'+' | b'=' => {
+                    let scale = self.change_speed(2.0);
+                    remaining = remaining.saturating_sub(started.elapsed()).div_f64(scale);
+                    started = Instant::now();
+                }
+                b'-' | b'_' => {
+                    let scale = self.change_speed(0.5);
+                    remaining = remaining.saturating_sub(started.elapsed()).div_f64(scale);
+                    started = Instant::now();
+                }
+                b's' => return Ok(Action::Skip),
+                b'n' => {
+                    if let Some(&target) = self.commands.iter().find(|&&c| c > self.position) {
+                        return Ok(Action::Jump(target));
+                    }
+                }
+                b'p' => {
+                    // Skip the command just jumped to, if any, so repeated
+                    // presses keep moving backwards
+                    let before = self.position.saturating_sub(1);
+                    if let Some(&target) = self.commands.iter().rev().find(|&&c| c < before) {
+                        return Ok(Action::Jump(target));
+                    }
+                }
+                b'0'..=b'9' => pending_number.push(key as char),
+                b'g' | b'\r' | b'\n' => {
+                    let number = pending_number.parse::<usize>().ok();
+                    pending_number.clear();
+                    if let Some(position) = number.and_then(|n| self.command_position(n).ok()) {
+                        return Ok(Action::Jump(position));
+                    }
+                }
+                b'q' | 3 => return Ok(Action::Quit),
+                _ => pending_number.clear(),
+            }
+            self.update_title();
+        }
+    }
+
+    fn change_speed(&mut self, factor: f64) -> f64 {
+        let old = self.speed;
+        self.speed = (self.speed * factor).clamp(MIN_SPEED, MAX_SPEED);
+        self.speed / old
+    }
+
+    fn command_position(&self, n: usize) -> Result<usize> {
+        if n == 0 || n > self.commands.len() {
+            anyhow::bail!(
+                "Command {} does not exist (session has {} commands)",
+                n,
+                self.commands.len()
+            );
+        }
+        Ok(self.commands[n - 1])
+    }
+
+    /// Writes all output in `start..end` immediately.
+    fn render_range(&self, start: usize, end: usize) -> Result<()> {
+        let mut stdout = std::io::stdout();
+        for event in &self.stream.events[start..end.min(self.stream.events.len())] {
+            if let Some(bytes) = playback_bytes(event) {
+                stdout.write_all(bytes.as_bytes())?;
+            }
+        }
+        stdout.flush()?;
+        Ok(())
+    }
+
+    fn update_title(&self) {
+        let state = if self.paused { " | paused" } else { "" };
+        print!("\x1b]2;Replay {}x{}\x07", self.speed, state);
+        std::io::stdout().flush().ok();
+    }
+}
+
+/// What to write to the instructor's terminal for an event: output as
+/// recorded, and resizes as an xterm window size request.
+fn playback_bytes(event: &StreamEvent) -> Option<String> {
+    match event.kind.as_str() {
+        "o" => Some(event.data.clone()),
+        "r" => {
+            let (width, height) = event.data.split_once('x')?;
+            Some(format!("\x1b[8;{};{}t", height, width))
+        }
+        _ => None,
+    }
+}
+
+/// Finds the input events where a non-empty command line was submitted.
+fn find_commands(stream: &TerminalStream) -> Vec<usize> {
+    let mut commands = Vec::new();
+    let mut line = String::new();
+    for (index, event) in stream.events.iter().enumerate() {
+        if event.kind != "i" {
+            continue;
+        }
+        for c in event.data.chars() {
+            match c {
+                '\r' | '\n' => {
+                    if !line.trim().is_empty() {
+                        commands.push(index);
+                    }
+                    line.clear();
+                }
+                '\x7f' | '\x08' => {
+                    line.pop();
+                }
+                c if !c.is_control() => line.push(c),
+                _ => {}
+            }
+        }
+    }
+    commands
+}
+
+fn set_raw_mode() -> Result<termios::Termios> {
+    use termios::*;
+
+    let mut termios = Termios::from_fd(0)
+        .context("Replay needs an interactive terminal")?;
+    let original = termios;
+
+    termios.c_lflag &= !(ECHO | ICANON | ISIG);
+    termios.c_cc[VMIN] = 0;
+    termios.c_cc[VTIME] = 0;
+    tcsetattr(0, TCSANOW, &termios)?;
+
+    Ok(original)
+}
+
+fn restore_mode(termios: termios::Termios) {
+    let _ = termios::tcsetattr(0, termios::TCSANOW, &termios);
+}
+
+fn read_key(timeout: Duration) -> Result<Option<u8>> {
+    let mut poll_fd = libc::pollfd { fd: 0, events: libc::POLLIN, revents: 0 };
+    let ready = unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as libc::c_int) };
+    if ready <= 0 {
+        return Ok(None);
+    }
+
+    let mut byte = 0u8;
+    let n = unsafe { libc::read(0, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+    Ok(if n == 1 { Some(byte) } else { None })
+}
@@ -16,24 +16,124 @@ impl Reporter {
         println!("=== Exam Viewer Report ===");
         println!("Author: A. Z. M. Arif | https://azmarif.dev");
         println!();
+        if let Some(exam_id) = &report.exam_id {
+            println!("Exam ID:                 {}", exam_id);
+        }
+        if let Some(student_id) = &report.student_id {
+            println!("Student ID:              {}", student_id);
+        }
         println!("Student Username:        {}", report.username);
         println!("Hostname:                {}", report.hostname);
         println!("Machine ID:              {}", report.machine_id);
         println!("Session Duration:        {}", report.session_duration);
+        if let Some(limit) = &report.time_limit {
+            println!("Time Limit:              {}", limit);
+        }
+        if !report.time_warnings.is_empty() {
+            println!("Time Warnings Shown:     {}", report.time_warnings.join(", "));
+        }
+        if let Some(program) = &report.recorded_program {
+            println!("Recorded Program:        {}", program);
+        }
+        if let Some(config) = format_config(report) {
+            match report.config_matches {
+                Some(false) => println!("Exam Config:             {}", config.red().bold()),
+                _ => println!("Exam Config:             {}", config),
+            }
+        }
+        if report.pastes_blocked {
+            println!("Paste Policy:            block");
+        }
         println!("Recorder Runs Before:    {}", report.recorder_runs_before);
+        if let Some(size) = &report.terminal_size {
+            println!("Terminal Size:           {}", size);
+        }
+        if let Some(dropped) = &report.output_dropped {
+            println!("Output Not Recorded:     {}", dropped.yellow());
+        }
+        if let Some(reason) = &report.end_reason {
+            println!("Session Ended By:        {}", reason);
+        }
+        if report.recovered {
+            println!("Recovered Session:       {}", "YES".yellow().bold());
+        }
         println!();
         println!("--- Typing Statistics ---");
         println!("Total Keystrokes:        {}", report.total_keystrokes);
         println!("Enter Pressed:           {}", report.enter_pressed);
         println!("Backspace Used:          {}", report.backspace_used);
+        println!("Delete Used:             {}", report.delete_used);
+        println!("Navigation Keys:         {}", report.navigation_keys);
         println!("Paste Events:            {}", report.paste_events);
         println!("Total Pasted Characters: {}", report.total_pasted_chars);
+        if let Some(gap) = &report.longest_input_pause {
+            println!("Longest Input Pause:     {}", gap);
+        }
         println!();
         
+        if !report.key_usage.is_empty() {
+            println!("--- Key Usage ---");
+            println!("{}", format_key_usage(&report.key_usage));
+            println!();
+        }
+        
         if !report.commands.is_empty() {
             println!("--- Command Timeline ---");
             for (i, cmd) in report.commands.iter().enumerate() {
-                println!("{}. {}", i + 1, cmd);
+                match cmd.details() {
+                    Some(details) => println!("{}. {}  {}", i + 1, cmd.command, format!("({})", details).dimmed()),
+                    None => println!("{}. {}", i + 1, cmd.command),
+                }
+            }
+            println!();
+        }
+        
+        if !report.processes.is_empty() {
+            println!("--- Process Timeline ---");
+            for process in &report.processes {
+                let details = format!("({})", process.details()).dimmed();
+                if process.flagged {
+                    println!("[{}] {}  {} {}", process.started, process.command.red().bold(), details, "FLAGGED".red().bold());
+                } else {
+                    println!("[{}] {}  {}", process.started, process.command, details);
+                }
+            }
+            println!();
+        }
+        
+        if !report.connections.is_empty() {
+            println!("--- Network Connections ---");
+            for connection in &report.connections {
+                if connection.allowed || connection.inbound {
+                    println!("[{}] {}{}", connection.timestamp, connection.describe(), allowed_marker(connection.allowed));
+                } else {
+                    println!("[{}] {}", connection.timestamp, connection.describe().red().bold());
+                }
+            }
+            println!();
+        }
+        
+        if !report.pastes.is_empty() {
+            println!("--- Pasted Text ---");
+            for paste in &report.pastes {
+                println!("[{}] {} chars{}:", paste.timestamp, paste.length, blocked_marker(paste.blocked));
+                println!("{}", paste.text);
+            }
+            println!();
+        }
+        
+        if !report.collected_files.is_empty() {
+            println!("--- Collected Files ---");
+            for file in &report.collected_files {
+                println!("{}  {}", file.path, format!("({})", file.details()).dimmed());
+            }
+            println!();
+        }
+        
+        if !report.watched_files.is_empty() {
+            println!("--- File History ---");
+            for file in &report.watched_files {
+                println!("{}  {}", file.path, format!("({})", file.details()).dimmed());
             }
             println!();
         }
@@ -53,9 +153,16 @@ impl Reporter {
         
         println!("--- Integrity ---");
         if report.integrity_passed {
-            println!("SHA256 check: {}", "PASSED".green().bold());
+            println!("SHA256 check: {} ({})", "PASSED".green().bold(), report.integrity);
         } else {
-            println!("SHA256 check: {}", "FAILED - TAMPERED".red().bold());
+            println!("SHA256 check: {}: {}", "FAILED - TAMPERED".red().bold(), report.integrity);
+        }
+        if let Some(chain) = &report.event_chain {
+            if report.event_chain_passed == Some(true) {
+                println!("Event chain:  {}", chain.green());
+            } else {
+                println!("Event chain:  {}", chain.red().bold());
+            }
         }
         
         Ok(())
@@ -63,13 +170,44 @@ impl Reporter {
     
     pub fn print_summary(&self, report: &AnalysisReport) -> Result<()> {
         println!("=== Exam Summary ===");
-        println!("Student: {}", report.username);
+        if let Some(exam_id) = &report.exam_id {
+            println!("Exam: {}", exam_id);
+        }
+        println!("Student: {}", report.student_id.as_deref().unwrap_or(&report.username));
         println!("Duration: {}", report.session_duration);
         println!("Keystrokes: {}", report.total_keystrokes);
         println!("Paste Events: {}", report.paste_events);
         println!("Commands: {}", report.commands.len());
+        let flagged = report.processes.iter().filter(|process| process.flagged).count();
+        if flagged > 0 {
+            println!("Flagged Programs: {}", flagged);
+        }
+        let outbound = report.connections.iter().filter(|connection| !connection.inbound).count();
+        if outbound > 0 {
+            println!("Outbound Connections: {}", outbound);
+        }
+        if !report.watched_files.is_empty() {
+            let changed = report.watched_files.iter().filter(|file| file.changed).count();
+            println!("Files Changed: {} of {}", changed, report.watched_files.len());
+        }
+        match report.config_matches {
+            Some(true) => println!("Exam Config: MATCHES"),
+            Some(false) => println!("Exam Config: DOES NOT MATCH"),
+            None => {}
+        }
+        if let Some(reason) = &report.end_reason {
+            println!("Ended By: {}", reason);
+        }
+        if report.recovered {
+            println!("Recovered: YES");
+        }
         println!("Integrity: {}", 
             if report.integrity_passed { "PASSED" } else { "FAILED" });
+        match report.event_chain_passed {
+            Some(true) => println!("Event Chain: VERIFIED"),
+            Some(false) => println!("Event Chain: BROKEN"),
+            None => {}
+        }
         Ok(())
     }
     
@@ -85,25 +223,117 @@ impl Reporter {
         let mut content = String::new();
         content.push_str("# Exam Viewer Report\n\n");
         content.push_str("**Author:** A. Z. M. Arif | https://azmarif.dev\n\n");
+        if let Some(exam_id) = &report.exam_id {
+            content.push_str(&format!("**Exam ID:** {}\n", exam_id));
+        }
+        if let Some(student_id) = &report.student_id {
+            content.push_str(&format!("**Student ID:** {}\n", student_id));
+        }
         content.push_str(&format!("**Student Username:** {}\n", report.username));
         content.push_str(&format!("**Hostname:** {}\n", report.hostname));
         content.push_str(&format!("**Machine ID:** {}\n", report.machine_id));
         content.push_str(&format!("**Session Duration:** {}\n", report.session_duration));
-        content.push_str(&format!("**Recorder Runs Before:** {}\n\n", report.recorder_runs_before));
+        if let Some(limit) = &report.time_limit {
+            content.push_str(&format!("**Time Limit:** {}\n", limit));
+        }
+        if !report.time_warnings.is_empty() {
+            content.push_str(&format!("**Time Warnings Shown:** {}\n", report.time_warnings.join(", ")));
+        }
+        if let Some(program) = &report.recorded_program {
+            content.push_str(&format!("**Recorded Program:** `{}`\n", program));
+        }
+        if let Some(config) = format_config(report) {
+            content.push_str(&format!("**Exam Config:** {}\n", config));
+        }
+        if report.pastes_blocked {
+            content.push_str("**Paste Policy:** block\n");
+        }
+        content.push_str(&format!("**Recorder Runs Before:** {}\n", report.recorder_runs_before));
+        if let Some(size) = &report.terminal_size {
+            content.push_str(&format!("**Terminal Size:** {}\n", size));
+        }
+        if let Some(dropped) = &report.output_dropped {
+            content.push_str(&format!("**Output Not Recorded:** {}\n", dropped));
+        }
+        if let Some(reason) = &report.end_reason {
+            content.push_str(&format!("**Session Ended By:** {}\n", reason));
+        }
+        if report.recovered {
+            content.push_str("**Recovered Session:** Yes\n");
+        }
+        content.push('\n');
         
         content.push_str("## Typing Statistics\n\n");
         content.push_str(&format!("- Total Keystrokes: {}\n", report.total_keystrokes));
         content.push_str(&format!("- Enter Pressed: {}\n", report.enter_pressed));
         content.push_str(&format!("- Backspace Used: {}\n", report.backspace_used));
+        content.push_str(&format!("- Delete Used: {}\n", report.delete_used));
+        content.push_str(&format!("- Navigation Keys: {}\n", report.navigation_keys));
         content.push_str(&format!("- Paste Events: {}\n", report.paste_events));
-        content.push_str(&format!("- Total Pasted Characters: {}\n\n", report.total_pasted_chars));
+        content.push_str(&format!("- Total Pasted Characters: {}\n", report.total_pasted_chars));
+        if let Some(gap) = &report.longest_input_pause {
+            content.push_str(&format!("- Longest Input Pause: {}\n", gap));
+        }
+        content.push('\n');
+        
+        if !report.key_usage.is_empty() {
+            content.push_str("## Key Usage\n\n");
+            content.push_str(&format_key_usage(&report.key_usage));
+            content.push_str("\n\n");
+        }
         
         if !report.commands.is_empty() {
             content.push_str("## Command Timeline\n\n");
             for (i, cmd) in report.commands.iter().enumerate() {
-                content.push_str(&format!("{}. `{}`\n", i + 1, cmd));
+                match cmd.details() {
+                    Some(details) => content.push_str(&format!("{}. `{}` ({})\n", i + 1, cmd.command, details)),
+                    None => content.push_str(&format!("{}. `{}`\n", i + 1, cmd.command)),
+                }
+            }
+            content.push('\n');
+        }
+        
+        if !report.processes.is_empty() {
+            content.push_str("## Process Timeline\n\n");
+            for process in &report.processes {
+                content.push_str(&format!("- {} `{}` ({}){}\n",
+                    process.started, process.command, process.details(), flagged_marker(process.flagged)));
+            }
+            content.push('\n');
+        }
+        
+        if !report.connections.is_empty() {
+            content.push_str("## Network Connections\n\n");
+            for connection in &report.connections {
+                content.push_str(&format!("- {} {}{}\n",
+                    connection.timestamp, connection.describe(), allowed_marker(connection.allowed)));
+            }
+            content.push('\n');
+        }
+        
+        if !report.pastes.is_empty() {
+            content.push_str("## Pasted Text\n\n");
+            for paste in &report.pastes {
+                content.push_str(&format!("- {} ({} chars{}):\n\n```\n{}\n```\n", 
+                    paste.timestamp, paste.length, blocked_marker(paste.blocked), paste.text));
             }
-            content.push_str("\n");
+            content.push('\n');
+        }
+        
+        if !report.collected_files.is_empty() {
+            content.push_str("## Collected Files\n\n");
+            for file in &report.collected_files {
+                content.push_str(&format!("- `{}` ({})\n", file.path, file.details()));
+            }
+            content.push('\n');
+        }
+        
+        if !report.watched_files.is_empty() {
+            content.push_str("## File History\n\n");
+            for file in &report.watched_files {
+                content.push_str(&format!("- `{}` ({})\n", file.path, file.details()));
+            }
+            content.push('\n');
         }
         
         if !report.suspicious_activities.is_empty() {
@@ -112,12 +342,14 @@ impl Reporter {
                 content.push_str(&format!("- **{}** at {}: {}\n", 
                     activity.severity, activity.timestamp, activity.description));
             }
-            content.push_str("\n");
+            content.push('\n');
         }
         
         content.push_str("## Integrity\n\n");
-        content.push_str(&format!("SHA256 check: {}\n", 
-            if report.integrity_passed { "PASSED" } else { "FAILED - TAMPERED" }));
+        content.push_str(&format!("SHA256 check: {}\n", integrity_result(report)));
+        if let Some(chain) = &report.event_chain {
+            content.push_str(&format!("\nEvent chain: {}\n", chain));
+        }
         
         fs::write(path, content)?;
         Ok(())
@@ -125,17 +357,88 @@ impl Reporter {
     
     pub fn export_json(&self, report: &AnalysisReport, path: &Path) -> Result<()> {
         let json = serde_json::json!({
+            "exam_id": report.exam_id,
+            "student_id": report.student_id,
             "username": report.username,
             "hostname": report.hostname,
             "machine_id": report.machine_id,
             "session_duration": report.session_duration,
+            "time_limit": report.time_limit,
+            "time_warnings": report.time_warnings,
+            "recorded_program": report.recorded_program,
+            "config_hash": report.config_hash,
+            "config_matches": report.config_matches,
+            "pastes_blocked": report.pastes_blocked,
             "recorder_runs_before": report.recorder_runs_before,
             "total_keystrokes": report.total_keystrokes,
             "enter_pressed": report.enter_pressed,
             "backspace_used": report.backspace_used,
+            "delete_used": report.delete_used,
+            "navigation_keys": report.navigation_keys,
+            "key_usage": report.key_usage.iter().map(|(name, count)| {
+                serde_json::json!({ "key": name, "count": count })
+            }).collect::<Vec<_>>(),
             "paste_events": report.paste_events,
             "total_pasted_chars": report.total_pasted_chars,
-            "commands": report.commands,
+            "longest_input_pause": report.longest_input_pause,
+            "terminal_size": report.terminal_size,
+            "output_dropped": report.output_dropped,
+            "commands": report.commands.iter().map(|c| {
+                serde_json::json!({
+                    "command": c.command,
+                    "duration": c.duration,
+                    "exit_status": c.exit_status,
+                    "cwd": c.cwd,
+                    "mismatch": c.mismatch,
+                })
+            }).collect::<Vec<_>>(),
+            "processes": report.processes.iter().map(|p| {
+                serde_json::json!({
+                    "started": p.started,
+                    "pid": p.pid,
+                    "ppid": p.ppid,
+                    "command": p.command,
+                    "exe": p.exe,
+                    "duration": p.duration,
+                    "flagged": p.flagged,
+                })
+            }).collect::<Vec<_>>(),
+            "connections": report.connections.iter().map(|c| {
+                serde_json::json!({
+                    "timestamp": c.timestamp,
+                    "protocol": c.protocol,
+                    "remote": c.remote,
+                    "local_port": c.local_port,
+                    "inbound": c.inbound,
+                    "pid": c.pid,
+                    "command": c.command,
+                    "allowed": c.allowed,
+                })
+            }).collect::<Vec<_>>(),
+            "pastes": report.pastes.iter().map(|p| {
+                serde_json::json!({
+                    "timestamp": p.timestamp,
+                    "length": p.length,
+                    "text": p.text,
+                    "blocked": p.blocked,
+                })
+            }).collect::<Vec<_>>(),
+            "collected_files": report.collected_files.iter().map(|f| {
+                serde_json::json!({
+                    "path": f.path,
+                    "size": f.size,
+                    "sha256": f.sha256,
+                    "error": f.error,
+                })
+            }).collect::<Vec<_>>(),
+            "watched_files": report.watched_files.iter().map(|f| {
+                serde_json::json!({
+                    "path": f.path,
+                    "versions": f.versions,
+                    "changed": f.changed,
+                    "last": f.last,
+                })
+            }).collect::<Vec<_>>(),
             "suspicious_activities": report.suspicious_activities.iter().map(|a| {
                 serde_json::json!({
                     "timestamp": a.timestamp,
@@ -144,6 +447,11 @@ impl Reporter {
                 })
             }).collect::<Vec<_>>(),
             "integrity_passed": report.integrity_passed,
+            "integrity": report.integrity,
+            "event_chain": report.event_chain,
+            "event_chain_passed": report.event_chain_passed,
+            "recovered": report.recovered,
+            "end_reason": report.end_reason,
         });
         
         let content = serde_json::to_string_pretty(&json)?;
@@ -155,24 +463,116 @@ impl Reporter {
         let mut content = String::new();
         content.push_str("=== Exam Viewer Report ===\n");
         content.push_str("Author: A. Z. M. Arif | https://azmarif.dev\n\n");
+        if let Some(exam_id) = &report.exam_id {
+            content.push_str(&format!("Exam ID:                 {}\n", exam_id));
+        }
+        if let Some(student_id) = &report.student_id {
+            content.push_str(&format!("Student ID:              {}\n", student_id));
+        }
         content.push_str(&format!("Student Username:        {}\n", report.username));
         content.push_str(&format!("Hostname:                {}\n", report.hostname));
         content.push_str(&format!("Machine ID:              {}\n", report.machine_id));
         content.push_str(&format!("Session Duration:        {}\n", report.session_duration));
-        content.push_str(&format!("Recorder Runs Before:    {}\n\n", report.recorder_runs_before));
+        if let Some(limit) = &report.time_limit {
+            content.push_str(&format!("Time Limit:              {}\n", limit));
+        }
+        if !report.time_warnings.is_empty() {
+            content.push_str(&format!("Time Warnings Shown:     {}\n", report.time_warnings.join(", ")));
+        }
+        if let Some(program) = &report.recorded_program {
+            content.push_str(&format!("Recorded Program:        {}\n", program));
+        }
+        if let Some(config) = format_config(report) {
+            content.push_str(&format!("Exam Config:             {}\n", config));
+        }
+        if report.pastes_blocked {
+            content.push_str("Paste Policy:            block\n");
+        }
+        content.push_str(&format!("Recorder Runs Before:    {}\n", report.recorder_runs_before));
+        if let Some(size) = &report.terminal_size {
+            content.push_str(&format!("Terminal Size:           {}\n", size));
+        }
+        if let Some(dropped) = &report.output_dropped {
+            content.push_str(&format!("Output Not Recorded:     {}\n", dropped));
+        }
+        if let Some(reason) = &report.end_reason {
+            content.push_str(&format!("Session Ended By:        {}\n", reason));
+        }
+        if report.recovered {
+            content.push_str("Recovered Session:       YES\n");
+        }
+        content.push('\n');
         content.push_str("--- Typing Statistics ---\n");
         content.push_str(&format!("Total Keystrokes:        {}\n", report.total_keystrokes));
         content.push_str(&format!("Enter Pressed:           {}\n", report.enter_pressed));
         content.push_str(&format!("Backspace Used:          {}\n", report.backspace_used));
+        content.push_str(&format!("Delete Used:             {}\n", report.delete_used));
+        content.push_str(&format!("Navigation Keys:         {}\n", report.navigation_keys));
         content.push_str(&format!("Paste Events:            {}\n", report.paste_events));
-        content.push_str(&format!("Total Pasted Characters: {}\n\n", report.total_pasted_chars));
+        content.push_str(&format!("Total Pasted Characters: {}\n", report.total_pasted_chars));
+        if let Some(gap) = &report.longest_input_pause {
+            content.push_str(&format!("Longest Input Pause:     {}\n", gap));
+        }
+        content.push('\n');
+        
+        if !report.key_usage.is_empty() {
+            content.push_str("--- Key Usage ---\n");
+            content.push_str(&format_key_usage(&report.key_usage));
+            content.push_str("\n\n");
+        }
         
         if !report.commands.is_empty() {
             content.push_str("--- Command Timeline ---\n");
             for (i, cmd) in report.commands.iter().enumerate() {
-                content.push_str(&format!("{}. {}\n", i + 1, cmd));
+                match cmd.details() {
+                    Some(details) => content.push_str(&format!("{}. {}  ({})\n", i + 1, cmd.command, details)),
+                    None => content.push_str(&format!("{}. {}\n", i + 1, cmd.command)),
+                }
+            }
+            content.push('\n');
+        }
+        
+        if !report.processes.is_empty() {
+            content.push_str("--- Process Timeline ---\n");
+            for process in &report.processes {
+                content.push_str(&format!("[{}] {}  ({}){}\n",
+                    process.started, process.command, process.details(), flagged_marker(process.flagged)));
+            }
+            content.push('\n');
+        }
+        
+        if !report.connections.is_empty() {
+            content.push_str("--- Network Connections ---\n");
+            for connection in &report.connections {
+                content.push_str(&format!("[{}] {}{}\n",
+                    connection.timestamp, connection.describe(), allowed_marker(connection.allowed)));
+            }
+            content.push('\n');
+        }
+        
+        if !report.pastes.is_empty() {
+            content.push_str("--- Pasted Text ---\n");
+            for paste in &report.pastes {
+                content.push_str(&format!("[{}] {} chars{}:\n{}\n",
+                    paste.timestamp, paste.length, blocked_marker(paste.blocked), paste.text));
+            }
+            content.push('\n');
+        }
+        
+        if !report.collected_files.is_empty() {
+            content.push_str("--- Collected Files ---\n");
+            for file in &report.collected_files {
+                content.push_str(&format!("{}  ({})\n", file.path, file.details()));
             }
-            content.push_str("\n");
+            content.push('\n');
+        }
+        
+        if !report.watched_files.is_empty() {
+            content.push_str("--- File History ---\n");
+            for file in &report.watched_files {
+                content.push_str(&format!("{}  ({})\n", file.path, file.details()));
+            }
+            content.push('\n');
         }
         
         if !report.suspicious_activities.is_empty() {
@@ -181,14 +581,56 @@ impl Reporter {
                 content.push_str(&format!("[!] {} at {}: {}\n", 
                     activity.severity, activity.timestamp, activity.description));
             }
-            content.push_str("\n");
+            content.push('\n');
         }
         
         content.push_str("--- Integrity ---\n");
-        content.push_str(&format!("SHA256 check: {}\n", 
-            if report.integrity_passed { "PASSED" } else { "FAILED - TAMPERED" }));
+        content.push_str(&format!("SHA256 check: {}\n", integrity_result(report)));
+        if let Some(chain) = &report.event_chain {
+            content.push_str(&format!("Event chain:  {}\n", chain));
+        }
         
         content
     }
 }
 
+/// Config hash with the result of checking it against the expected config.
+fn format_config(report: &AnalysisReport) -> Option<String> {
+    let hash = report.config_hash.as_deref();
+    match (hash, report.config_matches) {
+        (Some(hash), Some(true)) => Some(format!("{} (matches expected config)", hash)),
+        (Some(hash), Some(false)) => Some(format!("{} (DOES NOT MATCH expected config)", hash)),
+        (None, Some(false)) => Some("none (DOES NOT MATCH expected config)".to_string()),
+        (Some(hash), None) => Some(hash.to_string()),
+        (None, _) => None,
+    }
+}
+
+/// "PASSED (manifest v2, 11 entries)" or "FAILED - TAMPERED: <problems>".
+fn integrity_result(report: &AnalysisReport) -> String {
+    if report.integrity_passed {
+        format!("PASSED ({})", report.integrity)
+    } else {
+        format!("FAILED - TAMPERED: {}", report.integrity)
+    }
+}
+
+fn blocked_marker(blocked: bool) -> &'static str {
+    if blocked { ", blocked" } else { "" }
+}
+
+fn flagged_marker(flagged: bool) -> &'static str {
+    if flagged { " FLAGGED" } else { "" }
+}
+
+fn allowed_marker(allowed: bool) -> &'static str {
+    if allowed { " (allowed)" } else { "" }
+}
+
+/// Renders key counts as "Up x12, Delete x3, ...".
+fn format_key_usage(usage: &[(String, usize)]) -> String {
+    usage.iter()
+        .map(|(name, count)| format!("{} x{}", name, count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
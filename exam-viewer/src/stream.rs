@@ -0,0 +1,101 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::io::BufRead;
+
+/// Header line of the asciicast v2 terminal stream.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StreamHeader {
+    pub version: u32,
+    pub width: u16,
+    pub height: u16,
+}
+
+/// One timed chunk of terminal input ("i") or output ("o"), or a terminal
+/// resize ("r", with data "COLSxROWS").
+#[derive(Debug, Clone)]
+pub struct StreamEvent {
+    /// Seconds since the session started, on the recorder's monotonic clock.
+    pub offset: f64,
+    pub kind: String,
+    pub data: String,
+}
+
+/// Timestamped terminal stream from `terminal_stream.cast.enc`.
+#[derive(Debug, Clone)]
+pub struct TerminalStream {
+    pub header: StreamHeader,
+    pub events: Vec<StreamEvent>,
+}
+
+/// What the report needs from the terminal stream, gathered in one pass
+/// without keeping its events.
+#[derive(Debug, Clone)]
+pub struct StreamSummary {
+    pub header: StreamHeader,
+    pub resize_count: usize,
+    /// Longest stretch, in seconds, without any input from the student.
+    pub longest_input_pause: f64,
+}
+
+impl StreamSummary {
+    pub fn read(reader: &mut dyn BufRead) -> Result<Self> {
+        let mut resize_count = 0;
+        let mut longest_input_pause: f64 = 0.0;
+        let mut last_input: Option<f64> = None;
+        let header = read_events(reader, |event| {
+            match event.kind.as_str() {
+                "r" => resize_count += 1,
+                "i" => {
+                    if let Some(last) = last_input {
+                        longest_input_pause = longest_input_pause.max(event.offset - last);
+                    }
+                    last_input = Some(event.offset);
+                }
+                _ => {}
+            }
+        })?;
+        Ok(StreamSummary { header, resize_count, longest_input_pause })
+    }
+}
+
+impl TerminalStream {
+    /// Parses the stream line by line as it is read.
+    pub fn read(reader: &mut dyn BufRead) -> Result<Self> {
+        let mut events = Vec::new();
+        let header = read_events(reader, |event| events.push(event))?;
+        Ok(TerminalStream { header, events })
+    }
+}
+
+/// Parses the stream line by line, passing each event to `each`, and
+/// returns its header.
+fn read_events(reader: &mut dyn BufRead, mut each: impl FnMut(StreamEvent)) -> Result<StreamHeader> {
+    let mut bytes = Vec::new();
+    let mut header = None;
+    let mut index = 0;
+    loop {
+        bytes.clear();
+        if reader.read_until(b'\n', &mut bytes)? == 0 {
+            break;
+        }
+        let line = String::from_utf8_lossy(&bytes);
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if header.is_none() {
+            let parsed: StreamHeader = serde_json::from_str(&line)
+                .context("Invalid terminal stream header")?;
+            if parsed.version != 2 {
+                anyhow::bail!("Unsupported terminal stream version: {}", parsed.version);
+            }
+            header = Some(parsed);
+            continue;
+        }
+        index += 1;
+        let (offset, kind, data): (f64, String, String) = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid terminal stream event {}", index))?;
+        each(StreamEvent { offset, kind, data });
+    }
+    header.context("Terminal stream is empty")
+}